@@ -0,0 +1,1043 @@
+//! JSON API served alongside the debug UI.
+
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use serde_json::{json, Value};
+use warp::http::StatusCode;
+use warp::{Filter, Rejection, Reply};
+
+use crate::breakpoint::Trigger;
+use crate::connection::Direction;
+use crate::sockopt::{self, SocketOptions, Sockets};
+use crate::state::State;
+
+pub fn routes(
+    state: Arc<State>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let list = warp::path!("api" / "connections")
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(with_state(state.clone()))
+        .map(|query: HashMap<String, String>, state: Arc<State>| {
+            // ?active=true leaves out recently closed connections.
+            let active = query.get("active").map(String::as_str) == Some("true");
+            let conns: Vec<Value> = state
+                .connections()
+                .iter()
+                .map(|c| c.summary())
+                .filter(|summary| !active || summary["closed_at"].is_null())
+                .collect();
+            warp::reply::json(&conns)
+        });
+
+    let stats = warp::path!("api" / "stats")
+        .and(warp::get())
+        .and(with_state(state.clone()))
+        .map(|state: Arc<State>| warp::reply::json(&state.snapshot().to_json()));
+
+    let get = warp::path!("api" / "connections" / u64)
+        .and(warp::get())
+        .and(with_state(state.clone()))
+        .map(|id, state: Arc<State>| match state.connection(id) {
+            Some(conn) => reply(StatusCode::OK, conn.detail()),
+            None => not_found(),
+        });
+
+    let kill = warp::path!("api" / "connections" / u64)
+        .and(warp::delete())
+        .and(with_state(state.clone()))
+        .map(|id, state: Arc<State>| match state.connection(id) {
+            Some(conn) if conn.summary()["closed_at"].is_null() => {
+                conn.kill("killed");
+                reply(StatusCode::OK, conn.summary())
+            }
+            Some(_) => reply(
+                StatusCode::CONFLICT,
+                json!({ "error": "connection is already closed" }),
+            ),
+            None => not_found(),
+        });
+
+    let tags = warp::path!("api" / "connections" / u64 / "tags")
+        .and(warp::put())
+        .and(warp::body::json())
+        .and(with_state(state.clone()))
+        .map(|id, body: Value, state: Arc<State>| {
+            let conn = match state.connection(id) {
+                Some(conn) => conn,
+                None => return not_found(),
+            };
+            match parse_tags(body) {
+                Ok(tags) => {
+                    conn.set_tags(tags);
+                    reply(StatusCode::OK, json!(conn.tags()))
+                }
+                Err(err) => reply(StatusCode::BAD_REQUEST, json!({ "error": err })),
+            }
+        });
+
+    let get_defaults = warp::path!("api" / "socket")
+        .and(warp::get())
+        .and(with_state(state.clone()))
+        .map(|state: Arc<State>| {
+            reply(
+                StatusCode::OK,
+                state.socket_defaults.lock().unwrap().to_json(),
+            )
+        });
+
+    let put_defaults = warp::path!("api" / "socket")
+        .and(warp::put())
+        .and(warp::body::json())
+        .and(with_state(state.clone()))
+        .map(
+            |body: Value, state: Arc<State>| match SocketOptions::from_json(&body) {
+                Ok(opts) => {
+                    let mut defaults = state.socket_defaults.lock().unwrap();
+                    defaults.merge(&opts);
+                    reply(StatusCode::OK, defaults.to_json())
+                }
+                Err(err) => reply(StatusCode::BAD_REQUEST, json!({ "error": err })),
+            },
+        );
+
+    let get_socket = warp::path!("api" / "connections" / u64 / "socket")
+        .and(warp::get())
+        .and(with_state(state.clone()))
+        .map(|id, state: Arc<State>| match state.connection(id) {
+            Some(conn) => conn.with_sockets(|sockets| match read_sockets(sockets) {
+                Ok(current) => reply(StatusCode::OK, current),
+                Err(err) => err,
+            }),
+            None => not_found(),
+        });
+
+    let put_socket = warp::path!("api" / "connections" / u64 / "socket")
+        .and(warp::put())
+        .and(warp::body::json())
+        .and(with_state(state.clone()))
+        .map(|id, body: Value, state: Arc<State>| {
+            let conn = match state.connection(id) {
+                Some(conn) => conn,
+                None => return not_found(),
+            };
+            let (side, opts) = match parse_socket_update(body) {
+                Ok(update) => update,
+                Err(err) => return reply(StatusCode::BAD_REQUEST, json!({ "error": err })),
+            };
+            conn.with_sockets(|mut sockets| {
+                if side == "upstream" {
+                    sockets.downstream = None;
+                } else if side == "downstream" {
+                    sockets.upstream = None;
+                }
+                for fd in [sockets.downstream, sockets.upstream].into_iter().flatten() {
+                    if let Err(err) = sockopt::apply(fd, &opts) {
+                        let body = json!({ "error": err.to_string() });
+                        return reply(StatusCode::BAD_REQUEST, body);
+                    }
+                }
+                match read_sockets(sockets) {
+                    Ok(current) => reply(StatusCode::OK, current),
+                    Err(err) => err,
+                }
+            })
+        });
+
+    let transcript = warp::path!("api" / "connections" / u64 / "transcript")
+        .and(warp::get())
+        .and(with_state(state.clone()))
+        .map(|id: u64, state: Arc<State>| {
+            let capture = match state.capture.lock().unwrap().clone() {
+                Some(capture) => capture,
+                None => {
+                    let body = json!({ "error": "start tproxy with --capture for transcripts" });
+                    return reply(StatusCode::NOT_FOUND, body).into_response();
+                }
+            };
+            let events = match capture.transcript(id) {
+                Ok(events) if events.is_empty() => return not_found().into_response(),
+                Ok(events) => events,
+                Err(err) => {
+                    let body = json!({ "error": err });
+                    return reply(StatusCode::INTERNAL_SERVER_ERROR, body).into_response();
+                }
+            };
+            // In capture format, so `tproxy replay` can read it.
+            let body: String = events.iter().map(|event| format!("{}\n", event)).collect();
+            let disposition = format!("attachment; filename=\"tproxy-{}.ndjson\"", id);
+            let reply = warp::reply::with_header(body, "content-type", "application/x-ndjson");
+            warp::reply::with_header(reply, "content-disposition", disposition).into_response()
+        });
+
+    let get_breakpoints = warp::path!("api" / "connections" / u64 / "breakpoints")
+        .and(warp::get())
+        .and(with_state(state.clone()))
+        .map(|id, state: Arc<State>| match state.connection(id) {
+            Some(conn) => reply(StatusCode::OK, conn.breakpoints.to_json()),
+            None => not_found(),
+        });
+
+    let add_breakpoint = warp::path!("api" / "connections" / u64 / "breakpoints")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(with_state(state.clone()))
+        .map(|id, body: Value, state: Arc<State>| {
+            let conn = match state.connection(id) {
+                Some(conn) => conn,
+                None => return not_found(),
+            };
+            let added = parse_breakpoint(&body)
+                .and_then(|(direction, trigger)| conn.breakpoints.add(direction, trigger));
+            match added {
+                Ok(breakpoint) => reply(StatusCode::OK, breakpoint.to_json()),
+                Err(err) => reply(StatusCode::BAD_REQUEST, json!({ "error": err })),
+            }
+        });
+
+    let remove_breakpoint = warp::path!("api" / "connections" / u64 / "breakpoints" / u64)
+        .and(warp::delete())
+        .and(with_state(state.clone()))
+        .map(
+            |id, breakpoint, state: Arc<State>| match state.connection(id) {
+                Some(conn) if conn.breakpoints.remove(breakpoint) => {
+                    reply(StatusCode::OK, conn.breakpoints.to_json())
+                }
+                _ => not_found(),
+            },
+        );
+
+    let resume = warp::path!("api" / "connections" / u64 / "resume")
+        .and(warp::post())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(with_state(state.clone()))
+        .map(|id, query: HashMap<String, String>, state: Arc<State>| {
+            let conn = match state.connection(id) {
+                Some(conn) => conn,
+                None => return not_found(),
+            };
+            let direction = match query.get("direction").map(|d| d.parse()).transpose() {
+                Ok(direction) => direction,
+                Err(err) => return reply(StatusCode::BAD_REQUEST, json!({ "error": err })),
+            };
+            if conn.breakpoints.resume(direction) {
+                reply(StatusCode::OK, conn.breakpoints.to_json())
+            } else {
+                reply(
+                    StatusCode::CONFLICT,
+                    json!({ "error": "connection is not paused" }),
+                )
+            }
+        });
+
+    let get_toxics = warp::path!("api" / "connections" / u64 / "toxics")
+        .and(warp::get())
+        .and(with_state(state.clone()))
+        .map(|id, state: Arc<State>| match state.connection(id) {
+            Some(conn) => reply(StatusCode::OK, conn.toxics().to_json()),
+            None => not_found(),
+        });
+
+    let put_toxics = warp::path!("api" / "connections" / u64 / "toxics")
+        .and(warp::put())
+        .and(warp::body::json())
+        .and(with_state(state.clone()))
+        .map(|id, body: Value, state: Arc<State>| {
+            let conn = match state.connection(id) {
+                Some(conn) => conn,
+                None => return not_found(),
+            };
+            let updated = parse_toxics(body)
+                .and_then(|(direction, toxics)| conn.update_toxics(direction, &toxics));
+            match updated {
+                Ok(()) => reply(StatusCode::OK, conn.toxics().to_json()),
+                Err(err) => reply(StatusCode::BAD_REQUEST, json!({ "error": err })),
+            }
+        });
+
+    let clear_toxics = warp::path!("api" / "connections" / u64 / "toxics")
+        .and(warp::delete())
+        .and(with_state(state.clone()))
+        .map(|id, state: Arc<State>| {
+            let conn = match state.connection(id) {
+                Some(conn) => conn,
+                None => return not_found(),
+            };
+            let clear = json!({ "latency": null, "jitter": null, "rate": null });
+            conn.update_toxics(None, &clear).unwrap();
+            reply(StatusCode::OK, conn.toxics().to_json())
+        });
+
+    let latency = warp::path!("api" / "latency")
+        .and(warp::get())
+        .and(with_state(state.clone()))
+        .map(|state: Arc<State>| {
+            reply(
+                StatusCode::OK,
+                json!({ "connect": state.connect_heatmap.summary() }),
+            )
+        });
+
+    let nat = warp::path!("api" / "nat")
+        .and(warp::get())
+        .and(with_state(state.clone()))
+        .map(|state: Arc<State>| {
+            let entries: Vec<Value> = state
+                .connections()
+                .iter()
+                .filter_map(|c| c.nat_entry())
+                .collect();
+            warp::reply::json(&entries)
+        });
+
+    let quotas = warp::path!("api" / "quotas")
+        .and(warp::get())
+        .and(with_state(state.clone()))
+        .map(|state: Arc<State>| reply(StatusCode::OK, state.quotas.to_json()));
+
+    let probes = warp::path!("api" / "probes")
+        .and(warp::get())
+        .and(with_state(state.clone()))
+        .map(|state: Arc<State>| {
+            let mut probes: Vec<_> = state.probes.snapshot().into_iter().collect();
+            probes.sort_by(|a, b| a.0.cmp(&b.0));
+            let probes: Vec<Value> = probes
+                .iter()
+                .map(|(upstream, probe)| probe.to_json(upstream))
+                .collect();
+            warp::reply::json(&probes)
+        });
+
+    let routes = warp::path!("api" / "routes")
+        .and(warp::get())
+        .and(with_state(state.clone()))
+        .map(|state: Arc<State>| {
+            let mut routes: Vec<_> = state.routes.snapshot().into_iter().collect();
+            routes.sort_by(|a, b| a.0.cmp(&b.0));
+            let routes: Vec<Value> = routes
+                .iter()
+                .map(|(name, stats)| stats.to_json(name))
+                .collect();
+            warp::reply::json(&routes)
+        });
+
+    let http_connect_hosts = warp::path!("api" / "http-connect" / "hosts")
+        .and(warp::get())
+        .and(with_state(state.clone()))
+        .map(|state: Arc<State>| {
+            let mut hosts: Vec<_> = state.http_connect_hosts.snapshot().into_iter().collect();
+            hosts.sort_by(|a, b| a.0.cmp(&b.0));
+            let hosts: Vec<Value> = hosts
+                .iter()
+                .map(|(host, stats)| stats.to_json(host))
+                .collect();
+            warp::reply::json(&hosts)
+        });
+
+    let get_drain = warp::path!("api" / "drain")
+        .and(warp::get())
+        .and(with_state(state.clone()))
+        .map(|state: Arc<State>| reply(StatusCode::OK, drain_status(&state)));
+
+    let start_drain = warp::path!("api" / "drain")
+        .and(warp::post())
+        .and(with_state(state.clone()))
+        .map(|state: Arc<State>| {
+            state.draining.send_replace(true);
+            reply(StatusCode::OK, drain_status(&state))
+        });
+
+    let stop_drain = warp::path!("api" / "drain")
+        .and(warp::delete())
+        .and(with_state(state.clone()))
+        .map(|state: Arc<State>| {
+            state.draining.send_replace(false);
+            reply(StatusCode::OK, drain_status(&state))
+        });
+
+    let heatmap = warp::path!("api" / "latency" / "heatmap")
+        .and(warp::get())
+        .and(with_state(state.clone()))
+        .map(|state: Arc<State>| {
+            reply(
+                StatusCode::OK,
+                json!({ "connect": state.connect_heatmap.series() }),
+            )
+        });
+
+    let bandwidth = warp::path!("api" / "bandwidth")
+        .and(warp::get())
+        .and(with_state(state.clone()))
+        .map(|state: Arc<State>| {
+            state.bandwidth.update(state.bytes_by_upstream());
+            reply(StatusCode::OK, state.bandwidth.to_json())
+        });
+
+    let get_overrides = warp::path!("api" / "resolver" / "overrides")
+        .and(warp::get())
+        .and(with_state(state.clone()))
+        .map(|state: Arc<State>| reply(StatusCode::OK, state.resolver.to_json()));
+
+    let put_overrides = warp::path!("api" / "resolver" / "overrides")
+        .and(warp::put())
+        .and(warp::body::json())
+        .and(with_state(state.clone()))
+        .map(
+            |body: Value, state: Arc<State>| match state.resolver.update(&body) {
+                Ok(()) => reply(StatusCode::OK, state.resolver.to_json()),
+                Err(err) => reply(StatusCode::BAD_REQUEST, json!({ "error": err })),
+            },
+        );
+
+    let clear_overrides = warp::path!("api" / "resolver" / "overrides")
+        .and(warp::delete())
+        .and(with_state(state.clone()))
+        .map(|state: Arc<State>| {
+            state.resolver.clear();
+            reply(StatusCode::OK, state.resolver.to_json())
+        });
+
+    let remove_override = warp::path!("api" / "resolver" / "overrides" / String)
+        .and(warp::delete())
+        .and(with_state(state.clone()))
+        .map(|host: String, state: Arc<State>| {
+            if !state.resolver.remove(&host) {
+                return not_found();
+            }
+            reply(StatusCode::OK, state.resolver.to_json())
+        });
+
+    let purge_cache = warp::path!("api" / "http" / "cache")
+        .and(warp::delete())
+        .and(with_state(state.clone()))
+        .map(|state: Arc<State>| {
+            let purged = state.http_cache.purge();
+            reply(StatusCode::OK, json!({ "purged": purged }))
+        });
+
+    let get_connect_toxic = warp::path!("api" / "toxics" / "connect")
+        .and(warp::get())
+        .and(with_state(state.clone()))
+        .map(|state: Arc<State>| {
+            reply(
+                StatusCode::OK,
+                state.connect_toxic.lock().unwrap().to_json(),
+            )
+        });
+
+    let put_connect_toxic = warp::path!("api" / "toxics" / "connect")
+        .and(warp::put())
+        .and(warp::body::json())
+        .and(with_state(state.clone()))
+        .map(|body: Value, state: Arc<State>| {
+            let mut toxic = state.connect_toxic.lock().unwrap();
+            match toxic.update(&body) {
+                Ok(()) => reply(StatusCode::OK, toxic.to_json()),
+                Err(err) => reply(StatusCode::BAD_REQUEST, json!({ "error": err })),
+            }
+        });
+
+    let get_toxic_defaults = warp::path!("api" / "toxics")
+        .and(warp::get())
+        .and(with_state(state.clone()))
+        .map(|state: Arc<State>| {
+            reply(
+                StatusCode::OK,
+                state.toxic_defaults.lock().unwrap().to_json(),
+            )
+        });
+
+    let put_toxic_defaults = warp::path!("api" / "toxics")
+        .and(warp::put())
+        .and(warp::body::json())
+        .and(with_state(state.clone()))
+        .map(|body: Value, state: Arc<State>| {
+            let mut defaults = state.toxic_defaults.lock().unwrap();
+            let updated = parse_toxics(body)
+                .and_then(|(direction, toxics)| defaults.update(direction, &toxics));
+            match updated {
+                Ok(()) => reply(StatusCode::OK, defaults.to_json()),
+                Err(err) => reply(StatusCode::BAD_REQUEST, json!({ "error": err })),
+            }
+        });
+
+    let config_history = warp::path!("api" / "config" / "history")
+        .and(warp::get())
+        .and(with_state(state.clone()))
+        .map(|state: Arc<State>| reply(StatusCode::OK, state.config_history.to_json()));
+
+    let config_rollback = warp::path!("api" / "config" / "rollback" / usize)
+        .and(warp::post())
+        .and(with_state(state))
+        .then(|n, state: Arc<State>| async move {
+            if n >= state.config_history.len() {
+                return not_found();
+            }
+            match state.config_history.rollback(n).await {
+                Ok(()) => reply(StatusCode::OK, state.config_history.to_json()),
+                Err(err) => reply(StatusCode::CONFLICT, json!({ "error": err })),
+            }
+        });
+
+    // Boxed in groups, as one chain of every route is too deep a type to compile.
+    let connections = list
+        .or(get)
+        .or(kill)
+        .or(tags)
+        .or(transcript)
+        .or(get_toxics)
+        .or(put_toxics)
+        .or(clear_toxics)
+        .or(get_breakpoints)
+        .or(add_breakpoint)
+        .or(remove_breakpoint)
+        .or(resume)
+        .or(get_socket)
+        .or(put_socket)
+        .boxed();
+
+    connections
+        .or(stats)
+        .or(latency)
+        .or(heatmap)
+        .or(bandwidth)
+        .or(nat)
+        .or(quotas)
+        .or(probes)
+        .or(routes)
+        .or(http_connect_hosts)
+        .or(get_drain)
+        .or(start_drain)
+        .or(stop_drain)
+        .or(get_overrides)
+        .or(put_overrides)
+        .or(clear_overrides)
+        .or(remove_override)
+        .or(purge_cache)
+        .or(get_connect_toxic)
+        .or(put_connect_toxic)
+        .or(get_toxic_defaults)
+        .or(put_toxic_defaults)
+        .or(get_defaults)
+        .or(put_defaults)
+        .or(config_history)
+        .or(config_rollback)
+}
+
+/// Whether the proxy is draining, and how many connections it has yet to finish.
+fn drain_status(state: &State) -> Value {
+    json!({
+        "draining": state.is_draining(),
+        "active_connections": state.active_connections.load(Ordering::Relaxed),
+    })
+}
+
+/// Breakpoints are a `direction` of `up` or `down` and a trigger for
+/// `Trigger::from_json`.
+fn parse_breakpoint(body: &Value) -> Result<(Direction, Trigger), String> {
+    let direction = body
+        .get("direction")
+        .and_then(Value::as_str)
+        .ok_or("direction must be up or down")?
+        .parse()?;
+    Ok((direction, Trigger::from_json(body)?))
+}
+
+/// Toxic updates are an object for `Toxic::update`, plus an optional `direction` of
+/// `up`, `down` or `both` (the default).
+fn parse_toxics(mut body: Value) -> Result<(Option<Direction>, Value), String> {
+    let direction = match body
+        .as_object_mut()
+        .and_then(|fields| fields.remove("direction"))
+    {
+        None => None,
+        Some(Value::String(direction)) if direction == "both" => None,
+        Some(Value::String(direction)) => Some(direction.parse()?),
+        Some(_) => return Err("direction must be up, down or both".to_string()),
+    };
+    Ok((direction, body))
+}
+
+/// Socket updates are an object of options for `SocketOptions::from_json`, plus an
+/// optional `side` of `downstream`, `upstream` or `both` (the default).
+fn parse_socket_update(mut body: Value) -> Result<(String, SocketOptions), String> {
+    let side = match body
+        .as_object_mut()
+        .and_then(|fields| fields.remove("side"))
+    {
+        None => "both".to_string(),
+        Some(Value::String(side)) if ["downstream", "upstream", "both"].contains(&&*side) => side,
+        Some(_) => return Err("side must be downstream, upstream or both".to_string()),
+    };
+    Ok((side, SocketOptions::from_json(&body)?))
+}
+
+/// The current options of a connection's sockets, or a conflict if it isn't being
+/// proxied.
+fn read_sockets(sockets: Sockets) -> Result<Value, warp::reply::WithStatus<warp::reply::Json>> {
+    let (downstream, upstream) = match (sockets.downstream, sockets.upstream) {
+        (None, None) => {
+            return Err(reply(
+                StatusCode::CONFLICT,
+                json!({ "error": "connection has no open sockets" }),
+            ))
+        }
+        sides => sides,
+    };
+    let read = |fd: Option<_>| {
+        fd.map(sockopt::read).transpose().map_err(|err| {
+            reply(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                json!({ "error": err.to_string() }),
+            )
+        })
+    };
+    Ok(json!({ "downstream": read(downstream)?, "upstream": read(upstream)? }))
+}
+
+/// Tag updates are a JSON object of string values, or `null` to remove a tag.
+fn parse_tags(body: Value) -> Result<Vec<(String, Option<String>)>, String> {
+    let fields = match body {
+        Value::Object(fields) => fields,
+        _ => return Err("expected an object of tags".to_string()),
+    };
+    fields
+        .into_iter()
+        .map(|(key, value)| match value {
+            _ if key.is_empty() => Err("tag keys must not be empty".to_string()),
+            Value::String(value) => Ok((key, Some(value))),
+            Value::Null => Ok((key, None)),
+            _ => Err(format!("tag {} must be a string or null", key)),
+        })
+        .collect()
+}
+
+fn with_state(
+    state: Arc<State>,
+) -> impl Filter<Extract = (Arc<State>,), Error = Infallible> + Clone {
+    warp::any().map(move || state.clone())
+}
+
+fn reply(status: StatusCode, body: Value) -> warp::reply::WithStatus<warp::reply::Json> {
+    warp::reply::with_status(warp::reply::json(&body), status)
+}
+
+fn not_found() -> warp::reply::WithStatus<warp::reply::Json> {
+    reply(StatusCode::NOT_FOUND, json!({ "error": "not found" }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    use crate::connection::Direction;
+
+    #[tokio::test]
+    async fn test_get_connection() {
+        let state = Arc::new(State::new());
+        let conn = state.open_connection("127.0.0.1:1234".parse().unwrap(), "up:80".into());
+        state.close_connection(&conn, "completed".into());
+        let routes = routes(state);
+
+        let resp = warp::test::request()
+            .path("/api/connections")
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(body[0]["id"], conn.id);
+        assert!(body[0]["duration_ms"].is_u64());
+
+        let resp = warp::test::request()
+            .path("/api/connections?active=true")
+            .reply(&routes)
+            .await;
+        let body: Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(body, json!([]));
+
+        let resp = warp::test::request()
+            .path("/api/stats")
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(body["active_connections"], 0);
+        assert_eq!(body["clients"], 0);
+
+        let resp = warp::test::request()
+            .path(&format!("/api/connections/{}", conn.id))
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(body["close_reason"], "completed");
+        assert_eq!(body["downstream_addr"], "127.0.0.1:1234");
+
+        let resp = warp::test::request()
+            .path("/api/connections/999")
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_transcript() {
+        let state = Arc::new(State::new());
+        let routes = routes(state.clone());
+        let resp = warp::test::request()
+            .path("/api/connections/1/transcript")
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let path = std::env::temp_dir().join(format!("tproxy-transcript-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        let writer = crate::capture::Writer::rotating(path, 0, None).unwrap();
+        writer.record(1, Direction::Up, b"PING\r\n");
+        writer.record(1, Direction::Down, b"PONG\r\n");
+        *state.capture.lock().unwrap() = Some(Arc::new(writer));
+        let resp = warp::test::request()
+            .path("/api/connections/1/transcript")
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers()["content-disposition"],
+            "attachment; filename=\"tproxy-1.ndjson\""
+        );
+        let body = std::str::from_utf8(resp.body()).unwrap();
+        let events: Vec<Value> = body
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[1]["data"], "PONG\r\n");
+
+        let resp = warp::test::request()
+            .path("/api/connections/2/transcript")
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_put_tags() {
+        let state = Arc::new(State::new());
+        let conn = state.open_connection("127.0.0.1:1234".parse().unwrap(), "up:80".into());
+        let routes = routes(state);
+        let path = format!("/api/connections/{}/tags", conn.id);
+
+        let resp = warp::test::request()
+            .method("PUT")
+            .path(&path)
+            .json(&json!({ "team": "db", "env": "staging" }))
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let resp = warp::test::request()
+            .method("PUT")
+            .path(&path)
+            .json(&json!({ "env": null }))
+            .reply(&routes)
+            .await;
+        let body: Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(body, json!({ "team": "db" }));
+
+        let resp = warp::test::request()
+            .method("PUT")
+            .path(&path)
+            .json(&json!({ "team": 1 }))
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_socket_options() {
+        use std::os::unix::io::AsRawFd;
+
+        let state = Arc::new(State::new());
+        let conn = state.open_connection("127.0.0.1:1234".parse().unwrap(), "up:80".into());
+        let routes = routes(state.clone());
+        let path = format!("/api/connections/{}/socket", conn.id);
+
+        let resp = warp::test::request()
+            .method("PUT")
+            .path("/api/socket")
+            .json(&json!({ "nodelay": true }))
+            .reply(&routes)
+            .await;
+        let body: Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(body["nodelay"], true);
+        assert_eq!(state.socket_defaults.lock().unwrap().nodelay, Some(true));
+
+        let resp = warp::test::request().path(&path).reply(&routes).await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = tokio::net::TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (downstream, _) = listener.accept().await.unwrap();
+        let guard = conn.register_sockets(downstream.as_raw_fd(), upstream.as_raw_fd());
+
+        let resp = warp::test::request()
+            .method("PUT")
+            .path(&path)
+            .json(&json!({ "side": "upstream", "nodelay": true, "keepalive": "45s" }))
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(body["downstream"], Value::Null);
+        assert_eq!(body["upstream"]["nodelay"], true);
+        assert_eq!(body["upstream"]["keepalive"], "45s");
+
+        let resp = warp::test::request().path(&path).reply(&routes).await;
+        let body: Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(body["downstream"]["keepalive"], "0s");
+        assert_eq!(body["upstream"]["keepalive"], "45s");
+
+        let resp = warp::test::request()
+            .method("PUT")
+            .path(&path)
+            .json(&json!({ "side": "left" }))
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        drop(guard);
+        let resp = warp::test::request().path(&path).reply(&routes).await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+    }
+
+    #[tokio::test]
+    async fn test_breakpoints() {
+        let state = Arc::new(State::new());
+        let conn = state.open_connection("127.0.0.1:1234".parse().unwrap(), "up:80".into());
+        let routes = routes(state);
+        let path = format!("/api/connections/{}/breakpoints", conn.id);
+
+        let resp = warp::test::request()
+            .method("POST")
+            .path(&path)
+            .json(&json!({ "direction": "down", "pattern": "+OK" }))
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(body["pattern_hex"], "2b4f4b");
+        let id = body["id"].as_u64().unwrap();
+
+        let resp = warp::test::request()
+            .method("POST")
+            .path(&path)
+            .json(&json!({ "direction": "sideways", "offset": 1 }))
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let resp = warp::test::request().path(&path).reply(&routes).await;
+        let body: Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(body["breakpoints"][0]["direction"], "down");
+        assert_eq!(body["paused"], json!({ "up": null, "down": null }));
+
+        let resp = warp::test::request()
+            .method("POST")
+            .path(&format!(
+                "/api/connections/{}/resume?direction=down",
+                conn.id
+            ))
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+
+        let resp = warp::test::request()
+            .method("DELETE")
+            .path(&format!("{}/{}", path, id))
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = warp::test::request()
+            .method("DELETE")
+            .path(&format!("{}/{}", path, id))
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_latency() {
+        let state = Arc::new(State::new());
+        let conn = state.open_connection("127.0.0.1:1234".parse().unwrap(), "up:80".into());
+        state.observe_connect(&conn, Duration::from_millis(2));
+        state.observe_connect(&conn, Duration::from_millis(40));
+        let routes = routes(state);
+
+        let resp = warp::test::request()
+            .path("/api/latency")
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(body["connect"]["count"], 2);
+        assert_eq!(body["connect"]["max_ms"], 40.0);
+
+        let resp = warp::test::request()
+            .path("/api/latency/heatmap")
+            .reply(&routes)
+            .await;
+        let body: Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(body["connect"]["buckets_ms"].as_array().unwrap().len(), 2);
+        let windows = body["connect"]["windows"].as_array().unwrap();
+        assert_eq!(
+            windows
+                .iter()
+                .map(|w| w["count"].as_u64().unwrap())
+                .sum::<u64>(),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bandwidth() {
+        let state = Arc::new(State::new());
+        let closed = state.open_connection("127.0.0.1:1234".parse().unwrap(), "up:80".into());
+        closed.add_bytes(Direction::Up, 3);
+        state.close_connection(&closed, "completed".into());
+        let open = state.open_connection("127.0.0.1:1235".parse().unwrap(), "up:80".into());
+        open.add_bytes(Direction::Down, 5);
+        let routes = routes(state);
+
+        let resp = warp::test::request()
+            .path("/api/bandwidth")
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: Value = serde_json::from_slice(resp.body()).unwrap();
+        let windows = &body["minute"]["upstreams"]["up:80"];
+        assert_eq!(windows[0]["bytes_up"], 3);
+        assert_eq!(windows[0]["bytes_down"], 5);
+        assert_eq!(body["hour"]["total"], body["hour"]["upstreams"]["up:80"]);
+    }
+
+    #[tokio::test]
+    async fn test_resolver_overrides() {
+        let state = Arc::new(State::new());
+        let routes = routes(state.clone());
+
+        let resp = warp::test::request()
+            .method("PUT")
+            .path("/api/resolver/overrides")
+            .json(&json!({ "db.internal": ["127.0.0.1"] }))
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(body, json!({ "db.internal": ["127.0.0.1"] }));
+        assert_eq!(
+            state.resolver.resolve("db.internal:80").await.unwrap(),
+            ["127.0.0.1:80".parse().unwrap()]
+        );
+
+        let resp = warp::test::request()
+            .method("PUT")
+            .path("/api/resolver/overrides")
+            .json(&json!({ "db.internal": "127.0.0.1" }))
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let resp = warp::test::request()
+            .method("DELETE")
+            .path("/api/resolver/overrides/db.internal")
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = warp::test::request()
+            .method("DELETE")
+            .path("/api/resolver/overrides/db.internal")
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_toxics() {
+        let state = Arc::new(State::new());
+        let conn = state.open_connection("127.0.0.1:1234".parse().unwrap(), "up:80".into());
+        let other = state.open_connection("127.0.0.1:1235".parse().unwrap(), "up:80".into());
+        let routes = routes(state.clone());
+        let path = format!("/api/connections/{}/toxics", conn.id);
+
+        let resp = warp::test::request()
+            .method("PUT")
+            .path(&path)
+            .json(&json!({ "direction": "down", "latency": "200ms", "rate": "1MBps" }))
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(body["down"]["latency"], "200ms");
+        assert_eq!(body["down"]["rate"], 1_000_000);
+        assert_eq!(body["up"]["rate"], Value::Null);
+        assert_eq!(other.toxics(), Default::default());
+
+        let resp = warp::test::request()
+            .method("PUT")
+            .path(&path)
+            .json(&json!({ "latency": "fast" }))
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let resp = warp::test::request()
+            .method("DELETE")
+            .path(&path)
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(conn.toxics(), Default::default());
+
+        // Defaults apply to connections opened after they're set.
+        let resp = warp::test::request()
+            .method("PUT")
+            .path("/api/toxics")
+            .json(&json!({ "direction": "up", "rate": "5Mbps" }))
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(body["up"]["rate"], 625_000);
+        assert_eq!(conn.toxics(), Default::default());
+        let conn = state.open_connection("127.0.0.1:1236".parse().unwrap(), "up:80".into());
+        assert_eq!(conn.toxics().up.rate, Some(625_000));
+        assert_eq!(conn.toxics().down.rate, None);
+
+        let resp = warp::test::request()
+            .method("PUT")
+            .path("/api/toxics/connect")
+            .json(&json!({ "delay": "50ms", "refuse": 0.25 }))
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(body["delay"], "50ms");
+        assert_eq!(state.connect_toxic.lock().unwrap().refuse, 0.25);
+        let resp = warp::test::request()
+            .method("PUT")
+            .path("/api/toxics/connect")
+            .json(&json!({ "timeout": 0.9 }))
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(state.connect_toxic.lock().unwrap().timeout, 0.0);
+    }
+}
@@ -0,0 +1,164 @@
+//! Load balancing across a comma-separated `--upstream-addr`, by `--lb-strategy`.
+//!
+//! Each address may carry a weight, as ADDR=WEIGHT, for the weighted strategy; the
+//! others ignore weights. Round-robin and weighted take turns in list order, so which
+//! upstream gets each connection is predictable in tests; least-conn goes by the
+//! connections open to each upstream now, the first in the list winning ties. Upstreams
+//! ejected by `--eject-after` are passed over while any other isn't.
+
+use std::str::FromStr;
+
+use rand::seq::SliceRandom;
+
+use crate::breaker;
+use crate::state::State;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Strategy {
+    RoundRobin,
+    LeastConn,
+    Random,
+    Weighted,
+}
+
+impl FromStr for Strategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "round-robin" => Ok(Strategy::RoundRobin),
+            "least-conn" => Ok(Strategy::LeastConn),
+            "random" => Ok(Strategy::Random),
+            "weighted" => Ok(Strategy::Weighted),
+            _ => Err(format!("unknown load-balancing strategy: {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Upstream<'a> {
+    pub addr: &'a str,
+    pub weight: u32,
+}
+
+/// Whether `upstream_addr` needs balancing, rather than being a single address.
+pub fn is_list(upstream_addr: &str) -> bool {
+    upstream_addr.contains(',') || upstream_addr.contains('=')
+}
+
+/// The upstreams in `upstream_addr`, ADDR[=WEIGHT],...
+pub fn parse(upstream_addr: &str) -> Result<Vec<Upstream<'_>>, String> {
+    upstream_addr
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| match entry.split_once('=') {
+            None => Ok(Upstream {
+                addr: entry,
+                weight: 1,
+            }),
+            Some((addr, weight)) => match weight.parse() {
+                Ok(weight) if weight > 0 && !addr.is_empty() => Ok(Upstream { addr, weight }),
+                _ => Err(format!("weights are whole numbers above 0: {}", entry)),
+            },
+        })
+        .collect()
+}
+
+/// The address of the next upstream in `upstream_addr` to forward a connection to.
+pub fn pick(upstream_addr: &str, strategy: Strategy, state: &State) -> String {
+    // Lists are checked at startup.
+    let mut upstreams = parse(upstream_addr).unwrap_or_default();
+    // Ejected upstreams only get connections if they all are, to fail fast.
+    if upstreams
+        .iter()
+        .any(|u| !breaker::is_ejected(state, u.addr))
+    {
+        upstreams.retain(|u| !breaker::is_ejected(state, u.addr));
+    }
+    let turn = |modulo: usize| {
+        state
+            .round_robin
+            .with_entry(upstream_addr.to_string(), 0, |next| {
+                let turn = *next % modulo.max(1);
+                *next = turn + 1;
+                turn
+            })
+    };
+    let picked = match strategy {
+        Strategy::RoundRobin => upstreams.get(turn(upstreams.len())),
+        Strategy::LeastConn => upstreams
+            .iter()
+            .min_by_key(|u| state.upstream_open.get(&u.addr.to_string()).unwrap_or(0)),
+        Strategy::Random => upstreams.choose(&mut rand::thread_rng()),
+        Strategy::Weighted => {
+            let total = upstreams.iter().map(|u| u.weight as usize).sum();
+            let mut turn = turn(total);
+            upstreams
+                .iter()
+                .find(|u| match turn.checked_sub(u.weight as usize) {
+                    Some(rest) => {
+                        turn = rest;
+                        false
+                    }
+                    None => true,
+                })
+        }
+    };
+    picked.map_or(upstream_addr, |u| u.addr).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            parse("a:1=3, b:1").unwrap(),
+            [
+                Upstream {
+                    addr: "a:1",
+                    weight: 3
+                },
+                Upstream {
+                    addr: "b:1",
+                    weight: 1
+                },
+            ]
+        );
+        assert!(parse("a:1=0").is_err());
+        assert!(parse("a:1=heavy").is_err());
+        assert!(is_list("a:1=2"));
+        assert!(!is_list("a:1"));
+    }
+
+    #[test]
+    fn test_pick() {
+        let state = State::new();
+        let picks = |list: &str, strategy, n| {
+            (0..n)
+                .map(|_| pick(list, strategy, &state))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            picks("a:1,b:1", Strategy::RoundRobin, 3),
+            ["a:1", "b:1", "a:1"]
+        );
+        assert_eq!(
+            picks("a:1=3,b:1", Strategy::Weighted, 5),
+            ["a:1", "a:1", "a:1", "b:1", "a:1"]
+        );
+        assert!(picks("a:1,b:1", Strategy::Random, 10)
+            .iter()
+            .all(|addr| addr == "a:1" || addr == "b:1"));
+
+        state.open_connection("127.0.0.1:1000".parse().unwrap(), "a:1".into());
+        assert_eq!(pick("a:1,b:1", Strategy::LeastConn, &state), "b:1");
+        state.open_connection("127.0.0.1:1001".parse().unwrap(), "b:1".into());
+        let conn = state.open_connection("127.0.0.1:1002".parse().unwrap(), "b:1".into());
+        assert_eq!(pick("a:1,b:1", Strategy::LeastConn, &state), "a:1");
+        state.close_connection(&conn, "completed".into());
+        assert_eq!(pick("a:1,b:1", Strategy::LeastConn, &state), "a:1");
+    }
+}
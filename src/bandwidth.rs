@@ -0,0 +1,227 @@
+//! Bytes copied per minute and per hour, in total and by upstream, for
+//! `/api/bandwidth`.
+//!
+//! Once a second [`run`] diffs the byte totals against the last tick and adds the
+//! difference to the current minute and hour. Only windows with traffic are kept, and
+//! only the last [`MINUTES`] minutes and [`HOURS`] hours of them, so a usage report
+//! after a test run needs nothing but the proxy itself.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde_json::{json, Value};
+
+use crate::state::State;
+
+pub const MINUTE: Duration = Duration::from_secs(60);
+pub const HOUR: Duration = Duration::from_secs(60 * 60);
+pub const MINUTES: u64 = 24 * 60;
+pub const HOURS: u64 = 7 * 24;
+
+/// Upstreams with their own series. Traffic to any more is counted under
+/// [`OTHER`] until one of them has gone a week without traffic.
+pub const MAX_UPSTREAMS: usize = 100;
+pub const OTHER: &str = "other";
+
+const TICK: Duration = Duration::from_secs(1);
+
+/// Bytes up and down by the start of their window, in seconds since the epoch.
+#[derive(Debug, Default)]
+struct Windows(VecDeque<(u64, u64, u64)>);
+
+impl Windows {
+    fn add(&mut self, interval: Duration, secs: u64, up: u64, down: u64) {
+        let start = secs - secs % interval.as_secs();
+        match self.0.back_mut() {
+            // A clock stepping backwards lands in the latest window.
+            Some((s, u, d)) if *s >= start => {
+                *u += up;
+                *d += down;
+            }
+            _ => self.0.push_back((start, up, down)),
+        }
+    }
+
+    fn prune(&mut self, interval: Duration, windows: u64, secs: u64) {
+        let oldest = secs.saturating_sub(interval.as_secs() * windows);
+        while self.0.front().is_some_and(|(start, _, _)| *start < oldest) {
+            self.0.pop_front();
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        self.0
+            .iter()
+            .map(|(start, up, down)| json!({ "start": start, "bytes_up": up, "bytes_down": down }))
+            .collect()
+    }
+}
+
+#[derive(Debug, Default)]
+struct Series {
+    minutes: Windows,
+    hours: Windows,
+}
+
+impl Series {
+    fn add(&mut self, secs: u64, up: u64, down: u64) {
+        self.minutes.add(MINUTE, secs, up, down);
+        self.hours.add(HOUR, secs, up, down);
+    }
+
+    fn prune(&mut self, secs: u64) {
+        self.minutes.prune(MINUTE, MINUTES, secs);
+        self.hours.prune(HOUR, HOURS, secs);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.hours.0.is_empty()
+    }
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    /// The totals at the last update, by upstream.
+    last: HashMap<String, (u64, u64)>,
+    total: Series,
+    upstreams: HashMap<String, Series>,
+}
+
+#[derive(Debug, Default)]
+pub struct Bandwidth {
+    inner: Mutex<Inner>,
+}
+
+impl Bandwidth {
+    /// Count whatever `totals`, bytes up and down so far by upstream, have grown by
+    /// since the last update.
+    pub fn update(&self, totals: HashMap<String, (u64, u64)>) {
+        self.update_at(totals, SystemTime::now());
+    }
+
+    fn update_at(&self, totals: HashMap<String, (u64, u64)>, now: SystemTime) {
+        let secs = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let mut inner = self.inner.lock().unwrap();
+        let inner = &mut *inner;
+        for (upstream, (up, down)) in &totals {
+            let (last_up, last_down) = inner.last.get(upstream).copied().unwrap_or_default();
+            let (up, down) = (up.saturating_sub(last_up), down.saturating_sub(last_down));
+            if up == 0 && down == 0 {
+                continue;
+            }
+            inner.total.add(secs, up, down);
+            let key = if inner.upstreams.contains_key(upstream)
+                || inner.upstreams.len() < MAX_UPSTREAMS
+            {
+                upstream.as_str()
+            } else {
+                OTHER
+            };
+            inner
+                .upstreams
+                .entry(key.to_string())
+                .or_default()
+                .add(secs, up, down);
+        }
+        inner.last = totals;
+        inner.total.prune(secs);
+        inner.upstreams.retain(|_, series| {
+            series.prune(secs);
+            !series.is_empty()
+        });
+    }
+
+    pub fn to_json(&self) -> Value {
+        let inner = self.inner.lock().unwrap();
+        let window = |interval: Duration, windows: fn(&Series) -> &Windows| {
+            let upstreams: serde_json::Map<String, Value> = inner
+                .upstreams
+                .iter()
+                .map(|(upstream, series)| (upstream.clone(), windows(series).to_json()))
+                .collect();
+            json!({
+                "interval_s": interval.as_secs(),
+                "total": windows(&inner.total).to_json(),
+                "upstreams": upstreams,
+            })
+        };
+        json!({
+            "minute": window(MINUTE, |series| &series.minutes),
+            "hour": window(HOUR, |series| &series.hours),
+        })
+    }
+}
+
+/// Keep `state.bandwidth` up to date with the byte totals.
+pub async fn run(state: Arc<State>) {
+    let mut interval = tokio::time::interval(TICK);
+    loop {
+        interval.tick().await;
+        state.bandwidth.update(state.bytes_by_upstream());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_update() {
+        let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+        let totals = |entries: &[(&str, u64, u64)]| {
+            entries
+                .iter()
+                .map(|(upstream, up, down)| (upstream.to_string(), (*up, *down)))
+                .collect()
+        };
+        let bandwidth = Bandwidth::default();
+        bandwidth.update_at(totals(&[("a:1", 10, 20)]), at(3600));
+        bandwidth.update_at(totals(&[("a:1", 15, 20), ("b:1", 1, 1)]), at(3630));
+        // Nothing new this minute, so no window for it.
+        bandwidth.update_at(totals(&[("a:1", 15, 20), ("b:1", 1, 1)]), at(3690));
+        bandwidth.update_at(totals(&[("a:1", 15, 20), ("b:1", 2, 4)]), at(3725));
+
+        let json = bandwidth.to_json();
+        assert_eq!(
+            json["minute"]["total"],
+            json!([
+                { "start": 3600, "bytes_up": 16, "bytes_down": 21 },
+                { "start": 3720, "bytes_up": 1, "bytes_down": 3 },
+            ])
+        );
+        assert_eq!(
+            json["minute"]["upstreams"]["a:1"],
+            json!([{ "start": 3600, "bytes_up": 15, "bytes_down": 20 }])
+        );
+        assert_eq!(
+            json["hour"]["upstreams"]["b:1"],
+            json!([{ "start": 3600, "bytes_up": 2, "bytes_down": 4 }])
+        );
+        assert_eq!(json["hour"]["interval_s"], 3600);
+
+        // A day on, the minutes have gone but the hours remain.
+        bandwidth.update_at(totals(&[("a:1", 16, 20), ("b:1", 2, 4)]), at(3780 + 86400));
+        let json = bandwidth.to_json();
+        assert_eq!(json["minute"]["upstreams"]["b:1"], json!([]));
+        assert_eq!(json["minute"]["total"].as_array().unwrap().len(), 1);
+        assert_eq!(json["hour"]["total"].as_array().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_max_upstreams() {
+        let bandwidth = Bandwidth::default();
+        let totals: HashMap<String, (u64, u64)> = (0..MAX_UPSTREAMS + 5)
+            .map(|i| (format!("10.0.0.{}:80", i), (1, 0)))
+            .collect();
+        bandwidth.update_at(totals, UNIX_EPOCH + HOUR);
+        let json = bandwidth.to_json();
+        let upstreams = json["hour"]["upstreams"].as_object().unwrap();
+        assert_eq!(upstreams.len(), MAX_UPSTREAMS + 1);
+        assert_eq!(upstreams[OTHER][0]["bytes_up"], 5);
+        assert_eq!(
+            json["hour"]["total"][0]["bytes_up"],
+            MAX_UPSTREAMS as u64 + 5
+        );
+    }
+}
@@ -0,0 +1,100 @@
+//! `--eject-after`: passive health checking, ejecting an upstream whose connects keep
+//! failing.
+//!
+//! After that many consecutive failed connects an upstream is ejected for
+//! `--eject-cooldown`. Connections to it fail fast meanwhile, and balancing picks
+//! another upstream if there is one. Once the cooldown is over connects are let through
+//! again, but a single failure ejects it again until one succeeds.
+
+use std::time::{Duration, Instant};
+
+use tracing::{info, warn};
+
+use crate::state::State;
+
+#[derive(Clone, Debug, Default)]
+pub struct Circuit {
+    /// Connects failed in a row.
+    failures: u32,
+    ejected_until: Option<Instant>,
+    /// Times the upstream has been ejected.
+    pub ejections: u64,
+}
+
+impl Circuit {
+    pub fn is_ejected(&self, now: Instant) -> bool {
+        self.ejected_until.is_some_and(|until| now < until)
+    }
+}
+
+/// Whether new connections to `addr` should fail fast.
+pub fn is_ejected(state: &State, addr: &str) -> bool {
+    state
+        .circuits
+        .get(&addr.to_string())
+        .is_some_and(|circuit| circuit.is_ejected(Instant::now()))
+}
+
+/// Count a connect to `addr` succeeding or failing, ejecting it after `threshold`
+/// failures in a row, or never if that's 0.
+pub fn record(state: &State, addr: &str, ok: bool, threshold: u32, cooldown: Duration) {
+    if threshold == 0 {
+        return;
+    }
+    let now = Instant::now();
+    let (ejected, recovered) = state.circuits.with_entry(
+        addr.to_string(),
+        Default::default(),
+        |circuit: &mut Circuit| {
+            if ok {
+                let recovered = circuit.failures >= threshold;
+                circuit.failures = 0;
+                circuit.ejected_until = None;
+                return (false, recovered);
+            }
+            circuit.failures += 1;
+            // Connects already in flight when it was ejected don't extend it.
+            if circuit.failures < threshold || circuit.is_ejected(now) {
+                return (false, false);
+            }
+            circuit.ejected_until = Some(now + cooldown);
+            circuit.ejections += 1;
+            (true, false)
+        },
+    );
+    if ejected {
+        warn!(upstream = %addr, cooldown = ?cooldown, "ejected upstream after failed connects");
+    }
+    if recovered {
+        info!(upstream = %addr, "upstream recovered");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record() {
+        let state = State::new();
+        let cooldown = Duration::from_millis(50);
+        record(&state, "a:1", false, 2, cooldown);
+        assert!(!is_ejected(&state, "a:1"));
+        record(&state, "a:1", false, 2, cooldown);
+        assert!(is_ejected(&state, "a:1"));
+        // In-flight failures don't extend the ejection.
+        record(&state, "a:1", false, 2, Duration::from_secs(60));
+        std::thread::sleep(cooldown);
+        assert!(!is_ejected(&state, "a:1"));
+
+        // One more failure ejects it again, a success recovers it.
+        record(&state, "a:1", false, 2, cooldown);
+        assert!(is_ejected(&state, "a:1"));
+        record(&state, "a:1", true, 2, cooldown);
+        assert!(!is_ejected(&state, "a:1"));
+        assert_eq!(state.circuits.get(&"a:1".to_string()).unwrap().ejections, 2);
+
+        record(&state, "b:1", false, 0, cooldown);
+        assert!(!is_ejected(&state, "b:1"));
+    }
+}
@@ -0,0 +1,323 @@
+//! Breakpoints for stepping through a connection's bytes.
+//!
+//! A breakpoint pauses one direction of a connection when forwarding reaches a
+//! byte offset, or just before a byte pattern. Bytes keep being read until the
+//! socket buffer fills, but nothing past the breakpoint is written until the
+//! direction is resumed through the API. Pattern breakpoints stay set and fire on
+//! every match; offset breakpoints naturally fire once.
+
+use std::sync::Mutex;
+
+use serde_json::{json, Value};
+use tokio::sync::Notify;
+
+use crate::connection::Direction;
+
+/// Patterns are matched across reads by keeping this many bytes, less one.
+pub const MAX_PATTERN_LEN: usize = 256;
+
+/// How many breakpoints a connection may have set at once.
+const MAX_BREAKPOINTS: usize = 64;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Trigger {
+    /// Pause once this many bytes have been forwarded.
+    Offset(u64),
+    /// Pause before forwarding these bytes.
+    Pattern(Vec<u8>),
+}
+
+impl Trigger {
+    /// Parse `{"offset": 1024}`, `{"pattern": "PING"}` or `{"pattern_hex": "00ff"}`.
+    pub fn from_json(body: &Value) -> Result<Self, String> {
+        let pattern = if let Some(offset) = body.get("offset") {
+            let offset = offset
+                .as_u64()
+                .ok_or("offset must be a non-negative integer")?;
+            return Ok(Trigger::Offset(offset));
+        } else if let Some(pattern) = body.get("pattern") {
+            pattern
+                .as_str()
+                .ok_or("pattern must be a string")?
+                .as_bytes()
+                .to_vec()
+        } else if let Some(hex) = body.get("pattern_hex") {
+            parse_hex(hex.as_str().ok_or("pattern_hex must be a string")?)?
+        } else {
+            return Err("expected an offset, pattern or pattern_hex".to_string());
+        };
+        if pattern.is_empty() || pattern.len() > MAX_PATTERN_LEN {
+            return Err(format!(
+                "pattern must be between 1 and {} bytes",
+                MAX_PATTERN_LEN
+            ));
+        }
+        Ok(Trigger::Pattern(pattern))
+    }
+}
+
+pub fn parse_hex(hex: &str) -> Result<Vec<u8>, String> {
+    if !hex.len().is_multiple_of(2) {
+        return Err("hex must have an even number of digits".to_string());
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            hex.get(i..i + 2)
+                .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+                .ok_or_else(|| format!("invalid hex: {}", hex))
+        })
+        .collect()
+}
+
+#[derive(Clone, Debug)]
+pub struct Breakpoint {
+    pub id: u64,
+    pub direction: Direction,
+    pub trigger: Trigger,
+}
+
+impl Breakpoint {
+    pub fn to_json(&self) -> Value {
+        let mut value = json!({
+            "id": self.id,
+            "direction": self.direction.as_str(),
+        });
+        let fields = value.as_object_mut().unwrap();
+        match &self.trigger {
+            Trigger::Offset(offset) => fields.insert("offset".into(), json!(offset)),
+            Trigger::Pattern(pattern) => fields.insert(
+                "pattern_hex".into(),
+                json!(pattern
+                    .iter()
+                    .map(|b| format!("{:02x}", b))
+                    .collect::<String>()),
+            ),
+        };
+        value
+    }
+}
+
+/// Where a direction is paused.
+#[derive(Clone, Copy, Debug)]
+struct Pause {
+    breakpoint: u64,
+    offset: u64,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    next_id: u64,
+    set: Vec<Breakpoint>,
+    paused_up: Option<Pause>,
+    paused_down: Option<Pause>,
+}
+
+impl Inner {
+    fn paused(&mut self, direction: Direction) -> &mut Option<Pause> {
+        match direction {
+            Direction::Up => &mut self.paused_up,
+            Direction::Down => &mut self.paused_down,
+        }
+    }
+}
+
+/// A breakpoint which forwarding has reached.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Hit {
+    pub id: u64,
+    /// Where the trigger is in the stream: the offset, or where the match starts.
+    pub point: u64,
+    /// How many bytes to forward before pausing. This is after `point` when a
+    /// match started in bytes which were already forwarded.
+    pub stop: u64,
+}
+
+/// The breakpoints set on one connection.
+#[derive(Debug, Default)]
+pub struct Breakpoints {
+    inner: Mutex<Inner>,
+    resumed: Notify,
+}
+
+impl Breakpoints {
+    pub fn add(&self, direction: Direction, trigger: Trigger) -> Result<Breakpoint, String> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.set.len() >= MAX_BREAKPOINTS {
+            return Err(format!(
+                "at most {} breakpoints may be set",
+                MAX_BREAKPOINTS
+            ));
+        }
+        inner.next_id += 1;
+        let breakpoint = Breakpoint {
+            id: inner.next_id,
+            direction,
+            trigger,
+        };
+        inner.set.push(breakpoint.clone());
+        Ok(breakpoint)
+    }
+
+    /// Remove a breakpoint, resuming its direction if it's paused there. Returns
+    /// whether the breakpoint existed.
+    pub fn remove(&self, id: u64) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        let len = inner.set.len();
+        inner.set.retain(|bp| bp.id != id);
+        for direction in [Direction::Up, Direction::Down] {
+            let paused = inner.paused(direction);
+            if paused.is_some_and(|pause| pause.breakpoint == id) {
+                *paused = None;
+            }
+        }
+        self.resumed.notify_waiters();
+        inner.set.len() != len
+    }
+
+    /// Resume one direction, or both. Returns whether anything was paused.
+    pub fn resume(&self, direction: Option<Direction>) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        let mut resumed = false;
+        for d in [Direction::Up, Direction::Down] {
+            if direction.is_none_or(|direction| direction == d) {
+                resumed |= inner.paused(d).take().is_some();
+            }
+        }
+        self.resumed.notify_waiters();
+        resumed
+    }
+
+    pub fn to_json(&self) -> Value {
+        let mut inner = self.inner.lock().unwrap();
+        let set: Vec<Value> = inner.set.iter().map(Breakpoint::to_json).collect();
+        let mut paused = serde_json::Map::new();
+        for direction in [Direction::Up, Direction::Down] {
+            let pause = inner
+                .paused(direction)
+                .map(|pause| json!({ "breakpoint": pause.breakpoint, "offset": pause.offset }));
+            paused.insert(direction.as_str().into(), json!(pause));
+        }
+        json!({ "breakpoints": set, "paused": paused })
+    }
+
+    /// The first breakpoint reached in `chunk`, which follows `written` bytes that
+    /// have been forwarded, the last of which are `tail`. Only triggers after
+    /// `after` count, so that resuming doesn't pause at the same place again.
+    pub fn next_hit(
+        &self,
+        direction: Direction,
+        written: u64,
+        after: Option<u64>,
+        tail: &[u8],
+        chunk: &[u8],
+    ) -> Option<Hit> {
+        let inner = self.inner.lock().unwrap();
+        let end = written + chunk.len() as u64;
+        let unseen = |point: u64| after.is_none_or(|after| point > after);
+        let mut window = None;
+        inner
+            .set
+            .iter()
+            .filter(|bp| bp.direction == direction)
+            .filter_map(|bp| match &bp.trigger {
+                Trigger::Offset(offset) => (*offset >= written && *offset < end && unseen(*offset))
+                    .then_some(Hit {
+                        id: bp.id,
+                        point: *offset,
+                        stop: *offset,
+                    }),
+                Trigger::Pattern(pattern) => {
+                    let window = window.get_or_insert_with(|| [tail, chunk].concat());
+                    let base = written - tail.len() as u64;
+                    window
+                        .windows(pattern.len())
+                        .enumerate()
+                        .map(|(i, bytes)| (base + i as u64, bytes))
+                        .find(|(start, bytes)| {
+                            bytes == pattern
+                                && unseen(*start)
+                                && start + pattern.len() as u64 > written
+                        })
+                        .map(|(start, _)| Hit {
+                            id: bp.id,
+                            point: start,
+                            stop: start.max(written),
+                        })
+                }
+            })
+            .min_by_key(|hit| (hit.stop, hit.point))
+    }
+
+    /// Wait until `direction` is resumed.
+    pub async fn pause(&self, direction: Direction, breakpoint: u64, offset: u64) {
+        *self.inner.lock().unwrap().paused(direction) = Some(Pause { breakpoint, offset });
+        loop {
+            let resumed = self.resumed.notified();
+            if self.inner.lock().unwrap().paused(direction).is_none() {
+                return;
+            }
+            resumed.await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_trigger() {
+        assert_eq!(
+            Trigger::from_json(&json!({ "offset": 10 })),
+            Ok(Trigger::Offset(10))
+        );
+        assert_eq!(
+            Trigger::from_json(&json!({ "pattern": "PING" })),
+            Ok(Trigger::Pattern(b"PING".to_vec()))
+        );
+        assert_eq!(
+            Trigger::from_json(&json!({ "pattern_hex": "00fF" })),
+            Ok(Trigger::Pattern(vec![0, 255]))
+        );
+        assert!(Trigger::from_json(&json!({ "pattern_hex": "0" })).is_err());
+        assert!(Trigger::from_json(&json!({ "pattern": "" })).is_err());
+        assert!(Trigger::from_json(&json!({})).is_err());
+    }
+
+    #[test]
+    fn test_next_hit() {
+        let breakpoints = Breakpoints::default();
+        let pattern = breakpoints
+            .add(Direction::Up, Trigger::Pattern(b"abc".to_vec()))
+            .unwrap();
+        let offset = breakpoints.add(Direction::Up, Trigger::Offset(8)).unwrap();
+        breakpoints
+            .add(Direction::Down, Trigger::Offset(0))
+            .unwrap();
+
+        // A match split across reads stops at the start of the new bytes.
+        let hit = breakpoints.next_hit(Direction::Up, 4, None, b"xxab", b"cdefghij");
+        assert_eq!(
+            hit,
+            Some(Hit {
+                id: pattern.id,
+                point: 2,
+                stop: 4
+            })
+        );
+        let hit = breakpoints.next_hit(Direction::Up, 4, Some(2), b"xxab", b"cdefghij");
+        assert_eq!(
+            hit,
+            Some(Hit {
+                id: offset.id,
+                point: 8,
+                stop: 8
+            })
+        );
+        assert_eq!(
+            breakpoints.next_hit(Direction::Up, 12, Some(8), b"ij", b"xyz"),
+            None
+        );
+    }
+}
@@ -0,0 +1,302 @@
+//! Capture the bytes of TCP-mode connections, see `--capture`, for `tproxy replay`.
+//!
+//! A capture is newline-delimited JSON with one event per chunk forwarded:
+//!
+//! ```text
+//! {"id":1,"at_ms":12.5,"direction":"up","data":"GET / HTTP/1.1\r\n..."}
+//! {"id":1,"at_ms":14.0,"direction":"down","data_hex":"1f8b08..."}
+//! ```
+//!
+//! `at_ms` is the time since the capture started, so events from every connection
+//! share one timeline. Chunks which aren't UTF-8 are written as `data_hex`. Bytes
+//! are captured as forwarded, after any `--replace` rules.
+//!
+//! `GET /api/connections/ID/transcript` serves one connection's events in the same
+//! format, from the files still on disk, to attach to a bug report or replay.
+
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde_json::{json, Value};
+use tokio::sync::mpsc::UnboundedSender;
+use tracing::warn;
+
+use crate::breakpoint::parse_hex;
+use crate::connection::Direction;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Event {
+    pub id: u64,
+    pub at: Duration,
+    pub direction: Direction,
+    pub data: Vec<u8>,
+}
+
+impl Event {
+    pub fn to_json(&self) -> Value {
+        let mut event = json!({
+            "id": self.id,
+            "at_ms": self.at.as_secs_f64() * 1000.0,
+            "direction": self.direction.as_str(),
+        });
+        match std::str::from_utf8(&self.data) {
+            Ok(data) => event["data"] = data.into(),
+            Err(_) => {
+                let mut hex = String::with_capacity(self.data.len() * 2);
+                for b in &self.data {
+                    let _ = write!(hex, "{:02x}", b);
+                }
+                event["data_hex"] = hex.into();
+            }
+        }
+        event
+    }
+
+    pub fn from_json(event: &Value) -> Result<Self, String> {
+        let id = event
+            .get("id")
+            .and_then(Value::as_u64)
+            .ok_or("id must be a non-negative integer")?;
+        let at = event
+            .get("at_ms")
+            .and_then(Value::as_f64)
+            .filter(|at| *at >= 0.0 && at.is_finite())
+            .ok_or("at_ms must be a non-negative number")?;
+        let direction = event
+            .get("direction")
+            .and_then(Value::as_str)
+            .ok_or("direction must be up or down")?
+            .parse()?;
+        let data = if let Some(data) = event.get("data") {
+            data.as_str()
+                .ok_or("data must be a string")?
+                .as_bytes()
+                .to_vec()
+        } else if let Some(hex) = event.get("data_hex") {
+            parse_hex(hex.as_str().ok_or("data_hex must be a string")?)?
+        } else {
+            return Err("expected data or data_hex".to_string());
+        };
+        Ok(Event {
+            id,
+            at: Duration::from_secs_f64(at / 1000.0),
+            direction,
+            data,
+        })
+    }
+}
+
+/// Appends events to a capture file.
+#[derive(Debug)]
+pub struct Writer {
+    started: Instant,
+    started_at: SystemTime,
+    path: String,
+    /// Roll over to a new file once this many bytes are written, or never if zero.
+    rotate_bytes: u64,
+    /// Told the path of each file rolled over.
+    rotated: Option<UnboundedSender<String>>,
+    file: Mutex<Output>,
+}
+
+#[derive(Debug)]
+struct Output {
+    file: BufWriter<File>,
+    written: u64,
+}
+
+impl Writer {
+    /// A writer which renames the capture to `PATH.UNIX_MILLIS` once it has
+    /// `rotate_bytes` in it, starts a new one, and sends the renamed path on `rotated`.
+    pub fn rotating(
+        path: &str,
+        rotate_bytes: u64,
+        rotated: Option<UnboundedSender<String>>,
+    ) -> io::Result<Self> {
+        Ok(Writer {
+            started: Instant::now(),
+            started_at: SystemTime::now(),
+            path: path.to_string(),
+            rotate_bytes,
+            rotated,
+            file: Mutex::new(Output {
+                file: BufWriter::new(File::create(path)?),
+                written: 0,
+            }),
+        })
+    }
+
+    pub fn record(&self, id: u64, direction: Direction, data: &[u8]) {
+        let event = Event {
+            id,
+            at: self.started.elapsed(),
+            direction,
+            data: data.to_vec(),
+        };
+        let line = format!("{}\n", event.to_json());
+        let mut output = self.file.lock().unwrap();
+        // Flush each event, so the capture is complete up to the moment the proxy
+        // is stopped.
+        let written = output
+            .file
+            .write_all(line.as_bytes())
+            .and_then(|()| output.file.flush());
+        if let Err(err) = written {
+            warn!(error = %err, "failed to write capture");
+            return;
+        }
+        output.written += line.len() as u64;
+        if self.rotate_bytes > 0 && output.written >= self.rotate_bytes {
+            if let Err(err) = self.rotate(&mut output) {
+                warn!(error = %err, "failed to rotate capture");
+            }
+        }
+    }
+
+    /// Every event still on disk for connection `id`, oldest first, with a `unix_ms`
+    /// timestamp added. Rotated files which have been uploaded, or deleted, are gone.
+    pub fn transcript(&self, id: u64) -> Result<Vec<Value>, String> {
+        // Hold the lock so we don't read a half-written line, or miss a rotation.
+        let _output = self.file.lock().unwrap();
+        let mut paths = self.rotated_paths().map_err(|err| err.to_string())?;
+        paths.push(self.path.clone());
+        let started_at = self
+            .started_at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut events = Vec::new();
+        for path in paths {
+            for event in read(&path)?.into_iter().filter(|event| event.id == id) {
+                let mut json = event.to_json();
+                json["unix_ms"] = ((started_at + event.at).as_secs_f64() * 1000.0).into();
+                events.push(json);
+            }
+        }
+        Ok(events)
+    }
+
+    /// Files this capture rolled over to which are still on disk, oldest first.
+    fn rotated_paths(&self) -> io::Result<Vec<String>> {
+        let path = Path::new(&self.path);
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let prefix = format!(
+            "{}.",
+            path.file_name().unwrap_or_default().to_string_lossy()
+        );
+        let mut rotated = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+            if let Some(millis) = name.strip_prefix(&prefix) {
+                if let Ok(millis) = millis.parse::<u128>() {
+                    rotated.push((millis, dir.join(&name).to_string_lossy().into_owned()));
+                }
+            }
+        }
+        rotated.sort();
+        Ok(rotated.into_iter().map(|(_, path)| path).collect())
+    }
+
+    fn rotate(&self, output: &mut Output) -> io::Result<()> {
+        let mut millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        // Rotating twice in a millisecond mustn't overwrite the first file.
+        while Path::new(&format!("{}.{}", self.path, millis)).exists() {
+            millis += 1;
+        }
+        let rotated = format!("{}.{}", self.path, millis);
+        std::fs::rename(&self.path, &rotated)?;
+        *output = Output {
+            file: BufWriter::new(File::create(&self.path)?),
+            written: 0,
+        };
+        if let Some(tx) = &self.rotated {
+            let _ = tx.send(rotated);
+        }
+        Ok(())
+    }
+}
+
+/// Read every event in a capture.
+pub fn read(path: &str) -> Result<Vec<Event>, String> {
+    let file = File::open(path).map_err(|err| format!("{}: {}", path, err))?;
+    let mut events = Vec::new();
+    for (n, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|err| format!("{}: {}", path, err))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let event = serde_json::from_str(&line)
+            .map_err(|err| err.to_string())
+            .and_then(|event| Event::from_json(&event))
+            .map_err(|err| format!("{}:{}: {}", path, n + 1, err))?;
+        events.push(event);
+    }
+    Ok(events)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let path =
+            std::env::temp_dir().join(format!("tproxy-capture-{}.ndjson", std::process::id()));
+        let path = path.to_str().unwrap();
+        let writer = Writer::rotating(path, 0, None).unwrap();
+        writer.record(1, Direction::Up, b"PING\r\n");
+        writer.record(1, Direction::Down, &[0x00, 0xff]);
+        drop(writer);
+
+        let events = read(path).unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].data, b"PING\r\n");
+        assert_eq!(events[1].direction, Direction::Down);
+        assert_eq!(events[1].data, [0x00, 0xff]);
+        assert_eq!(events[1].to_json()["data_hex"], "00ff");
+        assert!(events[0].at <= events[1].at);
+        std::fs::remove_file(path).unwrap();
+
+        assert!(Event::from_json(&json!({ "id": 1, "at_ms": 0, "direction": "up" })).is_err());
+
+        // Every event goes over a byte, so each rolls the file over.
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let writer = Writer::rotating(path, 1, Some(tx)).unwrap();
+        writer.record(1, Direction::Up, b"a");
+        writer.record(1, Direction::Up, b"b");
+        for expected in [b"a", b"b"] {
+            let rotated = rx.try_recv().unwrap();
+            assert_eq!(read(&rotated).unwrap()[0].data, expected);
+            std::fs::remove_file(&rotated).unwrap();
+        }
+        assert!(read(path).unwrap().is_empty());
+        std::fs::remove_file(path).unwrap();
+
+        let writer = Writer::rotating(path, 20, None).unwrap();
+        writer.record(1, Direction::Up, b"first, rotated");
+        writer.record(2, Direction::Up, b"other");
+        writer.record(1, Direction::Down, b"second");
+        let transcript = writer.transcript(1).unwrap();
+        assert_eq!(transcript.len(), 2);
+        assert_eq!(transcript[0]["data"], "first, rotated");
+        assert_eq!(transcript[1]["direction"], "down");
+        assert!(transcript[0]["unix_ms"].as_f64().unwrap() > 0.0);
+        for rotated in writer.rotated_paths().unwrap() {
+            std::fs::remove_file(rotated).unwrap();
+        }
+        std::fs::remove_file(path).unwrap();
+        assert!(
+            Event::from_json(&json!({ "id": 1, "at_ms": -1, "direction": "up", "data": "" }))
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,215 @@
+//! `tproxy compare`: run the same small echo workload against an upstream directly and
+//! through a proxy in front of it, and report how much latency and throughput the proxy
+//! costs, to check it isn't what's skewing a benchmark.
+//!
+//! The upstream must echo what it receives, e.g. `socat TCP-LISTEN:6000,fork EXEC:cat`.
+//! The proxy should have no toxics or other faults set, or they'll be measured too.
+
+use std::error::Error;
+use std::io;
+use std::time::Duration;
+
+use clap::Parser;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::Instant;
+
+use crate::connection::COPY_BUFFER_SIZE;
+
+/// Compare latency and throughput to an echo upstream, direct and through a proxy
+#[derive(Parser, Clone, Debug)]
+#[clap(name = "tproxy compare", bin_name = "tproxy compare")]
+pub struct Args {
+    /// Address of an upstream which echoes what it receives
+    #[clap(long)]
+    upstream: String,
+
+    /// Address of a tproxy listener forwarding to --upstream
+    #[clap(long)]
+    proxy: String,
+
+    /// How many round trips to time on each path
+    #[clap(long, default_value = "1000")]
+    rounds: usize,
+
+    /// Bytes sent in each round trip
+    #[clap(long, default_value = "64")]
+    size: usize,
+
+    /// Bytes to echo when measuring throughput
+    #[clap(long, default_value = "67108864")]
+    bulk_bytes: usize,
+}
+
+/// What one path measured.
+#[derive(Debug)]
+pub struct Measurement {
+    pub connect: Duration,
+    /// Sorted round-trip times.
+    pub rtts: Vec<Duration>,
+    /// Bytes per second echoed.
+    pub throughput: f64,
+}
+
+impl Measurement {
+    /// The `q` quantile round trip, from 0 to 1.
+    pub fn rtt(&self, q: f64) -> Duration {
+        let i = ((self.rtts.len() - 1) as f64 * q).round() as usize;
+        self.rtts[i]
+    }
+
+    fn summary(&self) -> String {
+        format!(
+            "connect={:.3}ms rtt_p50={:.3}ms rtt_p99={:.3}ms throughput={:.1}MB/s",
+            ms(self.connect),
+            ms(self.rtt(0.5)),
+            ms(self.rtt(0.99)),
+            self.throughput / 1e6
+        )
+    }
+}
+
+fn ms(d: Duration) -> f64 {
+    d.as_secs_f64() * 1000.0
+}
+
+pub async fn run(args: Args) -> Result<(), Box<dyn Error>> {
+    if args.rounds == 0 || args.size == 0 || args.bulk_bytes == 0 {
+        return Err("rounds, size and bulk-bytes must be positive".into());
+    }
+    let direct = measure(&args.upstream, args.rounds, args.size, args.bulk_bytes)
+        .await
+        .map_err(|err| format!("failed to measure {} directly: {}", args.upstream, err))?;
+    let proxied = measure(&args.proxy, args.rounds, args.size, args.bulk_bytes)
+        .await
+        .map_err(|err| format!("failed to measure through {}: {}", args.proxy, err))?;
+    println!("{}", report(&direct, &proxied));
+    Ok(())
+}
+
+/// Describe `proxied` relative to `direct`.
+pub fn report(direct: &Measurement, proxied: &Measurement) -> String {
+    let delta = |proxied: Duration, direct: Duration| ms(proxied) - ms(direct);
+    format!(
+        "direct: {}\nproxied: {}\noverhead: connect={:+.3}ms rtt_p50={:+.3}ms rtt_p99={:+.3}ms throughput={:+.1}%",
+        direct.summary(),
+        proxied.summary(),
+        delta(proxied.connect, direct.connect),
+        delta(proxied.rtt(0.5), direct.rtt(0.5)),
+        delta(proxied.rtt(0.99), direct.rtt(0.99)),
+        (proxied.throughput / direct.throughput - 1.0) * 100.0
+    )
+}
+
+/// Time a connect to `addr`, then `rounds` round trips of `size` bytes one at a time,
+/// then echoing `bulk_bytes` as fast as it goes.
+pub async fn measure(
+    addr: &str,
+    rounds: usize,
+    size: usize,
+    bulk_bytes: usize,
+) -> io::Result<Measurement> {
+    let start = Instant::now();
+    let mut stream = TcpStream::connect(addr).await?;
+    let connect = start.elapsed();
+    stream.set_nodelay(true)?;
+
+    let message = vec![b'x'; size];
+    let mut echoed = vec![0; size];
+    let mut rtts = Vec::with_capacity(rounds);
+    for _ in 0..rounds {
+        let start = Instant::now();
+        stream.write_all(&message).await?;
+        stream.read_exact(&mut echoed).await?;
+        rtts.push(start.elapsed());
+    }
+    rtts.sort();
+
+    let (mut r, mut w) = stream.split();
+    let start = Instant::now();
+    let send = async {
+        let chunk = vec![b'x'; COPY_BUFFER_SIZE];
+        let mut left = bulk_bytes;
+        while left > 0 {
+            let n = left.min(chunk.len());
+            w.write_all(&chunk[..n]).await?;
+            left -= n;
+        }
+        Ok::<_, io::Error>(())
+    };
+    let receive = async {
+        let mut buf = vec![0; COPY_BUFFER_SIZE];
+        let mut left = bulk_bytes;
+        while left > 0 {
+            let n = r.read(&mut buf[..left.min(COPY_BUFFER_SIZE)]).await?;
+            if n == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            left -= n;
+        }
+        Ok(())
+    };
+    tokio::try_join!(send, receive)?;
+    let throughput = bulk_bytes as f64 / start.elapsed().as_secs_f64();
+    Ok(Measurement {
+        connect,
+        rtts,
+        throughput,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use futures::FutureExt;
+    use tokio::net::TcpListener;
+    use tokio::sync::oneshot;
+
+    use crate::state::State;
+
+    #[tokio::test]
+    async fn test_compare() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let (mut r, mut w) = stream.split();
+                    let _ = tokio::io::copy(&mut r, &mut w).await;
+                });
+            }
+        });
+        let args = crate::Args::parse_from([
+            "tproxy",
+            "--listen-addr",
+            "127.0.0.1:0",
+            "--upstream-addr",
+            &upstream,
+            "--delay",
+            "5ms",
+        ]);
+        let (tx, rx) = oneshot::channel();
+        tokio::spawn(crate::listen(args, Arc::new(State::new()), tx).map(|r| {
+            if let Err(err) = r {
+                println!("failed to listen; error={}", err);
+            }
+        }));
+        let proxy = rx.await.unwrap().to_string();
+
+        let direct = measure(&upstream, 10, 16, 1 << 20).await.unwrap();
+        let proxied = measure(&proxy, 10, 16, 1 << 20).await.unwrap();
+        assert_eq!(proxied.rtts.len(), 10);
+        // Each round trip is delayed both ways.
+        assert!(proxied.rtt(0.5) >= Duration::from_millis(10));
+        assert!(direct.rtt(0.5) < proxied.rtt(0.5));
+        let report = report(&direct, &proxied);
+        assert!(report.starts_with("direct: connect="));
+        assert!(
+            report.contains("\noverhead: connect=+") || report.contains("\noverhead: connect=-")
+        );
+    }
+}
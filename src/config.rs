@@ -0,0 +1,412 @@
+//! `--config`: flags read from a file, with named profiles layered on top.
+//!
+//! The file is a small subset of TOML. Top-level keys are long flag names (`listen_addr`
+//! or `listen-addr`) and apply to every run; `[profile.NAME]` tables override them
+//! when selected with `--profile NAME`, so one checked-in file can describe every
+//! scenario:
+//!
+//! ```toml
+//! listen_addr = "127.0.0.1:5000"
+//! upstream_addr = "127.0.0.1:6000"
+//!
+//! [profile.staging]
+//! upstream_addr = "staging.internal:6000"
+//!
+//! [profile.chaos]
+//! toxics = ["lte", "down:latency=200ms"]
+//! max_upstream_connections = 10
+//!
+//! [profile.flaky-staging-db]
+//! route = ["pg=127.0.0.1:6432->staging-db:5432", "cache=127.0.0.1:6379->cache:6379"]
+//! route_toxics = ["pg=both:latency=300ms,jitter=200ms"]
+//! ```
+//!
+//! Values are strings, numbers, booleans (for switches) or arrays (for repeatable
+//! flags). A profile's array replaces the top-level one rather than adding to it, and
+//! `false` turns off a switch set at the top level. Flags on the command line override
+//! both.
+//!
+//! The file is read again on SIGHUP; see `reload` for which changes apply.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+
+use clap::{ArgSettings, IntoApp};
+use serde_json::json;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Value {
+    Bool(bool),
+    /// Strings and numbers, which become a flag's argument as written.
+    Scalar(String),
+    Array(Vec<String>),
+}
+
+type Table = BTreeMap<String, Value>;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Config {
+    base: Table,
+    profiles: BTreeMap<String, Table>,
+}
+
+impl Config {
+    pub fn load(path: &str) -> Result<Config, Box<dyn Error>> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|err| format!("failed to read {}: {}", path, err))?;
+        Ok(Config::parse(&text).map_err(|err| format!("{}: {}", path, err))?)
+    }
+
+    pub fn parse(text: &str) -> Result<Config, String> {
+        let mut config = Config::default();
+        let mut profile = None;
+        for (i, line) in text.lines().enumerate() {
+            let line = strip_comment(line).trim();
+            if line.is_empty() {
+                continue;
+            }
+            let err = |msg: String| format!("line {}: {}", i + 1, msg);
+            if let Some(section) = line.strip_prefix('[') {
+                let section = section
+                    .strip_suffix(']')
+                    .ok_or_else(|| err("unterminated section".into()))?
+                    .trim();
+                let name = section
+                    .strip_prefix("profile.")
+                    .filter(|name| !name.is_empty())
+                    .ok_or_else(|| err(format!("unknown section {}", section)))?;
+                if config.profiles.contains_key(name) {
+                    return Err(err(format!("profile {} is defined twice", name)));
+                }
+                config.profiles.insert(name.to_string(), Table::new());
+                profile = Some(name.to_string());
+                continue;
+            }
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| err("expected KEY = VALUE".into()))?;
+            let key = key.trim().replace('_', "-");
+            if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+                return Err(err(format!("invalid key {}", key)));
+            }
+            if key == "config" || key == "profile" {
+                return Err(err(format!(
+                    "{} can only be given on the command line",
+                    key
+                )));
+            }
+            let value = parse_value(value.trim()).map_err(err)?;
+            let table = match &profile {
+                Some(name) => config.profiles.get_mut(name).unwrap(),
+                None => &mut config.base,
+            };
+            if table.insert(key.clone(), value).is_some() {
+                return Err(err(format!("{} is set twice", key)));
+            }
+        }
+        Ok(config)
+    }
+
+    /// The flags for `profile`, or for the top level alone without one: each flag's
+    /// name and its value, unless it's a switch.
+    fn flags(&self, profile: Option<&str>) -> Result<Vec<(String, Option<String>)>, String> {
+        let mut table = self.base.clone();
+        if let Some(name) = profile {
+            let overrides = self.profiles.get(name).ok_or_else(|| {
+                let names: Vec<&str> = self.profiles.keys().map(String::as_str).collect();
+                format!("no profile {}; profiles are {}", name, names.join(", "))
+            })?;
+            table.extend(overrides.clone());
+        }
+        let mut flags = Vec::new();
+        for (key, value) in table {
+            match value {
+                Value::Bool(true) => flags.push((key, None)),
+                Value::Bool(false) => {}
+                Value::Scalar(value) => flags.push((key, Some(value))),
+                Value::Array(values) => {
+                    flags.extend(values.into_iter().map(|value| (key.clone(), Some(value))))
+                }
+            }
+        }
+        Ok(flags)
+    }
+}
+
+/// Everything before a `#` outside a string.
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            '#' if !quoted => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn parse_value(s: &str) -> Result<Value, String> {
+    match s {
+        "true" => return Ok(Value::Bool(true)),
+        "false" => return Ok(Value::Bool(false)),
+        _ => {}
+    }
+    if let Some(items) = s.strip_prefix('[') {
+        let items = items.strip_suffix(']').ok_or("unterminated array")?;
+        let mut values = Vec::new();
+        let mut rest = items.trim();
+        while !rest.is_empty() {
+            let (value, tail) = parse_scalar(rest)?;
+            values.push(value);
+            rest = tail.trim_start();
+            rest = match rest.strip_prefix(',') {
+                Some(tail) => tail.trim_start(),
+                None if rest.is_empty() => rest,
+                None => return Err("expected , between array items".into()),
+            };
+        }
+        return Ok(Value::Array(values));
+    }
+    match parse_scalar(s)? {
+        (value, "") => Ok(Value::Scalar(value)),
+        _ => Err(format!("unexpected characters after value {}", s)),
+    }
+}
+
+/// A string or number at the start of `s`, and what follows it.
+fn parse_scalar(s: &str) -> Result<(String, &str), String> {
+    if let Some(body) = s.strip_prefix('"') {
+        let mut value = String::new();
+        let mut chars = body.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => return Ok((value, &body[i + 1..])),
+                '\\' => match chars.next().map(|(_, c)| c) {
+                    Some('"') => value.push('"'),
+                    Some('\\') => value.push('\\'),
+                    Some('n') => value.push('\n'),
+                    Some('t') => value.push('\t'),
+                    _ => return Err("invalid escape in string".into()),
+                },
+                c => value.push(c),
+            }
+        }
+        return Err("unterminated string".into());
+    }
+    let end = s.find([',', ']', ' ']).unwrap_or(s.len());
+    let number = &s[..end];
+    if number.parse::<f64>().is_err() {
+        return Err(format!("{} is not a string, number or boolean", number));
+    }
+    Ok((number.to_string(), &s[end..]))
+}
+
+/// The value of `--NAME VALUE` or `--NAME=VALUE` on the command line.
+fn flag<'a>(argv: &'a [String], name: &str) -> Option<&'a str> {
+    let flag = format!("--{}", name);
+    let prefix = format!("--{}=", name);
+    argv.iter().enumerate().find_map(|(i, arg)| {
+        if *arg == flag {
+            argv.get(i + 1).map(String::as_str)
+        } else {
+            arg.strip_prefix(&prefix)
+        }
+    })
+}
+
+/// Expand `--config` (and `--profile`) in `argv` into the flags they select, ahead of
+/// those on the command line. Repeatable flags given on the command line replace the
+/// file's rather than adding to them.
+pub fn expand(argv: Vec<String>) -> Result<Vec<String>, Box<dyn Error>> {
+    let path = match flag(&argv, "config") {
+        Some(path) => path,
+        None => {
+            if flag(&argv, "profile").is_some() {
+                return Err("--profile needs --config".into());
+            }
+            return Ok(argv);
+        }
+    };
+    let flags = Config::load(path)?.flags(flag(&argv, "profile"))?;
+    let given: Vec<&str> = argv[1..]
+        .iter()
+        .filter_map(|arg| arg.strip_prefix("--"))
+        .map(|arg| arg.split('=').next().unwrap())
+        .collect();
+    let mut expanded = vec![argv[0].clone()];
+    for (name, value) in flags {
+        if !given.contains(&name.as_str()) {
+            expanded.push(format!("--{}", name));
+            expanded.extend(value);
+        }
+    }
+    expanded.extend_from_slice(&argv[1..]);
+    Ok(expanded)
+}
+
+/// Every flag's effective value for `argv` (as returned by `expand`): from the command
+/// line, the config file or its default. Switches are booleans, repeatable flags are
+/// arrays, and options without a value are null. Keys are long flag names, sorted.
+pub fn effective(argv: &[String]) -> Result<serde_json::Value, clap::Error> {
+    let mut app = crate::Args::into_app();
+    let matches = app.try_get_matches_from_mut(argv)?;
+    let mut config = serde_json::Map::new();
+    for arg in app.get_arguments() {
+        let name = arg.get_name();
+        if name == "help" || name == "version" {
+            continue;
+        }
+        let value = if !arg.is_set(ArgSettings::TakesValue) {
+            json!(matches.is_present(name))
+        } else {
+            let values: Vec<&str> = matches
+                .values_of(name)
+                .map(|values| values.collect())
+                .unwrap_or_default();
+            if arg.is_set(ArgSettings::MultipleOccurrences) {
+                json!(values)
+            } else {
+                json!(values.first())
+            }
+        };
+        config.insert(arg.get_long().unwrap_or(name).to_string(), value);
+    }
+    Ok(config.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use clap::Parser;
+
+    const CONFIG: &str = r#"
+# Shared by every run.
+listen_addr = "127.0.0.1:5000"
+upstream-addr = "127.0.0.1:6000"
+toxics = ["dsl"]
+
+[profile.staging]
+upstream_addr = "staging.internal:6000"  # not a # comment
+
+[profile.chaos]
+toxics = ["lte", "down:latency=200ms"]
+max_upstream_connections = 10
+transparent = true
+"#;
+
+    fn strings(args: &[&str]) -> Vec<String> {
+        args.iter().map(|s| s.to_string()).collect()
+    }
+
+    fn args(config: &Config, profile: Option<&str>) -> Vec<String> {
+        config
+            .flags(profile)
+            .unwrap()
+            .into_iter()
+            .flat_map(|(name, value)| std::iter::once(format!("--{}", name)).chain(value))
+            .collect()
+    }
+
+    #[test]
+    fn test_profiles() {
+        let config = Config::parse(CONFIG).unwrap();
+        assert_eq!(
+            args(&config, None),
+            strings(&[
+                "--listen-addr",
+                "127.0.0.1:5000",
+                "--toxics",
+                "dsl",
+                "--upstream-addr",
+                "127.0.0.1:6000",
+            ])
+        );
+        assert_eq!(
+            args(&config, Some("staging"))[4..],
+            strings(&["--upstream-addr", "staging.internal:6000"])
+        );
+        assert_eq!(
+            args(&config, Some("chaos")),
+            strings(&[
+                "--listen-addr",
+                "127.0.0.1:5000",
+                "--max-upstream-connections",
+                "10",
+                "--toxics",
+                "lte",
+                "--toxics",
+                "down:latency=200ms",
+                "--transparent",
+                "--upstream-addr",
+                "127.0.0.1:6000",
+            ])
+        );
+        assert_eq!(
+            config.flags(Some("prod")).unwrap_err(),
+            "no profile prod; profiles are chaos, staging"
+        );
+
+        for (text, err) in [
+            ("[other]", "line 1: unknown section other"),
+            ("a = 1\na = 2", "line 2: a is set twice"),
+            (
+                "a = nope",
+                "line 1: nope is not a string, number or boolean",
+            ),
+            ("a = \"open", "line 1: unterminated string"),
+            (
+                "config = \"x\"",
+                "line 1: config can only be given on the command line",
+            ),
+        ] {
+            assert_eq!(Config::parse(text).unwrap_err(), err);
+        }
+    }
+
+    #[test]
+    fn test_expand() {
+        let path = std::env::temp_dir().join(format!("tproxy-config-{}.toml", std::process::id()));
+        std::fs::write(&path, CONFIG).unwrap();
+        let path = path.to_str().unwrap();
+
+        let argv = strings(&[
+            "tproxy",
+            "--config",
+            path,
+            "--profile=chaos",
+            "--toxics",
+            "3g",
+        ]);
+        let expanded = expand(argv.clone()).unwrap();
+        assert_eq!(expanded[0], "tproxy");
+        assert!(!expanded.contains(&"lte".to_string()));
+        assert!(expanded.contains(&"--transparent".to_string()));
+        assert_eq!(expanded[expanded.len() - 5..], argv[1..]);
+
+        let argv = strings(&["tproxy", "--config", path, "-u", "127.0.0.1:7000"]);
+        let args = crate::Args::parse_from(expand(argv).unwrap());
+        assert_eq!(args.listen_addr, "127.0.0.1:5000");
+        assert_eq!(args.upstream_addr, "127.0.0.1:7000");
+
+        let argv = expand(strings(&["tproxy", "--config", path, "--profile", "chaos"])).unwrap();
+        let config = effective(&argv).unwrap();
+        assert_eq!(config["listen-addr"], "127.0.0.1:5000");
+        assert_eq!(config["debug-addr"], "127.0.0.1:2222");
+        assert_eq!(config["toxics"], json!(["lte", "down:latency=200ms"]));
+        assert_eq!(config["transparent"], true);
+        assert_eq!(config["http-cache"], false);
+        assert_eq!(config["geoip-db"], json!([]));
+        assert!(config["capture"].is_null());
+        assert!(config.get("help").is_none());
+
+        let argv = strings(&["tproxy", "-l", "127.0.0.1:0"]);
+        assert_eq!(expand(argv.clone()).unwrap(), argv);
+        assert!(expand(strings(&["tproxy", "--profile", "chaos"])).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+}
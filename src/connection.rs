@@ -0,0 +1,792 @@
+//! Per-connection records, kept for the debug UI and API.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::os::unix::io::RawFd;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde_json::{json, Value};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::watch;
+use tracing::warn;
+
+use crate::breakpoint::{Breakpoints, MAX_PATTERN_LEN};
+use crate::events::{Event, Events};
+use crate::qos::Scheduler;
+use crate::sockopt::{self, Sockets};
+use crate::state::Segment;
+use crate::toxic::{Toxic, Toxics};
+use crate::{http2, websocket};
+
+/// How often byte counts are sampled while a connection is open.
+pub const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How many samples to keep per connection; older ones are dropped.
+const MAX_SAMPLES: usize = 600;
+
+pub const COPY_BUFFER_SIZE: usize = 8 * 1024;
+
+/// Which way bytes are flowing through a connection.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    /// From the downstream to the upstream.
+    Up,
+    /// From the upstream to the downstream.
+    Down,
+}
+
+impl Direction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Direction::Up => "up",
+            Direction::Down => "down",
+        }
+    }
+}
+
+impl FromStr for Direction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "up" => Ok(Direction::Up),
+            "down" => Ok(Direction::Down),
+            _ => Err(format!("unknown direction: {} (expected up or down)", s)),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Connection {
+    pub id: u64,
+    pub downstream_addr: SocketAddr,
+    pub upstream_addr: String,
+    pub started_at: SystemTime,
+    started: Instant,
+    /// Bytes copied from the downstream to the upstream.
+    pub bytes_up: AtomicU64,
+    /// Bytes copied from the upstream to the downstream.
+    pub bytes_down: AtomicU64,
+    /// When bytes were last copied either way, in milliseconds since `started`.
+    last_activity_ms: AtomicU64,
+    detail: Mutex<Detail>,
+    /// Set while the connection is being proxied, see `register_sockets`.
+    sockets: Mutex<Sockets>,
+    pub breakpoints: Breakpoints,
+    toxics: Mutex<Toxics>,
+    /// The `--bandwidth-cap` scheduler and the connection's priority under it.
+    qos: Mutex<Option<(Arc<Scheduler>, usize)>>,
+    /// Why `kill` was called, if it has been. Only changed with `sockets` locked.
+    killed: watch::Sender<Option<&'static str>>,
+    /// Where to announce being connected and bytes milestones, see `set_events`.
+    events: OnceLock<Events>,
+    /// The metrics labels it's counted under, see `State::segment`.
+    segment: OnceLock<Arc<Segment>>,
+}
+
+#[derive(Debug, Default)]
+struct Detail {
+    connected_at: Option<SystemTime>,
+    closed_at: Option<SystemTime>,
+    close_reason: Option<String>,
+    samples: VecDeque<Sample>,
+    tags: BTreeMap<String, String>,
+    /// Set once the connection has upgraded to a WebSocket.
+    websocket: Option<websocket::Stats>,
+    /// Set once the client has sent the HTTP/2 connection preface.
+    http2: Option<http2::Stats>,
+    /// How many times each `--replace` rule has rewritten the stream.
+    replacements: BTreeMap<String, u64>,
+    /// Set for connections accepted with `--transparent`.
+    nat: Option<Nat>,
+    /// Which direction finished first, and when, while the other stayed open.
+    half_closed: Option<(Direction, SystemTime)>,
+}
+
+/// How an intercepted connection was handled.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct Nat {
+    /// Where the client addressed the connection, if it was redirected to us.
+    original_dst: Option<SocketAddr>,
+    /// The local address of the upstream connection: what the upstream sees as the
+    /// client.
+    source: Option<SocketAddr>,
+}
+
+/// A point-in-time copy of a connection's counters, see [`Connection::activity`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct Activity {
+    pub id: u64,
+    pub upstream_addr: String,
+    pub started_at: SystemTime,
+    pub last_activity: SystemTime,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+}
+
+#[derive(Debug)]
+struct Sample {
+    elapsed: Duration,
+    bytes_up: u64,
+    bytes_down: u64,
+}
+
+impl Connection {
+    pub fn new(id: u64, downstream_addr: SocketAddr, upstream_addr: String) -> Self {
+        Self {
+            id,
+            downstream_addr,
+            upstream_addr,
+            started_at: SystemTime::now(),
+            started: Instant::now(),
+            bytes_up: AtomicU64::new(0),
+            bytes_down: AtomicU64::new(0),
+            last_activity_ms: AtomicU64::new(0),
+            detail: Mutex::new(Detail::default()),
+            sockets: Mutex::default(),
+            breakpoints: Breakpoints::default(),
+            toxics: Mutex::default(),
+            qos: Mutex::default(),
+            killed: watch::channel(None).0,
+            events: OnceLock::new(),
+            segment: OnceLock::new(),
+        }
+    }
+
+    /// The connection's metrics segment, from `init` the first time.
+    pub fn segment(&self, init: impl FnOnce() -> Arc<Segment>) -> Arc<Segment> {
+        self.segment.get_or_init(init).clone()
+    }
+
+    /// Announce the connection reaching its upstream and its bytes milestones to
+    /// `events`. Only the first call has any effect.
+    pub fn set_events(&self, events: Events) {
+        let _ = self.events.set(events);
+    }
+
+    /// Count `n` bytes copied in `direction`.
+    pub fn add_bytes(&self, direction: Direction, n: u64) {
+        let counter = match direction {
+            Direction::Up => &self.bytes_up,
+            Direction::Down => &self.bytes_down,
+        };
+        let before = counter.fetch_add(n, Ordering::Relaxed);
+        if let Some(events) = self.events.get() {
+            events.bytes(self.id, direction, before, before + n);
+        }
+        self.last_activity_ms
+            .store(self.started.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// When bytes were last copied either way, or when the connection started.
+    pub fn last_activity(&self) -> SystemTime {
+        self.started_at + Duration::from_millis(self.last_activity_ms.load(Ordering::Relaxed))
+    }
+
+    /// Wait until no bytes have been copied either way for `timeout`.
+    pub async fn idle_for(&self, timeout: Duration) {
+        loop {
+            let last = Duration::from_millis(self.last_activity_ms.load(Ordering::Relaxed));
+            let idle = self.started.elapsed().saturating_sub(last);
+            if idle >= timeout {
+                return;
+            }
+            tokio::time::sleep(timeout - idle).await;
+        }
+    }
+
+    /// Wait until the connection has been open for `limit`.
+    pub async fn open_for(&self, limit: Duration) {
+        tokio::time::sleep(limit.saturating_sub(self.started.elapsed())).await;
+    }
+
+    /// What the connection has done so far.
+    pub fn activity(&self) -> Activity {
+        Activity {
+            id: self.id,
+            upstream_addr: self.upstream_addr.clone(),
+            started_at: self.started_at,
+            last_activity: self.last_activity(),
+            bytes_up: self.bytes_up.load(Ordering::Relaxed),
+            bytes_down: self.bytes_down.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Record that the upstream connection was established.
+    pub fn connected(&self) {
+        self.detail.lock().unwrap().connected_at = Some(SystemTime::now());
+        if let Some(events) = self.events.get() {
+            events.send(Event::Connected {
+                id: self.id,
+                upstream_addr: self.upstream_addr.clone(),
+            });
+        }
+    }
+
+    /// Record that `finished` has reached EOF while the other direction is still open.
+    pub fn half_close(&self, finished: Direction) {
+        self.detail.lock().unwrap().half_closed = Some((finished, SystemTime::now()));
+    }
+
+    /// Record the original destination of a connection accepted with `--transparent`.
+    pub fn set_original_dst(&self, original_dst: Option<SocketAddr>) {
+        self.detail.lock().unwrap().nat = Some(Nat {
+            original_dst,
+            source: None,
+        });
+    }
+
+    /// Record the local address of the upstream connection, for intercepted
+    /// connections.
+    pub fn set_source_addr(&self, source: SocketAddr) {
+        if let Some(nat) = &mut self.detail.lock().unwrap().nat {
+            nat.source = Some(source);
+        }
+    }
+
+    /// The NAT table entry for an intercepted connection.
+    pub fn nat_entry(&self) -> Option<Value> {
+        let detail = self.detail.lock().unwrap();
+        let nat = detail.nat?;
+        Some(json!({
+            "id": self.id,
+            "downstream_addr": self.downstream_addr.to_string(),
+            "original_dst": nat.original_dst.map(|addr| addr.to_string()),
+            "upstream_addr": self.upstream_addr,
+            "source_addr": nat.source.map(|addr| addr.to_string()),
+            "closed_at": detail.closed_at.map(unix_millis),
+        }))
+    }
+
+    /// Record that the connection is over, and why.
+    pub fn close(&self, reason: String) {
+        self.sample();
+        let mut detail = self.detail.lock().unwrap();
+        detail.closed_at = Some(SystemTime::now());
+        detail.close_reason = Some(reason);
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.detail.lock().unwrap().closed_at.is_some()
+    }
+
+    /// Set the given tags, replacing any previous values for the same keys. A `None`
+    /// value removes the tag.
+    pub fn set_tags(&self, tags: impl IntoIterator<Item = (String, Option<String>)>) {
+        let mut detail = self.detail.lock().unwrap();
+        for (key, value) in tags {
+            match value {
+                Some(value) => detail.tags.insert(key, value),
+                None => detail.tags.remove(&key),
+            };
+        }
+    }
+
+    pub fn tags(&self) -> BTreeMap<String, String> {
+        self.detail.lock().unwrap().tags.clone()
+    }
+
+    /// Tags formatted for log lines, e.g. `team=db,env=staging`.
+    pub fn tag_string(&self) -> String {
+        let detail = self.detail.lock().unwrap();
+        let tags: Vec<String> = detail
+            .tags
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect();
+        tags.join(",")
+    }
+
+    /// Record that the connection upgraded to a WebSocket.
+    pub fn set_websocket(&self) {
+        let mut detail = self.detail.lock().unwrap();
+        detail.websocket.get_or_insert_with(Default::default);
+    }
+
+    #[cfg(test)]
+    pub fn is_websocket(&self) -> bool {
+        self.detail.lock().unwrap().websocket.is_some()
+    }
+
+    /// Update the WebSocket statistics, if the connection is a WebSocket.
+    pub fn update_websocket(&self, f: impl FnOnce(&mut websocket::Stats)) {
+        if let Some(stats) = self.detail.lock().unwrap().websocket.as_mut() {
+            f(stats);
+        }
+    }
+
+    /// Record that the client is speaking HTTP/2.
+    pub fn set_http2(&self) {
+        let mut detail = self.detail.lock().unwrap();
+        detail.http2.get_or_insert_with(Default::default);
+    }
+
+    /// Update the HTTP/2 statistics, if the connection is HTTP/2.
+    pub fn update_http2(&self, f: impl FnOnce(&mut http2::Stats)) {
+        if let Some(stats) = self.detail.lock().unwrap().http2.as_mut() {
+            f(stats);
+        }
+    }
+
+    pub fn toxics(&self) -> Toxics {
+        *self.toxics.lock().unwrap()
+    }
+
+    /// Send through `scheduler` with `priority`, as well as any rate toxic.
+    pub fn set_qos(&self, scheduler: Arc<Scheduler>, priority: usize) {
+        *self.qos.lock().unwrap() = Some((scheduler, priority));
+    }
+
+    pub fn set_toxics(&self, toxics: Toxics) {
+        *self.toxics.lock().unwrap() = toxics;
+    }
+
+    /// Change the toxics of one direction or both, see `Toxic::update`.
+    pub fn update_toxics(&self, direction: Option<Direction>, body: &Value) -> Result<(), String> {
+        self.toxics.lock().unwrap().update(direction, body)
+    }
+
+    fn toxic(&self, direction: Direction) -> Toxic {
+        self.toxics.lock().unwrap().get(direction)
+    }
+
+    /// Forward bytes in one direction until `reader` reaches EOF, counting them and
+    /// applying the connection's breakpoints and toxics. `inspect` sees each chunk as
+    /// it's read.
+    pub async fn forward<R, W>(
+        &self,
+        reader: &mut R,
+        writer: &mut W,
+        direction: Direction,
+        mut inspect: impl FnMut(&[u8]),
+    ) -> io::Result<u64>
+    where
+        R: AsyncRead + Unpin + ?Sized,
+        W: AsyncWrite + Unpin + ?Sized,
+    {
+        let mut buf = vec![0; COPY_BUFFER_SIZE];
+        let mut total = 0;
+        let mut tail = Vec::new();
+        let mut last_hit = None;
+        let mut next_send = tokio::time::Instant::now();
+        loop {
+            let n = reader.read(&mut buf).await?;
+            if n == 0 {
+                return Ok(total);
+            }
+            let read_at = tokio::time::Instant::now();
+            inspect(&buf[..n]);
+            if let Some(delay) = self.toxic(direction).delay() {
+                tokio::time::sleep_until(read_at + delay).await;
+            }
+            let mut chunk = &buf[..n];
+            loop {
+                let hit = self
+                    .breakpoints
+                    .next_hit(direction, total, last_hit, &tail, chunk);
+                let len = hit.map_or(chunk.len(), |hit| (hit.stop - total) as usize);
+                self.write_throttled(writer, direction, &chunk[..len], &mut next_send)
+                    .await?;
+                total += len as u64;
+                tail.extend_from_slice(&chunk[..len]);
+                tail.drain(..tail.len().saturating_sub(MAX_PATTERN_LEN - 1));
+                chunk = &chunk[len..];
+                match hit {
+                    Some(hit) => {
+                        last_hit = Some(hit.point);
+                        self.breakpoints.pause(direction, hit.id, total).await;
+                    }
+                    None => break,
+                }
+            }
+        }
+    }
+
+    /// Write `data`, no faster than the direction's rate toxic and any `--bandwidth-cap`
+    /// allow. `next_send` is when the rate next allows bytes to be sent.
+    async fn write_throttled<W>(
+        &self,
+        writer: &mut W,
+        direction: Direction,
+        mut data: &[u8],
+        next_send: &mut tokio::time::Instant,
+    ) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin + ?Sized,
+    {
+        while !data.is_empty() {
+            let rate = self.toxic(direction).rate;
+            let qos = self.qos.lock().unwrap().clone();
+            let cap = qos.as_ref().and_then(|(scheduler, _)| scheduler.rate());
+            // Send about 50ms worth at a time, so the rate is smooth.
+            let n = [rate, cap]
+                .into_iter()
+                .flatten()
+                .fold(data.len(), |n, rate| n.min((rate / 20).max(1) as usize));
+            if let Some(rate) = rate {
+                tokio::time::sleep_until(*next_send).await;
+                *next_send = (*next_send).max(tokio::time::Instant::now())
+                    + Duration::from_secs_f64(n as f64 / rate as f64);
+            }
+            if let Some((scheduler, priority)) = qos {
+                scheduler.acquire(direction, priority, n).await;
+            }
+            writer.write_all(&data[..n]).await?;
+            self.add_bytes(direction, n as u64);
+            data = &data[n..];
+        }
+        Ok(())
+    }
+
+    pub fn add_replacements(&self, rule: &str, count: u64) {
+        let mut detail = self.detail.lock().unwrap();
+        *detail.replacements.entry(rule.to_string()).or_default() += count;
+    }
+
+    /// Make the connection's sockets available to `with_sockets` until the returned
+    /// guard is dropped. The guard must be dropped before the sockets are closed, so
+    /// that a descriptor is never used after it could have been reused.
+    pub fn register_sockets(&self, downstream: RawFd, upstream: RawFd) -> SocketsGuard<'_> {
+        let mut sockets = self.sockets.lock().unwrap();
+        *sockets = Sockets {
+            downstream: Some(downstream),
+            upstream: Some(upstream),
+        };
+        if self.killed().is_some() {
+            shutdown(*sockets);
+        }
+        drop(sockets);
+        SocketsGuard(self)
+    }
+
+    /// Forcibly close the connection, recording `reason` as why: shut down both its
+    /// sockets, so proxying ends as though both sides had hung up, and release any
+    /// breakpoints. A connection still dialing its upstream is shut down as soon as it
+    /// connects. Returns false if the connection had already been killed.
+    ///
+    /// HTTP-mode connections don't register their sockets, and instead drop any
+    /// in-flight request once they see [`Connection::until_killed`] return.
+    pub fn kill(&self, reason: &'static str) -> bool {
+        let sockets = self.sockets.lock().unwrap();
+        if self.killed().is_some() {
+            return false;
+        }
+        self.killed.send_replace(Some(reason));
+        shutdown(*sockets);
+        drop(sockets);
+        self.breakpoints.resume(None);
+        true
+    }
+
+    /// Why the connection was killed, if it was.
+    pub fn killed(&self) -> Option<&'static str> {
+        *self.killed.borrow()
+    }
+
+    pub async fn until_killed(&self) {
+        let mut killed = self.killed.subscribe();
+        while killed.borrow().is_none() {
+            if killed.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Run `f` with the connection's sockets, which stay open until it returns.
+    pub fn with_sockets<R>(&self, f: impl FnOnce(Sockets) -> R) -> R {
+        let sockets = self.sockets.lock().unwrap();
+        f(*sockets)
+    }
+
+    /// Append the current byte counts to the connection's history.
+    pub fn sample(&self) {
+        let sample = Sample {
+            elapsed: self.started.elapsed(),
+            bytes_up: self.bytes_up.load(Ordering::Relaxed),
+            bytes_down: self.bytes_down.load(Ordering::Relaxed),
+        };
+        let mut detail = self.detail.lock().unwrap();
+        if detail.samples.len() == MAX_SAMPLES {
+            detail.samples.pop_front();
+        }
+        detail.samples.push_back(sample);
+    }
+
+    /// Roughly how many heap bytes this record holds.
+    pub fn estimated_size(&self) -> usize {
+        let detail = self.detail.lock().unwrap();
+        std::mem::size_of::<Self>()
+            + self.upstream_addr.capacity()
+            + detail.close_reason.as_ref().map_or(0, |r| r.capacity())
+            + detail.samples.capacity() * std::mem::size_of::<Sample>()
+            + detail
+                .tags
+                .iter()
+                .map(|(k, v)| k.capacity() + v.capacity())
+                .sum::<usize>()
+    }
+
+    /// The fields shown when listing connections.
+    pub fn summary(&self) -> Value {
+        let detail = self.detail.lock().unwrap();
+        json!({
+            "id": self.id,
+            "downstream_addr": self.downstream_addr.to_string(),
+            "upstream_addr": self.upstream_addr,
+            "started_at": unix_millis(self.started_at),
+            "closed_at": detail.closed_at.map(unix_millis),
+            "duration_ms": detail
+                .closed_at
+                .unwrap_or_else(SystemTime::now)
+                .duration_since(self.started_at)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            "bytes_up": self.bytes_up.load(Ordering::Relaxed),
+            "bytes_down": self.bytes_down.load(Ordering::Relaxed),
+            "last_activity_at": unix_millis(self.last_activity()),
+            "tags": detail.tags,
+            "websocket": detail.websocket.is_some(),
+            "http2": detail.http2.is_some(),
+            "half_closed": detail.half_closed.map(|(finished, at)| {
+                let mut half_closed = json!({
+                    "finished": finished.as_str(),
+                    "at": unix_millis(at),
+                });
+                if detail.closed_at.is_none() {
+                    let age = at.elapsed().unwrap_or_default();
+                    half_closed["age_ms"] = (age.as_millis() as u64).into();
+                }
+                half_closed
+            }),
+        })
+    }
+
+    /// Everything known about the connection.
+    pub fn detail(&self) -> Value {
+        let mut value = self.summary();
+        let detail = self.detail.lock().unwrap();
+        let samples: Vec<Value> = detail
+            .samples
+            .iter()
+            .map(|s| {
+                json!({
+                    "elapsed_ms": s.elapsed.as_millis() as u64,
+                    "bytes_up": s.bytes_up,
+                    "bytes_down": s.bytes_down,
+                })
+            })
+            .collect();
+        let fields = value.as_object_mut().unwrap();
+        fields.insert(
+            "connected_at".into(),
+            json!(detail.connected_at.map(unix_millis)),
+        );
+        fields.insert("close_reason".into(), json!(detail.close_reason));
+        if let Some(nat) = detail.nat {
+            fields.insert(
+                "original_dst".into(),
+                json!(nat.original_dst.map(|addr| addr.to_string())),
+            );
+            fields.insert(
+                "source_addr".into(),
+                json!(nat.source.map(|addr| addr.to_string())),
+            );
+        }
+        fields.insert("samples".into(), json!(samples));
+        fields.insert(
+            "websocket_frames".into(),
+            detail
+                .websocket
+                .as_ref()
+                .map_or(Value::Null, websocket::Stats::to_json),
+        );
+        fields.insert(
+            "http2_streams".into(),
+            detail
+                .http2
+                .as_ref()
+                .map_or(Value::Null, http2::Stats::to_json),
+        );
+        fields.insert("replacements".into(), json!(detail.replacements));
+        fields.insert("breakpoints".into(), self.breakpoints.to_json());
+        fields.insert("toxics".into(), self.toxics().to_json());
+        value
+    }
+}
+
+pub struct SocketsGuard<'a>(&'a Connection);
+
+impl Drop for SocketsGuard<'_> {
+    fn drop(&mut self) {
+        *self.0.sockets.lock().unwrap() = Sockets::default();
+    }
+}
+
+/// Shut down registered sockets. They must stay open until we return.
+fn shutdown(sockets: Sockets) {
+    for fd in [sockets.downstream, sockets.upstream].into_iter().flatten() {
+        if let Err(err) = sockopt::shutdown(fd) {
+            warn!(error = %err, "failed to shut down socket");
+        }
+    }
+}
+
+pub fn unix_millis(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
+
+/// Like `tokio::io::copy`, but counts every chunk against `conn` as it's written and
+/// passes it to `inspect` first.
+pub async fn copy<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    conn: &Connection,
+    direction: Direction,
+    mut inspect: impl FnMut(&[u8]),
+) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin + ?Sized,
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let mut buf = vec![0; COPY_BUFFER_SIZE];
+    let mut total = 0;
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(total);
+        }
+        inspect(&buf[..n]);
+        writer.write_all(&buf[..n]).await?;
+        total += n as u64;
+        conn.add_bytes(direction, n as u64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use crate::breakpoint::Trigger;
+
+    #[tokio::test]
+    async fn test_copy_counts_bytes() {
+        let conn = Connection::new(1, "127.0.0.1:1234".parse().unwrap(), "up:80".into());
+        assert_eq!(conn.last_activity(), conn.started_at);
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        let mut reader: &[u8] = b"hello world";
+        let mut writer = Vec::new();
+        assert_eq!(
+            copy(&mut reader, &mut writer, &conn, Direction::Down, |_| {})
+                .await
+                .unwrap(),
+            11
+        );
+        assert_eq!(writer, b"hello world");
+        let activity = conn.activity();
+        assert_eq!((activity.bytes_up, activity.bytes_down), (0, 11));
+        assert!(activity.last_activity >= conn.started_at + Duration::from_millis(5));
+    }
+
+    #[test]
+    fn test_detail() {
+        let conn = Connection::new(7, "127.0.0.1:1234".parse().unwrap(), "up:80".into());
+        conn.bytes_up.fetch_add(5, Ordering::Relaxed);
+        conn.connected();
+        conn.close("completed".into());
+        let detail = conn.detail();
+        assert!(detail["closed_at"].is_u64());
+        assert_eq!(detail["id"], 7);
+        assert_eq!(detail["bytes_up"], 5);
+        assert_eq!(detail["close_reason"], "completed");
+        assert_eq!(detail["samples"][0]["bytes_up"], 5);
+    }
+
+    #[test]
+    fn test_tags() {
+        let conn = Connection::new(1, "127.0.0.1:1234".parse().unwrap(), "up:80".into());
+        conn.set_tags([
+            ("team".to_string(), Some("db".to_string())),
+            ("env".to_string(), Some("staging".to_string())),
+        ]);
+        assert_eq!(conn.tag_string(), "env=staging,team=db");
+        conn.set_tags([("env".to_string(), None)]);
+        assert_eq!(conn.summary()["tags"], json!({ "team": "db" }));
+    }
+
+    #[tokio::test]
+    async fn test_forward_breakpoint() {
+        let conn = Arc::new(Connection::new(
+            1,
+            "127.0.0.1:1234".parse().unwrap(),
+            "up:80".into(),
+        ));
+        conn.breakpoints
+            .add(Direction::Up, Trigger::Pattern(b"STOP".to_vec()))
+            .unwrap();
+        let (mut client, mut reader) = tokio::io::duplex(64);
+        let (mut writer, mut server) = tokio::io::duplex(64);
+        let forward = tokio::spawn({
+            let conn = conn.clone();
+            async move {
+                conn.forward(&mut reader, &mut writer, Direction::Up, |_| {})
+                    .await
+            }
+        });
+
+        client.write_all(b"go STOP go").await.unwrap();
+        let mut buf = [0; 3];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"go ");
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while conn.breakpoints.to_json()["paused"]["up"].is_null() {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(conn.breakpoints.to_json()["paused"]["up"]["offset"], 3);
+        assert_eq!(conn.bytes_up.load(Ordering::Relaxed), 3);
+
+        assert!(conn.breakpoints.resume(Some(Direction::Up)));
+        drop(client);
+        let mut rest = Vec::new();
+        server.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(rest, b"STOP go");
+        assert_eq!(forward.await.unwrap().unwrap(), 10);
+    }
+
+    #[tokio::test]
+    async fn test_forward_toxics() {
+        let conn = Connection::new(1, "127.0.0.1:1234".parse().unwrap(), "up:80".into());
+        conn.update_toxics(
+            Some(Direction::Down),
+            &json!({ "latency": "50ms", "rate": 2000 }),
+        )
+        .unwrap();
+        assert_eq!(conn.toxics().up, Toxic::default());
+
+        let mut reader: &[u8] = &[0; 200];
+        let mut writer = Vec::new();
+        let start = Instant::now();
+        conn.forward(&mut reader, &mut writer, Direction::Down, |_| {})
+            .await
+            .unwrap();
+        // 50ms of latency, then 100 bytes every 50ms.
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert_eq!(writer.len(), 200);
+        assert_eq!(conn.bytes_down.load(Ordering::Relaxed), 200);
+
+        let start = Instant::now();
+        let mut reader: &[u8] = &[0; 200];
+        conn.forward(&mut reader, &mut writer, Direction::Up, |_| {})
+            .await
+            .unwrap();
+        assert!(start.elapsed() < Duration::from_millis(50));
+    }
+}
@@ -0,0 +1,570 @@
+//! DNS proxying for `--mode dns`.
+//!
+//! Queries are accepted over both UDP and TCP on the listen address and forwarded
+//! to the upstream resolver using the same transport. Each query and response is
+//! logged, and `--dns-fault` can answer a fraction of queries with an error rcode,
+//! drop them, or delay them, while `--dns-ttl` rewrites the TTL of every record in
+//! forwarded responses.
+
+use std::error::Error;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::FutureExt;
+use rand::Rng;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::oneshot;
+use tracing::{info, warn};
+
+use crate::connection::Direction;
+use crate::state::State;
+use crate::{bind, connect_upstream, parse_duration, track_close, track_open, Args};
+
+/// How long to wait for the upstream to answer a query.
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(5);
+
+const HEADER_SIZE: usize = 12;
+
+const RCODE_SERVFAIL: u8 = 2;
+const RCODE_NXDOMAIN: u8 = 3;
+const RCODE_REFUSED: u8 = 5;
+
+/// The OPT pseudo-record, whose TTL field holds EDNS flags rather than a TTL.
+const TYPE_OPT: u16 = 41;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    /// Answer with this rcode instead of asking the upstream.
+    Rcode(u8),
+    /// Don't answer at all.
+    Drop,
+    /// Forward the query as usual, but only after the fault's delay.
+    Delay,
+}
+
+/// A fault applied to a random fraction of queries. Written as
+/// `ACTION:PROBABILITY[:DELAY]`, where the action is one of `nxdomain`, `servfail`,
+/// `refused`, `drop` or `delay`, e.g. `nxdomain:0.1` or `delay:0.5:2s`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Fault {
+    pub action: Action,
+    pub probability: f64,
+    pub delay: Duration,
+}
+
+impl FromStr for Fault {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(3, ':');
+        let action = match parts.next() {
+            Some("nxdomain") => Action::Rcode(RCODE_NXDOMAIN),
+            Some("servfail") => Action::Rcode(RCODE_SERVFAIL),
+            Some("refused") => Action::Rcode(RCODE_REFUSED),
+            Some("drop") => Action::Drop,
+            Some("delay") => Action::Delay,
+            _ => return Err(format!("invalid dns fault action: {}", s)),
+        };
+        let probability = parts
+            .next()
+            .and_then(|p| p.parse::<f64>().ok())
+            .filter(|p| (0.0..=1.0).contains(p))
+            .ok_or_else(|| format!("invalid fault probability: {}", s))?;
+        let delay = match parts.next() {
+            Some(delay) => parse_duration(delay)?,
+            None if action == Action::Delay => {
+                return Err(format!("delay fault needs a delay: {}", s))
+            }
+            None => Duration::ZERO,
+        };
+        Ok(Fault {
+            action,
+            probability,
+            delay,
+        })
+    }
+}
+
+/// The first question of a message.
+#[derive(Debug, PartialEq)]
+struct Question {
+    name: String,
+    qtype: u16,
+    /// Where the question section ends.
+    end: usize,
+}
+
+fn u16_at(msg: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(msg.get(pos..pos + 2)?.try_into().ok()?))
+}
+
+/// Read a possibly compressed name starting at `pos`, returning it and the position
+/// just after it.
+fn read_name(msg: &[u8], mut pos: usize) -> Option<(String, usize)> {
+    let mut labels: Vec<String> = Vec::new();
+    let mut end = None;
+    // Bound the pointers followed so a malicious loop can't hang us.
+    for _ in 0..128 {
+        let len = *msg.get(pos)? as usize;
+        if len & 0xc0 == 0xc0 {
+            let target = (u16_at(msg, pos)? & 0x3fff) as usize;
+            end.get_or_insert(pos + 2);
+            pos = target;
+            continue;
+        }
+        if len == 0 {
+            let name = if labels.is_empty() {
+                ".".to_string()
+            } else {
+                labels.join(".")
+            };
+            return Some((name, end.unwrap_or(pos + 1)));
+        }
+        let label = msg.get(pos + 1..pos + 1 + len)?;
+        labels.push(String::from_utf8_lossy(label).into_owned());
+        pos += 1 + len;
+    }
+    None
+}
+
+fn parse_question(msg: &[u8]) -> Option<Question> {
+    if u16_at(msg, 4)? == 0 {
+        return None;
+    }
+    let (name, pos) = read_name(msg, HEADER_SIZE)?;
+    let qtype = u16_at(msg, pos)?;
+    u16_at(msg, pos + 2)?;
+    Some(Question {
+        name,
+        qtype,
+        end: pos + 4,
+    })
+}
+
+fn type_name(qtype: u16) -> String {
+    match qtype {
+        1 => "A".into(),
+        2 => "NS".into(),
+        5 => "CNAME".into(),
+        6 => "SOA".into(),
+        12 => "PTR".into(),
+        15 => "MX".into(),
+        16 => "TXT".into(),
+        28 => "AAAA".into(),
+        33 => "SRV".into(),
+        65 => "HTTPS".into(),
+        n => format!("TYPE{}", n),
+    }
+}
+
+fn rcode(msg: &[u8]) -> u8 {
+    msg.get(3).map_or(0, |b| b & 0x0f)
+}
+
+/// Set the TTL of every record in a response, returning how many were changed.
+fn rewrite_ttls(msg: &mut [u8], ttl: u32) -> Option<usize> {
+    let question = parse_question(msg)?;
+    let records: usize = (6..12)
+        .step_by(2)
+        .map(|pos| u16_at(msg, pos).map(usize::from))
+        .sum::<Option<usize>>()?;
+    let mut pos = question.end;
+    let mut rewritten = 0;
+    for _ in 0..records {
+        let (_, after_name) = read_name(msg, pos)?;
+        let rtype = u16_at(msg, after_name)?;
+        let rdlength = u16_at(msg, after_name + 8)? as usize;
+        if rtype != TYPE_OPT {
+            msg.get_mut(after_name + 4..after_name + 8)?
+                .copy_from_slice(&ttl.to_be_bytes());
+            rewritten += 1;
+        }
+        pos = after_name + 10 + rdlength;
+    }
+    Some(rewritten)
+}
+
+/// A response to `query` with the given rcode and no records.
+fn synthesize(query: &[u8], question: &Question, rcode: u8) -> Vec<u8> {
+    let mut msg = query[..question.end].to_vec();
+    // Set QR and keep the opcode and RD bit; set RA and the rcode.
+    msg[2] = 0x80 | (query[2] & 0x79);
+    msg[3] = 0x80 | rcode;
+    // One question, no answer, authority or additional records.
+    msg[4..12].copy_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+    msg
+}
+
+fn pick_fault(faults: &[Fault]) -> Option<&Fault> {
+    let mut rng = rand::thread_rng();
+    faults.iter().find(|f| rng.gen_bool(f.probability))
+}
+
+/// Answer one query, using `forward` to ask the upstream. Returns `None` when the
+/// query should go unanswered.
+async fn respond<F, Fut>(
+    args: &Args,
+    state: &State,
+    client: SocketAddr,
+    query: Vec<u8>,
+    forward: F,
+) -> Option<Vec<u8>>
+where
+    F: FnOnce(Vec<u8>) -> Fut,
+    Fut: Future<Output = io::Result<Vec<u8>>>,
+{
+    state.dns_queries.fetch_add(1, Ordering::Relaxed);
+    let question = match parse_question(&query) {
+        Some(question) => question,
+        None => {
+            warn!(%client, "dropping malformed dns query");
+            return None;
+        }
+    };
+    info!(
+        %client,
+        id = u16_at(&query, 0).unwrap_or(0),
+        name = %question.name,
+        r#type = %type_name(question.qtype),
+        "dns query"
+    );
+
+    if let Some(fault) = pick_fault(&args.dns_fault) {
+        state.dns_faults_injected.fetch_add(1, Ordering::Relaxed);
+        tokio::time::sleep(fault.delay).await;
+        match fault.action {
+            Action::Rcode(rcode) => {
+                info!(name = %question.name, rcode, "injecting dns fault");
+                return Some(synthesize(&query, &question, rcode));
+            }
+            Action::Drop => {
+                warn!(name = %question.name, "dropping dns query");
+                return None;
+            }
+            Action::Delay => {}
+        }
+    }
+
+    let mut response = match tokio::time::timeout(UPSTREAM_TIMEOUT, forward(query.clone())).await {
+        Ok(Ok(response)) => response,
+        Ok(Err(err)) => {
+            warn!(name = %question.name, error = %err, "failed to query upstream");
+            return Some(synthesize(&query, &question, RCODE_SERVFAIL));
+        }
+        Err(_) => {
+            warn!(name = %question.name, error = "timed out", "failed to query upstream");
+            return Some(synthesize(&query, &question, RCODE_SERVFAIL));
+        }
+    };
+    if let Some(ttl) = args.dns_ttl {
+        rewrite_ttls(&mut response, ttl);
+    }
+    info!(
+        name = %question.name,
+        rcode = rcode(&response),
+        answers = u16_at(&response, 6).unwrap_or(0),
+        "dns response"
+    );
+    Some(response)
+}
+
+/// Serve DNS over UDP and TCP on the same address.
+pub async fn listen(
+    args: Arc<Args>,
+    state: Arc<State>,
+    ready: oneshot::Sender<SocketAddr>,
+) -> Result<(), Box<dyn Error>> {
+    let mut attempts = 0;
+    let (socket, listener) = loop {
+        let socket = UdpSocket::bind(&args.listen_addr).await?;
+        let addr = socket.local_addr()?;
+        // Use the UDP socket's port, which matters when listening on port 0. The TCP
+        // port may be taken even though the UDP one was free, so then try another.
+        match bind(&addr.to_string(), args.backlog).await {
+            Ok(listener) => break (Arc::new(socket), listener),
+            Err(err)
+                if err.kind() == io::ErrorKind::AddrInUse
+                    && args.listen_addr.ends_with(":0")
+                    && attempts < 10 =>
+            {
+                attempts += 1;
+            }
+            Err(err) => return Err(err.into()),
+        }
+    };
+    let addr = socket.local_addr()?;
+    let _ = ready.send(addr);
+
+    let tcp = {
+        let args = args.clone();
+        let state = state.clone();
+        async move {
+            loop {
+                let (stream, client) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(err) => {
+                        warn!(error = %err, "failed to accept");
+                        state.accept_errors.fetch_add(1, Ordering::Relaxed);
+                        tokio::time::sleep(args.accept_backoff).await;
+                        continue;
+                    }
+                };
+                let state = state.clone();
+                tokio::spawn(serve_tcp(stream, client, args.clone(), state.clone()).map(
+                    move |r| {
+                        if let Err(err) = r {
+                            warn!(%client, error = %err, "failed to serve dns over tcp");
+                        }
+                    },
+                ));
+            }
+        }
+    };
+    tokio::spawn(tcp);
+
+    let mut buf = vec![0; 65535];
+    loop {
+        let (n, client) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(err) => {
+                warn!(error = %err, "failed to receive");
+                continue;
+            }
+        };
+        let query = buf[..n].to_vec();
+        let (args, state, socket) = (args.clone(), state.clone(), socket.clone());
+        tokio::spawn(async move {
+            let upstream_addr = args.upstream_addr.clone();
+            let upstream_state = state.clone();
+            let response = respond(&args, &state, client, query, |query| {
+                forward_udp(upstream_addr, upstream_state, query)
+            })
+            .await;
+            if let Some(response) = response {
+                if let Err(err) = socket.send_to(&response, client).await {
+                    warn!(%client, error = %err, "failed to send dns response");
+                }
+            }
+        });
+    }
+}
+
+async fn forward_udp(
+    upstream_addr: String,
+    state: Arc<State>,
+    query: Vec<u8>,
+) -> io::Result<Vec<u8>> {
+    let upstream = crate::udp::connect(&upstream_addr, &state).await?;
+    upstream.send(&query).await?;
+    let mut buf = vec![0; 65535];
+    loop {
+        let n = upstream.recv(&mut buf).await?;
+        // Ignore anything that isn't the answer to this query.
+        if n >= 2 && buf[..2] == query[..2] {
+            return Ok(buf[..n].to_vec());
+        }
+    }
+}
+
+/// Serve length-prefixed queries on one TCP connection, forwarding them over a
+/// single upstream TCP connection.
+async fn serve_tcp(
+    mut downstream: TcpStream,
+    client: SocketAddr,
+    args: Arc<Args>,
+    state: Arc<State>,
+) -> Result<(), Box<dyn Error>> {
+    let conn = state.open_connection(client, args.upstream_addr.clone());
+    let upstream = connect_upstream(&args.upstream_addr, &args, &state).await;
+    let result = match upstream {
+        Ok(mut upstream) => {
+            conn.connected();
+            track_open(&state, &conn);
+            let result: Result<(), Box<dyn Error>> = async {
+                while let Some(query) = read_message(&mut downstream).await? {
+                    conn.add_bytes(Direction::Up, query.len() as u64 + 2);
+                    let upstream = &mut upstream;
+                    let response = respond(&args, &state, client, query, |query| async move {
+                        write_message(upstream, &query).await?;
+                        read_message(upstream).await?.ok_or_else(|| {
+                            io::Error::new(io::ErrorKind::UnexpectedEof, "upstream closed")
+                        })
+                    })
+                    .await;
+                    if let Some(response) = response {
+                        conn.add_bytes(Direction::Down, response.len() as u64 + 2);
+                        write_message(&mut downstream, &response).await?;
+                    }
+                }
+                Ok(())
+            }
+            .await;
+            track_close(&state, &conn, result.is_ok());
+            result
+        }
+        Err(err) => Err(err.into()),
+    };
+    let reason = match &result {
+        Ok(()) => "completed".to_string(),
+        Err(err) => err.to_string(),
+    };
+    state.close_connection(&conn, reason);
+    result
+}
+
+/// Read a two byte length prefixed message, or `None` at a clean end of stream.
+async fn read_message(stream: &mut TcpStream) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0; 2];
+    match stream.read_exact(&mut len).await {
+        Ok(_) => {}
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    }
+    let mut msg = vec![0; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut msg).await?;
+    Ok(Some(msg))
+}
+
+async fn write_message(stream: &mut TcpStream, msg: &[u8]) -> io::Result<()> {
+    let mut framed = (msg.len() as u16).to_be_bytes().to_vec();
+    framed.extend_from_slice(msg);
+    stream.write_all(&framed).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use clap::Parser;
+
+    /// A query for `name`, with the RD bit set.
+    fn query(id: u16, name: &str, qtype: u16) -> Vec<u8> {
+        let mut msg = id.to_be_bytes().to_vec();
+        msg.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+        for label in name.split('.') {
+            msg.push(label.len() as u8);
+            msg.extend_from_slice(label.as_bytes());
+        }
+        msg.push(0);
+        msg.extend_from_slice(&qtype.to_be_bytes());
+        msg.extend_from_slice(&[0, 1]);
+        msg
+    }
+
+    /// Answer `query` with one A record, using a compressed name, with a TTL of 300.
+    fn answer(query: &[u8]) -> Vec<u8> {
+        let mut msg = query.to_vec();
+        msg[2] = 0x81;
+        msg[3] = 0x80;
+        msg[6..8].copy_from_slice(&[0, 1]);
+        msg.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1]);
+        msg.extend_from_slice(&300u32.to_be_bytes());
+        msg.extend_from_slice(&[0, 4, 10, 0, 0, 1]);
+        msg
+    }
+
+    fn ttl(msg: &[u8]) -> u32 {
+        let end = parse_question(msg).unwrap().end;
+        u32::from_be_bytes(msg[end + 6..end + 10].try_into().unwrap())
+    }
+
+    #[test]
+    fn test_parse() {
+        let q = query(7, "www.example.com", 28);
+        let question = parse_question(&q).unwrap();
+        assert_eq!(question.name, "www.example.com");
+        assert_eq!(type_name(question.qtype), "AAAA");
+        assert_eq!(question.end, q.len());
+
+        let mut a = answer(&q);
+        assert_eq!(ttl(&a), 300);
+        assert_eq!(rewrite_ttls(&mut a, 5), Some(1));
+        assert_eq!(ttl(&a), 5);
+
+        let nx = synthesize(&q, &question, RCODE_NXDOMAIN);
+        assert_eq!(&nx[..2], &[0, 7]);
+        assert_eq!(rcode(&nx), RCODE_NXDOMAIN);
+        assert_eq!(nx[2] & 0x80, 0x80);
+
+        // A pointer to itself.
+        assert_eq!(read_name(&[0xc0, 0], 0), None);
+        assert!(parse_question(&q[..q.len() - 1]).is_none());
+    }
+
+    #[test]
+    fn test_parse_fault() {
+        assert_eq!(
+            "nxdomain:0.5".parse::<Fault>(),
+            Ok(Fault {
+                action: Action::Rcode(RCODE_NXDOMAIN),
+                probability: 0.5,
+                delay: Duration::ZERO,
+            })
+        );
+        assert_eq!(
+            "delay:1:2s".parse::<Fault>().unwrap().delay,
+            Duration::from_secs(2)
+        );
+        assert!("delay:1".parse::<Fault>().is_err());
+        assert!("bogus:1".parse::<Fault>().is_err());
+    }
+
+    async fn start(extra_args: &[&str]) -> (SocketAddr, Arc<State>) {
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = vec![0; 512];
+            loop {
+                let (n, from) = upstream.recv_from(&mut buf).await.unwrap();
+                upstream.send_to(&answer(&buf[..n]), from).await.unwrap();
+            }
+        });
+        let mut args = vec!["tproxy", "--listen-addr", "127.0.0.1:0", "--upstream-addr"];
+        let upstream_addr = upstream_addr.to_string();
+        args.push(&upstream_addr);
+        args.extend_from_slice(&["--mode", "dns"]);
+        args.extend_from_slice(extra_args);
+        let state = Arc::new(State::new());
+        let (tx, rx) = oneshot::channel();
+        tokio::spawn(
+            listen(Arc::new(Args::parse_from(args)), state.clone(), tx).map(|r| {
+                if let Err(err) = r {
+                    println!("failed to listen; error={}", err);
+                }
+            }),
+        );
+        (rx.await.unwrap(), state)
+    }
+
+    async fn ask(proxy: SocketAddr, query: &[u8]) -> Vec<u8> {
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(query, proxy).await.unwrap();
+        let mut buf = vec![0; 512];
+        let n = client.recv(&mut buf).await.unwrap();
+        buf[..n].to_vec()
+    }
+
+    #[tokio::test]
+    async fn test_dns_proxy() {
+        let (proxy, state) = start(&["--dns-ttl", "10"]).await;
+        let response = ask(proxy, &query(1, "example.com", 1)).await;
+        assert_eq!(rcode(&response), 0);
+        assert_eq!(ttl(&response), 10);
+        assert_eq!(state.snapshot().dns_queries, 1);
+    }
+
+    #[tokio::test]
+    async fn test_dns_fault() {
+        let (proxy, state) = start(&["--dns-fault", "nxdomain:1"]).await;
+        let response = ask(proxy, &query(2, "example.com", 1)).await;
+        assert_eq!(rcode(&response), RCODE_NXDOMAIN);
+        assert_eq!(&response[..2], &[0, 2]);
+        assert_eq!(state.snapshot().dns_faults_injected, 1);
+    }
+}
@@ -0,0 +1,177 @@
+//! Connection events for embedders: accepted, connected upstream, bytes copied passing
+//! milestones, and closed.
+//!
+//! A test framework running the proxy in-process can subscribe to `State::events`
+//! before connecting and assert on what the proxy saw, rather than polling the HTTP
+//! API. Events are broadcast, so a subscriber more than [`CAPACITY`] events behind
+//! misses the oldest and is told how many.
+
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::task::JoinHandle;
+use tracing::warn;
+
+use crate::connection::Direction;
+
+/// How many events a subscriber may fall behind by.
+pub const CAPACITY: usize = 4096;
+
+/// Byte counts announced unless [`Events::set_milestones`] says otherwise: the first
+/// byte, then 1KiB, 1MiB and 1GiB.
+pub const DEFAULT_MILESTONES: [u64; 4] = [1, 1 << 10, 1 << 20, 1 << 30];
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    /// A client connected.
+    Accepted {
+        id: u64,
+        downstream_addr: SocketAddr,
+    },
+    /// The connection reached its upstream.
+    Connected { id: u64, upstream_addr: String },
+    /// The bytes copied `direction` reached the milestone `bytes`.
+    Bytes {
+        id: u64,
+        direction: Direction,
+        bytes: u64,
+    },
+    /// The connection closed, having copied these many bytes.
+    Closed {
+        id: u64,
+        reason: String,
+        bytes_up: u64,
+        bytes_down: u64,
+    },
+}
+
+#[derive(Clone, Debug)]
+pub struct Events {
+    sender: broadcast::Sender<Event>,
+    /// Sorted, without duplicates.
+    milestones: Arc<Mutex<Vec<u64>>>,
+}
+
+impl Default for Events {
+    fn default() -> Self {
+        Events {
+            sender: broadcast::channel(CAPACITY).0,
+            milestones: Arc::new(Mutex::new(DEFAULT_MILESTONES.to_vec())),
+        }
+    }
+}
+
+impl Events {
+    /// Every event from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+
+    /// Call `f` with every event from now on, in a task of its own.
+    pub fn on(&self, mut f: impl FnMut(Event) + Send + 'static) -> JoinHandle<()> {
+        let mut events = self.subscribe();
+        tokio::spawn(async move {
+            loop {
+                match events.recv().await {
+                    Ok(event) => f(event),
+                    Err(RecvError::Lagged(missed)) => warn!(missed, "event callback fell behind"),
+                    Err(RecvError::Closed) => return,
+                }
+            }
+        })
+    }
+
+    /// Announce when a connection's bytes copied either way pass each of `milestones`.
+    pub fn set_milestones(&self, mut milestones: Vec<u64>) {
+        milestones.sort_unstable();
+        milestones.dedup();
+        *self.milestones.lock().unwrap() = milestones;
+    }
+
+    pub fn send(&self, event: Event) {
+        // Nobody listening is the usual case.
+        let _ = self.sender.send(event);
+    }
+
+    /// Announce the milestones passed as connection `id`'s bytes copied `direction` went
+    /// from `before` to `after`.
+    pub fn bytes(&self, id: u64, direction: Direction, before: u64, after: u64) {
+        if self.sender.receiver_count() == 0 {
+            return;
+        }
+        let milestones = self.milestones.lock().unwrap();
+        for &bytes in milestones.iter().filter(|m| before < **m && **m <= after) {
+            self.send(Event::Bytes {
+                id,
+                direction,
+                bytes,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::state::State;
+
+    #[tokio::test]
+    async fn test_events() {
+        let state = State::new();
+        state.events.set_milestones(vec![10, 5, 10]);
+        let mut events = state.events.subscribe();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let callback = {
+            let seen = seen.clone();
+            state
+                .events
+                .on(move |event| seen.lock().unwrap().push(event))
+        };
+
+        let downstream_addr = "127.0.0.1:1234".parse().unwrap();
+        let conn = state.open_connection(downstream_addr, "up:80".into());
+        conn.connected();
+        conn.add_bytes(Direction::Up, 4);
+        conn.add_bytes(Direction::Up, 20);
+        conn.add_bytes(Direction::Down, 1);
+        state.close_connection(&conn, "completed".into());
+
+        let id = conn.id;
+        let expected = vec![
+            Event::Accepted {
+                id,
+                downstream_addr,
+            },
+            Event::Connected {
+                id,
+                upstream_addr: "up:80".into(),
+            },
+            Event::Bytes {
+                id,
+                direction: Direction::Up,
+                bytes: 5,
+            },
+            Event::Bytes {
+                id,
+                direction: Direction::Up,
+                bytes: 10,
+            },
+            Event::Closed {
+                id,
+                reason: "completed".into(),
+                bytes_up: 24,
+                bytes_down: 1,
+            },
+        ];
+        for event in &expected {
+            assert_eq!(&events.recv().await.unwrap(), event);
+        }
+        while seen.lock().unwrap().len() < expected.len() {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(*seen.lock().unwrap(), expected);
+        callback.abort();
+    }
+}
@@ -0,0 +1,98 @@
+//! File-descriptor budget tracking.
+//!
+//! Every proxied connection costs two descriptors, so a busy proxy runs into
+//! `RLIMIT_NOFILE` long before it runs out of anything else.
+
+use std::io;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tracing::warn;
+
+use crate::state::State;
+
+/// How often the monitor samples the number of open descriptors.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Raise the soft `RLIMIT_NOFILE` towards `target`, capped at the hard limit.
+/// Returns the resulting soft limit.
+pub fn raise_nofile_limit(target: u64) -> io::Result<u64> {
+    let mut limit = get_nofile_limit()?;
+    let wanted = target.min(limit.rlim_max as u64);
+    if wanted > limit.rlim_cur as u64 {
+        limit.rlim_cur = wanted as libc::rlim_t;
+        // SAFETY: `limit` is a valid, initialized rlimit.
+        if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &limit) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(limit.rlim_cur as u64)
+}
+
+/// The current soft `RLIMIT_NOFILE`.
+pub fn nofile_limit() -> io::Result<u64> {
+    Ok(get_nofile_limit()?.rlim_cur as u64)
+}
+
+fn get_nofile_limit() -> io::Result<libc::rlimit> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: `limit` is a valid out pointer for the duration of the call.
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(limit)
+}
+
+/// The number of descriptors currently open by this process.
+pub fn open_fds() -> io::Result<usize> {
+    let dir = if cfg!(target_os = "linux") {
+        "/proc/self/fd"
+    } else {
+        "/dev/fd"
+    };
+    // The directory handle used for listing is itself counted, so don't report it.
+    Ok(std::fs::read_dir(dir)?.count().saturating_sub(1))
+}
+
+/// Periodically record descriptor usage in `state`, warning once usage crosses
+/// `warn_ratio` of the limit.
+pub async fn monitor(state: Arc<State>, warn_ratio: f64) {
+    let mut warned = false;
+    let mut interval = tokio::time::interval(POLL_INTERVAL);
+    loop {
+        interval.tick().await;
+        let (open, limit) = match (open_fds(), nofile_limit()) {
+            (Ok(open), Ok(limit)) => (open, limit),
+            (Err(err), _) | (_, Err(err)) => {
+                warn!(error = %err, "failed to sample file descriptors");
+                continue;
+            }
+        };
+        let exhausted = open as f64 >= limit as f64 * warn_ratio;
+        if exhausted && !warned {
+            warn!(open, limit, "file descriptors near exhaustion");
+        }
+        warned = exhausted;
+
+        state.open_fds.store(open, Ordering::Relaxed);
+        state.fd_limit.store(limit, Ordering::Relaxed);
+        state.fd_exhausted.store(exhausted, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_raise_nofile_limit() {
+        let before = nofile_limit().unwrap();
+        assert_eq!(raise_nofile_limit(0).unwrap(), before);
+        assert!(raise_nofile_limit(before + 1).unwrap() >= before);
+        assert!(open_fds().unwrap() > 0);
+    }
+}
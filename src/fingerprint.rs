@@ -0,0 +1,186 @@
+//! JA3 and JA4 fingerprints of TLS ClientHellos, for `--tls-fingerprint`.
+//!
+//! Both summarize what a client offers (versions, cipher suites, extensions and so on)
+//! rather than who it is, so every connection from the same TLS library and
+//! configuration gets the same fingerprint. JA3 is the MD5 of those lists in the order
+//! they were sent. JA4 sorts them first, so clients which shuffle their extensions
+//! still match, and keeps a readable prefix such as `t13d1516h2`. GREASE values are
+//! left out of both.
+
+use crate::digest::{hex, md5, sha256};
+use crate::hello::{ClientHello, EXTENSION_ALPN, EXTENSION_SERVER_NAME};
+use crate::state::State;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Fingerprint {
+    /// The MD5 of the JA3 string, in hex.
+    pub ja3: String,
+    pub ja4: String,
+}
+
+impl Fingerprint {
+    pub fn of(hello: &ClientHello) -> Self {
+        Fingerprint {
+            ja3: hex(&md5(ja3(hello).as_bytes())),
+            ja4: ja4(hello),
+        }
+    }
+
+    /// Tag the connection with `tls.ja3` and `tls.ja4`, and count it.
+    pub fn record(self, state: &State, tags: &mut Vec<(String, String)>) {
+        tags.push(("tls.ja3".to_string(), self.ja3.clone()));
+        tags.push(("tls.ja4".to_string(), self.ja4.clone()));
+        state
+            .tls_fingerprints
+            .with_entry((self.ja3, self.ja4), 0, |count| *count += 1);
+    }
+}
+
+/// GREASE values (RFC 8701) are 0x0a0a, 0x1a1a, ... 0xfafa.
+fn is_grease(value: u16) -> bool {
+    value & 0x0f0f == 0x0a0a && value >> 8 == value & 0xff
+}
+
+fn without_grease(values: &[u16]) -> Vec<u16> {
+    values.iter().copied().filter(|v| !is_grease(*v)).collect()
+}
+
+fn join<T: ToString>(values: &[T], separator: &str) -> String {
+    values
+        .iter()
+        .map(|v| v.to_string())
+        .collect::<Vec<_>>()
+        .join(separator)
+}
+
+/// `VERSION,CIPHERS,EXTENSIONS,GROUPS,POINT_FORMATS`, each list dash separated.
+pub fn ja3(hello: &ClientHello) -> String {
+    format!(
+        "{},{},{},{},{}",
+        hello.version,
+        join(&without_grease(&hello.cipher_suites), "-"),
+        join(&without_grease(&hello.extensions), "-"),
+        join(&without_grease(&hello.supported_groups), "-"),
+        join(&hello.point_formats, "-"),
+    )
+}
+
+/// The first 12 hex digits of the SHA-256 of `s`, or zeros if it's empty.
+fn truncated_hash(s: &str) -> String {
+    if s.is_empty() {
+        return "0".repeat(12);
+    }
+    hex(&sha256(s.as_bytes()))[..12].to_string()
+}
+
+fn hex_list(values: &[u16]) -> String {
+    values
+        .iter()
+        .map(|v| format!("{:04x}", v))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+pub fn ja4(hello: &ClientHello) -> String {
+    let version = without_grease(&hello.supported_versions)
+        .into_iter()
+        .max()
+        .unwrap_or(hello.version);
+    let version = match version {
+        0x0304 => "13",
+        0x0303 => "12",
+        0x0302 => "11",
+        0x0301 => "10",
+        0x0300 => "s3",
+        _ => "00",
+    };
+    let sni = if hello.server_name.is_some() {
+        'd'
+    } else {
+        'i'
+    };
+    let mut ciphers = without_grease(&hello.cipher_suites);
+    let mut extensions = without_grease(&hello.extensions);
+    let alpn = match hello.alpn.first().map(|p| p.as_bytes()) {
+        Some([first, .., last]) | Some([first @ last]) => {
+            if first.is_ascii_alphanumeric() && last.is_ascii_alphanumeric() {
+                format!("{}{}", *first as char, *last as char)
+            } else {
+                let (first, last) = (format!("{:02x}", first), format!("{:02x}", last));
+                format!("{}{}", &first[..1], &last[1..])
+            }
+        }
+        _ => "00".to_string(),
+    };
+    let prefix = format!(
+        "t{}{}{:02}{:02}{}",
+        version,
+        sni,
+        ciphers.len().min(99),
+        extensions.len().min(99),
+        alpn
+    );
+
+    ciphers.sort_unstable();
+    extensions.retain(|e| *e != EXTENSION_SERVER_NAME && *e != EXTENSION_ALPN);
+    extensions.sort_unstable();
+    let mut offered = hex_list(&extensions);
+    if !offered.is_empty() && !hello.signature_algorithms.is_empty() {
+        offered.push('_');
+        offered += &hex_list(&hello.signature_algorithms);
+    }
+    format!(
+        "{}_{}_{}",
+        prefix,
+        truncated_hash(&hex_list(&ciphers)),
+        truncated_hash(&offered)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hello() -> ClientHello {
+        ClientHello {
+            server_name: Some("db.example.com".to_string()),
+            alpn: vec!["h2".to_string(), "http/1.1".to_string()],
+            version: 0x0303,
+            cipher_suites: vec![0x2a2a, 0x1302, 0x1301, 0xc02b],
+            extensions: vec![0x3a3a, 0, 23, 16, 13, 10, 11, 43],
+            supported_groups: vec![0x4a4a, 29, 23],
+            point_formats: vec![0],
+            supported_versions: vec![0x5a5a, 0x0304, 0x0303],
+            signature_algorithms: vec![0x0403, 0x0804],
+        }
+    }
+
+    #[test]
+    fn test_ja3() {
+        assert_eq!(
+            ja3(&hello()),
+            "771,4866-4865-49195,0-23-16-13-10-11-43,29-23,0"
+        );
+        assert_eq!(
+            Fingerprint::of(&hello()).ja3,
+            "2f258da2496565fdd8ac415d981974c6"
+        );
+        assert!(is_grease(0xfafa));
+        assert!(!is_grease(0x0a1a));
+    }
+
+    #[test]
+    fn test_ja4() {
+        assert_eq!(ja4(&hello()), "t13d0307h2_5559582ccdc4_38dbf9c86be1");
+        let bare = ClientHello {
+            version: 0x0301,
+            ..Default::default()
+        };
+        assert_eq!(ja4(&bare), "t10i000000_000000000000_000000000000");
+        let odd = ClientHello {
+            alpn: vec!["\u{1}x\u{ff}".to_string()],
+            ..hello()
+        };
+        assert!(ja4(&odd).starts_with("t13d03070f_"));
+    }
+}
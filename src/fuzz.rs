@@ -0,0 +1,301 @@
+//! `--fuzz-rate`: mutating the bytes of TCP-mode connections, to find how a backend
+//! copes with malformed input.
+//!
+//! Each byte starts a mutation with probability `--fuzz-rate`, using one of the
+//! `--fuzz-strategy` strategies. Where mutations land, and what they do, is decided by a
+//! generator seeded per connection and direction by stream offset rather than by read,
+//! so the same seed and the same bytes give the same mutations however they arrive. The
+//! seed is logged and tagged on the connection as `fuzz.seed`; run again with
+//! `--fuzz-seed` to reproduce a crash. The down direction is seeded with the seed plus
+//! one.
+
+use std::io;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::Ordering;
+use std::task::{Context, Poll};
+
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use tokio::io::{AsyncRead, ReadBuf};
+
+use crate::connection::COPY_BUFFER_SIZE;
+use crate::state::State;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Strategy {
+    /// Flip one bit of the byte.
+    BitFlip,
+    /// End the stream before the byte.
+    Truncate,
+    /// Send the next 1 to 64 bytes twice.
+    Duplicate,
+    /// Mangle the next HTTP field boundary (CR, LF, space or colon): drop it, repeat it
+    /// or swap it for another.
+    Http,
+}
+
+pub const STRATEGIES: [Strategy; 4] = [
+    Strategy::BitFlip,
+    Strategy::Truncate,
+    Strategy::Duplicate,
+    Strategy::Http,
+];
+
+impl FromStr for Strategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bitflip" => Ok(Strategy::BitFlip),
+            "truncate" => Ok(Strategy::Truncate),
+            "duplicate" => Ok(Strategy::Duplicate),
+            "http" => Ok(Strategy::Http),
+            _ => Err(format!(
+                "unknown fuzz strategy: {} (expected bitflip, truncate, duplicate or http)",
+                s
+            )),
+        }
+    }
+}
+
+const BOUNDARIES: &[u8] = b"\r\n :";
+
+/// The mutations of one direction of a connection.
+#[derive(Debug)]
+pub struct Fuzzer {
+    rng: StdRng,
+    rate: f64,
+    strategies: Vec<Strategy>,
+    /// Bytes of the stream seen so far.
+    offset: u64,
+    /// Where the next mutation starts.
+    next: u64,
+    /// Bytes to send again once this many more have been seen.
+    duplicating: Option<(Vec<u8>, usize)>,
+    /// Waiting for a field boundary to mangle.
+    http: bool,
+    truncated: bool,
+    pub mutations: u64,
+}
+
+impl Fuzzer {
+    /// Mutate a `rate` of bytes by `strategies`, or all of them if empty.
+    pub fn new(seed: u64, rate: f64, strategies: &[Strategy]) -> Self {
+        let mut fuzzer = Fuzzer {
+            rng: StdRng::seed_from_u64(seed),
+            rate,
+            strategies: if strategies.is_empty() {
+                STRATEGIES.to_vec()
+            } else {
+                strategies.to_vec()
+            },
+            offset: 0,
+            next: 0,
+            duplicating: None,
+            http: false,
+            truncated: false,
+            mutations: 0,
+        };
+        fuzzer.next = fuzzer.gap();
+        fuzzer
+    }
+
+    /// Bytes until the next mutation, each being the start of one with `rate`.
+    fn gap(&mut self) -> u64 {
+        if self.rate <= 0.0 {
+            return u64::MAX;
+        }
+        if self.rate >= 1.0 {
+            return 0;
+        }
+        let u: f64 = self.rng.gen_range(f64::EPSILON..1.0);
+        (u.ln() / (1.0 - self.rate).ln()) as u64
+    }
+
+    /// Whether a truncation has ended the stream.
+    pub fn is_truncated(&self) -> bool {
+        self.truncated
+    }
+
+    /// Append the mutation of `input`, the next bytes of the stream, to `out`.
+    pub fn mutate(&mut self, input: &[u8], out: &mut Vec<u8>) {
+        for &b in input {
+            if self.truncated {
+                return;
+            }
+            let offset = self.offset;
+            self.offset += 1;
+            let mut byte = Some(b);
+            if offset == self.next {
+                self.mutations += 1;
+                self.next = offset.saturating_add(1).saturating_add(self.gap());
+                match *self.strategies.choose(&mut self.rng).unwrap() {
+                    Strategy::BitFlip => byte = Some(b ^ (1 << self.rng.gen_range(0..8))),
+                    Strategy::Truncate => {
+                        self.truncated = true;
+                        return;
+                    }
+                    Strategy::Duplicate => {
+                        self.duplicating = Some((Vec::new(), self.rng.gen_range(1..=64)));
+                    }
+                    Strategy::Http => self.http = true,
+                }
+            }
+            if self.http && BOUNDARIES.contains(&b) {
+                self.http = false;
+                byte = None;
+                match self.rng.gen_range(0..3) {
+                    0 => {}
+                    1 => out.extend(std::iter::repeat_n(b, self.rng.gen_range(2..=8))),
+                    _ => out.push(*BOUNDARIES.choose(&mut self.rng).unwrap()),
+                }
+            }
+            if let Some(byte) = byte {
+                out.push(byte);
+                if let Some((seen, len)) = &mut self.duplicating {
+                    seen.push(byte);
+                    if seen.len() == *len {
+                        out.extend_from_slice(seen);
+                        self.duplicating = None;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Reads from `inner`, mutated by `fuzzer` if there is one.
+pub struct Reader<'a, R> {
+    inner: R,
+    fuzzer: Option<Fuzzer>,
+    state: &'a State,
+    buf: Vec<u8>,
+    /// Mutated bytes, from `out_pos`, waiting to be read.
+    out: Vec<u8>,
+    out_pos: usize,
+}
+
+impl<'a, R> Reader<'a, R> {
+    pub fn new(inner: R, fuzzer: Option<Fuzzer>, state: &'a State) -> Self {
+        Self {
+            inner,
+            fuzzer,
+            state,
+            buf: Vec::new(),
+            out: Vec::new(),
+            out_pos: 0,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Reader<'_, R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let fuzzer = match &mut this.fuzzer {
+            Some(fuzzer) => fuzzer,
+            None => return Pin::new(&mut this.inner).poll_read(cx, buf),
+        };
+        loop {
+            if this.out_pos < this.out.len() {
+                let n = buf.remaining().min(this.out.len() - this.out_pos);
+                buf.put_slice(&this.out[this.out_pos..this.out_pos + n]);
+                this.out_pos += n;
+                return Poll::Ready(Ok(()));
+            }
+            if fuzzer.is_truncated() {
+                return Poll::Ready(Ok(()));
+            }
+            this.out.clear();
+            this.out_pos = 0;
+            this.buf.resize(COPY_BUFFER_SIZE, 0);
+            let mut read = ReadBuf::new(&mut this.buf);
+            match Pin::new(&mut this.inner).poll_read(cx, &mut read) {
+                Poll::Ready(Ok(())) => {
+                    let n = read.filled().len();
+                    if n == 0 {
+                        return Poll::Ready(Ok(()));
+                    }
+                    let before = fuzzer.mutations;
+                    fuzzer.mutate(&this.buf[..n], &mut this.out);
+                    this.state
+                        .fuzz_mutations
+                        .fetch_add((fuzzer.mutations - before) as usize, Ordering::Relaxed);
+                }
+                other => return other,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::io::AsyncReadExt;
+
+    #[test]
+    fn test_mutate() {
+        let input = b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n".repeat(50);
+        let mutate = |seed, strategies: &[Strategy], chunk: usize| {
+            let mut fuzzer = Fuzzer::new(seed, 0.01, strategies);
+            let mut out = Vec::new();
+            for chunk in input.chunks(chunk) {
+                fuzzer.mutate(chunk, &mut out);
+            }
+            (out, fuzzer.mutations)
+        };
+        // The same seed mutates the same way however the bytes arrive.
+        let (out, mutations) = mutate(7, &[], 3);
+        assert!(mutations > 0);
+        assert_ne!(out, input);
+        assert_eq!(mutate(7, &[], 1000), (out.clone(), mutations));
+        assert_ne!(mutate(8, &[], 3).0, out);
+
+        let (out, mutations) = mutate(7, &[Strategy::BitFlip], 3);
+        assert_eq!(out.len(), input.len());
+        let flipped: u32 = out
+            .iter()
+            .zip(&input)
+            .map(|(a, b)| (a ^ b).count_ones())
+            .sum();
+        assert_eq!(flipped as u64, mutations);
+
+        let (out, _) = mutate(7, &[Strategy::Truncate], 3);
+        assert!(out.len() < input.len() && input.starts_with(&out));
+        let (out, _) = mutate(7, &[Strategy::Duplicate], 3);
+        assert!(out.len() > input.len());
+        // Only field boundaries change.
+        let (out, _) = mutate(7, &[Strategy::Http], 3);
+        let strip = |b: &[u8]| -> Vec<u8> {
+            b.iter()
+                .copied()
+                .filter(|b| !BOUNDARIES.contains(b))
+                .collect()
+        };
+        assert_ne!(out, input);
+        assert_eq!(strip(&out), strip(&input));
+    }
+
+    #[tokio::test]
+    async fn test_reader() {
+        let state = State::new();
+        let input = vec![0u8; 10_000];
+        let fuzzer = Fuzzer::new(1, 0.5, &[Strategy::Truncate]);
+        let mut reader = Reader::new(&input[..], Some(fuzzer), &state);
+        let mut out = Vec::new();
+        reader.read_to_end(&mut out).await.unwrap();
+        assert!(out.len() < 100);
+        assert_eq!(state.fuzz_mutations.load(Ordering::Relaxed), 1);
+
+        let mut reader = Reader::new(&input[..], None, &state);
+        let mut out = Vec::new();
+        reader.read_to_end(&mut out).await.unwrap();
+        assert_eq!(out, input);
+    }
+}
@@ -0,0 +1,438 @@
+//! GeoIP lookups of client addresses in MaxMind databases, see `--geoip-db`.
+//!
+//! Connections are tagged with `geo.country` and `geo.asn`, counted by country, and
+//! can be refused or given toxics by location with `--geo-allow`, `--geo-deny` and
+//! `--geo-toxics`. Databases are read in the MaxMind DB format used by GeoLite2 and
+//! GeoIP2: a binary search tree over address bits whose leaves point into a data
+//! section of typed values.
+
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use serde_json::{Map, Value};
+
+use crate::toxic::Setting;
+
+/// Marks the start of the metadata, which is somewhere in the last 128KiB.
+const METADATA_MARKER: &[u8] = b"\xab\xcd\xefMaxMind.com";
+const METADATA_MAX_SIZE: usize = 128 * 1024;
+
+/// The gap between the search tree and the data section.
+const DATA_SEPARATOR: usize = 16;
+
+/// How many pointers and nested values are followed before a record is rejected.
+const MAX_DEPTH: usize = 32;
+
+pub struct Database {
+    bytes: Vec<u8>,
+    node_count: usize,
+    record_size: usize,
+    ip_version: u64,
+    data_start: usize,
+}
+
+impl fmt::Debug for Database {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Database")
+            .field("node_count", &self.node_count)
+            .field("ip_version", &self.ip_version)
+            .finish()
+    }
+}
+
+impl Database {
+    pub fn open(path: &str) -> Result<Self, String> {
+        let bytes = std::fs::read(path).map_err(|err| format!("{}: {}", path, err))?;
+        Self::from_bytes(bytes).map_err(|err| format!("{}: {}", path, err))
+    }
+
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<Self, String> {
+        let search_from = bytes.len().saturating_sub(METADATA_MAX_SIZE);
+        let marker = bytes[search_from..]
+            .windows(METADATA_MARKER.len())
+            .rposition(|w| w == METADATA_MARKER)
+            .ok_or("not a MaxMind database")?;
+        let metadata = &bytes[search_from + marker + METADATA_MARKER.len()..];
+        let (metadata, _) = decode(metadata, 0, 0).ok_or("invalid database metadata")?;
+        let field = |name: &str| {
+            metadata
+                .get(name)
+                .and_then(Value::as_u64)
+                .ok_or(format!("database metadata has no {}", name))
+        };
+        let node_count = field("node_count")? as usize;
+        let record_size = field("record_size")? as usize;
+        let ip_version = field("ip_version")?;
+        if ![24, 28, 32].contains(&record_size) {
+            return Err(format!("unsupported record size: {}", record_size));
+        }
+        if ![4, 6].contains(&ip_version) {
+            return Err(format!("unsupported IP version: {}", ip_version));
+        }
+        let tree_size = node_count * record_size / 4;
+        if tree_size + DATA_SEPARATOR > search_from + marker {
+            return Err("database is truncated".to_string());
+        }
+        Ok(Database {
+            bytes,
+            node_count,
+            record_size,
+            ip_version,
+            data_start: tree_size + DATA_SEPARATOR,
+        })
+    }
+
+    /// The left (`bit` 0) or right record of `node`.
+    fn record(&self, node: usize, bit: u8) -> usize {
+        let b = &self.bytes[node * self.record_size / 4..];
+        let be = |bytes: &[u8]| bytes.iter().fold(0, |n, b| n << 8 | *b as usize);
+        match (self.record_size, bit) {
+            (24, 0) => be(&b[0..3]),
+            (24, _) => be(&b[3..6]),
+            (28, 0) => ((b[3] as usize & 0xf0) << 20) | be(&b[0..3]),
+            (28, _) => ((b[3] as usize & 0x0f) << 24) | be(&b[4..7]),
+            (_, 0) => be(&b[0..4]),
+            (_, _) => be(&b[4..8]),
+        }
+    }
+
+    /// The record for the network containing `addr`, if there is one.
+    pub fn lookup(&self, addr: IpAddr) -> Option<Value> {
+        let bits: Vec<u8> = match (addr, self.ip_version) {
+            (IpAddr::V4(addr), 4) => addr.octets().to_vec(),
+            // IPv4 addresses are found under ::/96 in IPv6 databases.
+            (IpAddr::V4(addr), _) => addr.to_ipv6_compatible().octets().to_vec(),
+            (IpAddr::V6(addr), 6) => match addr.to_ipv4_mapped() {
+                Some(addr) => return self.lookup(IpAddr::V4(addr)),
+                None => addr.octets().to_vec(),
+            },
+            (IpAddr::V6(addr), _) => match addr.to_ipv4_mapped() {
+                Some(addr) => return self.lookup(IpAddr::V4(addr)),
+                None => return None,
+            },
+        };
+        let mut node = 0;
+        for i in 0..bits.len() * 8 {
+            if node >= self.node_count {
+                break;
+            }
+            node = self.record(node, (bits[i / 8] >> (7 - i % 8)) & 1);
+        }
+        if node <= self.node_count {
+            return None;
+        }
+        let offset = node - self.node_count - DATA_SEPARATOR;
+        decode(&self.bytes[self.data_start..], offset, 0).map(|(value, _)| value)
+    }
+}
+
+/// Decode the value at `offset` in `data`, returning it and the offset after it.
+fn decode(data: &[u8], offset: usize, depth: usize) -> Option<(Value, usize)> {
+    if depth > MAX_DEPTH {
+        return None;
+    }
+    let ctrl = *data.get(offset)?;
+    let mut pos = offset + 1;
+    let mut kind = ctrl >> 5;
+    if kind == 0 {
+        kind = 7 + *data.get(pos)?;
+        pos += 1;
+    }
+    let uint = |bytes: &[u8]| bytes.iter().fold(0u128, |n, b| n << 8 | *b as u128);
+    if kind == 1 {
+        let len = ((ctrl >> 3) & 3) as usize + 1;
+        let bytes = data.get(pos..pos + len)?;
+        let low = (ctrl & 7) as usize;
+        let target = match len {
+            1 => low << 8 | bytes[0] as usize,
+            2 => (low << 16 | uint(bytes) as usize) + 2048,
+            3 => (low << 24 | uint(bytes) as usize) + 526336,
+            _ => uint(bytes) as usize,
+        };
+        let (value, _) = decode(data, target, depth + 1)?;
+        return Some((value, pos + len));
+    }
+    let mut size = (ctrl & 0x1f) as usize;
+    if size >= 29 {
+        let len = size - 28;
+        let extra = uint(data.get(pos..pos + len)?) as usize;
+        size = [29, 285, 65821][len - 1] + extra;
+        pos += len;
+    }
+    let mut payload = |len: usize| {
+        let bytes = data.get(pos..pos + len);
+        pos += len;
+        bytes
+    };
+    let value = match kind {
+        2 => Value::from(String::from_utf8_lossy(payload(size)?).into_owned()),
+        3 if size == 8 => Value::from(f64::from_be_bytes(payload(8)?.try_into().ok()?)),
+        4 => Value::from(payload(size)?.to_vec()),
+        5 | 6 | 9 | 10 if size <= 16 => {
+            let n = uint(payload(size)?);
+            match u64::try_from(n) {
+                Ok(n) => Value::from(n),
+                Err(_) => Value::from(n.to_string()),
+            }
+        }
+        8 if size <= 4 => Value::from(uint(payload(size)?) as u32 as i32),
+        7 => {
+            let mut map = Map::new();
+            for _ in 0..size {
+                let (key, next) = decode(data, pos, depth + 1)?;
+                let (value, next) = decode(data, next, depth + 1)?;
+                map.insert(key.as_str()?.to_string(), value);
+                pos = next;
+            }
+            Value::Object(map)
+        }
+        11 => {
+            let mut values = Vec::with_capacity(size.min(64));
+            for _ in 0..size {
+                let (value, next) = decode(data, pos, depth + 1)?;
+                values.push(value);
+                pos = next;
+            }
+            Value::Array(values)
+        }
+        14 => Value::from(size != 0),
+        15 if size == 4 => Value::from(f32::from_be_bytes(payload(4)?.try_into().ok()?)),
+        _ => return None,
+    };
+    Some((value, pos))
+}
+
+/// Where a client is, as far as the databases know.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Location {
+    /// The ISO 3166 code, such as `AU`.
+    pub country: Option<String>,
+    pub asn: Option<u64>,
+}
+
+impl Location {
+    /// Whether the location matches a country code or `AS<number>`.
+    pub fn matches(&self, place: &str) -> bool {
+        match place.strip_prefix("AS").map(str::parse::<u64>) {
+            Some(Ok(asn)) => self.asn == Some(asn),
+            _ => self
+                .country
+                .as_ref()
+                .is_some_and(|country| country.eq_ignore_ascii_case(place)),
+        }
+    }
+
+    /// Whether a client here may connect: it's in one of `allow`, if there are any,
+    /// and in none of `deny`.
+    pub fn allowed(&self, allow: &[String], deny: &[String]) -> bool {
+        (allow.is_empty() || allow.iter().any(|place| self.matches(place)))
+            && !deny.iter().any(|place| self.matches(place))
+    }
+
+    pub fn tags(&self) -> Vec<(String, String)> {
+        let mut tags = Vec::new();
+        if let Some(country) = &self.country {
+            tags.push(("geo.country".to_string(), country.clone()));
+        }
+        if let Some(asn) = self.asn {
+            tags.push(("geo.asn".to_string(), asn.to_string()));
+        }
+        tags
+    }
+}
+
+/// Country and ASN databases, such as GeoLite2-Country and GeoLite2-ASN.
+#[derive(Debug, Default)]
+pub struct GeoIp {
+    databases: Vec<Database>,
+}
+
+impl GeoIp {
+    pub fn open(paths: &[String]) -> Result<Self, String> {
+        let databases = paths
+            .iter()
+            .map(|path| Database::open(path))
+            .collect::<Result<_, _>>()?;
+        Ok(GeoIp { databases })
+    }
+
+    /// Look `addr` up in every database, taking the first answer for each field.
+    pub fn locate(&self, addr: IpAddr) -> Location {
+        let mut location = Location::default();
+        for record in self.databases.iter().filter_map(|db| db.lookup(addr)) {
+            if location.country.is_none() {
+                location.country = record
+                    .pointer("/country/iso_code")
+                    .and_then(Value::as_str)
+                    .map(str::to_string);
+            }
+            if location.asn.is_none() {
+                location.asn = record
+                    .get("autonomous_system_number")
+                    .and_then(Value::as_u64);
+            }
+        }
+        location
+    }
+}
+
+/// A `--geo-toxics` rule, `PLACE=SETTING` with a `--toxics` setting for clients in a
+/// country or `AS<number>`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GeoToxics {
+    pub place: String,
+    pub setting: Setting,
+}
+
+impl FromStr for GeoToxics {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (place, setting) = s
+            .split_once('=')
+            .filter(|(place, _)| !place.is_empty())
+            .ok_or_else(|| format!("expected PLACE=TOXICS: {}", s))?;
+        Ok(GeoToxics {
+            place: place.to_string(),
+            setting: setting.parse()?,
+        })
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    fn encode(value: &Value, out: &mut Vec<u8>) {
+        let header = |out: &mut Vec<u8>, kind: u8, size: usize| {
+            assert!(size < 29);
+            out.push(kind << 5 | size as u8);
+        };
+        match value {
+            Value::String(s) => {
+                header(out, 2, s.len());
+                out.extend_from_slice(s.as_bytes());
+            }
+            Value::Number(n) => {
+                let bytes = (n.as_u64().unwrap() as u32).to_be_bytes();
+                header(out, 6, 4);
+                out.extend_from_slice(&bytes);
+            }
+            Value::Object(map) => {
+                header(out, 7, map.len());
+                for (key, value) in map {
+                    encode(&Value::from(key.as_str()), out);
+                    encode(value, out);
+                }
+            }
+            _ => panic!("unsupported value in test database: {}", value),
+        }
+    }
+
+    /// An IPv6 database with 24-bit records holding `networks`.
+    pub(crate) fn database(networks: &[(IpAddr, usize, Value)]) -> Vec<u8> {
+        // Records are `Err(data offset)` until the node count is known.
+        let mut nodes: Vec<[Result<usize, usize>; 2]> = vec![[Ok(0), Ok(0)]];
+        let mut data = Vec::new();
+        for (addr, prefix, value) in networks {
+            let (bits, prefix) = match addr {
+                IpAddr::V4(addr) => (addr.to_ipv6_compatible().octets(), prefix + 96),
+                IpAddr::V6(addr) => (addr.octets(), *prefix),
+            };
+            let mut node = 0;
+            for i in 0..prefix {
+                let bit = ((bits[i / 8] >> (7 - i % 8)) & 1) as usize;
+                if i == prefix - 1 {
+                    nodes[node][bit] = Err(data.len());
+                } else {
+                    if nodes[node][bit] == Ok(0) {
+                        nodes.push([Ok(0), Ok(0)]);
+                        nodes[node][bit] = Ok(nodes.len() - 1);
+                    }
+                    node = *nodes[node][bit].as_ref().unwrap();
+                }
+            }
+            encode(value, &mut data);
+        }
+        let node_count = nodes.len();
+        let mut out = Vec::new();
+        for node in &nodes {
+            for record in node {
+                let n = match *record {
+                    Ok(0) => node_count,
+                    Ok(n) => n,
+                    Err(offset) => node_count + DATA_SEPARATOR + offset,
+                };
+                out.extend_from_slice(&(n as u32).to_be_bytes()[1..]);
+            }
+        }
+        out.extend_from_slice(&[0; DATA_SEPARATOR]);
+        out.extend_from_slice(&data);
+        out.extend_from_slice(METADATA_MARKER);
+        let metadata = json!({ "node_count": node_count, "record_size": 24, "ip_version": 6 });
+        encode(&metadata, &mut out);
+        out
+    }
+
+    #[test]
+    fn test_lookup() {
+        let db = Database::from_bytes(database(&[
+            (
+                "1.2.3.0".parse().unwrap(),
+                24,
+                json!({ "country": { "iso_code": "AU" } }),
+            ),
+            (
+                "2001:db8::".parse().unwrap(),
+                32,
+                json!({ "country": { "iso_code": "NZ" }, "autonomous_system_number": 64500 }),
+            ),
+        ]))
+        .unwrap();
+        let geoip = GeoIp {
+            databases: vec![db],
+        };
+        let location = geoip.locate("1.2.3.4".parse().unwrap());
+        assert_eq!(location.country.as_deref(), Some("AU"));
+        assert!(location.matches("au"));
+        assert_eq!(geoip.locate("::ffff:1.2.3.200".parse().unwrap()), location);
+        assert_eq!(
+            geoip.locate("1.2.4.1".parse().unwrap()),
+            Location::default()
+        );
+
+        let location = geoip.locate("2001:db8::1".parse().unwrap());
+        assert_eq!(location.asn, Some(64500));
+        assert!(location.matches("AS64500"));
+        assert!(!location.matches("AU"));
+        assert!(location.allowed(&[], &["AU".to_string()]));
+        assert!(!location.allowed(&["AU".to_string()], &[]));
+        assert!(!location.allowed(&[], &["NZ".to_string()]));
+        assert_eq!(
+            location.tags(),
+            [
+                ("geo.country".to_string(), "NZ".to_string()),
+                ("geo.asn".to_string(), "64500".to_string()),
+            ]
+        );
+
+        assert!(Database::from_bytes(b"not a database".to_vec()).is_err());
+    }
+
+    #[test]
+    fn test_decode() {
+        // A map holding a pointer back to the string before it, then a boolean.
+        let data = [
+            0x42, b'h', b'i', 0xe2, 0x41, b'a', 0x20, 0x00, 0x41, b'b', 0x01, 0x07,
+        ];
+        assert_eq!(
+            decode(&data, 3, 0),
+            Some((json!({ "a": "hi", "b": true }), data.len()))
+        );
+        // A pointer to itself.
+        assert_eq!(decode(&[0x20, 0x00], 0, 0), None);
+    }
+}
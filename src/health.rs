@@ -0,0 +1,310 @@
+//! Upstream health checks, run by the `--probe-interval` prober once it has connected.
+//!
+//! `--probe-check` picks a built-in checker; embedders can implement [`HealthChecker`]
+//! for anything else and set it on `Args`.
+
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use futures::future::BoxFuture;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Decides whether an upstream is healthy, given a fresh connection to it.
+pub trait HealthChecker: Send + Sync {
+    /// Speak the upstream's protocol over `stream`. The prober bounds the whole check
+    /// with `--probe-timeout`. `upstream` is the address as configured, for checks
+    /// which need a host name.
+    fn check<'a>(
+        &'a self,
+        upstream: &'a str,
+        stream: TcpStream,
+    ) -> BoxFuture<'a, Result<(), String>>;
+}
+
+/// A built-in `--probe-check`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Check {
+    /// Healthy once connected.
+    Tcp,
+    /// Healthy if the upstream answers a ClientHello with a ServerHello. The
+    /// certificate isn't checked.
+    Tls,
+    /// Healthy if `GET PATH` gets a 2xx or 3xx response.
+    Http { path: String },
+    /// Healthy if `PING` gets `+PONG`.
+    Redis,
+}
+
+impl FromStr for Check {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tcp" => Ok(Check::Tcp),
+            "tls" => Ok(Check::Tls),
+            "redis" => Ok(Check::Redis),
+            "http" => Ok(Check::Http {
+                path: "/".to_string(),
+            }),
+            _ => match s.strip_prefix("http:") {
+                Some(path) if path.starts_with('/') && !path.contains(char::is_whitespace) => {
+                    Ok(Check::Http {
+                        path: path.to_string(),
+                    })
+                }
+                _ => Err(format!(
+                    "unknown check: {} (expected tcp, tls, http[:/PATH] or redis)",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+impl HealthChecker for Check {
+    fn check<'a>(
+        &'a self,
+        upstream: &'a str,
+        stream: TcpStream,
+    ) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            match self {
+                Check::Tcp => Ok(()),
+                Check::Tls => check_tls(upstream, stream).await,
+                Check::Http { path } => check_http(upstream, path, stream).await,
+                Check::Redis => check_redis(stream).await,
+            }
+        })
+    }
+}
+
+/// A shareable checker, for `Args`.
+#[derive(Clone)]
+pub struct Checker(pub Arc<dyn HealthChecker>);
+
+impl fmt::Debug for Checker {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Checker")
+    }
+}
+
+/// The host part of `HOST:PORT`.
+fn host(upstream: &str) -> &str {
+    match upstream.rsplit_once(':') {
+        Some((host, _)) => host.trim_start_matches('[').trim_end_matches(']'),
+        None => upstream,
+    }
+}
+
+async fn read_some(stream: &mut TcpStream, buf: &mut Vec<u8>) -> Result<(), String> {
+    let mut chunk = [0; 512];
+    let n = stream
+        .read(&mut chunk)
+        .await
+        .map_err(|err| err.to_string())?;
+    if n == 0 {
+        return Err("upstream closed the connection".to_string());
+    }
+    buf.extend_from_slice(&chunk[..n]);
+    Ok(())
+}
+
+async fn check_http(upstream: &str, path: &str, mut stream: TcpStream) -> Result<(), String> {
+    let request = format!(
+        "GET {} HTTP/1.1\r\nhost: {}\r\nuser-agent: tproxy\r\nconnection: close\r\n\r\n",
+        path, upstream
+    );
+    stream
+        .write_all(request.as_bytes())
+        .await
+        .map_err(|err| err.to_string())?;
+    let mut buf = Vec::new();
+    while !buf.windows(2).any(|w| w == b"\r\n") {
+        read_some(&mut stream, &mut buf).await?;
+    }
+    let line = String::from_utf8_lossy(&buf);
+    let status = line
+        .strip_prefix("HTTP/1.")
+        .and_then(|rest| rest.get(2..5))
+        .and_then(|code| code.parse::<u16>().ok())
+        .ok_or_else(|| "upstream did not answer with HTTP".to_string())?;
+    if (200..400).contains(&status) {
+        Ok(())
+    } else {
+        Err(format!("upstream answered {}", status))
+    }
+}
+
+async fn check_redis(mut stream: TcpStream) -> Result<(), String> {
+    stream
+        .write_all(b"*1\r\n$4\r\nPING\r\n")
+        .await
+        .map_err(|err| err.to_string())?;
+    let mut buf = Vec::new();
+    while !buf.ends_with(b"\r\n") {
+        read_some(&mut stream, &mut buf).await?;
+    }
+    match &buf[..] {
+        b"+PONG\r\n" => Ok(()),
+        reply => Err(format!(
+            "upstream answered {}",
+            String::from_utf8_lossy(reply).trim_end()
+        )),
+    }
+}
+
+async fn check_tls(upstream: &str, mut stream: TcpStream) -> Result<(), String> {
+    stream
+        .write_all(&client_hello(host(upstream)))
+        .await
+        .map_err(|err| err.to_string())?;
+    let mut buf = Vec::new();
+    while buf.len() < 6 {
+        read_some(&mut stream, &mut buf).await?;
+    }
+    match (buf[0], buf[5]) {
+        // A handshake record starting with a ServerHello.
+        (0x16, 0x02) => Ok(()),
+        (0x15, _) if buf.len() >= 7 => Err(format!("upstream sent TLS alert {}", buf[6])),
+        (0x15, _) => Err("upstream sent a TLS alert".to_string()),
+        _ => Err("upstream did not answer with TLS".to_string()),
+    }
+}
+
+fn extension(out: &mut Vec<u8>, kind: u16, data: &[u8]) {
+    out.extend_from_slice(&kind.to_be_bytes());
+    out.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    out.extend_from_slice(data);
+}
+
+/// A list of `u16`s with a length prefix of `prefix` bytes.
+fn u16_list(values: &[u16], prefix: usize) -> Vec<u8> {
+    let len = (values.len() * 2) as u16;
+    let mut out = len.to_be_bytes()[2 - prefix..].to_vec();
+    for v in values {
+        out.extend_from_slice(&v.to_be_bytes());
+    }
+    out
+}
+
+/// A ClientHello offering TLS 1.2 and 1.3 with common cipher suites, which any
+/// current server should answer with a ServerHello.
+fn client_hello(server_name: &str) -> Vec<u8> {
+    let mut extensions = Vec::new();
+    if server_name.parse::<std::net::IpAddr>().is_err() {
+        let name = server_name.as_bytes();
+        let mut sni = (name.len() as u16 + 3).to_be_bytes().to_vec();
+        sni.push(0);
+        sni.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        sni.extend_from_slice(name);
+        extension(&mut extensions, 0x0000, &sni);
+    }
+    // x25519, secp256r1 and secp384r1.
+    extension(
+        &mut extensions,
+        0x000a,
+        &u16_list(&[0x001d, 0x0017, 0x0018], 2),
+    );
+    // Uncompressed points only.
+    extension(&mut extensions, 0x000b, &[1, 0]);
+    let signatures = [
+        0x0403, 0x0804, 0x0401, 0x0503, 0x0805, 0x0501, 0x0806, 0x0601,
+    ];
+    extension(&mut extensions, 0x000d, &u16_list(&signatures, 2));
+    extension(&mut extensions, 0x002b, &u16_list(&[0x0304, 0x0303], 1));
+    // An x25519 key share: any 32 bytes will do, as the handshake goes no further.
+    let mut key_share = 38u16.to_be_bytes().to_vec();
+    key_share.extend_from_slice(&0x001du16.to_be_bytes());
+    key_share.extend_from_slice(&32u16.to_be_bytes());
+    key_share.extend_from_slice(&rand::random::<[u8; 32]>());
+    extension(&mut extensions, 0x0033, &key_share);
+
+    let mut body = vec![0x03, 0x03];
+    body.extend_from_slice(&rand::random::<[u8; 32]>());
+    body.push(0);
+    let suites = [
+        0x1301, 0x1302, 0x1303, 0xc02b, 0xc02f, 0xc02c, 0xc030, 0xcca9, 0xcca8, 0x009c, 0x009d,
+    ];
+    body.extend_from_slice(&u16_list(&suites, 2));
+    body.extend_from_slice(&[1, 0]);
+    body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+    body.extend_from_slice(&extensions);
+
+    let mut handshake = vec![0x01];
+    handshake.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+    handshake.extend_from_slice(&body);
+    let mut record = vec![0x16, 0x03, 0x01];
+    record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+    record.extend_from_slice(&handshake);
+    record
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::net::TcpListener;
+
+    use crate::hello::parse_client_hello;
+
+    /// Answer one connection with `reply` once something is received, and return
+    /// what the checker says about it.
+    async fn check(check: &str, reply: &'static [u8]) -> Result<(), String> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 1024];
+            if !reply.is_empty() {
+                let _ = stream.read(&mut buf).await;
+            }
+            let _ = stream.write_all(reply).await;
+        });
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let check: Check = check.parse().unwrap();
+        check.check("db.example.com:6379", stream).await
+    }
+
+    #[tokio::test]
+    async fn test_checks() {
+        assert_eq!(check("tcp", b"").await, Ok(()));
+        assert_eq!(check("redis", b"+PONG\r\n").await, Ok(()));
+        assert_eq!(
+            check("redis", b"-NOAUTH Authentication required.\r\n").await,
+            Err("upstream answered -NOAUTH Authentication required.".to_string())
+        );
+        assert_eq!(
+            check("http:/healthz", b"HTTP/1.1 204 No Content\r\n\r\n").await,
+            Ok(())
+        );
+        assert_eq!(
+            check("http", b"HTTP/1.1 503 Service Unavailable\r\n\r\n").await,
+            Err("upstream answered 503".to_string())
+        );
+        assert_eq!(
+            check("tls", &[0x16, 0x03, 0x03, 0x00, 0x30, 0x02]).await,
+            Ok(())
+        );
+        assert_eq!(
+            check("tls", &[0x15, 0x03, 0x03, 0x00, 0x02, 0x02, 0x28]).await,
+            Err("upstream sent TLS alert 40".to_string())
+        );
+        assert!(check("tls", b"HTTP/1.1 400 Bad Request\r\n\r\n")
+            .await
+            .is_err());
+        assert!("ftp".parse::<Check>().is_err());
+        assert!("http:healthz".parse::<Check>().is_err());
+
+        let hello = parse_client_hello(&client_hello("db.example.com")).unwrap();
+        assert_eq!(hello.server_name.as_deref(), Some("db.example.com"));
+        assert_eq!(
+            parse_client_hello(&client_hello("10.0.0.1"))
+                .unwrap()
+                .server_name,
+            None
+        );
+        assert_eq!(host("[::1]:443"), "::1");
+    }
+}
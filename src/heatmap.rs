@@ -0,0 +1,241 @@
+//! Latency distributions over time, for `/api/latency`.
+//!
+//! Latencies are counted in HDR-style log-linear buckets: exact below 16µs, then 16
+//! buckets per power of two, so any percentile is within about 6% of the true value
+//! however wide the range. Alongside the running total there is one histogram per
+//! [`WINDOW`], keeping the last [`WINDOWS`], which dashboards can plot as a heatmap or
+//! as percentiles over time.
+
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde_json::{json, Value};
+
+/// Buckets per power of two.
+const SUB_BUCKETS: u64 = 16;
+const SUB_BUCKET_BITS: u32 = SUB_BUCKETS.trailing_zeros();
+
+pub const WINDOW: Duration = Duration::from_secs(10);
+pub const WINDOWS: usize = 60;
+
+const PERCENTILES: &[(&str, f64)] = &[
+    ("p50_ms", 0.5),
+    ("p90_ms", 0.9),
+    ("p99_ms", 0.99),
+    ("p999_ms", 0.999),
+];
+
+fn bucket(micros: u64) -> u32 {
+    if micros < SUB_BUCKETS {
+        return micros as u32;
+    }
+    let shift = 63 - micros.leading_zeros() - SUB_BUCKET_BITS;
+    let mantissa = (micros >> shift) - SUB_BUCKETS;
+    (shift + 1) * SUB_BUCKETS as u32 + mantissa as u32
+}
+
+/// The smallest and largest microseconds counted in `bucket`.
+fn bucket_range(bucket: u32) -> (u64, u64) {
+    let bucket = bucket as u64;
+    if bucket < SUB_BUCKETS {
+        return (bucket, bucket);
+    }
+    let shift = bucket / SUB_BUCKETS - 1;
+    let low = (SUB_BUCKETS + bucket % SUB_BUCKETS) << shift;
+    (low, low + ((1 << shift) - 1))
+}
+
+fn ms(micros: u64) -> f64 {
+    micros as f64 / 1000.0
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Histogram {
+    /// Counts by bucket. Only buckets which have been used are present.
+    counts: BTreeMap<u32, u64>,
+    count: u64,
+    min: u64,
+    max: u64,
+}
+
+impl Histogram {
+    pub fn record(&mut self, latency: Duration) {
+        let micros = latency.as_micros().min(u64::MAX as u128) as u64;
+        *self.counts.entry(bucket(micros)).or_default() += 1;
+        self.min = if self.count == 0 {
+            micros
+        } else {
+            self.min.min(micros)
+        };
+        self.max = self.max.max(micros);
+        self.count += 1;
+    }
+
+    /// The latency at quantile `q`, in microseconds: the largest value in the bucket
+    /// holding it, short of the largest recorded.
+    pub fn quantile(&self, q: f64) -> Option<u64> {
+        let rank = ((q * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, count) in &self.counts {
+            seen += count;
+            if seen >= rank {
+                return Some(bucket_range(*bucket).1.min(self.max));
+            }
+        }
+        None
+    }
+
+    /// The count and percentiles, in milliseconds.
+    pub fn summary(&self) -> Value {
+        let mut summary = json!({ "count": self.count });
+        if self.count > 0 {
+            summary["min_ms"] = ms(self.min).into();
+            summary["max_ms"] = ms(self.max).into();
+            for (name, q) in PERCENTILES {
+                summary[*name] = json!(self.quantile(*q).map(ms));
+            }
+        }
+        summary
+    }
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    total: Histogram,
+    /// Histograms by the start of their window, in seconds since the epoch.
+    windows: VecDeque<(u64, Histogram)>,
+}
+
+/// A latency histogram in total and by window.
+#[derive(Debug, Default)]
+pub struct Heatmap {
+    inner: Mutex<Inner>,
+}
+
+impl Heatmap {
+    pub fn record(&self, latency: Duration) {
+        self.record_at(latency, SystemTime::now());
+    }
+
+    fn record_at(&self, latency: Duration, now: SystemTime) {
+        let secs = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let start = secs - secs % WINDOW.as_secs();
+        let mut inner = self.inner.lock().unwrap();
+        inner.total.record(latency);
+        if inner.windows.back().is_none_or(|(s, _)| *s < start) {
+            if inner.windows.len() == WINDOWS {
+                inner.windows.pop_front();
+            }
+            inner.windows.push_back((start, Histogram::default()));
+        }
+        // A clock stepping backwards lands in the latest window.
+        inner.windows.back_mut().unwrap().1.record(latency);
+    }
+
+    pub fn summary(&self) -> Value {
+        self.inner.lock().unwrap().total.summary()
+    }
+
+    /// Each window's percentiles and bucket counts. `buckets_ms` are the lower bounds
+    /// of the buckets used in any window, and every window's `counts` line up with them.
+    pub fn series(&self) -> Value {
+        let inner = self.inner.lock().unwrap();
+        let buckets: BTreeSet<u32> = inner
+            .windows
+            .iter()
+            .flat_map(|(_, h)| h.counts.keys().copied())
+            .collect();
+        let windows: Vec<Value> = inner
+            .windows
+            .iter()
+            .map(|(start, histogram)| {
+                let mut window = histogram.summary();
+                window["start"] = (*start).into();
+                window["counts"] = buckets
+                    .iter()
+                    .map(|b| histogram.counts.get(b).copied().unwrap_or(0))
+                    .collect::<Vec<_>>()
+                    .into();
+                window
+            })
+            .collect();
+        json!({
+            "interval_ms": WINDOW.as_millis() as u64,
+            "buckets_ms": buckets.iter().map(|b| ms(bucket_range(*b).0)).collect::<Vec<_>>(),
+            "windows": windows,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buckets() {
+        for micros in [
+            0,
+            1,
+            15,
+            16,
+            17,
+            31,
+            32,
+            1000,
+            123_456,
+            10_000_000_000,
+            u64::MAX,
+        ] {
+            let (low, high) = bucket_range(bucket(micros));
+            assert!(
+                low <= micros && micros <= high,
+                "{} in {}..={}",
+                micros,
+                low,
+                high
+            );
+            assert!((high - low) as f64 <= micros as f64 / 16.0);
+        }
+        assert_eq!(bucket(15) + 1, bucket(16));
+        assert_eq!(bucket_range(bucket(32)), (32, 33));
+    }
+
+    #[test]
+    fn test_quantile() {
+        let mut h = Histogram::default();
+        for ms in 1..=1000 {
+            h.record(Duration::from_millis(ms));
+        }
+        let p50 = h.quantile(0.5).unwrap() as f64;
+        assert!((p50 - 500_000.0).abs() / 500_000.0 < 0.07);
+        let p999 = h.quantile(0.999).unwrap() as f64;
+        assert!((p999 - 999_000.0).abs() / 999_000.0 < 0.07);
+        assert_eq!(h.quantile(1.0), Some(1_000_000));
+        assert_eq!(h.summary()["min_ms"], 1.0);
+        assert_eq!(Histogram::default().quantile(0.5), None);
+    }
+
+    #[test]
+    fn test_series() {
+        let heatmap = Heatmap::default();
+        let t = UNIX_EPOCH + Duration::from_secs(1000);
+        heatmap.record_at(Duration::from_millis(1), t);
+        heatmap.record_at(Duration::from_millis(100), t + Duration::from_secs(5));
+        heatmap.record_at(Duration::from_millis(100), t + WINDOW);
+        let series = heatmap.series();
+        assert_eq!(series["buckets_ms"].as_array().unwrap().len(), 2);
+        assert_eq!(series["windows"][0]["start"], 1000);
+        assert_eq!(series["windows"][0]["counts"], json!([1, 1]));
+        assert_eq!(series["windows"][1]["counts"], json!([0, 1]));
+        assert_eq!(heatmap.summary()["count"], 3);
+
+        for i in 0..WINDOWS as u64 * 2 {
+            heatmap.record_at(Duration::from_millis(1), t + WINDOW * i as u32);
+        }
+        assert_eq!(
+            heatmap.series()["windows"].as_array().unwrap().len(),
+            WINDOWS
+        );
+    }
+}
@@ -0,0 +1,323 @@
+//! Decide how to route a connection from the first bytes its client sends.
+//!
+//! When `Args::client_hello` is set, the proxy peeks at each new connection's first
+//! bytes (a whole TLS ClientHello, if that's what they start with) before dialing,
+//! and passes them to the callback. The returned [`Decision`] can pick a different
+//! upstream and tag the connection. `--sni-route` is implemented this way, and
+//! embedders can install their own callback without forking the proxy.
+
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::net::TcpStream;
+
+use crate::protocol::{self, Match, Protocol};
+
+/// The most that is peeked. ClientHellos are usually well under this, but
+/// post-quantum key shares can take them past a few KiB.
+const MAX_PEEK: usize = 16 * 1024;
+
+pub const EXTENSION_SERVER_NAME: u16 = 0;
+const EXTENSION_SUPPORTED_GROUPS: u16 = 10;
+const EXTENSION_EC_POINT_FORMATS: u16 = 11;
+const EXTENSION_SIGNATURE_ALGORITHMS: u16 = 13;
+pub const EXTENSION_ALPN: u16 = 16;
+const EXTENSION_SUPPORTED_VERSIONS: u16 = 43;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Decision {
+    /// Forward to this upstream instead of `--upstream-addr`.
+    pub upstream: Option<String>,
+    /// Tags to set on the connection.
+    pub tags: Vec<(String, String)>,
+}
+
+type CallbackFn = dyn Fn(SocketAddr, &[u8]) -> Decision + Send + Sync;
+
+/// Called with a new connection's client address and first bytes.
+#[derive(Clone)]
+pub struct Callback(Arc<CallbackFn>);
+
+impl Callback {
+    pub fn new(f: impl Fn(SocketAddr, &[u8]) -> Decision + Send + Sync + 'static) -> Self {
+        Self(Arc::new(f))
+    }
+
+    pub fn call(&self, downstream_addr: SocketAddr, bytes: &[u8]) -> Decision {
+        (self.0)(downstream_addr, bytes)
+    }
+}
+
+impl fmt::Debug for Callback {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Callback")
+    }
+}
+
+/// The fields of a TLS ClientHello which are useful for routing and fingerprinting.
+/// Lists are in the order the client sent them, GREASE values included.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ClientHello {
+    pub server_name: Option<String>,
+    pub alpn: Vec<String>,
+    /// The legacy version field, which TLS 1.3 clients leave at TLS 1.2.
+    pub version: u16,
+    pub cipher_suites: Vec<u16>,
+    /// Extension types.
+    pub extensions: Vec<u16>,
+    pub supported_groups: Vec<u16>,
+    pub point_formats: Vec<u8>,
+    pub supported_versions: Vec<u16>,
+    pub signature_algorithms: Vec<u16>,
+}
+
+/// Parse the ClientHello at the start of `bytes`, which must hold the whole first
+/// TLS record.
+pub fn parse_client_hello(bytes: &[u8]) -> Option<ClientHello> {
+    if Protocol::Tls.matches(bytes) != Match::Yes {
+        return None;
+    }
+    let record_len = u16::from_be_bytes([bytes[3], bytes[4]]) as usize;
+    let record = bytes.get(5..5 + record_len)?;
+    // The handshake type and length, then the version and random.
+    let mut r = Reader(record.get(4..)?);
+    let mut hello = ClientHello {
+        version: r.u16()?,
+        ..Default::default()
+    };
+    r.take(32)?;
+    let session_id = r.u8()? as usize;
+    r.take(session_id)?;
+    let cipher_suites = r.u16()? as usize;
+    hello.cipher_suites = Reader(r.take(cipher_suites)?).u16s();
+    let compression = r.u8()? as usize;
+    r.take(compression)?;
+
+    // Extensions are optional before TLS 1.3.
+    let extensions_len = match r.u16() {
+        Some(len) => len as usize,
+        None => return Some(hello),
+    };
+    let mut extensions = Reader(r.take(extensions_len)?);
+    while let Some(kind) = extensions.u16() {
+        let len = extensions.u16()? as usize;
+        let mut data = Reader(extensions.take(len)?);
+        hello.extensions.push(kind);
+        match kind {
+            EXTENSION_SERVER_NAME => {
+                data.u16()?;
+                // Only host names (type 0) are defined.
+                if data.u8()? == 0 {
+                    let len = data.u16()? as usize;
+                    let name = data.take(len)?;
+                    hello.server_name = Some(String::from_utf8_lossy(name).into_owned());
+                }
+            }
+            EXTENSION_ALPN => {
+                let len = data.u16()? as usize;
+                let mut protocols = Reader(data.take(len)?);
+                while let Some(len) = protocols.u8() {
+                    let protocol = protocols.take(len as usize)?;
+                    hello
+                        .alpn
+                        .push(String::from_utf8_lossy(protocol).into_owned());
+                }
+            }
+            EXTENSION_SUPPORTED_GROUPS | EXTENSION_SIGNATURE_ALGORITHMS => {
+                let len = data.u16()? as usize;
+                let values = Reader(data.take(len)?).u16s();
+                if kind == EXTENSION_SUPPORTED_GROUPS {
+                    hello.supported_groups = values;
+                } else {
+                    hello.signature_algorithms = values;
+                }
+            }
+            EXTENSION_EC_POINT_FORMATS => {
+                let len = data.u8()? as usize;
+                hello.point_formats = data.take(len)?.to_vec();
+            }
+            EXTENSION_SUPPORTED_VERSIONS => {
+                let len = data.u8()? as usize;
+                hello.supported_versions = Reader(data.take(len)?).u16s();
+            }
+            _ => {}
+        }
+    }
+    Some(hello)
+}
+
+/// A cursor over big-endian fields.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        let taken = self.0.get(..n)?;
+        self.0 = &self.0[n..];
+        Some(taken)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    /// The rest as a list of u16s, ignoring an odd trailing byte.
+    fn u16s(&mut self) -> Vec<u16> {
+        std::iter::from_fn(|| self.u16()).collect()
+    }
+}
+
+/// Peek at the first bytes of `stream` without consuming them: the whole first TLS
+/// record when the client starts with a ClientHello, or whatever has arrived
+/// otherwise. Returns what has been seen if `timeout` passes first.
+pub async fn peek(stream: &TcpStream, timeout: Duration) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0; MAX_PEEK];
+    let mut seen = 0;
+    protocol::peek_until(stream, &mut buf, timeout, |bytes| {
+        seen = bytes.len();
+        let complete = match Protocol::Tls.matches(bytes) {
+            _ if seen == 0 || seen == MAX_PEEK => true,
+            Match::No => true,
+            Match::NeedMore => false,
+            Match::Yes => seen >= 5 + u16::from_be_bytes([bytes[3], bytes[4]]) as usize,
+        };
+        Ok(complete.then_some(()))
+    })
+    .await?;
+    buf.truncate(seen);
+    Ok(buf)
+}
+
+/// A `--sni-route` rule, `SERVER_NAME=UPSTREAM`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SniRoute {
+    server_name: String,
+    upstream: String,
+}
+
+impl FromStr for SniRoute {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((server_name, upstream)) if !server_name.is_empty() && !upstream.is_empty() => {
+                Ok(SniRoute {
+                    server_name: server_name.to_ascii_lowercase(),
+                    upstream: upstream.to_string(),
+                })
+            }
+            _ => Err(format!("expected SERVER_NAME=UPSTREAM: {}", s)),
+        }
+    }
+}
+
+impl SniRoute {
+    pub fn upstream(&self) -> &str {
+        &self.upstream
+    }
+}
+
+/// Route TLS connections by server name, tagging them with `tls.sni` and `tls.alpn`.
+pub fn sni_router(routes: Vec<SniRoute>) -> Callback {
+    Callback::new(move |_, bytes| {
+        let hello = match parse_client_hello(bytes) {
+            Some(hello) => hello,
+            None => return Decision::default(),
+        };
+        let mut decision = Decision::default();
+        if let Some(name) = &hello.server_name {
+            let name = name.to_ascii_lowercase();
+            decision.upstream = routes
+                .iter()
+                .find(|route| route.server_name == name)
+                .map(|route| route.upstream.clone());
+            decision.tags.push(("tls.sni".to_string(), name));
+        }
+        if !hello.alpn.is_empty() {
+            decision
+                .tags
+                .push(("tls.alpn".to_string(), hello.alpn.join(",")));
+        }
+        decision
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// A minimal ClientHello record with the given SNI and ALPN protocols.
+    pub(crate) fn client_hello(server_name: &str, alpn: &[&str]) -> Vec<u8> {
+        let mut extensions = Vec::new();
+        let name = server_name.as_bytes();
+        extensions.extend_from_slice(&EXTENSION_SERVER_NAME.to_be_bytes());
+        extensions.extend_from_slice(&(name.len() as u16 + 5).to_be_bytes());
+        extensions.extend_from_slice(&(name.len() as u16 + 3).to_be_bytes());
+        extensions.push(0);
+        extensions.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        extensions.extend_from_slice(name);
+        let protocols: Vec<u8> = alpn
+            .iter()
+            .flat_map(|p| [&[p.len() as u8][..], p.as_bytes()].concat())
+            .collect();
+        extensions.extend_from_slice(&EXTENSION_ALPN.to_be_bytes());
+        extensions.extend_from_slice(&(protocols.len() as u16 + 2).to_be_bytes());
+        extensions.extend_from_slice(&(protocols.len() as u16).to_be_bytes());
+        extensions.extend_from_slice(&protocols);
+
+        let mut body = vec![0x03, 0x03];
+        body.extend_from_slice(&[0; 32]);
+        body.push(0);
+        body.extend_from_slice(&[0, 2, 0x13, 0x01]);
+        body.extend_from_slice(&[1, 0]);
+        body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        body.extend_from_slice(&extensions);
+
+        let mut handshake = vec![0x01];
+        handshake.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        handshake.extend_from_slice(&body);
+        let mut record = vec![0x16, 0x03, 0x01];
+        record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+        record.extend_from_slice(&handshake);
+        record
+    }
+
+    #[test]
+    fn test_parse_client_hello() {
+        let record = client_hello("db.example.com", &["h2", "http/1.1"]);
+        let hello = parse_client_hello(&record).unwrap();
+        assert_eq!(hello.server_name.as_deref(), Some("db.example.com"));
+        assert_eq!(hello.alpn, ["h2", "http/1.1"]);
+        assert_eq!(hello.version, 0x0303);
+        assert_eq!(hello.cipher_suites, [0x1301]);
+        assert_eq!(hello.extensions, [EXTENSION_SERVER_NAME, EXTENSION_ALPN]);
+        assert_eq!(parse_client_hello(&record[..record.len() - 1]), None);
+        assert_eq!(parse_client_hello(b"GET / HTTP/1.1\r\n"), None);
+    }
+
+    #[test]
+    fn test_sni_router() {
+        let router = sni_router(vec!["DB.example.com=db:5432".parse().unwrap()]);
+        let addr = "127.0.0.1:1234".parse().unwrap();
+        let decision = router.call(addr, &client_hello("db.example.com", &["h2"]));
+        assert_eq!(decision.upstream.as_deref(), Some("db:5432"));
+        assert_eq!(
+            decision.tags,
+            [
+                ("tls.sni".to_string(), "db.example.com".to_string()),
+                ("tls.alpn".to_string(), "h2".to_string()),
+            ]
+        );
+        let decision = router.call(addr, &client_hello("other.example.com", &[]));
+        assert_eq!(decision.upstream, None);
+        assert_eq!(router.call(addr, b"SSH-2.0-x\r\n"), Decision::default());
+        assert!("nohost".parse::<SniRoute>().is_err());
+    }
+}
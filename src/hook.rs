@@ -0,0 +1,249 @@
+//! Connection hooks: embedders' own inspection, mutation and rejection of connections,
+//! without forking the copy loop.
+//!
+//! Hooks are set with [`crate::Proxy::hook`] and called in the order they were added.
+//! Every TCP-accepted connection goes through `on_accept` and `on_close`; in tcp mode,
+//! connections also go through `on_connect_upstream`, and each chunk of bytes through
+//! `on_data` before it's forwarded. A hook returning an error closes the connection,
+//! with the error as its close reason.
+
+use std::fmt;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use futures::future::{self, BoxFuture, FutureExt};
+use tokio::io::{AsyncRead, ReadBuf};
+
+use crate::connection::{Connection, Direction, COPY_BUFFER_SIZE};
+
+pub trait ConnectionHook: Send + Sync {
+    /// Called when a client connects, before anything else happens to the connection.
+    fn on_accept<'a>(&'a self, _conn: &'a Connection) -> BoxFuture<'a, Result<(), String>> {
+        future::ready(Ok(())).boxed()
+    }
+
+    /// Called once the upstream is connected, before any bytes are forwarded.
+    fn on_connect_upstream<'a>(
+        &'a self,
+        _conn: &'a Connection,
+    ) -> BoxFuture<'a, Result<(), String>> {
+        future::ready(Ok(())).boxed()
+    }
+
+    /// Called with each chunk of bytes read going `direction`, returning what to
+    /// forward in their place: the same bytes, others, or none at all.
+    fn on_data<'a>(
+        &'a self,
+        _conn: &'a Connection,
+        _direction: Direction,
+        bytes: Vec<u8>,
+    ) -> BoxFuture<'a, Result<Vec<u8>, String>> {
+        future::ready(Ok(bytes)).boxed()
+    }
+
+    /// Called as the connection closes, with why.
+    fn on_close<'a>(&'a self, _conn: &'a Connection, _reason: &'a str) -> BoxFuture<'a, ()> {
+        future::ready(()).boxed()
+    }
+}
+
+/// A shareable hook, for `Args`.
+#[derive(Clone)]
+pub struct Hook(pub Arc<dyn ConnectionHook>);
+
+impl fmt::Debug for Hook {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Hook")
+    }
+}
+
+pub async fn accept(hooks: &[Hook], conn: &Connection) -> Result<(), String> {
+    for hook in hooks {
+        hook.0.on_accept(conn).await?;
+    }
+    Ok(())
+}
+
+pub async fn connect_upstream(hooks: &[Hook], conn: &Connection) -> Result<(), String> {
+    for hook in hooks {
+        hook.0.on_connect_upstream(conn).await?;
+    }
+    Ok(())
+}
+
+pub async fn close(hooks: &[Hook], conn: &Connection, reason: &str) {
+    for hook in hooks {
+        hook.0.on_close(conn, reason).await;
+    }
+}
+
+fn data<'a>(
+    hooks: &'a [Hook],
+    conn: &'a Connection,
+    direction: Direction,
+    mut bytes: Vec<u8>,
+) -> BoxFuture<'a, Result<Vec<u8>, String>> {
+    async move {
+        for hook in hooks {
+            bytes = hook.0.on_data(conn, direction, bytes).await?;
+        }
+        Ok(bytes)
+    }
+    .boxed()
+}
+
+/// Reads from `inner`, passing each chunk through the hooks' `on_data`.
+pub struct Reader<'a, R> {
+    inner: R,
+    hooks: &'a [Hook],
+    conn: &'a Connection,
+    direction: Direction,
+    /// The hooks working on the latest chunk read.
+    pending: Option<BoxFuture<'a, Result<Vec<u8>, String>>>,
+    /// Bytes from the hooks, from `out_pos`, waiting to be read.
+    out: Vec<u8>,
+    out_pos: usize,
+}
+
+impl<'a, R> Reader<'a, R> {
+    pub fn new(inner: R, hooks: &'a [Hook], direction: Direction, conn: &'a Connection) -> Self {
+        Self {
+            inner,
+            hooks,
+            conn,
+            direction,
+            pending: None,
+            out: Vec::new(),
+            out_pos: 0,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Reader<'_, R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.hooks.is_empty() {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+        loop {
+            if this.out_pos < this.out.len() {
+                let n = buf.remaining().min(this.out.len() - this.out_pos);
+                buf.put_slice(&this.out[this.out_pos..this.out_pos + n]);
+                this.out_pos += n;
+                return Poll::Ready(Ok(()));
+            }
+            if let Some(pending) = &mut this.pending {
+                let result = futures::ready!(pending.as_mut().poll(cx));
+                this.pending = None;
+                this.out = result.map_err(io::Error::other)?;
+                this.out_pos = 0;
+                continue;
+            }
+            let mut chunk = vec![0; COPY_BUFFER_SIZE];
+            let mut read = ReadBuf::new(&mut chunk);
+            futures::ready!(Pin::new(&mut this.inner).poll_read(cx, &mut read))?;
+            let n = read.filled().len();
+            if n == 0 {
+                return Poll::Ready(Ok(()));
+            }
+            chunk.truncate(n);
+            this.pending = Some(data(this.hooks, this.conn, this.direction, chunk));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use crate::Proxy;
+
+    /// Rejects every other connection, shouts, and remembers how connections ended.
+    #[derive(Default)]
+    struct Shout {
+        accepted: Mutex<u64>,
+        closed: Mutex<Vec<String>>,
+    }
+
+    impl ConnectionHook for Shout {
+        fn on_accept<'a>(&'a self, _conn: &'a Connection) -> BoxFuture<'a, Result<(), String>> {
+            async move {
+                let mut accepted = self.accepted.lock().unwrap();
+                *accepted += 1;
+                if (*accepted).is_multiple_of(2) {
+                    return Err("rejected by hook".to_string());
+                }
+                Ok(())
+            }
+            .boxed()
+        }
+
+        fn on_data<'a>(
+            &'a self,
+            _conn: &'a Connection,
+            direction: Direction,
+            bytes: Vec<u8>,
+        ) -> BoxFuture<'a, Result<Vec<u8>, String>> {
+            async move {
+                tokio::task::yield_now().await;
+                match direction {
+                    Direction::Up => Ok(bytes.to_ascii_uppercase()),
+                    Direction::Down => Ok(bytes),
+                }
+            }
+            .boxed()
+        }
+
+        fn on_close<'a>(&'a self, _conn: &'a Connection, reason: &'a str) -> BoxFuture<'a, ()> {
+            self.closed.lock().unwrap().push(reason.to_string());
+            future::ready(()).boxed()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_hooks() {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = upstream.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let (mut r, mut w) = stream.split();
+                    let _ = tokio::io::copy(&mut r, &mut w).await;
+                });
+            }
+        });
+        let hook = Arc::new(Shout::default());
+        let handle = Proxy::new("127.0.0.1:0", &upstream_addr)
+            .hook(hook.clone())
+            .start()
+            .await
+            .unwrap();
+
+        let mut client = TcpStream::connect(handle.local_addr()).await.unwrap();
+        client.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 5];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"HELLO");
+        drop(client);
+
+        let mut rejected = TcpStream::connect(handle.local_addr()).await.unwrap();
+        assert_eq!(rejected.read(&mut buf).await.unwrap_or(0), 0);
+        handle.shutdown(Duration::from_secs(5)).await.unwrap();
+        let mut closed = hook.closed.lock().unwrap().clone();
+        closed.sort();
+        assert_eq!(closed, ["completed", "rejected by hook"]);
+    }
+}
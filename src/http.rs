@@ -0,0 +1,608 @@
+//! HTTP/1 aware proxying (`--mode http`).
+//!
+//! Each downstream connection is served by hyper and its requests are forwarded,
+//! one at a time, over a single upstream connection dialed on first use. Working at
+//! the request level lets tproxy answer some requests itself (see [`Fault`]), or from
+//! its cache (see `http_cache`).
+
+use std::error::Error;
+use std::io;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use hyper::client::conn::SendRequest;
+use hyper::header::{HeaderName, HeaderValue, CONNECTION};
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{Body, HeaderMap, Request, Response, StatusCode};
+use rand::Rng;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+use tracing::warn;
+
+use crate::connection::{Connection, Direction};
+use crate::http_cache::{self, Pending, Policy};
+use crate::mirror;
+use crate::pattern::Pattern;
+use crate::state::State;
+use crate::{connect_upstream, parse_duration, Args};
+
+/// A synthetic response returned instead of contacting the upstream, for a random
+/// fraction of requests. Written as `STATUS:PROBABILITY[:DELAY]`, e.g. `503:0.1:200ms`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Fault {
+    pub status: StatusCode,
+    pub probability: f64,
+    pub delay: Duration,
+}
+
+impl FromStr for Fault {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(3, ':');
+        let status = parts
+            .next()
+            .and_then(|p| p.parse::<u16>().ok())
+            .and_then(|p| StatusCode::from_u16(p).ok())
+            .ok_or_else(|| format!("invalid fault status: {}", s))?;
+        let probability = parts
+            .next()
+            .and_then(|p| p.parse::<f64>().ok())
+            .filter(|p| (0.0..=1.0).contains(p))
+            .ok_or_else(|| format!("invalid fault probability: {}", s))?;
+        let delay = match parts.next() {
+            Some(delay) => parse_duration(delay)?,
+            None => Duration::ZERO,
+        };
+        Ok(Fault {
+            status,
+            probability,
+            delay,
+        })
+    }
+}
+
+/// A rule applied to request or response headers, written as one of
+///
+/// - `add:NAME:VALUE` to append a header,
+/// - `remove:NAME` to delete every value of a header,
+/// - `replace:NAME:/PATTERN/REPLACEMENT/` to replace matches of a [`Pattern`] in a
+///   header's values. A `/` inside the pattern or replacement is written `\/`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HeaderRule {
+    name: HeaderName,
+    action: HeaderAction,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum HeaderAction {
+    Add(HeaderValue),
+    Remove,
+    Replace(Pattern, Vec<u8>),
+}
+
+impl FromStr for HeaderRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(3, ':');
+        let action = parts.next().unwrap_or("");
+        let name = parts
+            .next()
+            .and_then(|n| HeaderName::from_bytes(n.as_bytes()).ok())
+            .ok_or_else(|| format!("invalid header name in rule: {}", s))?;
+        let arg = parts.next();
+        let action = match (action, arg) {
+            ("add", Some(value)) => HeaderAction::Add(
+                HeaderValue::from_str(value)
+                    .map_err(|_| format!("invalid header value in rule: {}", s))?,
+            ),
+            ("remove", None) => HeaderAction::Remove,
+            ("replace", Some(arg)) => {
+                let fields = split_slashes(arg)
+                    .ok_or_else(|| format!("expected /PATTERN/REPLACEMENT/ in rule: {}", s))?;
+                HeaderAction::Replace(fields.0.parse()?, fields.1.into_bytes())
+            }
+            _ => return Err(format!("invalid header rule: {}", s)),
+        };
+        Ok(HeaderRule { name, action })
+    }
+}
+
+/// Split `/a/b/` into `a` and `b`, unescaping `\/`.
+pub(crate) fn split_slashes(s: &str) -> Option<(String, String)> {
+    let s = s.strip_prefix('/')?;
+    let mut fields = vec![String::new()];
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if chars.peek() == Some(&'/') => {
+                fields.last_mut().unwrap().push('/');
+                chars.next();
+            }
+            '/' => fields.push(String::new()),
+            c => fields.last_mut().unwrap().push(c),
+        }
+    }
+    // Splitting `a/b/` leaves a trailing empty field after the closing slash.
+    if fields.len() != 3 || !fields[2].is_empty() {
+        return None;
+    }
+    fields.pop();
+    let replacement = fields.pop()?;
+    Some((fields.pop()?, replacement))
+}
+
+impl HeaderRule {
+    fn apply(&self, headers: &mut HeaderMap) {
+        match &self.action {
+            HeaderAction::Add(value) => {
+                headers.append(self.name.clone(), value.clone());
+            }
+            HeaderAction::Remove => {
+                headers.remove(&self.name);
+            }
+            HeaderAction::Replace(pattern, replacement) => {
+                if let hyper::header::Entry::Occupied(mut entry) = headers.entry(&self.name) {
+                    for value in entry.iter_mut() {
+                        let (replaced, count) = pattern.replace_all(value.as_bytes(), replacement);
+                        if count > 0 {
+                            // Leave values alone if the result isn't a valid header value.
+                            if let Ok(replaced) = HeaderValue::from_bytes(&replaced) {
+                                *value = replaced;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn apply_rules(rules: &[HeaderRule], headers: &mut HeaderMap) {
+    for rule in rules {
+        rule.apply(headers);
+    }
+}
+
+/// Serve HTTP/1 requests from `downstream`, forwarding them to the upstream.
+pub async fn proxy(
+    downstream: TcpStream,
+    args: Arc<Args>,
+    state: Arc<State>,
+    conn: Arc<Connection>,
+) -> Result<(), Box<dyn Error>> {
+    let killed = conn.clone();
+    let downstream = Counted {
+        inner: downstream,
+        conn: conn.clone(),
+    };
+    let upstream = Arc::new(Mutex::new(None));
+    let mut drain = state.draining.subscribe();
+    let service = service_fn(move |req| {
+        let state = state.clone();
+        let response = handle(
+            req,
+            args.clone(),
+            state.clone(),
+            conn.clone(),
+            upstream.clone(),
+        );
+        async move {
+            let mut resp = response.await?;
+            if state.is_draining() {
+                // Tell the client we're about to close, so it doesn't reuse the connection.
+                resp.headers_mut()
+                    .insert(CONNECTION, HeaderValue::from_static("close"));
+            }
+            Ok::<_, hyper::Error>(resp)
+        }
+    });
+    let serving = Http::new()
+        .http1_only(true)
+        .serve_connection(downstream, service);
+    tokio::pin!(serving);
+    let mut shutting_down = false;
+    loop {
+        if !shutting_down && *drain.borrow() {
+            // Finish the in-flight request, if any, then close.
+            serving.as_mut().graceful_shutdown();
+            shutting_down = true;
+        }
+        tokio::select! {
+            result = &mut serving => return Ok(result?),
+            changed = drain.changed(), if !shutting_down => changed?,
+            // Dropping the connection closes it.
+            _ = killed.until_killed() => return Ok(()),
+        }
+    }
+}
+
+async fn handle(
+    mut req: Request<Body>,
+    args: Arc<Args>,
+    state: Arc<State>,
+    conn: Arc<Connection>,
+    upstream: Arc<Mutex<Option<SendRequest<Body>>>>,
+) -> Result<Response<Body>, hyper::Error> {
+    state.http_requests.fetch_add(1, Ordering::Relaxed);
+
+    if let Some(fault) = pick_fault(&args.http_fault) {
+        state.http_faults_injected.fetch_add(1, Ordering::Relaxed);
+        tokio::time::sleep(fault.delay).await;
+        return Ok(synthetic(fault.status, "injected by tproxy\n"));
+    }
+
+    apply_rules(&args.request_header_rule, req.headers_mut());
+    let req = mirror::tee(req, &args, &state).await?;
+    let policy = Policy {
+        violations: &args.http_cache_violate,
+        ttl: args.http_cache_ttl,
+    };
+    let mut pending = None;
+    if args.http_cache {
+        if let Some(mut resp) = state.http_cache.lookup(&req, &policy) {
+            state.http_cache_hits.fetch_add(1, Ordering::Relaxed);
+            resp.headers_mut()
+                .insert(http_cache::X_CACHE, HeaderValue::from_static("HIT"));
+            return Ok(resp);
+        }
+        state.http_cache_misses.fetch_add(1, Ordering::Relaxed);
+        pending = Pending::new(&req);
+    }
+
+    let mut upstream = upstream.lock().await;
+    if upstream.is_none() {
+        match connect(&args, &state, &conn).await {
+            Ok(sender) => *upstream = Some(sender),
+            Err(err) => {
+                warn!(id = conn.id, error = %err, "failed to connect upstream");
+                return Ok(synthetic(StatusCode::BAD_GATEWAY, "upstream unavailable\n"));
+            }
+        }
+    }
+    let sender = upstream.as_mut().unwrap();
+    match sender.send_request(req).await {
+        Ok(mut resp) => {
+            apply_rules(&args.response_header_rule, resp.headers_mut());
+            if let Some(pending) = pending {
+                let (stored, kept) = state.http_cache.store(pending, resp, &policy).await?;
+                if kept {
+                    state.http_cache_stores.fetch_add(1, Ordering::Relaxed);
+                }
+                resp = stored;
+                resp.headers_mut()
+                    .insert(http_cache::X_CACHE, HeaderValue::from_static("MISS"));
+            }
+            Ok(resp)
+        }
+        Err(err) => {
+            warn!(id = conn.id, error = %err, "failed to proxy request");
+            // The upstream connection is unusable after an error; dial again next time.
+            *upstream = None;
+            Ok(synthetic(
+                StatusCode::BAD_GATEWAY,
+                "upstream request failed\n",
+            ))
+        }
+    }
+}
+
+/// The first fault whose dice roll hits, if any.
+fn pick_fault(faults: &[Fault]) -> Option<&Fault> {
+    let mut rng = rand::thread_rng();
+    faults.iter().find(|f| rng.gen_bool(f.probability))
+}
+
+fn synthetic(status: StatusCode, body: &'static str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("content-type", "text/plain")
+        .body(Body::from(body))
+        .unwrap()
+}
+
+async fn connect(
+    args: &Args,
+    state: &State,
+    conn: &Connection,
+) -> Result<SendRequest<Body>, Box<dyn Error>> {
+    let connect_start = Instant::now();
+    let upstream = connect_upstream(&args.upstream_addr, args, state).await?;
+    state.observe_connect(conn, connect_start.elapsed());
+    conn.connected();
+    if let Ok(addr) = upstream.local_addr() {
+        conn.set_source_addr(addr);
+    }
+    let (sender, connection) = hyper::client::conn::handshake(upstream).await?;
+    let id = conn.id;
+    tokio::spawn(async move {
+        if let Err(err) = connection.await {
+            warn!(id, error = %err, "upstream connection failed");
+        }
+    });
+    Ok(sender)
+}
+
+/// Counts bytes read from and written to the downstream against its connection.
+struct Counted<T> {
+    inner: T,
+    conn: Arc<Connection>,
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Counted<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let n = buf.filled().len() - before;
+        self.conn.add_bytes(Direction::Up, n as u64);
+        result
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Counted<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = result {
+            self.conn.add_bytes(Direction::Down, n as u64);
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::SocketAddr;
+
+    use clap::Parser;
+    use futures::FutureExt;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use tokio::sync::oneshot;
+    use warp::Filter;
+
+    use crate::listen;
+
+    #[test]
+    fn test_parse_fault() {
+        assert_eq!(
+            "503:0.1:200ms".parse(),
+            Ok(Fault {
+                status: StatusCode::SERVICE_UNAVAILABLE,
+                probability: 0.1,
+                delay: Duration::from_millis(200),
+            })
+        );
+        assert_eq!(
+            "429:1".parse::<Fault>().unwrap().status,
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        assert!("503".parse::<Fault>().is_err());
+        assert!("503:2".parse::<Fault>().is_err());
+        assert!("99:0.5".parse::<Fault>().is_err());
+    }
+
+    #[test]
+    fn test_header_rules() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "host",
+            HeaderValue::from_static("internal.example.com:8080"),
+        );
+        headers.insert("x-secret", HeaderValue::from_static("hunter2"));
+        let rules: Vec<HeaderRule> = [
+            "add:x-via:tproxy",
+            "remove:x-secret",
+            "replace:host:/internal\\.example\\.com/public.example.com/",
+        ]
+        .iter()
+        .map(|r| r.parse().unwrap())
+        .collect();
+        apply_rules(&rules, &mut headers);
+        assert_eq!(headers["x-via"], "tproxy");
+        assert!(!headers.contains_key("x-secret"));
+        assert_eq!(headers["host"], "public.example.com:8080");
+
+        assert_eq!(
+            split_slashes("/a\\/b/c/"),
+            Some(("a/b".to_string(), "c".to_string()))
+        );
+        assert!("replace:host:/a/b".parse::<HeaderRule>().is_err());
+        assert!("remove:host:x".parse::<HeaderRule>().is_err());
+        assert!("add:bad name:x".parse::<HeaderRule>().is_err());
+        assert!("rename:host".parse::<HeaderRule>().is_err());
+    }
+
+    /// Start an HTTP upstream that answers every request with "hello", and a proxy in
+    /// front of it.
+    async fn start(extra_args: &[&str]) -> (SocketAddr, Arc<State>) {
+        let hello = warp::any().map(|| "hello");
+        let (upstream_addr, server) = warp::serve(hello).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        start_proxy(upstream_addr, extra_args).await
+    }
+
+    async fn start_proxy(
+        upstream_addr: SocketAddr,
+        extra_args: &[&str],
+    ) -> (SocketAddr, Arc<State>) {
+        let upstream_addr = upstream_addr.to_string();
+        let mut argv = vec![
+            "tproxy",
+            "--listen-addr",
+            "127.0.0.1:0",
+            "--upstream-addr",
+            &upstream_addr,
+            "--mode",
+            "http",
+        ];
+        argv.extend_from_slice(extra_args);
+        let args = Args::parse_from(argv);
+        let state = Arc::new(State::new());
+        let (listen_tx, listen_rx) = oneshot::channel();
+        tokio::spawn(listen(args, state.clone(), listen_tx).map(|r| {
+            if let Err(err) = r {
+                println!("failed to listen; error={}", err);
+            }
+        }));
+        (listen_rx.await.unwrap(), state)
+    }
+
+    async fn get(addr: SocketAddr) -> (StatusCode, String) {
+        let resp = hyper::Client::new()
+            .get(format!("http://{}/", addr).parse().unwrap())
+            .await
+            .unwrap();
+        let status = resp.status();
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_http_proxy() {
+        let (addr, state) = start(&["--http-fault", "503:0"]).await;
+        assert_eq!(get(addr).await, (StatusCode::OK, "hello".to_string()));
+        assert_eq!(get(addr).await, (StatusCode::OK, "hello".to_string()));
+        let snapshot = state.snapshot();
+        assert_eq!(snapshot.http_requests, 2);
+        assert_eq!(snapshot.http_faults_injected, 0);
+    }
+
+    #[tokio::test]
+    async fn test_http_cache() {
+        let requests = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counted = requests.clone();
+        let upstream = warp::any().map(move || {
+            let n = counted.fetch_add(1, Ordering::Relaxed);
+            warp::reply::with_header(format!("response {}", n), "cache-control", "max-age=60")
+        });
+        let (upstream_addr, server) = warp::serve(upstream).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let (addr, state) = start_proxy(upstream_addr, &["--http-cache"]).await;
+
+        assert_eq!(get(addr).await, (StatusCode::OK, "response 0".to_string()));
+        assert_eq!(get(addr).await, (StatusCode::OK, "response 0".to_string()));
+        assert_eq!(requests.load(Ordering::Relaxed), 1);
+        let snapshot = state.snapshot();
+        assert_eq!(snapshot.http_cache_hits, 1);
+        assert_eq!(snapshot.http_cache_misses, 1);
+        assert_eq!(snapshot.http_cache_stores, 1);
+    }
+
+    #[tokio::test]
+    async fn test_drain() {
+        let upstream = warp::any().and_then(|| async {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            Ok::<_, std::convert::Infallible>("slow")
+        });
+        let (upstream_addr, server) = warp::serve(upstream).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let (addr, state) = start_proxy(upstream_addr, &[]).await;
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(b"GET / HTTP/1.1\r\nhost: example.com\r\n\r\n")
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        state.draining.send_replace(true);
+
+        // The in-flight response completes, and then the connection closes.
+        let mut resp = String::new();
+        client.read_to_string(&mut resp).await.unwrap();
+        assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"), "{}", resp);
+        assert!(resp.contains("connection: close\r\n"), "{}", resp);
+        assert!(resp.ends_with("slow"), "{}", resp);
+
+        while !state.listener_closed.load(Ordering::Relaxed) {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        assert!(TcpStream::connect(addr).await.is_err());
+
+        state.draining.send_replace(false);
+        while state.listener_closed.load(Ordering::Relaxed) {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        assert_eq!(get(addr).await, (StatusCode::OK, "slow".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_http_mirror() {
+        let paths = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = paths.clone();
+        let mirror = warp::path::full().map(move |path: warp::path::FullPath| {
+            seen.lock().unwrap().push(path.as_str().to_string());
+            "ignored"
+        });
+        let (mirror_addr, server) = warp::serve(mirror).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let mirror_addr = mirror_addr.to_string();
+
+        let (addr, state) =
+            start(&["--http-mirror", &mirror_addr, "--http-mirror-path", "/api"]).await;
+        assert_eq!(get(addr).await, (StatusCode::OK, "hello".to_string()));
+        let resp = hyper::Client::new()
+            .get(format!("http://{}/api/users?page=2", addr).parse().unwrap())
+            .await
+            .unwrap();
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(body, "hello");
+        while state.http_mirror.in_flight() > 0 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        assert_eq!(*paths.lock().unwrap(), ["/api/users"]);
+        let snapshot = state.snapshot();
+        assert_eq!(snapshot.http_mirrored, 1);
+        assert_eq!(snapshot.http_mirror_errors, 0);
+
+        let (addr, state) = start(&[
+            "--http-mirror",
+            &mirror_addr,
+            "--http-mirror-max-in-flight",
+            "0",
+        ])
+        .await;
+        assert_eq!(get(addr).await, (StatusCode::OK, "hello".to_string()));
+        assert_eq!(state.snapshot().http_mirror_skipped, 1);
+
+        let (addr, state) =
+            start(&["--http-mirror", &mirror_addr, "--http-mirror-percent", "0"]).await;
+        assert_eq!(get(addr).await, (StatusCode::OK, "hello".to_string()));
+        assert_eq!(state.snapshot().http_mirrored, 0);
+        assert_eq!(paths.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_http_fault() {
+        let (addr, state) = start(&["--http-fault", "503:1"]).await;
+        let (status, _) = get(addr).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(state.snapshot().http_faults_injected, 1);
+        // The upstream was never dialed.
+        assert_eq!(state.connect_latency.count(), 0);
+    }
+}
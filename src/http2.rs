@@ -0,0 +1,317 @@
+//! HTTP/2 awareness for TCP mode.
+//!
+//! Like the WebSocket [`Tracker`](crate::websocket::Tracker), each direction of a
+//! connection is watched passively. A client that opens with the HTTP/2 connection
+//! preface (cleartext, prior knowledge) marks the connection as HTTP/2, and from
+//! then on frame headers are parsed in both directions to follow streams: how many
+//! are open at once, how many bytes of DATA each one carries, and how many were
+//! reset. Payloads are never decoded, so HPACK state isn't needed.
+
+use std::collections::BTreeMap;
+
+use serde_json::{json, Value};
+
+use crate::connection::{Connection, Direction};
+
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+const FRAME_HEADER_SIZE: usize = 9;
+
+const DATA: u8 = 0x0;
+const HEADERS: u8 = 0x1;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const END_STREAM: u8 = 0x1;
+
+/// How many streams to keep per connection. Beyond this the oldest closed streams
+/// are forgotten, though they still count towards the totals.
+const MAX_STREAMS: usize = 100;
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct StreamStats {
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    end_up: bool,
+    end_down: bool,
+    pub reset: bool,
+}
+
+impl StreamStats {
+    fn is_closed(&self) -> bool {
+        self.reset || (self.end_up && self.end_down)
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Stats {
+    pub streams_total: u64,
+    pub active_streams: u64,
+    pub max_concurrent_streams: u64,
+    pub resets_up: u64,
+    pub resets_down: u64,
+    streams: BTreeMap<u32, StreamStats>,
+}
+
+impl Stats {
+    fn record(&mut self, direction: Direction, frame: &Frame) {
+        if frame.stream == 0 {
+            return;
+        }
+        if !self.streams.contains_key(&frame.stream) {
+            if frame.kind != HEADERS {
+                // Frames for streams we've forgotten, or which never opened.
+                return;
+            }
+            self.streams_total += 1;
+            self.active_streams += 1;
+            self.max_concurrent_streams = self.max_concurrent_streams.max(self.active_streams);
+            self.streams.insert(frame.stream, StreamStats::default());
+            self.evict();
+        }
+        let stream = self.streams.get_mut(&frame.stream).unwrap();
+        if stream.is_closed() {
+            return;
+        }
+        let end = frame.flags & END_STREAM != 0;
+        match (frame.kind, direction) {
+            (DATA, Direction::Up) => stream.bytes_up += frame.len as u64,
+            (DATA, Direction::Down) => stream.bytes_down += frame.len as u64,
+            (RST_STREAM, Direction::Up) => {
+                stream.reset = true;
+                self.resets_up += 1;
+            }
+            (RST_STREAM, Direction::Down) => {
+                stream.reset = true;
+                self.resets_down += 1;
+            }
+            _ => {}
+        }
+        if end && (frame.kind == DATA || frame.kind == HEADERS) {
+            match direction {
+                Direction::Up => stream.end_up = true,
+                Direction::Down => stream.end_down = true,
+            }
+        }
+        if stream.is_closed() {
+            self.active_streams -= 1;
+        }
+    }
+
+    fn evict(&mut self) {
+        if self.streams.len() <= MAX_STREAMS {
+            return;
+        }
+        let oldest_closed = self
+            .streams
+            .iter()
+            .find(|(_, s)| s.is_closed())
+            .map(|(id, _)| *id);
+        if let Some(id) = oldest_closed {
+            self.streams.remove(&id);
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        let streams: Vec<Value> = self
+            .streams
+            .iter()
+            .map(|(id, s)| {
+                json!({
+                    "id": id,
+                    "bytes_up": s.bytes_up,
+                    "bytes_down": s.bytes_down,
+                    "closed": s.is_closed(),
+                    "reset": s.reset,
+                })
+            })
+            .collect();
+        json!({
+            "streams_total": self.streams_total,
+            "active_streams": self.active_streams,
+            "max_concurrent_streams": self.max_concurrent_streams,
+            "resets_up": self.resets_up,
+            "resets_down": self.resets_down,
+            "streams": streams,
+        })
+    }
+}
+
+#[derive(Debug)]
+enum Phase {
+    /// Waiting for enough bytes to recognise the preface (up) or a SETTINGS frame
+    /// (down).
+    Detect(Vec<u8>),
+    Frames,
+    Opaque,
+}
+
+#[derive(Debug)]
+struct Frame {
+    len: u32,
+    kind: u8,
+    flags: u8,
+    stream: u32,
+}
+
+/// Watches the bytes flowing in one direction of a connection.
+#[derive(Debug)]
+pub struct Tracker {
+    direction: Direction,
+    phase: Phase,
+    /// A partially received frame header.
+    header: Vec<u8>,
+    /// Payload bytes left to skip in the current frame.
+    remaining: u64,
+}
+
+impl Tracker {
+    pub fn new(direction: Direction) -> Self {
+        Self {
+            direction,
+            phase: Phase::Detect(Vec::new()),
+            header: Vec::new(),
+            remaining: 0,
+        }
+    }
+
+    /// Inspect the next chunk of bytes, recording what's seen on `conn`.
+    pub fn feed(&mut self, data: &[u8], conn: &Connection) {
+        if let Phase::Detect(buf) = &mut self.phase {
+            buf.extend_from_slice(data);
+            let expected = match self.direction {
+                Direction::Up => PREFACE.len(),
+                Direction::Down => FRAME_HEADER_SIZE,
+            };
+            let n = buf.len().min(expected);
+            let detected = match self.direction {
+                Direction::Up => buf[..n] == PREFACE[..n],
+                Direction::Down => n < 4 || buf[3] == SETTINGS,
+            };
+            if !detected {
+                self.phase = Phase::Opaque;
+                return;
+            }
+            if buf.len() < expected {
+                return;
+            }
+            let rest = match self.direction {
+                Direction::Up => {
+                    conn.set_http2();
+                    buf.split_off(PREFACE.len())
+                }
+                Direction::Down => std::mem::take(buf),
+            };
+            self.phase = Phase::Frames;
+            self.frames(&rest, conn);
+            return;
+        }
+        if let Phase::Frames = self.phase {
+            self.frames(data, conn);
+        }
+    }
+
+    fn frames(&mut self, mut data: &[u8], conn: &Connection) {
+        while !data.is_empty() {
+            if self.remaining > 0 {
+                let n = self.remaining.min(data.len() as u64);
+                self.remaining -= n;
+                data = &data[n as usize..];
+                continue;
+            }
+            let take = (FRAME_HEADER_SIZE - self.header.len()).min(data.len());
+            self.header.extend_from_slice(&data[..take]);
+            data = &data[take..];
+            if self.header.len() < FRAME_HEADER_SIZE {
+                continue;
+            }
+            let h = &self.header;
+            let frame = Frame {
+                len: u32::from_be_bytes([0, h[0], h[1], h[2]]),
+                kind: h[3],
+                flags: h[4],
+                stream: u32::from_be_bytes([h[5], h[6], h[7], h[8]]) & 0x7fff_ffff,
+            };
+            self.header.clear();
+            self.remaining = frame.len as u64;
+            let direction = self.direction;
+            conn.update_http2(|stats| stats.record(direction, &frame));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(kind: u8, flags: u8, stream: u32, payload: &[u8]) -> Vec<u8> {
+        let mut out = (payload.len() as u32).to_be_bytes()[1..].to_vec();
+        out.extend_from_slice(&[kind, flags]);
+        out.extend_from_slice(&stream.to_be_bytes());
+        out.extend_from_slice(payload);
+        out
+    }
+
+    #[test]
+    fn test_tracker() {
+        let conn = Connection::new(1, "127.0.0.1:1234".parse().unwrap(), "up:80".into());
+        let mut up = Tracker::new(Direction::Up);
+        let mut down = Tracker::new(Direction::Down);
+
+        let mut client = PREFACE.to_vec();
+        client.extend(frame(SETTINGS, 0, 0, &[]));
+        client.extend(frame(HEADERS, END_STREAM, 1, b"hpack"));
+        client.extend(frame(HEADERS, 0, 3, b"hpack"));
+        client.extend(frame(DATA, END_STREAM, 3, &[0; 100]));
+        client.extend(frame(HEADERS, 0, 5, b"hpack"));
+        client.extend(frame(RST_STREAM, 0, 5, &[0, 0, 0, 8]));
+        for b in &client {
+            up.feed(std::slice::from_ref(b), &conn);
+        }
+
+        let mut server = frame(SETTINGS, 0, 0, &[]);
+        server.extend(frame(HEADERS, 0, 1, b"hpack"));
+        server.extend(frame(DATA, END_STREAM, 1, &[0; 1000]));
+        down.feed(&server, &conn);
+
+        let detail = conn.detail();
+        let stats = &detail["http2_streams"];
+        assert_eq!(stats["streams_total"], 3);
+        assert_eq!(stats["max_concurrent_streams"], 3);
+        // Stream 3 is still waiting for the server.
+        assert_eq!(stats["active_streams"], 1);
+        assert_eq!(stats["resets_up"], 1);
+        assert_eq!(stats["streams"][0]["bytes_down"], 1000);
+        assert_eq!(stats["streams"][0]["closed"], true);
+        assert_eq!(stats["streams"][1]["bytes_up"], 100);
+        assert_eq!(stats["streams"][1]["closed"], false);
+        assert_eq!(stats["streams"][2]["reset"], true);
+    }
+
+    #[test]
+    fn test_not_http2() {
+        let conn = Connection::new(1, "127.0.0.1:1234".parse().unwrap(), "up:80".into());
+        let mut up = Tracker::new(Direction::Up);
+        up.feed(b"GET / HTTP/1.1\r\n\r\n", &conn);
+        assert!(matches!(up.phase, Phase::Opaque));
+        assert!(conn.detail()["http2_streams"].is_null());
+    }
+
+    #[test]
+    fn test_evict() {
+        let mut stats = Stats::default();
+        for i in 0..MAX_STREAMS as u32 + 10 {
+            let stream = i * 2 + 1;
+            let headers = Frame {
+                len: 0,
+                kind: HEADERS,
+                flags: END_STREAM,
+                stream,
+            };
+            stats.record(Direction::Up, &headers);
+            stats.record(Direction::Down, &headers);
+        }
+        assert_eq!(stats.streams.len(), MAX_STREAMS);
+        assert_eq!(stats.streams_total, MAX_STREAMS as u64 + 10);
+        assert_eq!(stats.active_streams, 0);
+    }
+}
@@ -0,0 +1,422 @@
+//! An in-memory cache of upstream responses in http mode, see `--http-cache`.
+//!
+//! By default the cache follows the rules for a shared cache in Cache-Control:
+//! `max-age` and `s-maxage` set how long a response is fresh, `no-store`, `no-cache`
+//! and `private` responses aren't kept, `Vary` keys entries on the request headers it
+//! names, and clients can ask to bypass it. Each [`Violation`] breaks one of those
+//! rules the way a misbehaving CDN would, for reproducing cache-related client bugs.
+//!
+//! Only GET responses with a `Content-Length` of at most [`MAX_ENTRY_SIZE`] are kept.
+//! `Expires` isn't read; responses without `max-age` use `--http-cache-ttl`.
+
+use std::collections::{HashMap, VecDeque};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use hyper::body::Bytes;
+use hyper::header::{self, HeaderName, HeaderValue};
+use hyper::{Body, HeaderMap, Method, Request, Response, StatusCode};
+
+/// The largest response body kept.
+pub const MAX_ENTRY_SIZE: u64 = 1024 * 1024;
+
+/// How many responses are kept. The oldest URLs are evicted first.
+const MAX_ENTRIES: usize = 1024;
+
+/// Statuses which are cacheable without explicit freshness information.
+const CACHEABLE_STATUSES: &[u16] = &[200, 203, 204, 300, 301, 404, 405, 410, 414, 501];
+
+pub const X_CACHE: &str = "x-cache";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Violation {
+    /// Keep `no-store`, `no-cache` and `private` responses, and responses to requests
+    /// with credentials.
+    NoStore,
+    /// Serve entries after they expire.
+    Expiry,
+    /// Ignore `Vary`, serving one client's variant to everyone.
+    Vary,
+    /// Ignore `no-cache` and `no-store` from clients.
+    RequestDirectives,
+}
+
+impl FromStr for Violation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "no-store" => Ok(Violation::NoStore),
+            "expiry" => Ok(Violation::Expiry),
+            "vary" => Ok(Violation::Vary),
+            "request-directives" => Ok(Violation::RequestDirectives),
+            _ => Err(format!("unknown cache violation: {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Policy<'a> {
+    pub violations: &'a [Violation],
+    /// How long responses without `max-age` are fresh.
+    pub ttl: Duration,
+}
+
+impl Policy<'_> {
+    fn violates(&self, violation: Violation) -> bool {
+        self.violations.contains(&violation)
+    }
+}
+
+/// The directives of every Cache-Control header, lowercase.
+fn directives(headers: &HeaderMap) -> Vec<(String, Option<String>)> {
+    headers
+        .get_all(header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter(|directive| !directive.trim().is_empty())
+        .map(|directive| match directive.split_once('=') {
+            Some((name, value)) => (
+                name.trim().to_ascii_lowercase(),
+                Some(value.trim().trim_matches('"').to_string()),
+            ),
+            None => (directive.trim().to_ascii_lowercase(), None),
+        })
+        .collect()
+}
+
+fn has(directives: &[(String, Option<String>)], name: &str) -> bool {
+    directives.iter().any(|(n, _)| n == name)
+}
+
+fn seconds(directives: &[(String, Option<String>)], name: &str) -> Option<Duration> {
+    directives
+        .iter()
+        .find(|(n, _)| n == name)
+        .and_then(|(_, value)| value.as_ref()?.parse().ok())
+        .map(Duration::from_secs)
+}
+
+fn primary_key(req: &Request<Body>) -> String {
+    let host = req
+        .headers()
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .unwrap_or("");
+    format!("{}{}", host, req.uri())
+}
+
+/// What's needed from a request to store its response, taken before it's sent.
+pub struct Pending {
+    key: String,
+    headers: HeaderMap,
+}
+
+impl Pending {
+    /// `None` if the response to `req` can't be cached.
+    pub fn new(req: &Request<Body>) -> Option<Self> {
+        (req.method() == Method::GET).then(|| Pending {
+            key: primary_key(req),
+            headers: req.headers().clone(),
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    /// The request's values of the headers named by `Vary`.
+    vary: Vec<(HeaderName, Option<HeaderValue>)>,
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    stored: Instant,
+    ttl: Duration,
+}
+
+impl Entry {
+    fn matches(&self, headers: &HeaderMap) -> bool {
+        self.vary
+            .iter()
+            .all(|(name, value)| headers.get(name) == value.as_ref())
+    }
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    entries: HashMap<String, Vec<Entry>>,
+    /// Primary keys, oldest first.
+    order: VecDeque<String>,
+    len: usize,
+}
+
+#[derive(Debug, Default)]
+pub struct Cache {
+    inner: Mutex<Inner>,
+}
+
+impl Cache {
+    /// A response to `req` from the cache, if there's a fresh one it may be served.
+    pub fn lookup(&self, req: &Request<Body>, policy: &Policy) -> Option<Response<Body>> {
+        if req.method() != Method::GET {
+            return None;
+        }
+        if !policy.violates(Violation::RequestDirectives) {
+            let directives = directives(req.headers());
+            let pragma = req
+                .headers()
+                .get(header::PRAGMA)
+                .is_some_and(|pragma| pragma.as_bytes().eq_ignore_ascii_case(b"no-cache"));
+            if has(&directives, "no-cache") || has(&directives, "no-store") || pragma {
+                return None;
+            }
+        }
+        let inner = self.inner.lock().unwrap();
+        let entry = inner.entries.get(&primary_key(req))?.iter().find(|entry| {
+            (policy.violates(Violation::Vary) || entry.matches(req.headers()))
+                && (policy.violates(Violation::Expiry) || entry.stored.elapsed() < entry.ttl)
+        })?;
+        let mut resp = Response::new(Body::from(entry.body.clone()));
+        *resp.status_mut() = entry.status;
+        *resp.headers_mut() = entry.headers.clone();
+        resp.headers_mut()
+            .insert(header::AGE, entry.stored.elapsed().as_secs().into());
+        Some(resp)
+    }
+
+    /// How long `resp` may be cached for, if at all.
+    fn freshness(
+        &self,
+        pending: &Pending,
+        resp: &Response<Body>,
+        policy: &Policy,
+    ) -> Option<Duration> {
+        let directives = directives(resp.headers());
+        let private = has(&directives, "no-store")
+            || has(&directives, "no-cache")
+            || has(&directives, "private")
+            // Shared caches may only keep authorized responses which allow it.
+            || (pending.headers.contains_key(header::AUTHORIZATION)
+                && !has(&directives, "public")
+                && !has(&directives, "s-maxage"));
+        if private && !policy.violates(Violation::NoStore) {
+            return None;
+        }
+        let ttl = seconds(&directives, "s-maxage")
+            .or_else(|| seconds(&directives, "max-age"))
+            .or_else(|| {
+                CACHEABLE_STATUSES
+                    .contains(&resp.status().as_u16())
+                    .then_some(policy.ttl)
+            })?;
+        let size_ok = resp
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|len| len.to_str().ok()?.parse::<u64>().ok())
+            .is_some_and(|len| len <= MAX_ENTRY_SIZE);
+        (size_ok && (!ttl.is_zero() || policy.violates(Violation::Expiry))).then_some(ttl)
+    }
+
+    /// Keep `resp` if it may be cached, returning it to be sent on.
+    pub async fn store(
+        &self,
+        pending: Pending,
+        resp: Response<Body>,
+        policy: &Policy<'_>,
+    ) -> Result<(Response<Body>, bool), hyper::Error> {
+        let ttl = match self.freshness(&pending, &resp, policy) {
+            Some(ttl) => ttl,
+            None => return Ok((resp, false)),
+        };
+        let mut vary = Vec::new();
+        if !policy.violates(Violation::Vary) {
+            let names = resp
+                .headers()
+                .get_all(header::VARY)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(','))
+                .map(str::trim);
+            for name in names {
+                match HeaderName::from_bytes(name.as_bytes()) {
+                    Ok(name) => vary.push(name),
+                    // `Vary: *` means no other request is the same.
+                    Err(_) => return Ok((resp, false)),
+                }
+            }
+        }
+        let (parts, body) = resp.into_parts();
+        let body = hyper::body::to_bytes(body).await?;
+        let entry = Entry {
+            vary: vary
+                .into_iter()
+                .map(|name| {
+                    let value = pending.headers.get(&name).cloned();
+                    (name, value)
+                })
+                .collect(),
+            status: parts.status,
+            headers: parts.headers.clone(),
+            body: body.clone(),
+            stored: Instant::now(),
+            ttl,
+        };
+        self.insert(pending.key, entry);
+        Ok((Response::from_parts(parts, Body::from(body)), true))
+    }
+
+    fn insert(&self, key: String, entry: Entry) {
+        let mut inner = self.inner.lock().unwrap();
+        let inner = &mut *inner;
+        let entries = inner.entries.entry(key.clone()).or_default();
+        if entries.is_empty() {
+            inner.order.push_back(key);
+        }
+        let before = entries.len();
+        entries.retain(|e| e.vary != entry.vary);
+        entries.push(entry);
+        inner.len = inner.len + entries.len() - before;
+        while inner.len > MAX_ENTRIES {
+            let oldest = match inner.order.pop_front() {
+                Some(oldest) => oldest,
+                None => break,
+            };
+            if let Some(evicted) = inner.entries.remove(&oldest) {
+                inner.len -= evicted.len();
+            }
+        }
+    }
+
+    /// Drop every entry, returning how many there were.
+    pub fn purge(&self) -> usize {
+        let mut inner = self.inner.lock().unwrap();
+        let purged = inner.len;
+        *inner = Inner::default();
+        purged
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(headers: &[(&str, &str)]) -> Request<Body> {
+        let mut req = Request::get("/page").header("host", "example.com");
+        for (name, value) in headers {
+            req = req.header(*name, *value);
+        }
+        req.body(Body::empty()).unwrap()
+    }
+
+    fn response(headers: &[(&str, &str)]) -> Response<Body> {
+        let mut resp = Response::builder().header("content-length", "5");
+        for (name, value) in headers {
+            resp = resp.header(*name, *value);
+        }
+        resp.body(Body::from("hello")).unwrap()
+    }
+
+    async fn store(
+        cache: &Cache,
+        req: &Request<Body>,
+        resp: Response<Body>,
+        policy: &Policy<'_>,
+    ) -> bool {
+        let pending = Pending::new(req).unwrap();
+        let (resp, stored) = cache.store(pending, resp, policy).await.unwrap();
+        assert_eq!(
+            hyper::body::to_bytes(resp.into_body()).await.unwrap(),
+            "hello"
+        );
+        stored
+    }
+
+    #[tokio::test]
+    async fn test_honor() {
+        let cache = Cache::default();
+        let policy = Policy {
+            violations: &[],
+            ttl: Duration::ZERO,
+        };
+        let req = request(&[("accept-language", "en")]);
+        assert!(!store(&cache, &req, response(&[]), &policy).await);
+        assert!(
+            !store(
+                &cache,
+                &req,
+                response(&[("cache-control", "no-store, max-age=60")]),
+                &policy
+            )
+            .await
+        );
+        assert!(cache.lookup(&req, &policy).is_none());
+
+        let resp = response(&[("cache-control", "max-age=60"), ("vary", "Accept-Language")]);
+        assert!(store(&cache, &req, resp, &policy).await);
+        let hit = cache.lookup(&req, &policy).unwrap();
+        assert_eq!(hit.headers()["age"], "0");
+        assert_eq!(
+            hyper::body::to_bytes(hit.into_body()).await.unwrap(),
+            "hello"
+        );
+        assert!(cache
+            .lookup(&request(&[("accept-language", "de")]), &policy)
+            .is_none());
+        let reload = request(&[("accept-language", "en"), ("cache-control", "no-cache")]);
+        assert!(cache.lookup(&reload, &policy).is_none());
+
+        let authorized = request(&[("authorization", "Bearer x")]);
+        assert!(
+            !store(
+                &cache,
+                &authorized,
+                response(&[("cache-control", "max-age=60")]),
+                &policy
+            )
+            .await
+        );
+        assert_eq!(cache.purge(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_violations() {
+        let cache = Cache::default();
+        let policy = Policy {
+            violations: &[
+                Violation::NoStore,
+                Violation::Vary,
+                Violation::RequestDirectives,
+            ],
+            ttl: Duration::from_secs(60),
+        };
+        let req = request(&[("accept-language", "en")]);
+        let resp = response(&[("cache-control", "private, no-store"), ("vary", "*")]);
+        assert!(store(&cache, &req, resp, &policy).await);
+        let other = request(&[("accept-language", "de"), ("cache-control", "no-cache")]);
+        assert!(cache.lookup(&other, &policy).is_some());
+
+        let expired = Policy {
+            violations: &[Violation::Expiry],
+            ttl: Duration::ZERO,
+        };
+        let req = request(&[]);
+        assert!(
+            store(
+                &cache,
+                &req,
+                response(&[("cache-control", "max-age=0")]),
+                &expired
+            )
+            .await
+        );
+        assert!(cache.lookup(&req, &expired).is_some());
+        let honest = Policy {
+            violations: &[],
+            ttl: Duration::ZERO,
+        };
+        assert!(cache.lookup(&req, &honest).is_none());
+
+        assert!("stale".parse::<Violation>().is_err());
+    }
+}
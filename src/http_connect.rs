@@ -0,0 +1,376 @@
+//! `--mode http-connect`: an HTTP CONNECT proxy, tunnelling each client to the
+//! `HOST:PORT` it asks for rather than to `--upstream-addr`.
+//!
+//! The CONNECT request is read before the connection is recorded, so connections show
+//! up with the host they asked for as their upstream. Hosts matching an
+//! `--http-connect-deny` rule are refused with a 403, as are those matching no
+//! `--http-connect-allow` rule if there are any. Once the upstream is connected the
+//! client is sent a 200 and the tunnel is proxied like any TCP connection, toxics and
+//! all. Tunnels are counted by destination host at `/api/http-connect/hosts`; as
+//! clients choose the hosts, those past the first thousand or so are counted together
+//! as `other`.
+
+use std::error::Error;
+use std::io;
+use std::str::FromStr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Value};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::connection::Connection;
+use crate::protocol;
+use crate::state::State;
+use crate::{connect_upstream, Args};
+
+/// The longest CONNECT request head we read.
+const MAX_HEAD: usize = 8 * 1024;
+
+/// An `--http-connect-allow` or `--http-connect-deny` rule, `HOST[:PORT]`: a host name,
+/// `*.example.com` for any name under it, or `*` for every host, on any port unless
+/// one is given.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rule {
+    host: String,
+    port: Option<u16>,
+}
+
+impl FromStr for Rule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host, port) = split_authority(s).ok_or_else(|| format!("invalid rule: {}", s))?;
+        let port = port
+            .map(|port| {
+                port.parse()
+                    .map_err(|_| format!("invalid port in rule: {}", s))
+            })
+            .transpose()?;
+        if host.is_empty() {
+            return Err(format!("rule needs a host: {}", s));
+        }
+        Ok(Rule {
+            host: normalize(host),
+            port,
+        })
+    }
+}
+
+impl Rule {
+    fn matches(&self, host: &str, port: u16) -> bool {
+        if self.port.is_some_and(|p| p != port) {
+            return false;
+        }
+        match self.host.strip_prefix('*') {
+            Some("") => true,
+            Some(suffix) if suffix.starts_with('.') => host.ends_with(suffix),
+            _ => host == self.host,
+        }
+    }
+}
+
+fn normalize(host: &str) -> String {
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// Split `HOST[:PORT]`, with IPv6 addresses in brackets.
+fn split_authority(s: &str) -> Option<(&str, Option<&str>)> {
+    if let Some(rest) = s.strip_prefix('[') {
+        let (host, rest) = rest.split_once(']')?;
+        return match rest {
+            "" => Some((host, None)),
+            _ => Some((host, Some(rest.strip_prefix(':')?))),
+        };
+    }
+    match s.rsplit_once(':') {
+        Some((host, _)) if host.contains(':') => None,
+        Some((host, port)) => Some((host, Some(port))),
+        None => Some((s, None)),
+    }
+}
+
+/// Whether the rules let a client tunnel to `host` on `port`.
+pub fn permits(allow: &[Rule], deny: &[Rule], host: &str, port: u16) -> bool {
+    let host = normalize(host);
+    !deny.iter().any(|rule| rule.matches(&host, port))
+        && (allow.is_empty() || allow.iter().any(|rule| rule.matches(&host, port)))
+}
+
+/// Tunnels to one destination host.
+#[derive(Clone, Debug, Default)]
+pub struct HostStats {
+    pub active_tunnels: u64,
+    /// Closed tunnels, and the bytes they copied.
+    pub tunnels: u64,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    /// Requests refused by the allow and deny rules.
+    pub denied: u64,
+    /// Requests whose upstream couldn't be connected.
+    pub failed: u64,
+}
+
+impl HostStats {
+    pub fn to_json(&self, host: &str) -> Value {
+        json!({
+            "host": host,
+            "active_tunnels": self.active_tunnels,
+            "tunnels": self.tunnels,
+            "bytes_up": self.bytes_up,
+            "bytes_down": self.bytes_down,
+            "denied": self.denied,
+            "failed": self.failed,
+        })
+    }
+}
+
+/// Update `host`'s stats, or the shared `other` entry's once there are too many hosts.
+fn count(state: &State, host: &str, f: impl FnOnce(&mut HostStats)) {
+    state
+        .http_connect_hosts
+        .with_capped_entry(normalize(host), HostStats::default(), f);
+}
+
+/// Read the client's CONNECT request and return the `HOST:PORT` it asks for, answering
+/// it with an error status if it's malformed or not allowed.
+pub async fn accept(
+    downstream: &mut TcpStream,
+    args: &Args,
+    state: &State,
+) -> Result<String, Box<dyn Error>> {
+    let head = read_head(downstream, args.protocol_timeout).await?;
+    let head = match head {
+        Some(head) => head,
+        None => {
+            respond(downstream, "431 Request Header Fields Too Large").await;
+            return Err("CONNECT request head is too long".into());
+        }
+    };
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut request = httparse::Request::new(&mut headers);
+    let target = match request.parse(&head) {
+        Ok(httparse::Status::Complete(_)) if request.method == Some("CONNECT") => {
+            request.path.unwrap_or_default().to_string()
+        }
+        Ok(httparse::Status::Complete(_)) => {
+            respond(downstream, "405 Method Not Allowed").await;
+            return Err(format!(
+                "expected a CONNECT request, got {}",
+                request.method.unwrap_or_default()
+            )
+            .into());
+        }
+        _ => {
+            respond(downstream, "400 Bad Request").await;
+            return Err("malformed CONNECT request".into());
+        }
+    };
+    let (host, port) = match split_authority(&target) {
+        Some((host, Some(port))) if !host.is_empty() => match port.parse::<u16>() {
+            Ok(port) => (host, port),
+            Err(_) => (host, 0),
+        },
+        _ => ("", 0),
+    };
+    if port == 0 {
+        respond(downstream, "400 Bad Request").await;
+        return Err(format!("invalid CONNECT target: {}", target).into());
+    }
+    if !permits(
+        &args.http_connect_allow,
+        &args.http_connect_deny,
+        host,
+        port,
+    ) {
+        count(state, host, |stats| stats.denied += 1);
+        respond(downstream, "403 Forbidden").await;
+        return Err(format!("CONNECT to {} is not allowed", target).into());
+    }
+    Ok(target)
+}
+
+/// Consume the request head, through its blank line, or return `None` if it's longer
+/// than `MAX_HEAD`. Bytes the client sends after it are left to be tunnelled.
+async fn read_head(
+    downstream: &mut TcpStream,
+    timeout: Duration,
+) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+    let mut buf = vec![0; MAX_HEAD];
+    let len = protocol::peek_until(downstream, &mut buf, timeout, |bytes| {
+        if bytes.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "client closed before sending a request",
+            ));
+        }
+        if let Some(end) = bytes.windows(4).position(|w| w == b"\r\n\r\n") {
+            return Ok(Some(Some(end + 4)));
+        }
+        Ok((bytes.len() == MAX_HEAD).then_some(None))
+    })
+    .await?
+    .ok_or("timed out waiting for a CONNECT request")?;
+    Ok(match len {
+        Some(len) => {
+            buf.truncate(len);
+            downstream.read_exact(&mut buf).await?;
+            Some(buf)
+        }
+        None => None,
+    })
+}
+
+async fn respond(downstream: &mut TcpStream, status: &str) {
+    let response = format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status);
+    // The client is told what it can be; the connection is closing either way.
+    let _ = downstream.write_all(response.as_bytes()).await;
+}
+
+/// Connect the tunnel `accept` asked for, tell the client, then proxy it.
+pub async fn proxy(
+    mut downstream: TcpStream,
+    args: &Arc<Args>,
+    state: &Arc<State>,
+    conn: &Arc<Connection>,
+) -> Result<(), Box<dyn Error>> {
+    let host = split_authority(&args.upstream_addr)
+        .map_or(&args.upstream_addr[..], |(host, _)| host)
+        .to_string();
+    let connect_start = Instant::now();
+    let upstream = match connect_upstream(&args.upstream_addr, args, state).await {
+        Ok(upstream) => upstream,
+        Err(err) => {
+            count(state, &host, |stats| stats.failed += 1);
+            respond(&mut downstream, "502 Bad Gateway").await;
+            return Err(err.into());
+        }
+    };
+    state.observe_connect(conn, connect_start.elapsed());
+    downstream
+        .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+        .await?;
+
+    count(state, &host, |stats| stats.active_tunnels += 1);
+    let result = crate::proxy_connected(downstream, upstream, args, state, conn).await;
+    count(state, &host, |stats| {
+        stats.active_tunnels -= 1;
+        stats.tunnels += 1;
+        stats.bytes_up += conn.bytes_up.load(Ordering::Relaxed);
+        stats.bytes_down += conn.bytes_down.load(Ordering::Relaxed);
+    });
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::net::TcpListener;
+
+    use crate::Proxy;
+
+    #[test]
+    fn test_permits() {
+        let rules =
+            |rules: &[&str]| -> Vec<Rule> { rules.iter().map(|r| r.parse().unwrap()).collect() };
+        let allow = rules(&["*.example.com:443", "db.internal", "[::1]:8080"]);
+        let deny = rules(&["admin.example.com"]);
+        assert!(permits(&allow, &deny, "API.example.com.", 443));
+        assert!(!permits(&allow, &deny, "api.example.com", 80));
+        assert!(!permits(&allow, &deny, "example.com", 443));
+        assert!(!permits(&allow, &deny, "admin.example.com", 443));
+        assert!(permits(&allow, &deny, "db.internal", 5432));
+        assert!(permits(&allow, &deny, "::1", 8080));
+        assert!(!permits(&allow, &deny, "elsewhere", 443));
+        assert!(permits(&[], &deny, "elsewhere", 443));
+        assert!(!permits(&[], &rules(&["*"]), "elsewhere", 443));
+
+        assert!("".parse::<Rule>().is_err());
+        assert!("host:http".parse::<Rule>().is_err());
+        assert!("::1".parse::<Rule>().is_err());
+    }
+
+    #[test]
+    fn test_hosts_capped() {
+        let state = State::new();
+        let client = "127.0.0.1:1234".parse().unwrap();
+        for i in 0..crate::state::MAX_KEYS + 5 {
+            let host = format!("random{}.test", i);
+            count(&state, &host, |stats| stats.denied += 1);
+            state.open_connection(client, format!("{}:443", host));
+        }
+        let hosts = state.http_connect_hosts.snapshot();
+        assert_eq!(hosts.len(), crate::state::MAX_KEYS + 1);
+        assert_eq!(hosts["other"].denied, 5);
+        let upstreams = state.snapshot().upstream_connections_total;
+        assert_eq!(upstreams.len(), crate::state::MAX_KEYS + 1);
+        assert_eq!(upstreams["other"], 5);
+    }
+
+    async fn connect(proxy: std::net::SocketAddr, target: &str) -> (TcpStream, String) {
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        let request = format!(
+            "CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\nhello",
+            target, target
+        );
+        client.write_all(request.as_bytes()).await.unwrap();
+        let mut response = Vec::new();
+        while !response.ends_with(b"\r\n\r\n") {
+            let mut byte = [0];
+            if client.read(&mut byte).await.unwrap() == 0 {
+                break;
+            }
+            response.push(byte[0]);
+        }
+        (client, String::from_utf8(response).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_http_connect() {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = upstream.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = upstream.accept().await.unwrap();
+            let mut buf = [0; 5];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream.write_all(&buf.to_ascii_uppercase()).await.unwrap();
+        });
+        let handle = Proxy::from_flags([
+            "--listen-addr",
+            "127.0.0.1:0",
+            "--mode",
+            "http-connect",
+            "--http-connect-deny",
+            "forbidden.test",
+        ])
+        .unwrap()
+        .start()
+        .await
+        .unwrap();
+        let state = handle.state().clone();
+
+        // The bytes sent straight after the request are tunnelled too.
+        let (mut client, response) =
+            connect(handle.local_addr(), &format!("127.0.0.1:{}", port)).await;
+        assert!(response.starts_with("HTTP/1.1 200 "), "{}", response);
+        let mut buf = [0; 5];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"HELLO");
+        drop(client);
+
+        let (_, response) = connect(handle.local_addr(), "forbidden.test:443").await;
+        assert!(response.starts_with("HTTP/1.1 403 "), "{}", response);
+
+        handle.shutdown(Duration::from_secs(5)).await.unwrap();
+        let hosts = state.http_connect_hosts.snapshot();
+        let tunnelled = &hosts["127.0.0.1"];
+        assert_eq!(
+            (tunnelled.tunnels, tunnelled.bytes_up, tunnelled.bytes_down),
+            (1, 5, 5)
+        );
+        assert_eq!(hosts["forbidden.test"].denied, 1);
+    }
+}
@@ -0,0 +1,543 @@
+//! Kafka aware proxying (`--mode kafka`).
+//!
+//! Requests are read frame by frame so each can be counted by API key, and the
+//! connection is tagged with the client ID from the first request header. Bytes are
+//! otherwise forwarded untouched.
+//!
+//! Kafka clients bootstrap from one broker and then connect directly to the
+//! brokers listed in Metadata responses, which bypasses the proxy. With
+//! `--kafka-advertised-addr` the broker addresses in Metadata responses are
+//! rewritten to the proxy's own address so clients keep coming back through it.
+//! Since every broker is rewritten to the same address this only makes sense for a
+//! single broker upstream (or one tproxy per broker, each advertising itself).
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::io;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tracing::warn;
+
+use crate::connection::{self, Connection, Direction};
+use crate::state::State;
+use crate::{connect_upstream, register_sockets, track_close, track_open, Args};
+
+const API_METADATA: i16 = 3;
+
+/// Metadata became a flexible version, with compact strings and tagged fields, at v9.
+const METADATA_FIRST_FLEXIBLE: i16 = 9;
+
+/// Refuse frames larger than this rather than trying to buffer them.
+const MAX_FRAME_SIZE: usize = 256 * 1024 * 1024;
+
+/// The name of a Kafka API key, for logs and metrics.
+pub fn api_name(key: i16) -> &'static str {
+    match key {
+        0 => "Produce",
+        1 => "Fetch",
+        2 => "ListOffsets",
+        3 => "Metadata",
+        8 => "OffsetCommit",
+        9 => "OffsetFetch",
+        10 => "FindCoordinator",
+        11 => "JoinGroup",
+        12 => "Heartbeat",
+        13 => "LeaveGroup",
+        14 => "SyncGroup",
+        15 => "DescribeGroups",
+        16 => "ListGroups",
+        17 => "SaslHandshake",
+        18 => "ApiVersions",
+        19 => "CreateTopics",
+        20 => "DeleteTopics",
+        22 => "InitProducerId",
+        36 => "SaslAuthenticate",
+        _ => "Unknown",
+    }
+}
+
+/// The advertised address given to clients in place of the real brokers.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Advertised {
+    pub host: String,
+    pub port: i32,
+}
+
+impl std::str::FromStr for Advertised {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host, port) = s
+            .rsplit_once(':')
+            .ok_or_else(|| format!("expected HOST:PORT: {}", s))?;
+        let port = port
+            .parse::<u16>()
+            .map_err(|_| format!("invalid port: {}", s))?;
+        Ok(Advertised {
+            host: host.to_string(),
+            port: port.into(),
+        })
+    }
+}
+
+/// The fields at the start of every request header.
+#[derive(Debug, PartialEq)]
+struct RequestHeader {
+    api_key: i16,
+    api_version: i16,
+    correlation_id: i32,
+    client_id: Option<String>,
+}
+
+fn parse_request_header(frame: &[u8]) -> Option<RequestHeader> {
+    let api_key = i16::from_be_bytes(frame.get(0..2)?.try_into().ok()?);
+    let api_version = i16::from_be_bytes(frame.get(2..4)?.try_into().ok()?);
+    let correlation_id = i32::from_be_bytes(frame.get(4..8)?.try_into().ok()?);
+    // The client ID is a nullable (non-compact) string in every header version.
+    let len = i16::from_be_bytes(frame.get(8..10)?.try_into().ok()?);
+    let client_id = if len < 0 {
+        None
+    } else {
+        let id = frame.get(10..10 + len as usize)?;
+        Some(String::from_utf8_lossy(id).into_owned())
+    };
+    Some(RequestHeader {
+        api_key,
+        api_version,
+        correlation_id,
+        client_id,
+    })
+}
+
+/// A cursor over a response body which copies what it reads into `out`.
+struct Rewriter<'a> {
+    body: &'a [u8],
+    pos: usize,
+    out: Vec<u8>,
+}
+
+impl<'a> Rewriter<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        let bytes = self.body.get(self.pos..self.pos.checked_add(n)?)?;
+        self.pos += n;
+        Some(bytes)
+    }
+
+    fn copy(&mut self, n: usize) -> Option<()> {
+        let bytes = self.take(n)?;
+        self.out.extend_from_slice(bytes);
+        Some(())
+    }
+
+    fn uvarint(&mut self) -> Option<u64> {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let b = *self.take(1)?.first()?;
+            value |= ((b & 0x7f) as u64) << shift;
+            if b & 0x80 == 0 {
+                return Some(value);
+            }
+        }
+        None
+    }
+
+    fn copy_uvarint(&mut self) -> Option<u64> {
+        let start = self.pos;
+        let value = self.uvarint()?;
+        self.out.extend_from_slice(&self.body[start..self.pos]);
+        Some(value)
+    }
+
+    fn copy_tagged_fields(&mut self) -> Option<()> {
+        for _ in 0..self.copy_uvarint()? {
+            self.copy_uvarint()?;
+            let size = self.copy_uvarint()? as usize;
+            self.copy(size)?;
+        }
+        Some(())
+    }
+
+    /// Skip a string, compact or not, without copying it.
+    fn skip_string(&mut self, flexible: bool) -> Option<()> {
+        let len = if flexible {
+            (self.uvarint()? as usize).checked_sub(1)?
+        } else {
+            // Negative lengths mean null, which these strings can't be.
+            usize::try_from(i16::from_be_bytes(self.take(2)?.try_into().ok()?)).ok()?
+        };
+        self.take(len)?;
+        Some(())
+    }
+
+    /// Copy a nullable string, compact or not.
+    fn copy_nullable_string(&mut self, flexible: bool) -> Option<()> {
+        if flexible {
+            let len = self.copy_uvarint()? as usize;
+            self.copy(len.saturating_sub(1))
+        } else {
+            let len = i16::from_be_bytes(self.body.get(self.pos..self.pos + 2)?.try_into().ok()?);
+            self.copy(2)?;
+            self.copy(len.max(0) as usize)
+        }
+    }
+}
+
+fn put_uvarint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// Rewrite every broker in a Metadata response body (everything after the
+/// correlation ID) to the advertised address.
+fn rewrite_metadata(body: &[u8], version: i16, advertised: &Advertised) -> Option<Vec<u8>> {
+    let flexible = version >= METADATA_FIRST_FLEXIBLE;
+    let mut r = Rewriter {
+        body,
+        pos: 0,
+        out: Vec::with_capacity(body.len()),
+    };
+    if flexible {
+        // The response header's tagged fields.
+        r.copy_tagged_fields()?;
+    }
+    if version >= 3 {
+        // throttle_time_ms
+        r.copy(4)?;
+    }
+    let brokers = if flexible {
+        r.copy_uvarint()?.saturating_sub(1)
+    } else {
+        let n = i32::from_be_bytes(r.body.get(r.pos..r.pos + 4)?.try_into().ok()?);
+        r.copy(4)?;
+        n.max(0) as u64
+    };
+    for _ in 0..brokers {
+        // node_id
+        r.copy(4)?;
+        r.skip_string(flexible)?;
+        let host = advertised.host.as_bytes();
+        if flexible {
+            put_uvarint(&mut r.out, host.len() as u64 + 1);
+        } else {
+            r.out.extend_from_slice(&(host.len() as i16).to_be_bytes());
+        }
+        r.out.extend_from_slice(host);
+        r.take(4)?;
+        r.out.extend_from_slice(&advertised.port.to_be_bytes());
+        if version >= 1 {
+            // rack
+            r.copy_nullable_string(flexible)?;
+        }
+        if flexible {
+            r.copy_tagged_fields()?;
+        }
+    }
+    r.out.extend_from_slice(&body[r.pos..]);
+    Some(r.out)
+}
+
+/// Copy exactly `n` bytes, counting them.
+async fn copy_exact<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    n: u64,
+    conn: &Connection,
+    direction: Direction,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let copied = connection::copy(&mut reader.take(n), writer, conn, direction, |_| {}).await?;
+    if copied < n {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(())
+}
+
+/// Read a frame's size prefix, or `None` at a clean end of stream.
+async fn read_size<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Option<usize>> {
+    let mut size = [0; 4];
+    match reader.read_exact(&mut size).await {
+        Ok(_) => {}
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    }
+    let size = i32::from_be_bytes(size);
+    if size < 0 || size as usize > MAX_FRAME_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid kafka frame size {}", size),
+        ));
+    }
+    Ok(Some(size as usize))
+}
+
+pub async fn proxy(
+    mut downstream: TcpStream,
+    args: &Args,
+    state: &State,
+    conn: &Connection,
+) -> Result<(), Box<dyn Error>> {
+    let connect_start = Instant::now();
+    let mut upstream = connect_upstream(&args.upstream_addr, args, state).await?;
+    state.observe_connect(conn, connect_start.elapsed());
+    conn.connected();
+    track_open(state, conn);
+    let _sockets = register_sockets(state, conn, &downstream, &upstream);
+    let (mut ri, mut wi) = downstream.split();
+    let (mut ro, mut wo) = upstream.split();
+    // The version of each Metadata request still waiting for the response to rewrite.
+    // Nothing else is tracked, as some requests, like Produce with acks=0, never get one.
+    let pending: Mutex<HashMap<i32, i16>> = Mutex::default();
+
+    let client_to_server = async {
+        while let Some(size) = read_size(&mut ri).await? {
+            // The fixed fields plus a client ID, which is all we look at.
+            let mut head = vec![0; size.min(10 + i16::MAX as usize)];
+            ri.read_exact(&mut head).await?;
+            if let Some(header) = parse_request_header(&head) {
+                if let Some(client_id) = &header.client_id {
+                    if !conn.tags().contains_key("kafka.client_id") {
+                        conn.set_tags([("kafka.client_id".to_string(), Some(client_id.clone()))]);
+                    }
+                }
+                state
+                    .kafka_requests
+                    .with_entry(header.api_key, 0, |count| *count += 1);
+                if header.api_key == API_METADATA && args.kafka_advertised_addr.is_some() {
+                    pending
+                        .lock()
+                        .unwrap()
+                        .insert(header.correlation_id, header.api_version);
+                }
+            }
+            wo.write_all(&(size as i32).to_be_bytes()).await?;
+            wo.write_all(&head).await?;
+            conn.add_bytes(Direction::Up, 4 + head.len() as u64);
+            copy_exact(
+                &mut ri,
+                &mut wo,
+                (size - head.len()) as u64,
+                conn,
+                Direction::Up,
+            )
+            .await?;
+        }
+        wo.shutdown().await
+    };
+
+    let server_to_client = async {
+        while let Some(size) = read_size(&mut ro).await? {
+            let mut correlation_id = [0; 4];
+            if size < 4 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "short kafka frame",
+                ));
+            }
+            ro.read_exact(&mut correlation_id).await?;
+            let metadata = pending
+                .lock()
+                .unwrap()
+                .remove(&i32::from_be_bytes(correlation_id));
+            let rewrite = args.kafka_advertised_addr.as_ref().zip(metadata);
+            if let Some((advertised, version)) = rewrite {
+                let mut body = vec![0; size - 4];
+                ro.read_exact(&mut body).await?;
+                if let Some(rewritten) = rewrite_metadata(&body, version, advertised) {
+                    body = rewritten;
+                } else {
+                    warn!(id = conn.id, version, "failed to rewrite kafka metadata");
+                }
+                let mut frame = ((body.len() + 4) as i32).to_be_bytes().to_vec();
+                frame.extend_from_slice(&correlation_id);
+                frame.extend_from_slice(&body);
+                wi.write_all(&frame).await?;
+                conn.add_bytes(Direction::Down, frame.len() as u64);
+            } else {
+                wi.write_all(&(size as i32).to_be_bytes()).await?;
+                wi.write_all(&correlation_id).await?;
+                conn.add_bytes(Direction::Down, 8);
+                copy_exact(&mut ro, &mut wi, (size - 4) as u64, conn, Direction::Down).await?;
+            }
+        }
+        wi.shutdown().await
+    };
+
+    let sample = async {
+        let mut interval = tokio::time::interval(connection::SAMPLE_INTERVAL);
+        loop {
+            interval.tick().await;
+            conn.sample();
+        }
+    };
+
+    let result = tokio::select! {
+        result = async { tokio::try_join!(client_to_server, server_to_client) } => result,
+        _ = sample => unreachable!(),
+    };
+
+    track_close(state, conn, result.is_ok());
+    result?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use clap::Parser;
+    use futures::FutureExt;
+    use tokio::net::TcpListener;
+    use tokio::sync::oneshot;
+
+    fn request(api_key: i16, version: i16, correlation_id: i32, client_id: &str) -> Vec<u8> {
+        let mut body = api_key.to_be_bytes().to_vec();
+        body.extend_from_slice(&version.to_be_bytes());
+        body.extend_from_slice(&correlation_id.to_be_bytes());
+        body.extend_from_slice(&(client_id.len() as i16).to_be_bytes());
+        body.extend_from_slice(client_id.as_bytes());
+        body.extend_from_slice(b"request body");
+        framed(&body)
+    }
+
+    fn framed(body: &[u8]) -> Vec<u8> {
+        let mut out = (body.len() as i32).to_be_bytes().to_vec();
+        out.extend_from_slice(body);
+        out
+    }
+
+    /// A v1 Metadata response body listing one broker, followed by a topics array.
+    fn metadata_v1(host: &str, port: i32) -> Vec<u8> {
+        let mut body = 1i32.to_be_bytes().to_vec();
+        body.extend_from_slice(&7i32.to_be_bytes());
+        body.extend_from_slice(&(host.len() as i16).to_be_bytes());
+        body.extend_from_slice(host.as_bytes());
+        body.extend_from_slice(&port.to_be_bytes());
+        // A null rack.
+        body.extend_from_slice(&(-1i16).to_be_bytes());
+        body.extend_from_slice(b"rest");
+        body
+    }
+
+    /// A v9 Metadata response body listing one broker, followed by a topics array.
+    fn metadata_v9(host: &str, port: i32) -> Vec<u8> {
+        // No header tagged fields, then throttle_time_ms and one broker.
+        let mut body = vec![0, 0, 0, 0, 0, 2];
+        body.extend_from_slice(&7i32.to_be_bytes());
+        body.push(host.len() as u8 + 1);
+        body.extend_from_slice(host.as_bytes());
+        body.extend_from_slice(&port.to_be_bytes());
+        // rack "r1", then no broker tagged fields.
+        body.extend_from_slice(&[3, b'r', b'1', 0]);
+        body.extend_from_slice(b"rest");
+        body
+    }
+
+    #[test]
+    fn test_parse_request_header() {
+        let frame = request(18, 3, 42, "my-app");
+        assert_eq!(
+            parse_request_header(&frame[4..]),
+            Some(RequestHeader {
+                api_key: 18,
+                api_version: 3,
+                correlation_id: 42,
+                client_id: Some("my-app".to_string()),
+            })
+        );
+        assert_eq!(parse_request_header(&[0, 3]), None);
+    }
+
+    #[test]
+    fn test_rewrite_metadata() {
+        let advertised: Advertised = "proxy.local:9999".parse().unwrap();
+        assert_eq!(
+            rewrite_metadata(&metadata_v1("broker-0.internal", 9092), 1, &advertised),
+            Some(metadata_v1("proxy.local", 9999))
+        );
+        assert_eq!(
+            rewrite_metadata(&metadata_v9("broker-0.internal", 9092), 9, &advertised),
+            Some(metadata_v9("proxy.local", 9999))
+        );
+        assert_eq!(rewrite_metadata(&[0, 0], 1, &advertised), None);
+        // One broker whose host is null, or impossibly long.
+        let null_host = [0, 0, 0, 1, 0, 0, 0, 0, 0xff, 0xff, 0, 0, 0, 0];
+        assert_eq!(rewrite_metadata(&null_host, 1, &advertised), None);
+        let mut long_host = vec![0, 0, 0, 0, 0, 2, 0, 0, 0, 0];
+        long_host.extend_from_slice(&[0xff; 9]);
+        long_host.push(0x01);
+        assert_eq!(rewrite_metadata(&long_host, 9, &advertised), None);
+    }
+
+    #[tokio::test]
+    async fn test_kafka_proxy() {
+        // A broker that answers every request with a Metadata v1 response.
+        let broker = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let broker_addr = broker.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = broker.accept().await.unwrap();
+            while let Ok(Some(size)) = read_size(&mut stream).await {
+                let mut req = vec![0; size];
+                stream.read_exact(&mut req).await.unwrap();
+                let mut body = req[4..8].to_vec();
+                body.extend(metadata_v1("broker-0.internal", 9092));
+                stream.write_all(&framed(&body)).await.unwrap();
+            }
+        });
+
+        let args = Args::parse_from([
+            "tproxy",
+            "--listen-addr",
+            "127.0.0.1:0",
+            "--upstream-addr",
+            &broker_addr.to_string(),
+            "--mode",
+            "kafka",
+            "--kafka-advertised-addr",
+            "localhost:19092",
+        ]);
+        let state = Arc::new(State::new());
+        let (tx, rx) = oneshot::channel();
+        tokio::spawn(crate::listen(args, state.clone(), tx).map(|r| {
+            if let Err(err) = r {
+                println!("failed to listen; error={}", err);
+            }
+        }));
+        let addr = rx.await.unwrap();
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        for (api_key, correlation_id) in [(18, 1), (3, 2)] {
+            client
+                .write_all(&request(api_key, 1, correlation_id, "my-app"))
+                .await
+                .unwrap();
+            let size = read_size(&mut client).await.unwrap().unwrap();
+            let mut resp = vec![0; size];
+            client.read_exact(&mut resp).await.unwrap();
+            assert_eq!(&resp[..4], &correlation_id.to_be_bytes());
+            // Only the Metadata response is rewritten.
+            let host = if api_key == 3 {
+                metadata_v1("localhost", 19092)
+            } else {
+                metadata_v1("broker-0.internal", 9092)
+            };
+            assert_eq!(&resp[4..], &host[..]);
+        }
+
+        let conn = &state.connections()[0];
+        assert_eq!(conn.tags()["kafka.client_id"], "my-app");
+        assert_eq!(state.kafka_requests.get(&3), Some(1));
+        assert_eq!(state.kafka_requests.get(&18), Some(1));
+    }
+}
@@ -0,0 +1,3142 @@
+//! A TCP proxy for testing how clients and servers cope with the network going wrong.
+//!
+//! The `tproxy` binary is [`run_cli`] on its command line. To run the proxy from a
+//! test instead, build a [`Proxy`], start it and connect to [`Handle::local_addr`]:
+//!
+//! ```no_run
+//! # async fn example() -> Result<(), Box<dyn std::error::Error>> {
+//! let proxy = tproxy::Proxy::new("127.0.0.1:0", "127.0.0.1:6379");
+//! let mut events = proxy.events().subscribe();
+//! let handle = proxy.start().await?;
+//! let client = tokio::net::TcpStream::connect(handle.local_addr()).await?;
+//! let accepted = events.recv().await?;
+//! handle.shutdown(std::time::Duration::from_secs(1)).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+// serde_json's json! macro recurses once per key, and /api/stats has many.
+#![recursion_limit = "256"]
+
+use std::error::Error;
+use std::io;
+use std::net::SocketAddr;
+use std::os::unix::io::AsRawFd;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use clap::Parser;
+use socket2::{Domain, Socket, Type};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{oneshot, watch};
+use tracing::level_filters::LevelFilter;
+use tracing::{info, warn, Instrument};
+use warp::Filter;
+
+pub use proxy::{Handle, Proxy};
+
+mod api;
+mod balance;
+mod bandwidth;
+mod breaker;
+mod breakpoint;
+mod capture;
+mod compare;
+mod config;
+pub mod connection;
+mod digest;
+mod dns;
+pub mod events;
+mod fd;
+mod fingerprint;
+mod fuzz;
+mod geoip;
+pub mod health;
+mod heatmap;
+pub mod hello;
+pub mod hook;
+mod http;
+mod http2;
+mod http_cache;
+mod http_connect;
+mod kafka;
+mod logging;
+mod mail;
+mod memory;
+mod metrics;
+mod mirror;
+mod network;
+mod pace;
+mod pattern;
+mod probe;
+mod process;
+mod protocol;
+mod proxy;
+mod proxy_protocol;
+mod qos;
+mod quota;
+mod reload;
+mod replay;
+pub mod resolver;
+mod retry;
+mod rewrite;
+mod route;
+#[cfg(feature = "runtime-metrics")]
+mod runtime_metrics;
+mod s3;
+mod schedule;
+mod shutdown;
+mod sockopt;
+mod ssh;
+pub mod state;
+mod toxic;
+mod tunnel;
+mod udp;
+mod websocket;
+mod zone;
+
+use connection::{Connection, Direction, COPY_BUFFER_SIZE};
+use metrics::Buckets;
+use protocol::Protocol;
+use state::State;
+
+/// How the proxy treats the bytes it forwards.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Mode {
+    /// Copy bytes verbatim.
+    Tcp,
+    /// Parse HTTP/1 requests, which enables request-level features such as `--http-fault`.
+    Http,
+    /// Accept HTTP CONNECT requests, tunnelling each to the host it asks for.
+    HttpConnect,
+    /// Forward UDP datagrams, with a session per client address.
+    Udp,
+    /// Forward QUIC over UDP, with sessions that follow QUIC connection IDs.
+    Quic,
+    /// Forward DNS queries over UDP and TCP, enabling `--dns-fault` and `--dns-ttl`.
+    Dns,
+    /// Follow Kafka requests to count them by API key and tag connections by client ID.
+    Kafka,
+    /// Follow SMTP's STARTTLS negotiation, see `--starttls`.
+    Smtp,
+    /// Follow IMAP's STARTTLS negotiation, see `--starttls`.
+    Imap,
+}
+
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tcp" => Ok(Mode::Tcp),
+            "http" => Ok(Mode::Http),
+            "http-connect" => Ok(Mode::HttpConnect),
+            "udp" => Ok(Mode::Udp),
+            "quic" => Ok(Mode::Quic),
+            "dns" => Ok(Mode::Dns),
+            "kafka" => Ok(Mode::Kafka),
+            "smtp" => Ok(Mode::Smtp),
+            "imap" => Ok(Mode::Imap),
+            _ => Err(format!("unknown mode: {}", s)),
+        }
+    }
+}
+
+/// What a listener does with new connections once it has --max-connections open.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Overflow {
+    /// Stop accepting, leaving clients queued in the kernel's backlog.
+    Queue,
+    /// Accept and close them straight away.
+    Reject,
+}
+
+impl FromStr for Overflow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "queue" => Ok(Overflow::Queue),
+            "reject" => Ok(Overflow::Reject),
+            _ => Err(format!("unknown overflow policy: {}", s)),
+        }
+    }
+}
+
+/// A simple TCP proxy
+#[derive(Parser, Clone, Debug)]
+#[clap(author, version, about, long_about = None)]
+#[clap(setting = clap::AppSettings::AllArgsOverrideSelf)]
+struct Args {
+    /// Address to listen on (required unless --route is given)
+    #[clap(short, long, default_value = "", hide_default_value = true)]
+    listen_addr: String,
+
+    /// Address to forward to, or a comma-separated list of addresses to balance new
+    /// connections across by --lb-strategy, each optionally ADDR=WEIGHT (required
+    /// unless --route is given)
+    #[clap(short, long, default_value = "", hide_default_value = true)]
+    upstream_addr: String,
+
+    /// How to pick from a list of upstream addresses: round-robin, least-conn (the
+    /// fewest connections open now), random or weighted (round-robin in proportion to
+    /// each ADDR=WEIGHT, 1 if not given)
+    #[clap(long, default_value = "round-robin")]
+    lb_strategy: balance::Strategy,
+
+    /// Look an upstream host up again once its last answer is this old, on every connect
+    /// if 0
+    #[clap(long, default_value = "0s", parse(try_from_str = parse_duration))]
+    upstream_dns_ttl: Duration,
+
+    /// Take turns connecting to each of the addresses an upstream host resolves to,
+    /// rather than always to the first that accepts
+    #[clap(long)]
+    upstream_dns_balance: bool,
+
+    /// Also listen on LISTEN_ADDR and forward to UPSTREAM_ADDR, as
+    /// NAME=LISTEN_ADDR->UPSTREAM_ADDR, with stats kept per NAME (repeatable). Every
+    /// other flag applies to each route
+    #[clap(long)]
+    route: Vec<route::Route>,
+
+    /// Give new connections on the --route NAME these toxics, after --toxics, as
+    /// NAME=TOXICS with TOXICS as for --toxics (repeatable, applied in order)
+    #[clap(long)]
+    route_toxics: Vec<route::RouteToxics>,
+
+    /// Balance across these upstreams instead of forwarding to --upstream-addr, as
+    /// ZONE=ADDR, preferring those in --zone while any there is healthy (repeatable)
+    #[clap(long)]
+    zone_upstream: Vec<zone::ZonedUpstream>,
+
+    /// The zone this proxy runs in, for --zone-upstream
+    #[clap(long)]
+    zone: Option<String>,
+
+    /// The name of the --route this listener serves
+    #[clap(skip)]
+    route_name: Option<String>,
+
+    /// Address to serve the debug UI, API and metrics on. If it's in use, binding is
+    /// retried for a few seconds before proxying carries on without them
+    #[clap(short, long, default_value = "127.0.0.1:2222")]
+    debug_addr: String,
+
+    /// Don't serve the debug UI, API or metrics at all
+    #[clap(long)]
+    no_admin: bool,
+
+    /// How to proxy connections: tcp, http to work at the level of HTTP/1 requests,
+    /// http-connect to be an HTTP CONNECT proxy, udp, quic to route datagrams by QUIC
+    /// connection ID, dns, kafka, smtp or imap
+    #[clap(long, default_value = "tcp")]
+    mode: Mode,
+
+    /// In udp and quic modes, end a client's session after this long without traffic
+    #[clap(long, default_value = "60s", parse(try_from_str = parse_duration))]
+    udp_session_timeout: Duration,
+
+    /// In udp and quic modes, drop, duplicate or reorder a fraction of datagrams:
+    /// KIND:PROBABILITY[:DIRECTION] with KIND one of drop, duplicate or reorder, and
+    /// DIRECTION up or down, both if not given (repeatable)
+    #[clap(long)]
+    udp_fault: Vec<udp::Fault>,
+
+    /// Close connections which have copied no bytes either way for this long (e.g. 5m,
+    /// 0 to disable)
+    #[clap(long, default_value = "0s", parse(try_from_str = parse_duration))]
+    idle_timeout: Duration,
+
+    /// Close connections once they've been open this long, however busy they are (e.g.
+    /// 10m, 0 to disable)
+    #[clap(long, default_value = "0s", parse(try_from_str = parse_duration))]
+    max_conn_duration: Duration,
+
+    /// How long to wait before accepting again after an accept error (e.g. 100ms, 0 to disable)
+    #[clap(long, default_value = "100ms", parse(try_from_str = parse_duration))]
+    accept_backoff: Duration,
+
+    /// Hold each accepted connection this long before serving it, reading nothing from
+    /// the client and not dialing the upstream, like an overloaded load balancer
+    #[clap(long, default_value = "0s", parse(try_from_str = parse_duration))]
+    accept_delay: Duration,
+
+    /// Try to raise the soft open-file limit to this many descriptors at startup
+    #[clap(long)]
+    nofile_target: Option<u64>,
+
+    /// Warn once this fraction of the open-file limit is in use
+    #[clap(long, default_value = "0.9")]
+    fd_warn_ratio: f64,
+
+    /// Close new connections immediately while file descriptors are near exhaustion
+    #[clap(long)]
+    fd_shed: bool,
+
+    /// Serve at most this many connections, or UDP sessions, at once on each listener (0
+    /// for unlimited). Datagrams which would start another session are dropped
+    #[clap(long, default_value = "0")]
+    max_connections: usize,
+
+    /// What to do with connections past --max-connections: queue (stop accepting, so
+    /// they wait in the backlog) or reject (accept and close them)
+    #[clap(long, default_value = "queue")]
+    overflow_policy: Overflow,
+
+    /// Give up on an upstream connect attempt after this long (0 to wait as long as the
+    /// OS does)
+    #[clap(long, default_value = "3s", parse(try_from_str = parse_duration))]
+    connect_timeout: Duration,
+
+    /// How many times to retry an upstream connect which fails or times out, with
+    /// exponential backoff from 100ms
+    #[clap(long, default_value = "0")]
+    connect_retries: u32,
+
+    /// Eject an upstream after this many connects to it fail in a row, failing new
+    /// connections to it fast and balancing around it for --eject-cooldown (0 never to)
+    #[clap(long, default_value = "0")]
+    eject_after: u32,
+
+    /// How long an upstream stays ejected by --eject-after
+    #[clap(long, default_value = "30s", parse(try_from_str = parse_duration))]
+    eject_cooldown: Duration,
+
+    /// How many times to retry an upstream connect that fails with EADDRNOTAVAIL
+    #[clap(long, default_value = "3")]
+    addr_not_avail_retries: u32,
+
+    /// Allow upstream connect retries up to this share of dials over the last 10s, so a
+    /// struggling upstream doesn't face a retry storm (a few are always allowed)
+    #[clap(long, default_value = "0.2", parse(try_from_str = parse_probability))]
+    retry_budget: f64,
+
+    /// Maximum concurrent connections to the upstream (0 for unlimited)
+    #[clap(long, default_value = "0")]
+    max_upstream_connections: usize,
+
+    /// Start at most this many upstream connects per second, however fast clients
+    /// arrive, queueing the rest (0 for unlimited, otherwise at least 0.001)
+    #[clap(long, default_value = "0", parse(try_from_str = parse_connect_rate))]
+    upstream_connect_rate: f64,
+
+    /// Connect to IPv6 upstreams with this flow label on every packet, for ECMP hashing
+    /// experiments (1 to 1048575, 0 to let the kernel choose)
+    #[clap(long, default_value = "0", parse(try_from_str = parse_flow_label))]
+    upstream_flow_label: u32,
+
+    /// Measure the TCP connect time to each upstream this often, independently of
+    /// proxied connections, and export it as tproxy_upstream_probe_rtt_seconds (0 to
+    /// disable)
+    #[clap(long, default_value = "0s", parse(try_from_str = parse_duration))]
+    probe_interval: Duration,
+
+    /// How long to wait for a probe to connect and pass --probe-check before counting
+    /// it as failed
+    #[clap(long, default_value = "1s", parse(try_from_str = parse_duration))]
+    probe_timeout: Duration,
+
+    /// What a probe checks once connected: tcp, tls (a ServerHello comes back),
+    /// http[:/PATH] (GET answered with 2xx or 3xx) or redis (PING answered with PONG)
+    #[clap(long, default_value = "tcp")]
+    probe_check: health::Check,
+
+    /// Checks upstream health for probes in place of --probe-check. Set by embedders
+    #[clap(skip)]
+    health_checker: Option<health::Checker>,
+
+    /// Called as connections are accepted, connected, forward bytes and close. Set by
+    /// embedders
+    #[clap(skip)]
+    connection_hooks: Vec<hook::Hook>,
+
+    /// In tcp mode, close connections which stay half-closed (one direction finished,
+    /// the other still open) for this long (0 to wait indefinitely)
+    #[clap(long, default_value = "0s", parse(try_from_str = parse_duration))]
+    half_close_timeout: Duration,
+
+    /// Maximum concurrent connections from each client IP (0 for unlimited)
+    #[clap(long, default_value = "0")]
+    client_max_connections: usize,
+
+    /// Maximum bytes in both directions each client IP may move per hour, checked as
+    /// its connections are accepted (0 for unlimited)
+    #[clap(long, default_value = "0")]
+    client_max_bytes_per_hour: u64,
+
+    /// Size of the listen backlog; raise it for connection-storm tests
+    #[clap(long, default_value = "1024")]
+    backlog: i32,
+
+    /// Close connections whose first bytes don't look like this protocol (tls, http, ssh)
+    #[clap(long)]
+    expect_protocol: Option<Protocol>,
+
+    /// How long to wait for a client's first bytes when --expect-protocol,
+    /// --accept-proxy-protocol, --drop-probability or --reset-probability is set
+    #[clap(long, default_value = "5s", parse(try_from_str = parse_duration))]
+    protocol_timeout: Duration,
+
+    /// Expect each connection to start with a PROXY protocol header (v1 or v2) and
+    /// treat its source as the client, closing connections without one
+    #[clap(long)]
+    accept_proxy_protocol: bool,
+
+    /// In tcp mode, send the upstream a PROXY protocol header (v1 or v2) with the
+    /// client's address
+    #[clap(long)]
+    send_proxy_protocol: Option<proxy_protocol::Version>,
+
+    /// Comma-separated upper bounds, in seconds, of the latency histogram buckets
+    #[clap(long, default_value = metrics::DEFAULT_LATENCY_BUCKETS)]
+    latency_buckets: Buckets,
+
+    /// Comma-separated upper bounds, in bytes, of the connection size histogram buckets
+    #[clap(long, default_value = metrics::DEFAULT_SIZE_BUCKETS)]
+    size_buckets: Buckets,
+
+    /// Tag key to break connection and byte totals down by in metrics (repeatable)
+    #[clap(long, parse(try_from_str = metrics::parse_label_name))]
+    metrics_tag_key: Vec<String>,
+
+    /// Tag connections with client_network=NAME when the client is in this subnet, as
+    /// NAME=ADDR/PREFIX (repeatable, first match wins, others are tagged "other"). The
+    /// per-connection metrics carry it as a label, next to the listener's route name
+    #[clap(long)]
+    client_network: Vec<network::Network>,
+
+    /// In http mode, answer a fraction of requests with a synthetic response instead of
+    /// forwarding them, as STATUS:PROBABILITY[:DELAY] (e.g. 503:0.1:200ms; repeatable)
+    #[clap(long)]
+    http_fault: Vec<http::Fault>,
+
+    /// In http mode, rewrite request headers: add:NAME:VALUE, remove:NAME or
+    /// replace:NAME:/PATTERN/REPLACEMENT/ (repeatable, applied in order)
+    #[clap(long)]
+    request_header_rule: Vec<http::HeaderRule>,
+
+    /// In http mode, rewrite response headers, using the same rules as --request-header-rule
+    #[clap(long)]
+    response_header_rule: Vec<http::HeaderRule>,
+
+    /// In http mode, cache GET responses in memory, following Cache-Control as a shared
+    /// cache would
+    #[clap(long)]
+    http_cache: bool,
+
+    /// Break a caching rule as a misbehaving CDN would: no-store (keep private and
+    /// uncacheable responses), expiry (serve expired entries), vary (ignore Vary) or
+    /// request-directives (ignore clients' no-cache) (repeatable)
+    #[clap(long)]
+    http_cache_violate: Vec<http_cache::Violation>,
+
+    /// How long to cache responses which don't set max-age (0 to not cache them)
+    #[clap(long, default_value = "0", parse(try_from_str = parse_duration))]
+    http_cache_ttl: Duration,
+
+    /// In http mode, also send requests to this address and discard its responses, to
+    /// try a new upstream on real traffic
+    #[clap(long)]
+    http_mirror: Option<String>,
+
+    /// The percentage of matching requests to mirror
+    #[clap(long, default_value = "100", parse(try_from_str = parse_percent))]
+    http_mirror_percent: f64,
+
+    /// Only mirror requests with this method (repeatable; default all)
+    #[clap(long)]
+    http_mirror_method: Vec<hyper::Method>,
+
+    /// Only mirror requests whose path starts with this prefix (repeatable; default all)
+    #[clap(long)]
+    http_mirror_path: Vec<String>,
+
+    /// The most mirrored requests awaiting a response at once; past it, requests are
+    /// forwarded without a copy
+    #[clap(long, default_value = "100")]
+    http_mirror_max_in_flight: usize,
+
+    /// In http-connect mode, only tunnel to hosts matching one of these HOST[:PORT]
+    /// rules, where HOST may be *.DOMAIN or * (repeatable; default any)
+    #[clap(long)]
+    http_connect_allow: Vec<http_connect::Rule>,
+
+    /// In http-connect mode, refuse to tunnel to hosts matching this HOST[:PORT] rule,
+    /// even if allowed (repeatable)
+    #[clap(long)]
+    http_connect_deny: Vec<http_connect::Rule>,
+
+    /// In dns mode, answer or delay a fraction of queries: ACTION:PROBABILITY[:DELAY] with
+    /// ACTION one of nxdomain, servfail, refused, drop or delay (repeatable)
+    #[clap(long)]
+    dns_fault: Vec<dns::Fault>,
+
+    /// In dns mode, rewrite the TTL of every record in responses to this many seconds
+    #[clap(long)]
+    dns_ttl: Option<u32>,
+
+    /// In kafka mode, rewrite the brokers in Metadata responses to this HOST:PORT, so
+    /// clients connect back through the proxy. Only suitable for a single broker upstream
+    #[clap(long)]
+    kafka_advertised_addr: Option<kafka::Advertised>,
+
+    /// In smtp and imap modes, pass STARTTLS through, or strip it from the server's
+    /// capabilities and refuse it
+    #[clap(long, default_value = "pass")]
+    starttls: mail::StartTls,
+
+    /// In tcp mode, rewrite bytes in transit: DIRECTION:/PATTERN/REPLACEMENT/ with
+    /// DIRECTION up, down or both (repeatable; matches are found up to 4KiB long)
+    #[clap(long)]
+    replace: Vec<rewrite::Rule>,
+
+    /// In tcp mode, start a mutation of the bytes in transit at each byte with this
+    /// probability, to fuzz the upstream (or client) with malformed input
+    #[clap(long, default_value = "0", parse(try_from_str = parse_probability))]
+    fuzz_rate: f64,
+
+    /// How --fuzz-rate mutates: bitflip, truncate, duplicate or http, to mangle HTTP
+    /// field boundaries (repeatable; all of them if not given)
+    #[clap(long)]
+    fuzz_strategy: Vec<fuzz::Strategy>,
+
+    /// Only fuzz bytes going this way, up or down (both if not given)
+    #[clap(long)]
+    fuzz_direction: Option<Direction>,
+
+    /// Seed --fuzz-rate mutations with this rather than at random, to reproduce a
+    /// connection's from its fuzz.seed tag
+    #[clap(long)]
+    fuzz_seed: Option<u64>,
+
+    /// Toxics new connections start with: a preset (dsl, cable, lte, 3g or satellite)
+    /// or DIRECTION:KEY=VALUE,... with DIRECTION up, down or both and keys latency,
+    /// jitter and rate (e.g. down:latency=20ms,rate=50Mbps; repeatable, applied in order)
+    #[clap(long)]
+    toxics: Vec<toxic::Setting>,
+
+    /// Delay each chunk forwarded in either direction by this long; shorthand for
+    /// --toxics both:latency=DELAY, which per-direction --toxics override
+    #[clap(long, parse(try_from_str = parse_duration))]
+    delay: Option<Duration>,
+
+    /// Vary --delay by up to this much either way
+    #[clap(long, parse(try_from_str = parse_duration))]
+    delay_jitter: Option<Duration>,
+
+    /// Limit each connection to this rate in either direction (e.g. 1MBps or 10Mbps);
+    /// shorthand for --toxics both:rate=RATE
+    #[clap(long, parse(try_from_str = toxic::parse_rate))]
+    rate_limit: Option<u64>,
+
+    /// Limit each connection's client to server bytes, overriding --rate-limit
+    #[clap(long, parse(try_from_str = toxic::parse_rate))]
+    rate_limit_up: Option<u64>,
+
+    /// Limit each connection's server to client bytes, overriding --rate-limit
+    #[clap(long, parse(try_from_str = toxic::parse_rate))]
+    rate_limit_down: Option<u64>,
+
+    /// Limit all connections together to this rate in each direction, sharing it out by
+    /// --qos-class
+    #[clap(long, parse(try_from_str = toxic::parse_rate))]
+    bandwidth_cap: Option<u64>,
+
+    /// Under --bandwidth-cap, give connections matching any of these conditions priority
+    /// over later classes and unmatched connections, as NAME=MATCH[,MATCH...] with each
+    /// MATCH cidr:ADDR/PREFIX (the client), port:PORT (the port it connected to) or
+    /// tag:KEY=VALUE (repeatable, highest priority first)
+    #[clap(long)]
+    qos_class: Vec<qos::Class>,
+
+    /// Toxics for every upstream connect, as KEY=VALUE,... with keys delay (before
+    /// dialing), refuse and timeout (chances from 0 to 1 of failing with ECONNREFUSED
+    /// or a timeout) and timeout_after (how long a timeout hangs, 10s by default)
+    #[clap(long)]
+    connect_toxic: Option<toxic::ConnectToxic>,
+
+    /// Chance from 0 to 1 that an accepted connection is closed, with a FIN, once the
+    /// client sends something, without the upstream being dialed
+    #[clap(long, default_value = "0", parse(try_from_str = parse_probability))]
+    drop_probability: f64,
+
+    /// Like --drop-probability, but resetting the connection (SO_LINGER 0) instead
+    #[clap(long, default_value = "0", parse(try_from_str = parse_probability))]
+    reset_probability: f64,
+
+    /// MaxMind database to look clients up in, tagging connections with geo.country and
+    /// geo.asn (e.g. GeoLite2-Country.mmdb and GeoLite2-ASN.mmdb; repeatable)
+    #[clap(long)]
+    geoip_db: Vec<String>,
+
+    /// Only accept clients in this country or AS<NUMBER> (e.g. DE or AS13335;
+    /// repeatable, needs --geoip-db)
+    #[clap(long)]
+    geo_allow: Vec<String>,
+
+    /// Refuse clients in this country or AS<NUMBER> (repeatable, needs --geoip-db)
+    #[clap(long)]
+    geo_deny: Vec<String>,
+
+    /// Give clients in a country or AS<NUMBER> these toxics, as PLACE=TOXICS with TOXICS
+    /// as for --toxics (e.g. AU=satellite; repeatable, needs --geoip-db)
+    #[clap(long)]
+    geo_toxics: Vec<geoip::GeoToxics>,
+
+    /// Databases opened from --geoip-db
+    #[clap(skip)]
+    geoip: Option<Arc<geoip::GeoIp>>,
+
+    /// Tag connections from clients on this host with the process which opened them
+    /// (process.pid, process.name and process.cgroup; Linux only)
+    #[clap(long)]
+    client_process: bool,
+
+    /// In tcp mode, write the bytes of every connection to this file as newline-delimited
+    /// JSON, to be replayed with `tproxy replay`
+    #[clap(long)]
+    capture: Option<String>,
+
+    /// Roll the capture over to CAPTURE.UNIX_MILLIS once it reaches this many bytes (0
+    /// to never roll over)
+    #[clap(long, default_value = "0")]
+    capture_rotate_bytes: u64,
+
+    /// Upload rolled-over captures to s3://BUCKET[/PREFIX], deleting them locally,
+    /// with credentials from AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY
+    #[clap(long)]
+    capture_upload: Option<String>,
+
+    /// The S3-compatible endpoint to upload to, as http://HOST[:PORT], addressed
+    /// path-style. Requests and captures go over plaintext HTTP, so this is meant for a
+    /// local MinIO-style store (required with --capture-upload)
+    #[clap(long)]
+    capture_upload_endpoint: Option<String>,
+
+    /// The region --capture-upload requests are signed for
+    #[clap(long, default_value = "us-east-1")]
+    capture_upload_region: String,
+
+    /// Delete uploaded captures rolled over longer ago than this (0 to keep them)
+    #[clap(long, default_value = "0s", parse(try_from_str = parse_duration))]
+    capture_upload_retention: Duration,
+
+    /// The file opened from --capture
+    #[clap(skip)]
+    capture_writer: Option<Arc<capture::Writer>>,
+
+    /// Only accept connections on a schedule, closing the listener outside it:
+    /// daily:HH:MM-HH:MM (UTC), flap:OPEN/CLOSED (e.g. flap:30s/10s), after:DURATION or
+    /// for:DURATION since startup (repeatable)
+    #[clap(long)]
+    schedule: Vec<schedule::Rule>,
+
+    /// Route TLS connections by the server name in their ClientHello, as
+    /// SERVER_NAME=UPSTREAM (repeatable); other connections go to --upstream-addr
+    #[clap(long)]
+    sni_route: Vec<hello::SniRoute>,
+
+    /// Tag TLS connections with the JA3 and JA4 fingerprints of their ClientHello, and
+    /// count connections by fingerprint on /metrics
+    #[clap(long)]
+    tls_fingerprint: bool,
+
+    /// Called with each new connection's first bytes to choose its upstream and tags.
+    /// Set by embedders, or from --sni-route
+    #[clap(skip)]
+    client_hello: Option<hello::Callback>,
+
+    /// Accept connections redirected here by an iptables REDIRECT rule, forwarding each
+    /// to where it was originally addressed (or --upstream-addr if it wasn't
+    /// redirected), and list them at /api/nat
+    #[clap(long)]
+    transparent: bool,
+
+    /// In tcp mode, multiplex connections over one persistent connection to
+    /// --upstream-addr, a tproxy run with --accept-tunnel, for when only a single port
+    /// crosses a firewall (--replace and --capture don't apply to tunneled connections)
+    #[clap(long)]
+    tunnel: bool,
+
+    /// Treat accepted connections as --tunnel connections from another tproxy, proxying
+    /// each stream in them to --upstream-addr
+    #[clap(long)]
+    accept_tunnel: bool,
+
+    /// Read flags from this file: KEY = VALUE lines of long flag names, with
+    /// [profile.NAME] tables overriding them. Flags given here override the file.
+    /// Re-read on SIGHUP, starting and stopping --route listeners and applying changed
+    /// upstreams and limits to new connections
+    #[clap(long)]
+    config: Option<String>,
+
+    /// Apply this profile from --config on top of its top-level flags
+    #[clap(long)]
+    profile: Option<String>,
+
+    /// Keep this many of the configs last applied at startup or on SIGHUP, for
+    /// /api/config/history and /api/config/rollback/N
+    #[clap(long, default_value = "10")]
+    config_history: usize,
+
+    /// Print the effective value of every flag, from the command line, --config or
+    /// defaults, as JSON and exit. The same is logged at startup
+    #[clap(long)]
+    print_config: bool,
+
+    /// On SIGINT or SIGTERM, stop accepting connections and wait this long for open
+    /// ones to finish before exiting
+    #[clap(long, default_value = "30s", parse(try_from_str = parse_duration))]
+    drain_timeout: Duration,
+
+    /// How to write logs: text, or json with an object per line
+    #[clap(long, default_value = "text")]
+    log_format: logging::Format,
+
+    /// The least severe logs to write: error, warn, info, debug, trace or off
+    #[clap(long, default_value = "info")]
+    log_level: LevelFilter,
+}
+
+/// Parse a duration such as `250ms`, `3s`, `5m` or `1h`. A bare number is taken as seconds.
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (value, unit) = s.split_at(split);
+    let value: u64 = value
+        .parse()
+        .map_err(|_| format!("invalid duration: {}", s))?;
+    let secs = |per: u64| {
+        value
+            .checked_mul(per)
+            .map(Duration::from_secs)
+            .ok_or_else(|| "duration too large".to_string())
+    };
+    match unit {
+        "ms" => Ok(Duration::from_millis(value)),
+        "" | "s" => secs(1),
+        "m" => secs(60),
+        "h" => secs(60 * 60),
+        _ => Err(format!("invalid duration unit: {}", unit)),
+    }
+}
+
+fn parse_flow_label(s: &str) -> Result<u32, String> {
+    match s.parse() {
+        Ok(label) if label <= 0xf_ffff => Ok(label),
+        _ => Err(format!("flow labels are 20 bits, from 0 to 1048575: {}", s)),
+    }
+}
+
+fn parse_percent(s: &str) -> Result<f64, String> {
+    match s.parse() {
+        Ok(p) if (0.0..=100.0).contains(&p) => Ok(p),
+        _ => Err(format!("percentages are from 0 to 100: {}", s)),
+    }
+}
+
+/// A connect rate: 0 for unlimited, or one connect every 1000s or more often.
+fn parse_connect_rate(s: &str) -> Result<f64, String> {
+    match s.parse() {
+        Ok(rate) if rate == 0.0 || (0.001..=f64::MAX).contains(&rate) => Ok(rate),
+        _ => Err(format!(
+            "connect rates are 0 for unlimited, or at least 0.001 per second: {}",
+            s
+        )),
+    }
+}
+
+fn parse_probability(s: &str) -> Result<f64, String> {
+    match s.parse() {
+        Ok(p) if (0.0..=1.0).contains(&p) => Ok(p),
+        _ => Err(format!("probabilities are from 0 to 1: {}", s)),
+    }
+}
+
+/// Run as the `tproxy` binary would with the command line `cli`, program name first,
+/// until SIGINT or SIGTERM.
+pub async fn run_cli(cli: Vec<String>) -> Result<(), Box<dyn Error>> {
+    let html = include_str!("static/index.html");
+    match cli.get(1).map(String::as_str) {
+        Some("replay") => return replay::run(replay::Args::parse_from(&cli[1..])).await,
+        Some("compare") => return compare::run(compare::Args::parse_from(&cli[1..])).await,
+        _ => {}
+    }
+    let argv = config::expand(cli.clone())?;
+    let args = Args::parse_from(&argv);
+    let effective = config::effective(&argv)?;
+    if args.print_config {
+        println!("{:#}", effective);
+        return Ok(());
+    }
+    logging::init(args.log_format, args.log_level)?;
+    let debug_addr: SocketAddr = args
+        .debug_addr
+        .parse()
+        .map_err(|err| format!("invalid --debug-addr {}: {}", args.debug_addr, err))?;
+    info!(version = env!("CARGO_PKG_VERSION"), config = %effective, "starting tproxy");
+    if let Some(target) = args.nofile_target {
+        match fd::raise_nofile_limit(target) {
+            Ok(limit) => info!(limit, "set open file limit"),
+            Err(err) => warn!(error = %err, "failed to raise open file limit"),
+        }
+    }
+    let state = Arc::new(State::with_buckets(
+        args.latency_buckets.clone(),
+        args.size_buckets.clone(),
+    ));
+    tokio::spawn(fd::monitor(state.clone(), args.fd_warn_ratio));
+    tokio::spawn(bandwidth::run(state.clone()));
+    #[cfg(feature = "runtime-metrics")]
+    tokio::spawn(runtime_metrics::probe(state.clone()));
+    let mut running = args.clone();
+    let listeners = start_listeners(&mut running, &state).await?;
+    if args.config.is_some() {
+        tokio::spawn(reload::run(cli, argv, running, listeners, state.clone()));
+    }
+    let mut signals = shutdown::Signals::new()?;
+    if !args.no_admin {
+        let api = api::routes(state.clone());
+        let metrics = metrics::routes(state.clone());
+        let memory = memory::routes(state.clone());
+        let snapshot = state.clone();
+        let stats = warp::path("stats").map(move || format!("{:#?}", snapshot.snapshot()));
+        let index = warp::any().map(|| warp::reply::html(html.to_string()));
+        let routes = api.or(metrics).or(memory).or(stats).or(index);
+        tokio::spawn(serve_admin(debug_addr, routes));
+    }
+    let signal = signals.recv().await;
+    info!(signal, open = state.open_connections(), "shutting down");
+    shutdown::drain(&state, args.drain_timeout, signals.recv()).await
+}
+
+/// How many times to try binding --debug-addr.
+const ADMIN_BIND_ATTEMPTS: u32 = 5;
+/// How long to wait before the first retry, doubling after each.
+const ADMIN_BIND_BACKOFF: Duration = Duration::from_millis(200);
+
+/// Serve the debug UI, API and metrics on `addr`. Connections are already being
+/// proxied by now, so if the port stays taken only the admin server is given up on.
+async fn serve_admin(
+    addr: SocketAddr,
+    routes: impl Filter<Extract = (impl warp::Reply,), Error = std::convert::Infallible>
+        + Clone
+        + Send
+        + Sync
+        + 'static,
+) {
+    let mut backoff = ADMIN_BIND_BACKOFF;
+    for attempt in 1..=ADMIN_BIND_ATTEMPTS {
+        match warp::serve(routes.clone()).try_bind_ephemeral(addr) {
+            Ok((addr, server)) => {
+                info!(%addr, "serving debug UI");
+                return server.await;
+            }
+            Err(err) if attempt < ADMIN_BIND_ATTEMPTS => {
+                warn!(error = %err, attempt, "failed to bind debug address; retrying");
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+            Err(err) => warn!(error = %err, "failed to bind debug address; proxying without it"),
+        }
+    }
+}
+
+/// Spawn the listener for --listen-addr, if given, and one for each --route, returning
+/// them once they're bound.
+async fn start_listeners(
+    args: &mut Args,
+    state: &Arc<State>,
+) -> Result<reload::Listeners, Box<dyn Error>> {
+    check_listeners(args)?;
+    // Routes share these rather than, say, each truncating the capture file.
+    open_resources(args)?;
+    *state.capture.lock().unwrap() = args.capture_writer.clone();
+    let mut listeners = reload::Listeners::default();
+    listeners.apply(args, state).await;
+    Ok(listeners)
+}
+
+fn check_listeners(args: &Args) -> Result<(), Box<dyn Error>> {
+    if matches!(args.mode, Mode::Udp | Mode::Quic | Mode::Dns)
+        && balance::is_list(&args.upstream_addr)
+    {
+        return Err("a list of upstream addresses is only supported in TCP modes".into());
+    }
+    let lists =
+        std::iter::once(&args.upstream_addr).chain(args.route.iter().map(|r| &r.upstream_addr));
+    for list in lists.filter(|list| balance::is_list(list)) {
+        balance::parse(list)?;
+    }
+    for rule in &args.route_toxics {
+        if !args.route.iter().any(|route| route.name == rule.route) {
+            return Err(format!("--route-toxics names an unknown route: {}", rule.route).into());
+        }
+    }
+    // CONNECT requests say where to go.
+    let has_upstream = !args.upstream_addr.is_empty()
+        || !args.zone_upstream.is_empty()
+        || args.mode == Mode::HttpConnect;
+    if args.listen_addr.is_empty() == has_upstream
+        || (args.listen_addr.is_empty() && args.route.is_empty())
+    {
+        return Err(
+            "--listen-addr and --upstream-addr are required unless --route is given".into(),
+        );
+    }
+    Ok(())
+}
+
+/// Build the runtime objects configured by flags which `listen` doesn't have yet.
+fn open_resources(args: &mut Args) -> Result<(), Box<dyn Error>> {
+    if !args.sni_route.is_empty() && args.client_hello.is_none() {
+        args.client_hello = Some(hello::sni_router(args.sni_route.clone()));
+    }
+    if let (Some(path), None) = (&args.capture, &args.capture_writer) {
+        let mut rotated = None;
+        if let Some(url) = &args.capture_upload {
+            if args.capture_rotate_bytes == 0 {
+                return Err("--capture-upload needs --capture-rotate-bytes".into());
+            }
+            let endpoint = args
+                .capture_upload_endpoint
+                .as_deref()
+                .ok_or("--capture-upload needs --capture-upload-endpoint")?;
+            let bucket = s3::Bucket::from_env(url, endpoint, &args.capture_upload_region)?;
+            let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+            tokio::spawn(s3::upload_rotated(
+                rx,
+                bucket,
+                args.capture_upload_retention,
+            ));
+            rotated = Some(tx);
+        }
+        let writer = capture::Writer::rotating(path, args.capture_rotate_bytes, rotated)?;
+        args.capture_writer = Some(Arc::new(writer));
+    }
+    if !args.geoip_db.is_empty() && args.geoip.is_none() {
+        args.geoip = Some(Arc::new(geoip::GeoIp::open(&args.geoip_db)?));
+    }
+    Ok(())
+}
+
+/// Accept downstream connections and forward each of them to the upstream.
+///
+/// Once the listener is bound its local address is sent on `ready`, so callers can
+/// wait for the proxy to accept connections (and learn the port when binding to 0).
+#[cfg(test)]
+async fn listen(
+    mut args: Args,
+    state: Arc<State>,
+    ready: oneshot::Sender<SocketAddr>,
+) -> Result<(), Box<dyn Error>> {
+    open_resources(&mut args)?;
+    let (_, updates) = watch::channel(Arc::new(args));
+    listen_for(updates, state, ready).await
+}
+
+/// Like `listen`, but each new connection is served with the latest args on `updates`,
+/// so a --config reload applies to new connections while open ones keep theirs.
+async fn listen_for(
+    updates: watch::Receiver<Arc<Args>>,
+    state: Arc<State>,
+    ready: oneshot::Sender<SocketAddr>,
+) -> Result<(), Box<dyn Error>> {
+    let mut args = updates.borrow().clone();
+    {
+        let mut defaults = state.toxic_defaults.lock().unwrap();
+        for (direction, rate) in [
+            (Direction::Up, args.rate_limit_up),
+            (Direction::Down, args.rate_limit_down),
+        ] {
+            let toxic = defaults.get_mut(direction);
+            toxic.latency = args.delay.unwrap_or(toxic.latency);
+            toxic.jitter = args.delay_jitter.unwrap_or(toxic.jitter);
+            toxic.rate = rate.or(args.rate_limit).or(toxic.rate);
+        }
+        for setting in &args.toxics {
+            setting.apply(&mut defaults);
+        }
+    }
+    if let Some(toxic) = args.connect_toxic {
+        *state.connect_toxic.lock().unwrap() = toxic;
+    }
+    state.connect_pacer.set_rate(args.upstream_connect_rate);
+    state.retry_budget.set_ratio(args.retry_budget);
+    state.qos.set_rate(args.bandwidth_cap);
+    state
+        .upstream_flow_label
+        .store(args.upstream_flow_label, Ordering::Relaxed);
+    if matches!(args.mode, Mode::Udp | Mode::Quic) {
+        return udp::listen(args, state, ready).await;
+    }
+    if args.mode == Mode::Dns {
+        return dns::listen(args, state, ready).await;
+    }
+    let mut listener = Some(bind(&args.listen_addr, args.backlog).await?);
+    let listen_addr = listener.as_ref().unwrap().local_addr()?;
+    // The receiver may have been dropped if nobody cares about readiness.
+    let _ = ready.send(listen_addr);
+    let started = Instant::now();
+    let mut drain = state.draining.subscribe();
+    let slots = Arc::new(Slots::default());
+
+    loop {
+        args = updates.borrow().clone();
+        let full = args.max_connections > 0 && slots.open() >= args.max_connections;
+        let (open, until_change) =
+            schedule::check(&args.schedule, SystemTime::now(), started.elapsed());
+        let draining = *drain.borrow();
+        let open = open && !draining;
+        if open != listener.is_some() {
+            state.listener_closed.store(!open, Ordering::Relaxed);
+            if open {
+                // Rebind the address we first bound, in case it was port 0.
+                listener = Some(bind(&listen_addr.to_string(), args.backlog).await?);
+            } else {
+                listener = None;
+            }
+            info!(
+                by = if draining { "drain" } else { "schedule" },
+                "listener {}",
+                if open { "opened" } else { "closed" }
+            );
+        }
+        // Wake when the schedule next changes, or every minute in case the clock jumps,
+        // or when draining starts or stops.
+        let wake = tokio::time::sleep(until_change.unwrap_or(Duration::from_secs(60)));
+        let accepted = match &listener {
+            Some(listener) => tokio::select! {
+                accepted = listener.accept(), if !full || args.overflow_policy == Overflow::Reject => {
+                    accepted
+                }
+                _ = slots.released.notified(), if full => continue,
+                _ = wake => continue,
+                _ = drain.changed() => continue,
+            },
+            None => {
+                tokio::select! {
+                    _ = wake => {}
+                    _ = drain.changed() => {}
+                }
+                continue;
+            }
+        };
+        let (downstream, downstream_addr) = match accepted {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                // Errors such as EMFILE are usually transient, so keep the listener alive
+                // rather than tearing down the whole proxy.
+                warn!(error = %err, "failed to accept");
+                state.accept_errors.fetch_add(1, Ordering::Relaxed);
+                if !args.accept_backoff.is_zero() {
+                    tokio::time::sleep(args.accept_backoff).await;
+                }
+                continue;
+            }
+        };
+        if args.fd_shed && state.fd_exhausted.load(Ordering::Relaxed) {
+            // Dropping the stream closes it; keep the remaining descriptors for the
+            // connections we're already serving.
+            state.shed_connections.fetch_add(1, Ordering::Relaxed);
+            continue;
+        }
+        if full {
+            state.overflow_rejections.fetch_add(1, Ordering::Relaxed);
+            continue;
+        }
+        let slot = Slot::take(&slots);
+        // The args may have been updated while we waited to accept.
+        let forwarding = forward(
+            downstream,
+            updates.borrow().clone(),
+            state.clone(),
+            downstream_addr,
+        );
+        tokio::spawn(async move {
+            forwarding.await;
+            drop(slot);
+        });
+    }
+}
+
+/// The connections a listener is serving, for --max-connections.
+#[derive(Default)]
+struct Slots {
+    open: AtomicUsize,
+    /// Notified as each connection finishes.
+    released: tokio::sync::Notify,
+}
+
+impl Slots {
+    fn open(&self) -> usize {
+        self.open.load(Ordering::Relaxed)
+    }
+}
+
+/// One connection counted in `Slots`, until dropped.
+struct Slot(Arc<Slots>);
+
+impl Slot {
+    fn take(slots: &Arc<Slots>) -> Self {
+        slots.open.fetch_add(1, Ordering::Relaxed);
+        Slot(slots.clone())
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.open.fetch_sub(1, Ordering::Relaxed);
+        self.0.released.notify_one();
+    }
+}
+
+/// Bind a listener like `TcpListener::bind`, but with a configurable backlog.
+async fn bind(addr: &str, backlog: i32) -> io::Result<TcpListener> {
+    let addr = tokio::net::lookup_host(addr).await?.next().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "listen address resolved to nothing",
+        )
+    })?;
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(backlog)?;
+    TcpListener::from_std(socket.into())
+}
+
+async fn forward(
+    mut downstream: TcpStream,
+    args: Arc<Args>,
+    state: Arc<State>,
+    mut downstream_addr: SocketAddr,
+) {
+    if args.accept_tunnel {
+        return tunnel::accept(downstream, args, state, downstream_addr).await;
+    }
+    let mut proxied_by = None;
+    let mut proxy_tags = Vec::new();
+    if args.accept_proxy_protocol {
+        match proxy_protocol::accept(&mut downstream, args.protocol_timeout).await {
+            Ok(header) => {
+                if let Some(source) = header.source {
+                    proxied_by = Some(std::mem::replace(&mut downstream_addr, source));
+                }
+                proxy_tags = header.tags;
+            }
+            Err(err) => {
+                warn!(peer = %downstream_addr, error = %err, "failed to read PROXY header");
+                state.proxy_header_errors.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+        }
+    }
+    let mut connect_target = None;
+    if args.mode == Mode::HttpConnect {
+        match http_connect::accept(&mut downstream, &args, &state).await {
+            Ok(target) => connect_target = Some(target),
+            Err(err) => {
+                warn!(peer = %downstream_addr, error = %err, "failed to accept CONNECT request");
+                return;
+            }
+        }
+    }
+    let mut decision = hello::Decision::default();
+    if args.client_hello.is_some() || args.tls_fingerprint {
+        match hello::peek(&downstream, args.protocol_timeout).await {
+            Ok(bytes) => {
+                if let Some(callback) = &args.client_hello {
+                    decision = callback.call(downstream_addr, &bytes);
+                }
+                if args.tls_fingerprint {
+                    if let Some(hello) = hello::parse_client_hello(&bytes) {
+                        fingerprint::Fingerprint::of(&hello).record(&state, &mut decision.tags);
+                    }
+                }
+            }
+            Err(err) => warn!(error = %err, "failed to peek"),
+        }
+    }
+    let mut original_dst = None;
+    if args.transparent {
+        match sockopt::original_dst(&downstream) {
+            Ok(addr) => original_dst = addr,
+            Err(err) => warn!(error = %err, "failed to get original destination"),
+        }
+    }
+    let mut upstream = connect_target
+        .or(decision.upstream)
+        .or_else(|| original_dst.map(|addr| addr.to_string()));
+    let mut zones = None;
+    if upstream.is_none() {
+        if let Some(picked) = zone::pick(&args.zone_upstream, args.zone.as_deref(), &state) {
+            let local = args.zone.as_deref().unwrap_or(zone::UNKNOWN);
+            zones = Some((local.to_string(), picked.zone.clone()));
+            upstream = Some(picked.addr.clone());
+        }
+    }
+    if upstream.is_none() && balance::is_list(&args.upstream_addr) {
+        upstream = Some(balance::pick(&args.upstream_addr, args.lb_strategy, &state));
+    }
+    let args = match upstream {
+        Some(upstream_addr) => Arc::new(Args {
+            upstream_addr,
+            ..(*args).clone()
+        }),
+        None => args,
+    };
+    let conn = state.open_connection(downstream_addr, args.upstream_addr.clone());
+    if args.transparent {
+        conn.set_original_dst(original_dst);
+    }
+    conn.set_tags(proxy_tags.into_iter().map(|(k, v)| (k, Some(v))));
+    if let Some(peer) = proxied_by {
+        conn.set_tags([("proxy.peer".to_string(), Some(peer.to_string()))]);
+    }
+    if let Some(route) = &args.route_name {
+        conn.set_tags([("route".to_string(), Some(route.clone()))]);
+        state.route_opened(route);
+        let mut toxics = conn.toxics();
+        for rule in args.route_toxics.iter().filter(|rule| &rule.route == route) {
+            rule.setting.apply(&mut toxics);
+        }
+        conn.set_toxics(toxics);
+    }
+    network::tag(&args.client_network, &conn);
+    if !decision.tags.is_empty() {
+        conn.set_tags(decision.tags.into_iter().map(|(k, v)| (k, Some(v))));
+    }
+    if let Some((_, to)) = &zones {
+        conn.set_tags([("upstream.zone".to_string(), Some(to.clone()))]);
+    }
+    if args.bandwidth_cap.is_some() {
+        match downstream.local_addr() {
+            Ok(local) => {
+                let (priority, class) = qos::classify(&args.qos_class, &conn, local);
+                conn.set_tags([("qos.class".to_string(), Some(class.to_string()))]);
+                conn.set_qos(state.qos.clone(), priority);
+            }
+            Err(err) => warn!(error = %err, "failed to get local address"),
+        }
+    }
+    let span = tracing::info_span!(
+        "connection",
+        id = conn.id,
+        peer = %downstream_addr,
+        upstream = %args.upstream_addr
+    );
+    handle(downstream, &args, &state, &conn)
+        .instrument(span)
+        .await;
+    if let Some(zones) = zones {
+        let (up, down) = (
+            conn.bytes_up.load(Ordering::Relaxed),
+            conn.bytes_down.load(Ordering::Relaxed),
+        );
+        state.zone_traffic.with_entry(zones, (0, 0, 0), |traffic| {
+            traffic.0 += 1;
+            traffic.1 += up;
+            traffic.2 += down;
+        });
+    }
+}
+
+/// Serve `conn` from open to close.
+async fn handle(
+    downstream: TcpStream,
+    args: &Arc<Args>,
+    state: &Arc<State>,
+    conn: &Arc<Connection>,
+) {
+    info!("connection opened");
+    if !args.accept_delay.is_zero() {
+        tokio::time::sleep(args.accept_delay).await;
+    }
+    let aborted = match hook::accept(&args.connection_hooks, conn).await {
+        Ok(()) => abort(&downstream, args, state).await.map(String::from),
+        Err(reason) => Some(reason),
+    };
+    let result = match aborted {
+        Some(reason) => Err(reason.into()),
+        None if args.idle_timeout.is_zero() && args.max_conn_duration.is_zero() => {
+            serve(downstream, args, state, conn).await
+        }
+        None => {
+            // Killing the connection makes serve return soon after, cleaning up as usual.
+            let watchdog = async {
+                let reason = tokio::select! {
+                    _ = conn.idle_for(args.idle_timeout), if !args.idle_timeout.is_zero() => {
+                        "idle timeout"
+                    }
+                    _ = conn.open_for(args.max_conn_duration), if !args.max_conn_duration.is_zero() => {
+                        "max duration"
+                    }
+                };
+                if conn.kill(reason) && reason == "max duration" {
+                    state.max_duration_closes.fetch_add(1, Ordering::Relaxed);
+                }
+                std::future::pending().await
+            };
+            tokio::select! {
+                result = serve(downstream, args, state, conn) => result,
+                never = watchdog => never,
+            }
+        }
+    };
+    let reason = match (result, conn.killed()) {
+        // However shutting the sockets down played out.
+        (_, Some(reason)) => reason.to_string(),
+        (Ok(()), None) => "completed".to_string(),
+        (Err(err), None) => {
+            warn!(tags = %conn.tag_string(), error = %err, "failed to forward");
+            err.to_string()
+        }
+    };
+    state.record_tags(conn, &args.metrics_tag_key);
+    if let Some(route) = &args.route_name {
+        state.route_closed(route, conn);
+    }
+    info!(
+        reason = %reason,
+        bytes_up = conn.bytes_up.load(Ordering::Relaxed),
+        bytes_down = conn.bytes_down.load(Ordering::Relaxed),
+        "connection closed"
+    );
+    hook::close(&args.connection_hooks, conn, &reason).await;
+    state.close_connection(conn, reason);
+}
+
+/// Roll --drop-probability and --reset-probability for a new connection. If either
+/// comes up, wait for the client to send something, so it sees its request fail rather
+/// than a refused connection, and return why the connection was closed.
+async fn abort(downstream: &TcpStream, args: &Args, state: &State) -> Option<&'static str> {
+    let roll: f64 = rand::random();
+    let reset = if roll < args.reset_probability {
+        true
+    } else if roll < args.reset_probability + args.drop_probability {
+        false
+    } else {
+        return None;
+    };
+    let _ = tokio::time::timeout(args.protocol_timeout, downstream.readable()).await;
+    if reset {
+        if let Err(err) = downstream.set_linger(Some(Duration::ZERO)) {
+            warn!(error = %err, "failed to set SO_LINGER");
+        }
+        state.resets_injected.fetch_add(1, Ordering::Relaxed);
+        Some("injected reset")
+    } else {
+        // Closing with unread data would send a reset rather than a FIN.
+        let mut buf = [0; COPY_BUFFER_SIZE];
+        while matches!(downstream.try_read(&mut buf), Ok(n) if n > 0) {}
+        state.drops_injected.fetch_add(1, Ordering::Relaxed);
+        Some("injected drop")
+    }
+}
+
+async fn serve(
+    downstream: TcpStream,
+    args: &Arc<Args>,
+    state: &Arc<State>,
+    conn: &Arc<Connection>,
+) -> Result<(), Box<dyn Error>> {
+    let downstream_addr = conn.downstream_addr;
+    if let Some(geoip) = &args.geoip {
+        locate(geoip, args, state, conn)?;
+    }
+    if args.client_process {
+        identify(&downstream, conn).await;
+    }
+    if let Some(protocol) = args.expect_protocol {
+        if !protocol::sniff(&downstream, protocol, args.protocol_timeout).await? {
+            state.protocol_mismatches.fetch_add(1, Ordering::Relaxed);
+            return Err(format!("client {} did not speak {}", downstream_addr, protocol).into());
+        }
+    }
+
+    let limits = quota::Limits {
+        max_connections: args.client_max_connections,
+        max_bytes_per_hour: args.client_max_bytes_per_hour,
+    };
+    if limits.is_unlimited() {
+        return serve_admitted(downstream, args, state, conn).await;
+    }
+    let client = downstream_addr.ip();
+    if let Err(exceeded) = state.quotas.admit(client, &limits) {
+        let counter = match exceeded {
+            quota::Exceeded::Connections => &state.quota_connection_rejections,
+            quota::Exceeded::Bytes => &state.quota_byte_rejections,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        return Err(format!("client {} is over its {} quota", client, exceeded).into());
+    }
+    let result = serve_admitted(downstream, args, state, conn).await;
+    let bytes = conn.bytes_up.load(Ordering::Relaxed) + conn.bytes_down.load(Ordering::Relaxed);
+    state.quotas.release(client, bytes);
+    result
+}
+
+/// Serve a connection which has passed the client's quotas.
+async fn serve_admitted(
+    downstream: TcpStream,
+    args: &Arc<Args>,
+    state: &Arc<State>,
+    conn: &Arc<Connection>,
+) -> Result<(), Box<dyn Error>> {
+    let reserved = state
+        .upstream_connections
+        .with_entry(args.upstream_addr.clone(), 0, |count| {
+            if args.max_upstream_connections > 0 && *count >= args.max_upstream_connections {
+                return false;
+            }
+            *count += 1;
+            true
+        });
+    if !reserved {
+        state
+            .upstream_cap_rejections
+            .fetch_add(1, Ordering::Relaxed);
+        return Err(format!("upstream {} is at its connection cap", args.upstream_addr).into());
+    }
+
+    let result = match args.mode {
+        Mode::Tcp if args.tunnel => tunnel::proxy(downstream, args, state, conn).await,
+        Mode::Tcp => proxy(downstream, args, state, conn).await,
+        Mode::Http => {
+            track_open(state, conn);
+            let result = http::proxy(downstream, args.clone(), state.clone(), conn.clone()).await;
+            track_close(state, conn, result.is_ok());
+            result
+        }
+        Mode::HttpConnect => http_connect::proxy(downstream, args, state, conn).await,
+        Mode::Kafka => kafka::proxy(downstream, args, state, conn).await,
+        Mode::Smtp | Mode::Imap => mail::proxy(downstream, args, state, conn).await,
+        Mode::Udp | Mode::Quic | Mode::Dns => unreachable!("served by their own listeners"),
+    };
+
+    state
+        .upstream_connections
+        .with_entry(args.upstream_addr.clone(), 0, |count| *count -= 1);
+    state
+        .upstream_connections
+        .remove_if(&args.upstream_addr, |count| *count == 0);
+    result
+}
+
+/// Tag `conn` with the process which opened it, if the client is on this host.
+async fn identify(downstream: &TcpStream, conn: &Connection) {
+    let local = match downstream.local_addr() {
+        Ok(local) => local,
+        Err(err) => {
+            warn!(error = %err, "failed to get local address");
+            return;
+        }
+    };
+    let peer = conn.downstream_addr;
+    if !process::is_local(peer, local) {
+        return;
+    }
+    // Finding the process means reading through /proc.
+    match tokio::task::spawn_blocking(move || process::lookup(peer, local)).await {
+        Ok(Ok(Some(owner))) => conn.set_tags(owner.tags()),
+        Ok(Ok(None)) => {}
+        Ok(Err(err)) => warn!(error = %err, "failed to look up client process"),
+        Err(err) => warn!(error = %err, "failed to look up client process"),
+    }
+}
+
+/// Tag `conn` with where its client is, and apply the geo policy.
+fn locate(
+    geoip: &geoip::GeoIp,
+    args: &Args,
+    state: &State,
+    conn: &Connection,
+) -> Result<(), Box<dyn Error>> {
+    let location = geoip.locate(conn.downstream_addr.ip());
+    conn.set_tags(location.tags().into_iter().map(|(k, v)| (k, Some(v))));
+    if let Some(country) = &location.country {
+        state
+            .geo_countries
+            .with_entry(country.clone(), 0, |count| *count += 1);
+    }
+    if !location.allowed(&args.geo_allow, &args.geo_deny) {
+        state.geo_denials.fetch_add(1, Ordering::Relaxed);
+        return Err(format!("client {} is denied by geo policy", conn.downstream_addr).into());
+    }
+    let mut toxics = conn.toxics();
+    for rule in &args.geo_toxics {
+        if location.matches(&rule.place) {
+            rule.setting.apply(&mut toxics);
+        }
+    }
+    conn.set_toxics(toxics);
+    Ok(())
+}
+
+/// Dial the upstream, retrying with backoff when the local ephemeral ports are exhausted
+/// (EADDRNOTAVAIL), and up to --connect-retries times on any other error, within
+/// --retry-budget. Each attempt waits its turn under --upstream-connect-rate, after any
+/// connect toxic, and gives up after --connect-timeout.
+async fn connect_upstream(addr: &str, args: &Args, state: &State) -> io::Result<TcpStream> {
+    if breaker::is_ejected(state, addr) {
+        state.ejected_connects.fetch_add(1, Ordering::Relaxed);
+        return Err(io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            format!("upstream {} is ejected", addr),
+        ));
+    }
+    let result = dial_upstream(addr, args, state).await;
+    if result.is_err() {
+        state.connect_errors.fetch_add(1, Ordering::Relaxed);
+        state
+            .upstream_failures
+            .insert(addr.to_string(), Instant::now());
+    }
+    breaker::record(
+        state,
+        addr,
+        result.is_ok(),
+        args.eject_after,
+        args.eject_cooldown,
+    );
+    result
+}
+
+/// The wait before the first --connect-retries retry, doubling for each after.
+const CONNECT_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_CONNECT_BACKOFF: Duration = Duration::from_secs(5);
+
+async fn dial_upstream(addr: &str, args: &Args, state: &State) -> io::Result<TcpStream> {
+    let toxic = *state.connect_toxic.lock().unwrap();
+    if !toxic.delay.is_zero() {
+        tokio::time::sleep(toxic.delay).await;
+    }
+    if let Some((hang, err)) = toxic.fault() {
+        state
+            .connect_faults_injected
+            .fetch_add(1, Ordering::Relaxed);
+        tokio::time::sleep(hang).await;
+        return Err(err);
+    }
+    state.retry_budget.dialed();
+    let (mut addr_not_avail_retries, mut retries) = (0, 0);
+    loop {
+        let addrs = state
+            .resolver
+            .lookup(addr, args.upstream_dns_ttl, args.upstream_dns_balance)
+            .await?;
+        if !state.connect_pacer.wait().await.is_zero() {
+            state.paced_connects.fetch_add(1, Ordering::Relaxed);
+        }
+        let label = state.upstream_flow_label.load(Ordering::Relaxed);
+        let connecting = async {
+            if label == 0 {
+                TcpStream::connect(&addrs[..]).await
+            } else {
+                connect_with_flow_label(&addrs, label).await
+            }
+        };
+        let connected = if args.connect_timeout.is_zero() {
+            connecting.await
+        } else {
+            match tokio::time::timeout(args.connect_timeout, connecting).await {
+                Ok(connected) => connected,
+                Err(_) => {
+                    state.connect_timeouts.fetch_add(1, Ordering::Relaxed);
+                    Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!("connect to {} timed out", addr),
+                    ))
+                }
+            }
+        };
+        let backoff = match connected.as_ref().map_err(|err| err.kind()) {
+            Ok(_) => return connected,
+            Err(io::ErrorKind::AddrNotAvailable) => {
+                state.addr_not_avail_errors.fetch_add(1, Ordering::Relaxed);
+                if addr_not_avail_retries >= args.addr_not_avail_retries {
+                    return connected;
+                }
+                addr_not_avail_retries += 1;
+                Duration::from_millis(10 << (addr_not_avail_retries - 1).min(8))
+            }
+            Err(_) if retries < args.connect_retries => {
+                retries += 1;
+                (CONNECT_BACKOFF * 2u32.pow((retries - 1).min(16))).min(MAX_CONNECT_BACKOFF)
+            }
+            Err(_) => return connected,
+        };
+        if !state.retry_budget.try_retry() {
+            state.retry_budget_exhausted.fetch_add(1, Ordering::Relaxed);
+            return connected;
+        }
+        state.connect_retries.fetch_add(1, Ordering::Relaxed);
+        tokio::time::sleep(backoff).await;
+    }
+}
+
+/// Try each of `addrs` in turn, like `TcpStream::connect`.
+async fn connect_with_flow_label(addrs: &[SocketAddr], label: u32) -> io::Result<TcpStream> {
+    let mut last_err = None;
+    for addr in addrs {
+        match sockopt::connect_with_flow_label(*addr, label).await {
+            Ok(stream) => return Ok(stream),
+            Err(err) => last_err = Some(err),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "upstream resolved to nothing")
+    }))
+}
+
+async fn proxy(
+    downstream: TcpStream,
+    args: &Args,
+    state: &State,
+    conn: &Connection,
+) -> Result<(), Box<dyn Error>> {
+    let connect_start = Instant::now();
+    let upstream = connect_upstream(&args.upstream_addr, args, state).await?;
+    state.observe_connect(conn, connect_start.elapsed());
+    proxy_connected(downstream, upstream, args, state, conn).await
+}
+
+/// Proxy `downstream` to `upstream`, already connected.
+async fn proxy_connected(
+    mut downstream: TcpStream,
+    mut upstream: TcpStream,
+    args: &Args,
+    state: &State,
+    conn: &Connection,
+) -> Result<(), Box<dyn Error>> {
+    if let Some(version) = args.send_proxy_protocol {
+        let header = proxy_protocol::encode(
+            version,
+            conn.downstream_addr,
+            downstream.local_addr()?,
+            &conn.tags(),
+        );
+        upstream.write_all(&header).await?;
+    }
+    conn.connected();
+    hook::connect_upstream(&args.connection_hooks, conn).await?;
+    track_open(state, conn);
+    let _sockets = register_sockets(state, conn, &downstream, &upstream);
+    let (ri, mut wi) = downstream.split();
+    let (ro, mut wo) = upstream.split();
+    let ri = hook::Reader::new(ri, &args.connection_hooks, Direction::Up, conn);
+    let ro = hook::Reader::new(ro, &args.connection_hooks, Direction::Down, conn);
+    let ri = rewrite::Reader::new(ri, &args.replace, Direction::Up, conn, state);
+    let ro = rewrite::Reader::new(ro, &args.replace, Direction::Down, conn, state);
+    let (fuzz_up, fuzz_down) = fuzzers(args, conn);
+    let mut ri = fuzz::Reader::new(ri, fuzz_up, state);
+    let mut ro = fuzz::Reader::new(ro, fuzz_down, state);
+
+    let mut websocket_up = websocket::Tracker::new(Direction::Up);
+    let mut websocket_down = websocket::Tracker::new(Direction::Down);
+    let mut http2_up = http2::Tracker::new(Direction::Up);
+    let mut http2_down = http2::Tracker::new(Direction::Down);
+    let ssh = ssh::Session::default();
+    let mut ssh_up = ssh::Tracker::new(Direction::Up, &ssh);
+    let mut ssh_down = ssh::Tracker::new(Direction::Down, &ssh);
+
+    let client_to_server = async {
+        conn.forward(&mut ri, &mut wo, Direction::Up, |b| {
+            if let Some(capture) = &args.capture_writer {
+                capture.record(conn.id, Direction::Up, b);
+            }
+            websocket_up.feed(b, conn);
+            http2_up.feed(b, conn);
+            ssh_up.feed(b, conn, state);
+        })
+        .await?;
+        wo.shutdown().await
+    };
+
+    let server_to_client = async {
+        conn.forward(&mut ro, &mut wi, Direction::Down, |b| {
+            if let Some(capture) = &args.capture_writer {
+                capture.record(conn.id, Direction::Down, b);
+            }
+            websocket_down.feed(b, conn);
+            http2_down.feed(b, conn);
+            ssh_down.feed(b, conn, state);
+        })
+        .await?;
+        wi.shutdown().await
+    };
+
+    let sample = async {
+        let mut interval = tokio::time::interval(connection::SAMPLE_INTERVAL);
+        loop {
+            interval.tick().await;
+            conn.sample();
+        }
+    };
+
+    tokio::pin!(client_to_server, server_to_client, sample);
+    let first = tokio::select! {
+        result = &mut client_to_server => result.map(|()| Direction::Up),
+        result = &mut server_to_client => result.map(|()| Direction::Down),
+        _ = &mut sample => unreachable!(),
+    };
+    let result = match first {
+        Ok(finished) => {
+            conn.half_close(finished);
+            state
+                .half_closed_connections
+                .fetch_add(1, Ordering::Relaxed);
+            let reap = async {
+                if args.half_close_timeout.is_zero() {
+                    futures::future::pending().await
+                } else {
+                    tokio::time::sleep(args.half_close_timeout).await
+                }
+            };
+            let result = tokio::select! {
+                result = &mut client_to_server, if finished == Direction::Down => result,
+                result = &mut server_to_client, if finished == Direction::Up => result,
+                _ = reap => {
+                    state.half_closed_reaped.fetch_add(1, Ordering::Relaxed);
+                    Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!("half-closed for longer than {:?}", args.half_close_timeout),
+                    ))
+                }
+                _ = &mut sample => unreachable!(),
+            };
+            state
+                .half_closed_connections
+                .fetch_sub(1, Ordering::Relaxed);
+            result
+        }
+        Err(err) => Err(err),
+    };
+
+    track_close(state, conn, result.is_ok());
+    result?;
+
+    Ok(())
+}
+
+/// The --fuzz-rate fuzzers for bytes going up and down `conn`, tagging it with their
+/// seed.
+fn fuzzers(args: &Args, conn: &Connection) -> (Option<fuzz::Fuzzer>, Option<fuzz::Fuzzer>) {
+    if args.fuzz_rate == 0.0 {
+        return (None, None);
+    }
+    let seed = args.fuzz_seed.unwrap_or_else(rand::random);
+    conn.set_tags([("fuzz.seed".to_string(), Some(seed.to_string()))]);
+    info!(id = conn.id, seed, "fuzzing connection");
+    let fuzzer = |direction, seed| {
+        let wanted = args.fuzz_direction.is_none_or(|d| d == direction);
+        wanted.then(|| fuzz::Fuzzer::new(seed, args.fuzz_rate, &args.fuzz_strategy))
+    };
+    (
+        fuzzer(Direction::Up, seed),
+        fuzzer(Direction::Down, seed.wrapping_add(1)),
+    )
+}
+
+/// Account for a connection which is now being proxied.
+fn track_open(state: &State, conn: &Connection) {
+    state.active_connections.fetch_add(1, Ordering::Relaxed);
+    state
+        .segment(conn)
+        .active_connections
+        .fetch_add(1, Ordering::Relaxed);
+    state.by_addr.insert(conn.downstream_addr, conn.id);
+}
+
+/// Apply the default socket options to a newly connected pair of streams and make
+/// them adjustable through the API. The guard must be dropped before the streams.
+fn register_sockets<'a>(
+    state: &State,
+    conn: &'a Connection,
+    downstream: &TcpStream,
+    upstream: &TcpStream,
+) -> connection::SocketsGuard<'a> {
+    let defaults = state.socket_defaults.lock().unwrap().clone();
+    for stream in [downstream, upstream] {
+        if let Err(err) = sockopt::apply(stream.as_raw_fd(), &defaults) {
+            warn!(error = %err, "failed to set socket options");
+        }
+    }
+    if let Ok(addr) = upstream.local_addr() {
+        conn.set_source_addr(addr);
+    }
+    conn.register_sockets(downstream.as_raw_fd(), upstream.as_raw_fd())
+}
+
+/// Account for the end of a connection previously passed to `track_open`.
+fn track_close(state: &State, conn: &Connection, completed: bool) {
+    let segment = state.segment(conn);
+    state.active_connections.fetch_sub(1, Ordering::Relaxed);
+    segment.active_connections.fetch_sub(1, Ordering::Relaxed);
+    state
+        .by_addr
+        .remove_if(&conn.downstream_addr, |id| *id == conn.id);
+    let (up, down) = (
+        conn.bytes_up.load(Ordering::Relaxed) as f64,
+        conn.bytes_down.load(Ordering::Relaxed) as f64,
+    );
+    state.connection_size_up.observe(up);
+    state.connection_size_down.observe(down);
+    segment.connection_size_up.observe(up);
+    segment.connection_size_down.observe(down);
+    if completed {
+        state.completed_connections.fetch_add(1, Ordering::Relaxed);
+        segment
+            .completed_connections
+            .fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::{BTreeMap, HashMap};
+
+    use futures::FutureExt;
+    use tokio::io::AsyncReadExt;
+
+    use crate::state::Snapshot;
+
+    #[tokio::test]
+    async fn test_forward() {
+        let (echo_tx, echo_rx) = oneshot::channel();
+        let t1 = tokio::spawn(echo("127.0.0.1:0".to_string(), echo_tx).map(|r| {
+            if let Err(err) = r {
+                println!("failed to echo; error={}", err);
+            }
+        }));
+        let upstream_addr = echo_rx.await.unwrap();
+
+        let args = Args::parse_from([
+            "tproxy",
+            "--listen-addr",
+            "127.0.0.1:0",
+            "--upstream-addr",
+            &upstream_addr.to_string(),
+        ]);
+
+        let state = Arc::new(State::new());
+
+        let (listen_tx, listen_rx) = oneshot::channel();
+        let t2 = tokio::spawn(listen(args.clone(), state.clone(), listen_tx).map(|r| {
+            if let Err(err) = r {
+                println!("failed to main; error={}", err);
+            }
+        }));
+        let listen_addr = listen_rx.await.unwrap();
+
+        let mut client1 = TcpStream::connect(listen_addr).await.unwrap();
+        client1.write_all(b"Hello!").await.unwrap();
+        let mut buf1 = [0; 6];
+        client1.read_exact(&mut buf1).await.unwrap();
+        assert_eq!(&buf1, b"Hello!");
+
+        let snapshot = state.snapshot();
+        let activity = &snapshot.by_addr[&client1.local_addr().unwrap()];
+        assert_eq!(activity.upstream_addr, upstream_addr.to_string());
+        assert_eq!((activity.bytes_up, activity.bytes_down), (6, 6));
+        assert!(activity.last_activity >= activity.started_at);
+        assert_eq!(
+            Snapshot {
+                by_addr: HashMap::new(),
+                ..snapshot
+            },
+            Snapshot {
+                active_connections: 1,
+                completed_connections: 0,
+                upstream_connections: HashMap::from_iter([(upstream_addr.to_string(), 1)]),
+                upstream_connections_total: HashMap::from_iter([(upstream_addr.to_string(), 1)]),
+                ..Default::default()
+            }
+        );
+
+        client1.shutdown().await.unwrap();
+        read_eof(&mut client1).await;
+        wait_for(&state, |s| s.completed_connections == 1).await;
+
+        let mut client2 = TcpStream::connect(listen_addr).await.unwrap();
+        client2.write_all(b"Hi!").await.unwrap();
+        let mut buf2 = [0; 3];
+        client2.read_exact(&mut buf2).await.unwrap();
+        assert_eq!(&buf2, b"Hi!");
+
+        client2.shutdown().await.unwrap();
+        read_eof(&mut client2).await;
+        wait_for(&state, |s| s.completed_connections == 2).await;
+
+        assert_eq!(state.snapshot().active_connections, 0);
+        assert_eq!(state.snapshot().completed_connections, 2);
+        assert!(state.snapshot().by_addr.is_empty());
+
+        t1.abort();
+        t2.abort();
+    }
+
+    #[tokio::test]
+    async fn test_max_upstream_connections() {
+        let (echo_tx, echo_rx) = oneshot::channel();
+        let t1 = tokio::spawn(echo("127.0.0.1:0".to_string(), echo_tx).map(|r| {
+            if let Err(err) = r {
+                println!("failed to echo; error={}", err);
+            }
+        }));
+        let upstream_addr = echo_rx.await.unwrap();
+
+        let args = Args::parse_from([
+            "tproxy",
+            "--listen-addr",
+            "127.0.0.1:0",
+            "--upstream-addr",
+            &upstream_addr.to_string(),
+            "--max-upstream-connections",
+            "1",
+        ]);
+        let state = Arc::new(State::new());
+        let (listen_tx, listen_rx) = oneshot::channel();
+        let t2 = tokio::spawn(listen(args, state.clone(), listen_tx).map(|r| {
+            if let Err(err) = r {
+                println!("failed to listen; error={}", err);
+            }
+        }));
+        let listen_addr = listen_rx.await.unwrap();
+
+        let mut client1 = TcpStream::connect(listen_addr).await.unwrap();
+        client1.write_all(b"Hello!").await.unwrap();
+        let mut buf1 = [0; 6];
+        client1.read_exact(&mut buf1).await.unwrap();
+
+        let mut client2 = TcpStream::connect(listen_addr).await.unwrap();
+        read_eof(&mut client2).await;
+        assert_eq!(state.snapshot().upstream_cap_rejections, 1);
+
+        client1.shutdown().await.unwrap();
+        read_eof(&mut client1).await;
+        wait_for(&state, |s| {
+            !s.upstream_connections
+                .contains_key(&upstream_addr.to_string())
+        })
+        .await;
+
+        t1.abort();
+        t2.abort();
+    }
+
+    #[tokio::test]
+    async fn test_expect_protocol() {
+        let (echo_tx, echo_rx) = oneshot::channel();
+        let t1 = tokio::spawn(echo("127.0.0.1:0".to_string(), echo_tx).map(|r| {
+            if let Err(err) = r {
+                println!("failed to echo; error={}", err);
+            }
+        }));
+        let upstream_addr = echo_rx.await.unwrap();
+
+        let args = Args::parse_from([
+            "tproxy",
+            "--listen-addr",
+            "127.0.0.1:0",
+            "--upstream-addr",
+            &upstream_addr.to_string(),
+            "--expect-protocol",
+            "http",
+        ]);
+        let state = Arc::new(State::new());
+        let (listen_tx, listen_rx) = oneshot::channel();
+        let t2 = tokio::spawn(listen(args, state.clone(), listen_tx).map(|r| {
+            if let Err(err) = r {
+                println!("failed to listen; error={}", err);
+            }
+        }));
+        let listen_addr = listen_rx.await.unwrap();
+
+        let mut client1 = TcpStream::connect(listen_addr).await.unwrap();
+        client1.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+        let mut buf1 = [0; 16];
+        client1.read_exact(&mut buf1).await.unwrap();
+        assert_eq!(&buf1, b"GET / HTTP/1.1\r\n");
+
+        let mut client2 = TcpStream::connect(listen_addr).await.unwrap();
+        client2
+            .write_all(b"\x16\x03\x01\x02\x00\x01")
+            .await
+            .unwrap();
+        read_eof(&mut client2).await;
+        assert_eq!(state.snapshot().protocol_mismatches, 1);
+
+        t1.abort();
+        t2.abort();
+    }
+
+    #[tokio::test]
+    async fn test_geoip() {
+        let (echo_tx, echo_rx) = oneshot::channel();
+        let t1 = tokio::spawn(echo("127.0.0.1:0".to_string(), echo_tx).map(|r| {
+            if let Err(err) = r {
+                println!("failed to echo; error={}", err);
+            }
+        }));
+        let upstream_addr = echo_rx.await.unwrap();
+
+        let db = geoip::tests::database(&[(
+            "127.0.0.0".parse().unwrap(),
+            8,
+            serde_json::json!({ "country": { "iso_code": "AU" } }),
+        )]);
+        let path = std::env::temp_dir().join(format!("tproxy-test-{}.mmdb", std::process::id()));
+        std::fs::write(&path, db).unwrap();
+
+        let mut args = Args::parse_from([
+            "tproxy",
+            "--listen-addr",
+            "127.0.0.1:0",
+            "--upstream-addr",
+            &upstream_addr.to_string(),
+            "--geoip-db",
+            path.to_str().unwrap(),
+            "--geo-toxics",
+            "AU=down:latency=50ms",
+        ]);
+        let state = Arc::new(State::new());
+        let (listen_tx, listen_rx) = oneshot::channel();
+        let t2 = tokio::spawn(listen(args.clone(), state.clone(), listen_tx).map(|r| {
+            if let Err(err) = r {
+                println!("failed to listen; error={}", err);
+            }
+        }));
+        let listen_addr = listen_rx.await.unwrap();
+
+        let mut client = TcpStream::connect(listen_addr).await.unwrap();
+        client.write_all(b"Hello!").await.unwrap();
+        let mut buf = [0; 6];
+        client.read_exact(&mut buf).await.unwrap();
+        let conn = state.connections().pop().unwrap();
+        assert_eq!(
+            conn.tags().get("geo.country").map(String::as_str),
+            Some("AU")
+        );
+        assert_eq!(conn.toxics().down.latency, Duration::from_millis(50));
+        assert_eq!(state.geo_countries.get(&"AU".to_string()), Some(1));
+        t2.abort();
+
+        args.geo_deny = vec!["AU".to_string()];
+        let state = Arc::new(State::new());
+        let (listen_tx, listen_rx) = oneshot::channel();
+        let t2 = tokio::spawn(listen(args, state.clone(), listen_tx).map(|r| {
+            if let Err(err) = r {
+                println!("failed to listen; error={}", err);
+            }
+        }));
+        let listen_addr = listen_rx.await.unwrap();
+        let mut client = TcpStream::connect(listen_addr).await.unwrap();
+        read_eof(&mut client).await;
+        assert_eq!(state.snapshot().geo_denials, 1);
+        std::fs::remove_file(path).unwrap();
+
+        t1.abort();
+        t2.abort();
+    }
+
+    #[tokio::test]
+    async fn test_replace() {
+        let (echo_tx, echo_rx) = oneshot::channel();
+        let t1 = tokio::spawn(echo("127.0.0.1:0".to_string(), echo_tx).map(|r| {
+            if let Err(err) = r {
+                println!("failed to echo; error={}", err);
+            }
+        }));
+        let upstream_addr = echo_rx.await.unwrap();
+
+        let args = Args::parse_from([
+            "tproxy",
+            "--listen-addr",
+            "127.0.0.1:0",
+            "--upstream-addr",
+            &upstream_addr.to_string(),
+            "--replace",
+            "up:/cat/dog/",
+            "--replace",
+            "down:/dog/bird/",
+        ]);
+        let state = Arc::new(State::new());
+        let (listen_tx, listen_rx) = oneshot::channel();
+        let t2 = tokio::spawn(listen(args, state.clone(), listen_tx).map(|r| {
+            if let Err(err) = r {
+                println!("failed to listen; error={}", err);
+            }
+        }));
+        let listen_addr = listen_rx.await.unwrap();
+
+        let mut client = TcpStream::connect(listen_addr).await.unwrap();
+        client.write_all(b"a cat!").await.unwrap();
+        let mut buf = [0; 7];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"a bird!");
+        assert_eq!(state.snapshot().stream_replacements, 2);
+
+        t1.abort();
+        t2.abort();
+    }
+
+    #[tokio::test]
+    async fn test_sni_route() {
+        let (echo_tx, echo_rx) = oneshot::channel();
+        let t1 = tokio::spawn(echo("127.0.0.1:0".to_string(), echo_tx).map(|r| {
+            if let Err(err) = r {
+                println!("failed to echo; error={}", err);
+            }
+        }));
+        let upstream_addr = echo_rx.await.unwrap();
+
+        let route = format!("db.example.com={}", upstream_addr);
+        let args = Args::parse_from([
+            "tproxy",
+            "--listen-addr",
+            "127.0.0.1:0",
+            "--upstream-addr",
+            // Nothing listens here, so only routed connections succeed.
+            "127.0.0.1:1",
+            "--sni-route",
+            &route,
+        ]);
+        let state = Arc::new(State::new());
+        let (listen_tx, listen_rx) = oneshot::channel();
+        let t2 = tokio::spawn(listen(args, state.clone(), listen_tx).map(|r| {
+            if let Err(err) = r {
+                println!("failed to listen; error={}", err);
+            }
+        }));
+        let listen_addr = listen_rx.await.unwrap();
+
+        let hello = hello::tests::client_hello("db.example.com", &["postgresql"]);
+        let mut client = TcpStream::connect(listen_addr).await.unwrap();
+        client.write_all(&hello).await.unwrap();
+        let mut buf = vec![0; hello.len()];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, hello);
+
+        let conn = &state.connections()[0];
+        assert_eq!(conn.upstream_addr, upstream_addr.to_string());
+        assert_eq!(conn.tags()["tls.sni"], "db.example.com");
+        assert_eq!(conn.tags()["tls.alpn"], "postgresql");
+
+        t1.abort();
+        t2.abort();
+    }
+
+    #[tokio::test]
+    async fn test_half_close_timeout() {
+        // An upstream which never finishes its side.
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        let t1 = tokio::spawn(async move {
+            let mut held = Vec::new();
+            loop {
+                held.push(upstream.accept().await.unwrap());
+            }
+        });
+
+        let args = Args::parse_from([
+            "tproxy",
+            "--listen-addr",
+            "127.0.0.1:0",
+            "--upstream-addr",
+            &upstream_addr.to_string(),
+            "--half-close-timeout",
+            "200ms",
+        ]);
+        let state = Arc::new(State::new());
+        let (listen_tx, listen_rx) = oneshot::channel();
+        let t2 = tokio::spawn(listen(args, state.clone(), listen_tx).map(|r| {
+            if let Err(err) = r {
+                println!("failed to listen; error={}", err);
+            }
+        }));
+        let listen_addr = listen_rx.await.unwrap();
+
+        let mut client = TcpStream::connect(listen_addr).await.unwrap();
+        client.shutdown().await.unwrap();
+        wait_for(&state, |s| s.half_closed_connections == 1).await;
+        let conn = &state.connections()[0];
+        assert_eq!(conn.summary()["half_closed"]["finished"], "up");
+
+        read_eof(&mut client).await;
+        wait_for(&state, |s| s.half_closed_connections == 0).await;
+        assert_eq!(state.snapshot().half_closed_reaped, 1);
+        assert_eq!(state.snapshot().active_connections, 0);
+
+        t1.abort();
+        t2.abort();
+    }
+
+    #[tokio::test]
+    async fn test_proxy_protocol() {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap().to_string();
+        let args = Args::parse_from([
+            "tproxy",
+            "--listen-addr",
+            "127.0.0.1:0",
+            "--upstream-addr",
+            &upstream_addr,
+            "--accept-proxy-protocol",
+            "--send-proxy-protocol",
+            "v2",
+        ]);
+        let state = Arc::new(State::new());
+        let (listen_tx, listen_rx) = oneshot::channel();
+        let t = tokio::spawn(listen(args, state.clone(), listen_tx).map(|r| {
+            if let Err(err) = r {
+                println!("failed to listen; error={}", err);
+            }
+        }));
+        let listen_addr = listen_rx.await.unwrap();
+
+        // The v1 header from a load balancer in front comes out as v2.
+        let mut client = TcpStream::connect(listen_addr).await.unwrap();
+        client
+            .write_all(b"PROXY TCP4 192.0.2.10 127.0.0.1 51234 80\r\nHello!")
+            .await
+            .unwrap();
+        client.shutdown().await.unwrap();
+        let (mut server, _) = upstream.accept().await.unwrap();
+        let mut received = Vec::new();
+        server.read_to_end(&mut received).await.unwrap();
+        let client_addr: SocketAddr = "192.0.2.10:51234".parse().unwrap();
+        let tags = BTreeMap::from([(
+            "proxy.peer".to_string(),
+            client.local_addr().unwrap().to_string(),
+        )]);
+        let header =
+            proxy_protocol::encode(proxy_protocol::Version::V2, client_addr, listen_addr, &tags);
+        assert_eq!(received[..header.len()], header[..]);
+        assert_eq!(&received[header.len()..], b"Hello!");
+        drop(server);
+        wait_for(&state, |s| s.completed_connections == 1).await;
+        let conn = &state.connections()[0];
+        assert_eq!(conn.downstream_addr, client_addr);
+        assert_eq!(conn.bytes_up.load(Ordering::Relaxed), 6);
+
+        // Tags from a v2 header are set on the connection and passed on.
+        let mut client = TcpStream::connect(listen_addr).await.unwrap();
+        let tags = BTreeMap::from([("team".to_string(), "db".to_string())]);
+        let header =
+            proxy_protocol::encode(proxy_protocol::Version::V2, client_addr, listen_addr, &tags);
+        client.write_all(&header).await.unwrap();
+        client.shutdown().await.unwrap();
+        let (mut server, _) = upstream.accept().await.unwrap();
+        let mut received = Vec::new();
+        server.read_to_end(&mut received).await.unwrap();
+        let forwarded = proxy_protocol::parse(&received).unwrap();
+        assert!(forwarded
+            .tags
+            .contains(&("team".to_string(), "db".to_string())));
+        drop(server);
+        wait_for(&state, |s| s.completed_connections == 2).await;
+        assert_eq!(state.connections()[1].tags()["team"], "db");
+
+        let mut client = TcpStream::connect(listen_addr).await.unwrap();
+        client.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        read_eof(&mut client).await;
+        assert_eq!(state.snapshot().proxy_header_errors, 1);
+        t.abort();
+    }
+
+    #[tokio::test]
+    async fn test_delay_and_rate_limit() {
+        let (echo_tx, echo_rx) = oneshot::channel();
+        let t1 = tokio::spawn(echo("127.0.0.1:0".to_string(), echo_tx).map(|r| {
+            if let Err(err) = r {
+                println!("failed to echo; error={}", err);
+            }
+        }));
+        let upstream_addr = echo_rx.await.unwrap();
+
+        let args = Args::parse_from([
+            "tproxy",
+            "--listen-addr",
+            "127.0.0.1:0",
+            "--upstream-addr",
+            &upstream_addr.to_string(),
+            "--delay",
+            "100ms",
+            "--delay-jitter",
+            "10ms",
+            "--toxics",
+            "down:latency=0ms",
+            "--rate-limit",
+            "1MBps",
+            "--rate-limit-down",
+            "8Mbps",
+        ]);
+        let state = Arc::new(State::new());
+        let (listen_tx, listen_rx) = oneshot::channel();
+        let t2 = tokio::spawn(listen(args, state.clone(), listen_tx).map(|r| {
+            if let Err(err) = r {
+                println!("failed to listen; error={}", err);
+            }
+        }));
+        let listen_addr = listen_rx.await.unwrap();
+        let defaults = *state.toxic_defaults.lock().unwrap();
+        assert_eq!(defaults.up.latency, Duration::from_millis(100));
+        assert_eq!(defaults.down.latency, Duration::ZERO);
+        assert_eq!(defaults.down.jitter, Duration::from_millis(10));
+        assert_eq!(defaults.up.rate, Some(1_000_000));
+        assert_eq!(defaults.down.rate, Some(1_000_000));
+
+        let start = Instant::now();
+        let mut client = TcpStream::connect(listen_addr).await.unwrap();
+        client.write_all(b"Hello!").await.unwrap();
+        let mut buf = [0; 6];
+        client.read_exact(&mut buf).await.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(90));
+
+        t1.abort();
+        t2.abort();
+    }
+
+    #[tokio::test]
+    async fn test_accept_delay() {
+        let (echo_tx, echo_rx) = oneshot::channel();
+        let t1 = tokio::spawn(echo("127.0.0.1:0".to_string(), echo_tx).map(|r| {
+            if let Err(err) = r {
+                println!("failed to echo; error={}", err);
+            }
+        }));
+        let upstream_addr = echo_rx.await.unwrap().to_string();
+
+        let args = Args::parse_from([
+            "tproxy",
+            "--listen-addr",
+            "127.0.0.1:0",
+            "--upstream-addr",
+            &upstream_addr,
+            "--accept-delay",
+            "200ms",
+        ]);
+        let state = Arc::new(State::new());
+        let (listen_tx, listen_rx) = oneshot::channel();
+        let t2 = tokio::spawn(listen(args, state.clone(), listen_tx).map(|r| {
+            if let Err(err) = r {
+                println!("failed to listen; error={}", err);
+            }
+        }));
+        let listen_addr = listen_rx.await.unwrap();
+
+        let start = Instant::now();
+        let mut client = TcpStream::connect(listen_addr).await.unwrap();
+        client.write_all(b"Hello!").await.unwrap();
+        while state.connections().is_empty() {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        // Accepted, but the upstream hasn't been dialed yet.
+        assert!(state.upstream_connections.get(&upstream_addr).is_none());
+        assert_eq!(state.snapshot().active_connections, 0);
+        let mut buf = [0; 6];
+        client.read_exact(&mut buf).await.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(200));
+
+        t1.abort();
+        t2.abort();
+    }
+
+    #[tokio::test]
+    async fn test_kill() {
+        let (echo_tx, echo_rx) = oneshot::channel();
+        let t1 = tokio::spawn(echo("127.0.0.1:0".to_string(), echo_tx).map(|r| {
+            if let Err(err) = r {
+                println!("failed to echo; error={}", err);
+            }
+        }));
+        let upstream_addr = echo_rx.await.unwrap().to_string();
+        let args = Args::parse_from([
+            "tproxy",
+            "--listen-addr",
+            "127.0.0.1:0",
+            "--upstream-addr",
+            &upstream_addr,
+        ]);
+        let state = Arc::new(State::new());
+        let (listen_tx, listen_rx) = oneshot::channel();
+        let t2 = tokio::spawn(listen(args, state.clone(), listen_tx).map(|r| {
+            if let Err(err) = r {
+                println!("failed to listen; error={}", err);
+            }
+        }));
+        let listen_addr = listen_rx.await.unwrap();
+
+        let mut client = TcpStream::connect(listen_addr).await.unwrap();
+        client.write_all(b"Hello!").await.unwrap();
+        let mut buf = [0; 6];
+        client.read_exact(&mut buf).await.unwrap();
+        let id = state.connections()[0].id;
+        let routes = api::routes(state.clone());
+        let path = format!("/api/connections/{}", id);
+        let resp = warp::test::request()
+            .method("DELETE")
+            .path(&path)
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), 200);
+        read_eof(&mut client).await;
+        wait_for(&state, |s| s.active_connections == 0).await;
+        let detail = state.connection(id).unwrap().detail();
+        assert_eq!(detail["close_reason"], "killed");
+
+        let resp = warp::test::request()
+            .method("DELETE")
+            .path(&path)
+            .reply(&routes)
+            .await;
+        assert_eq!(resp.status(), 409);
+        t1.abort();
+        t2.abort();
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout() {
+        let (echo_tx, echo_rx) = oneshot::channel();
+        let t1 = tokio::spawn(echo("127.0.0.1:0".to_string(), echo_tx).map(|r| {
+            if let Err(err) = r {
+                println!("failed to echo; error={}", err);
+            }
+        }));
+        let upstream_addr = echo_rx.await.unwrap().to_string();
+        let args = Args::parse_from([
+            "tproxy",
+            "--listen-addr",
+            "127.0.0.1:0",
+            "--upstream-addr",
+            &upstream_addr,
+            "--idle-timeout",
+            "300ms",
+        ]);
+        let state = Arc::new(State::new());
+        let (listen_tx, listen_rx) = oneshot::channel();
+        let t2 = tokio::spawn(listen(args, state.clone(), listen_tx).map(|r| {
+            if let Err(err) = r {
+                println!("failed to listen; error={}", err);
+            }
+        }));
+        let listen_addr = listen_rx.await.unwrap();
+
+        let mut client = TcpStream::connect(listen_addr).await.unwrap();
+        let start = Instant::now();
+        // Traffic keeps the connection open past the timeout.
+        for _ in 0..4 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            client.write_all(b"Hello!").await.unwrap();
+            let mut buf = [0; 6];
+            client.read_exact(&mut buf).await.unwrap();
+        }
+        read_eof(&mut client).await;
+        assert!(start.elapsed() >= Duration::from_millis(700));
+        wait_for(&state, |s| s.active_connections == 0).await;
+        let detail = state.connections()[0].detail();
+        assert_eq!(detail["close_reason"], "idle timeout");
+        t1.abort();
+        t2.abort();
+    }
+
+    #[tokio::test]
+    async fn test_max_conn_duration() {
+        let (echo_tx, echo_rx) = oneshot::channel();
+        let t1 = tokio::spawn(echo("127.0.0.1:0".to_string(), echo_tx).map(|r| {
+            if let Err(err) = r {
+                println!("failed to echo; error={}", err);
+            }
+        }));
+        let upstream_addr = echo_rx.await.unwrap().to_string();
+        let args = Args::parse_from([
+            "tproxy",
+            "--listen-addr",
+            "127.0.0.1:0",
+            "--upstream-addr",
+            &upstream_addr,
+            "--idle-timeout",
+            "1s",
+            "--max-conn-duration",
+            "300ms",
+        ]);
+        let state = Arc::new(State::new());
+        let (listen_tx, listen_rx) = oneshot::channel();
+        let t2 = tokio::spawn(listen(args, state.clone(), listen_tx).map(|r| {
+            if let Err(err) = r {
+                println!("failed to listen; error={}", err);
+            }
+        }));
+        let listen_addr = listen_rx.await.unwrap();
+
+        let mut client = TcpStream::connect(listen_addr).await.unwrap();
+        let start = Instant::now();
+        // Traffic doesn't keep the connection open past the limit.
+        for _ in 0..2 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            client.write_all(b"Hello!").await.unwrap();
+            let mut buf = [0; 6];
+            client.read_exact(&mut buf).await.unwrap();
+        }
+        read_eof(&mut client).await;
+        assert!(start.elapsed() < Duration::from_secs(1));
+        wait_for(&state, |s| s.active_connections == 0).await;
+        let detail = state.connections()[0].detail();
+        assert_eq!(detail["close_reason"], "max duration");
+        assert_eq!(state.snapshot().max_duration_closes, 1);
+        t1.abort();
+        t2.abort();
+    }
+
+    #[tokio::test]
+    async fn test_zone_upstream() {
+        let (echo_tx, echo_rx) = oneshot::channel();
+        let t1 = tokio::spawn(echo("127.0.0.1:0".to_string(), echo_tx).map(|r| {
+            if let Err(err) = r {
+                println!("failed to echo; error={}", err);
+            }
+        }));
+        let remote = format!("b={}", echo_rx.await.unwrap());
+        let args = Args::parse_from([
+            "tproxy",
+            "--listen-addr",
+            "127.0.0.1:0",
+            "--zone-upstream",
+            "a=127.0.0.1:1",
+            "--zone-upstream",
+            &remote,
+            "--zone",
+            "a",
+        ]);
+        let state = Arc::new(State::new());
+        let (listen_tx, listen_rx) = oneshot::channel();
+        let t2 = tokio::spawn(listen(args, state.clone(), listen_tx).map(|r| {
+            if let Err(err) = r {
+                println!("failed to listen; error={}", err);
+            }
+        }));
+        let listen_addr = listen_rx.await.unwrap();
+
+        // The local upstream is down, so once that's noticed traffic spills over.
+        let mut client = TcpStream::connect(listen_addr).await.unwrap();
+        read_eof(&mut client).await;
+        wait_for(&state, |s| s.active_connections == 0).await;
+        let mut client = TcpStream::connect(listen_addr).await.unwrap();
+        client.write_all(b"Hello!").await.unwrap();
+        let mut buf = [0; 6];
+        client.read_exact(&mut buf).await.unwrap();
+        drop(client);
+        wait_for(&state, |s| s.active_connections == 0).await;
+        while state.zone_traffic.get(&("a".into(), "b".into())).is_none() {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+
+        let metrics = metrics::render(&state);
+        assert!(metrics.contains("tproxy_zone_connections_total{from=\"a\",to=\"a\"} 1\n"));
+        assert!(metrics.contains("tproxy_cross_zone_bytes_total{direction=\"up\"} 6\n"));
+        let tags = state
+            .connections()
+            .iter()
+            .map(|c| c.tags())
+            .collect::<Vec<_>>();
+        assert!(tags.iter().any(|t| t["upstream.zone"] == "b"));
+        t1.abort();
+        t2.abort();
+    }
+
+    #[tokio::test]
+    async fn test_max_connections() {
+        let (echo_tx, echo_rx) = oneshot::channel();
+        let t1 = tokio::spawn(echo("127.0.0.1:0".to_string(), echo_tx).map(|r| {
+            if let Err(err) = r {
+                println!("failed to echo; error={}", err);
+            }
+        }));
+        let upstream_addr = echo_rx.await.unwrap().to_string();
+        let state = Arc::new(State::new());
+        let mut tasks = vec![t1];
+        let mut addrs = Vec::new();
+        for policy in ["queue", "reject"] {
+            let args = Args::parse_from([
+                "tproxy",
+                "--listen-addr",
+                "127.0.0.1:0",
+                "--upstream-addr",
+                &upstream_addr,
+                "--max-connections",
+                "1",
+                "--overflow-policy",
+                policy,
+            ]);
+            let (listen_tx, listen_rx) = oneshot::channel();
+            tasks.push(tokio::spawn(listen(args, state.clone(), listen_tx).map(
+                |r| {
+                    if let Err(err) = r {
+                        println!("failed to listen; error={}", err);
+                    }
+                },
+            )));
+            addrs.push(listen_rx.await.unwrap());
+        }
+        async fn echoes(client: &mut TcpStream) -> bool {
+            client.write_all(b"Hello!").await.unwrap();
+            let mut buf = [0; 6];
+            let read = client.read_exact(&mut buf);
+            matches!(
+                tokio::time::timeout(Duration::from_millis(200), read).await,
+                Ok(Ok(_))
+            )
+        }
+
+        // The second client waits in the backlog until the first is done.
+        let mut first = TcpStream::connect(addrs[0]).await.unwrap();
+        assert!(echoes(&mut first).await);
+        let mut second = TcpStream::connect(addrs[0]).await.unwrap();
+        assert!(!echoes(&mut second).await);
+        drop(first);
+        let mut buf = [0; 6];
+        second.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"Hello!");
+
+        // Or it's turned away.
+        let mut first = TcpStream::connect(addrs[1]).await.unwrap();
+        assert!(echoes(&mut first).await);
+        let mut second = TcpStream::connect(addrs[1]).await.unwrap();
+        read_eof(&mut second).await;
+        assert!(echoes(&mut first).await);
+        assert_eq!(state.snapshot().overflow_rejections, 1);
+        for task in tasks {
+            task.abort();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connect_retries() {
+        let state = State::new();
+        let args = |extra: &[&str]| {
+            let mut argv = vec![
+                "tproxy",
+                "--listen-addr",
+                "127.0.0.1:0",
+                "--upstream-addr",
+                "x",
+            ];
+            argv.extend_from_slice(extra);
+            Args::parse_from(argv)
+        };
+
+        // The upstream comes up between retries.
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let upstream = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(150)).await;
+            let listener = TcpListener::bind(addr).await.unwrap();
+            listener.accept().await.unwrap()
+        });
+        let retrying = args(&["--connect-retries", "5"]);
+        connect_upstream(&addr.to_string(), &retrying, &state)
+            .await
+            .unwrap();
+        upstream.await.unwrap();
+        assert!(state.snapshot().connect_retries >= 1);
+        assert_eq!(state.snapshot().connect_errors, 0);
+
+        // A full accept queue drops SYNs, so connects hang until they time out.
+        let socket = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
+        socket
+            .bind(&"127.0.0.1:0".parse::<SocketAddr>().unwrap().into())
+            .unwrap();
+        socket.listen(0).unwrap();
+        let addr = socket.local_addr().unwrap().as_socket().unwrap();
+        let _queued = std::net::TcpStream::connect(addr).unwrap();
+        let start = Instant::now();
+        let err = connect_upstream(
+            &addr.to_string(),
+            &args(&["--connect-timeout", "100ms"]),
+            &state,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(start.elapsed() < Duration::from_secs(1));
+        let snapshot = state.snapshot();
+        assert_eq!(snapshot.connect_timeouts, 1);
+        assert_eq!(snapshot.connect_errors, 1);
+    }
+
+    #[tokio::test]
+    async fn test_serve_admin() {
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = taken.local_addr().unwrap();
+        let admin = tokio::spawn(serve_admin(addr, warp::any().map(|| "ok")));
+        // The first attempt fails, and a retry binds once the port is free.
+        tokio::time::sleep(ADMIN_BIND_BACKOFF / 2).await;
+        drop(taken);
+        tokio::time::sleep(ADMIN_BIND_BACKOFF * 2).await;
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(b"GET / HTTP/1.0\r\n\r\n").await.unwrap();
+        let mut resp = String::new();
+        client.read_to_string(&mut resp).await.unwrap();
+        assert!(resp.ends_with("ok"), "{}", resp);
+        admin.abort();
+    }
+
+    #[tokio::test]
+    async fn test_round_robin() {
+        let mut tasks = Vec::new();
+        let mut upstreams = Vec::new();
+        for _ in 0..2 {
+            let (echo_tx, echo_rx) = oneshot::channel();
+            tasks.push(tokio::spawn(echo("127.0.0.1:0".to_string(), echo_tx).map(
+                |r| {
+                    if let Err(err) = r {
+                        println!("failed to echo; error={}", err);
+                    }
+                },
+            )));
+            upstreams.push(echo_rx.await.unwrap().to_string());
+        }
+        let upstream_addr = upstreams.join(",");
+        let args = Args::parse_from([
+            "tproxy",
+            "--listen-addr",
+            "127.0.0.1:0",
+            "--upstream-addr",
+            &upstream_addr,
+        ]);
+        let state = Arc::new(State::new());
+        let (listen_tx, listen_rx) = oneshot::channel();
+        tasks.push(tokio::spawn(listen(args, state.clone(), listen_tx).map(
+            |r| {
+                if let Err(err) = r {
+                    println!("failed to listen; error={}", err);
+                }
+            },
+        )));
+        let listen_addr = listen_rx.await.unwrap();
+
+        for _ in 0..4 {
+            let mut client = TcpStream::connect(listen_addr).await.unwrap();
+            client.write_all(b"Hello!").await.unwrap();
+            let mut buf = [0; 6];
+            client.read_exact(&mut buf).await.unwrap();
+        }
+        let totals = state.snapshot().upstream_connections_total;
+        assert_eq!(totals[&upstreams[0]], 2);
+        assert_eq!(totals[&upstreams[1]], 2);
+        assert!(metrics::render(&state).contains(&format!(
+            "tproxy_upstream_connections_total{{upstream=\"{}\"}} 2\n",
+            upstreams[0]
+        )));
+        for task in tasks {
+            task.abort();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_eject() {
+        let dead = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let dead_addr = dead.local_addr().unwrap().to_string();
+        drop(dead);
+        let (echo_tx, echo_rx) = oneshot::channel();
+        let echo_task = tokio::spawn(echo("127.0.0.1:0".to_string(), echo_tx).map(|r| {
+            if let Err(err) = r {
+                println!("failed to echo; error={}", err);
+            }
+        }));
+        let live_addr = echo_rx.await.unwrap().to_string();
+        let upstream_addr = format!("{},{}", dead_addr, live_addr);
+        let args = Args::parse_from([
+            "tproxy",
+            "--listen-addr",
+            "127.0.0.1:0",
+            "--upstream-addr",
+            &upstream_addr,
+            "--eject-after",
+            "1",
+        ]);
+        let state = Arc::new(State::new());
+        let (listen_tx, listen_rx) = oneshot::channel();
+        let listen_task = tokio::spawn(listen(args, state.clone(), listen_tx).map(|r| {
+            if let Err(err) = r {
+                println!("failed to listen; error={}", err);
+            }
+        }));
+        let listen_addr = listen_rx.await.unwrap();
+
+        // The first connection goes to the dead upstream and ejects it.
+        let mut client = TcpStream::connect(listen_addr).await.unwrap();
+        let mut buf = [0; 6];
+        assert!(client.read(&mut buf).await.unwrap_or(0) == 0);
+        assert!(breaker::is_ejected(&state, &dead_addr));
+        for _ in 0..3 {
+            let mut client = TcpStream::connect(listen_addr).await.unwrap();
+            client.write_all(b"Hello!").await.unwrap();
+            client.read_exact(&mut buf).await.unwrap();
+        }
+        assert_eq!(state.snapshot().upstream_connections_total[&live_addr], 3);
+        assert!(metrics::render(&state).contains(&format!(
+            "tproxy_upstream_ejected{{upstream=\"{}\"}} 1\n",
+            dead_addr
+        )));
+        listen_task.abort();
+        echo_task.abort();
+    }
+
+    #[tokio::test]
+    async fn test_drop_and_reset() {
+        let state = Arc::new(State::new());
+        let mut tasks = Vec::new();
+        let mut addrs = Vec::new();
+        for flag in ["--drop-probability", "--reset-probability"] {
+            let args = Args::parse_from([
+                "tproxy",
+                "--listen-addr",
+                "127.0.0.1:0",
+                "--upstream-addr",
+                "127.0.0.1:1",
+                flag,
+                "1",
+            ]);
+            let (listen_tx, listen_rx) = oneshot::channel();
+            tasks.push(tokio::spawn(listen(args, state.clone(), listen_tx).map(
+                |r| {
+                    if let Err(err) = r {
+                        println!("failed to listen; error={}", err);
+                    }
+                },
+            )));
+            addrs.push(listen_rx.await.unwrap());
+        }
+
+        let mut client = TcpStream::connect(addrs[0]).await.unwrap();
+        client.write_all(b"Hello!").await.unwrap();
+        let mut buf = [0; 6];
+        assert_eq!(client.read(&mut buf).await.unwrap(), 0);
+
+        let mut client = TcpStream::connect(addrs[1]).await.unwrap();
+        client.write_all(b"Hello!").await.unwrap();
+        let err = client.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+
+        let snapshot = state.snapshot();
+        assert_eq!(snapshot.drops_injected, 1);
+        assert_eq!(snapshot.resets_injected, 1);
+        assert!(Args::try_parse_from(["tproxy", "--drop-probability", "1.5"]).is_err());
+        for task in tasks {
+            task.abort();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_client_quotas() {
+        let (echo_tx, echo_rx) = oneshot::channel();
+        let t1 = tokio::spawn(echo("127.0.0.1:0".to_string(), echo_tx).map(|r| {
+            if let Err(err) = r {
+                println!("failed to echo; error={}", err);
+            }
+        }));
+        let upstream_addr = echo_rx.await.unwrap();
+
+        let args = Args::parse_from([
+            "tproxy",
+            "--listen-addr",
+            "127.0.0.1:0",
+            "--upstream-addr",
+            &upstream_addr.to_string(),
+            "--client-max-connections",
+            "1",
+            "--client-max-bytes-per-hour",
+            "10",
+        ]);
+        let state = Arc::new(State::new());
+        let (listen_tx, listen_rx) = oneshot::channel();
+        let t2 = tokio::spawn(listen(args, state.clone(), listen_tx).map(|r| {
+            if let Err(err) = r {
+                println!("failed to listen; error={}", err);
+            }
+        }));
+        let listen_addr = listen_rx.await.unwrap();
+
+        let mut client1 = TcpStream::connect(listen_addr).await.unwrap();
+        client1.write_all(b"Hello!").await.unwrap();
+        let mut buf = [0; 6];
+        client1.read_exact(&mut buf).await.unwrap();
+
+        let mut client2 = TcpStream::connect(listen_addr).await.unwrap();
+        read_eof(&mut client2).await;
+        assert_eq!(state.snapshot().quota_connection_rejections, 1);
+
+        // 12 bytes moved, so the next connection is over the byte quota.
+        client1.shutdown().await.unwrap();
+        read_eof(&mut client1).await;
+        while state.quotas.to_json()[0]["connections"] != 0 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        let mut client3 = TcpStream::connect(listen_addr).await.unwrap();
+        read_eof(&mut client3).await;
+        assert_eq!(state.snapshot().quota_byte_rejections, 1);
+
+        t1.abort();
+        t2.abort();
+    }
+
+    #[tokio::test]
+    async fn test_routes() {
+        let mut upstreams = Vec::new();
+        for _ in 0..2 {
+            let (echo_tx, echo_rx) = oneshot::channel();
+            tokio::spawn(echo("127.0.0.1:0".to_string(), echo_tx).map(|r| {
+                if let Err(err) = r {
+                    println!("failed to echo; error={}", err);
+                }
+            }));
+            upstreams.push(echo_rx.await.unwrap());
+        }
+
+        let pg = format!("pg=127.0.0.1:0->{}", upstreams[0]);
+        let redis = format!("redis=127.0.0.1:0->{}", upstreams[1]);
+        let args = Args::parse_from([
+            "tproxy",
+            "--route",
+            &pg,
+            "--route",
+            &redis,
+            "--route-toxics",
+            "redis=down:latency=20ms",
+            "--client-network",
+            "loopback=127.0.0.0/8",
+            "--metrics-tag-key",
+            "route",
+            "--metrics-tag-key",
+            "client_network",
+        ]);
+        let state = Arc::new(State::new());
+        let mut args = args;
+        let addrs = start_listeners(&mut args, &state).await.unwrap().addrs();
+        assert_eq!(addrs.len(), 2);
+
+        for (addr, message) in addrs.iter().zip([&b"pg"[..], b"redis"]) {
+            let mut client = TcpStream::connect(addr).await.unwrap();
+            client.write_all(message).await.unwrap();
+            client.shutdown().await.unwrap();
+            let mut buf = Vec::new();
+            client.read_to_end(&mut buf).await.unwrap();
+            assert_eq!(buf, message);
+        }
+        wait_for(&state, |s| s.completed_connections == 2).await;
+        let conns = state.connections();
+        assert_eq!(conns[0].upstream_addr, upstreams[0].to_string());
+        assert_eq!(conns[1].tags()["route"], "redis");
+        assert_eq!(conns[0].toxics().down.latency, Duration::ZERO);
+        assert_eq!(conns[1].toxics().down.latency, Duration::from_millis(20));
+
+        // Stats are recorded just after the connection is counted as completed.
+        while state.routes.get(&"redis".to_string()).unwrap().connections == 0 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        let redis = state.routes.get(&"redis".to_string()).unwrap();
+        assert_eq!(redis.active_connections, 0);
+        assert_eq!(redis.bytes_up, 5);
+        assert_eq!(redis.bytes_down, 5);
+        let tagged = state
+            .tagged
+            .get(&"route=\"redis\",client_network=\"loopback\"".to_string());
+        assert_eq!(tagged.unwrap().connections, 1);
+        let segment = state
+            .segments
+            .get(&"listener=\"redis\",client_network=\"loopback\"".to_string())
+            .unwrap();
+        assert_eq!(segment.active_connections.load(Ordering::Relaxed), 0);
+        assert_eq!(segment.connect_latency.count(), 1);
+
+        let mut args = Args::parse_from(["tproxy", "--listen-addr", "127.0.0.1:0"]);
+        assert!(start_listeners(&mut args, &state).await.is_err());
+        let mut args = Args::parse_from(["tproxy", "--route", &pg, "--route-toxics", "redis=lte"]);
+        assert!(start_listeners(&mut args, &state).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_transparent() {
+        let (echo_tx, echo_rx) = oneshot::channel();
+        let t1 = tokio::spawn(echo("127.0.0.1:0".to_string(), echo_tx).map(|r| {
+            if let Err(err) = r {
+                println!("failed to echo; error={}", err);
+            }
+        }));
+        let upstream_addr = echo_rx.await.unwrap();
+
+        let args = Args::parse_from([
+            "tproxy",
+            "--listen-addr",
+            "127.0.0.1:0",
+            "--upstream-addr",
+            &upstream_addr.to_string(),
+            "--transparent",
+        ]);
+        let state = Arc::new(State::new());
+        let (listen_tx, listen_rx) = oneshot::channel();
+        let t2 = tokio::spawn(listen(args, state.clone(), listen_tx).map(|r| {
+            if let Err(err) = r {
+                println!("failed to listen; error={}", err);
+            }
+        }));
+        let listen_addr = listen_rx.await.unwrap();
+
+        // Connecting directly isn't redirected, so it goes to --upstream-addr.
+        let mut client = TcpStream::connect(listen_addr).await.unwrap();
+        client.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 5];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+
+        let entry = state.connections()[0].nat_entry().unwrap();
+        assert_eq!(
+            entry["downstream_addr"],
+            client.local_addr().unwrap().to_string()
+        );
+        assert!(entry["original_dst"].is_null());
+        assert_eq!(entry["upstream_addr"], upstream_addr.to_string());
+        assert!(entry["source_addr"].is_string());
+        assert!(entry["closed_at"].is_null());
+
+        t1.abort();
+        t2.abort();
+    }
+
+    #[tokio::test]
+    async fn test_schedule() {
+        let (echo_tx, echo_rx) = oneshot::channel();
+        let t1 = tokio::spawn(echo("127.0.0.1:0".to_string(), echo_tx).map(|r| {
+            if let Err(err) = r {
+                println!("failed to echo; error={}", err);
+            }
+        }));
+        let upstream_addr = echo_rx.await.unwrap();
+
+        let args = Args::parse_from([
+            "tproxy",
+            "--listen-addr",
+            "127.0.0.1:0",
+            "--upstream-addr",
+            &upstream_addr.to_string(),
+            "--schedule",
+            "flap:200ms/200ms",
+        ]);
+        let state = Arc::new(State::new());
+        let (listen_tx, listen_rx) = oneshot::channel();
+        let t2 = tokio::spawn(listen(args, state.clone(), listen_tx).map(|r| {
+            if let Err(err) = r {
+                println!("failed to listen; error={}", err);
+            }
+        }));
+        let listen_addr = listen_rx.await.unwrap();
+
+        let mut client = TcpStream::connect(listen_addr).await.unwrap();
+        client.write_all(b"Hello!").await.unwrap();
+        wait_for(&state, |s| s.listener_closed).await;
+        let err = TcpStream::connect(listen_addr).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+        // Connections accepted while open carry on.
+        let mut buf = [0; 6];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"Hello!");
+
+        wait_for(&state, |s| !s.listener_closed).await;
+        TcpStream::connect(listen_addr).await.unwrap();
+
+        t1.abort();
+        t2.abort();
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("250ms"), Ok(Duration::from_millis(250)));
+        assert_eq!(parse_duration("3s"), Ok(Duration::from_secs(3)));
+        assert_eq!(parse_duration("5m"), Ok(Duration::from_secs(300)));
+        assert_eq!(parse_duration("1h"), Ok(Duration::from_secs(3600)));
+        assert_eq!(parse_duration("0"), Ok(Duration::ZERO));
+        assert!(parse_duration("ms").is_err());
+        assert!(parse_duration("5d").is_err());
+        assert_eq!(
+            parse_duration("99999999999999999h"),
+            Err("duration too large".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_connect_rate() {
+        assert_eq!(parse_connect_rate("0"), Ok(0.0));
+        assert_eq!(parse_connect_rate("0.5"), Ok(0.5));
+        assert_eq!(parse_connect_rate("1000"), Ok(1000.0));
+        for rate in ["-1", "1e-30", "inf", "NaN", "fast"] {
+            assert!(parse_connect_rate(rate).is_err(), "{}", rate);
+        }
+    }
+
+    /// Wait for the proxy to close its side of the connection. A proxy which closes
+    /// without reading everything we sent resets the connection instead.
+    async fn read_eof(stream: &mut TcpStream) {
+        let mut buf = [0; 16];
+        match stream.read(&mut buf).await {
+            Ok(n) => assert_eq!(n, 0),
+            Err(err) => assert_eq!(err.kind(), io::ErrorKind::ConnectionReset),
+        }
+    }
+
+    /// Poll `state` until `f` holds. The proxy updates its counters just after closing
+    /// the downstream, so there is a short window where a client has seen EOF but the
+    /// counters haven't caught up yet.
+    pub(crate) async fn wait_for(state: &State, f: impl Fn(&Snapshot) -> bool) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !f(&state.snapshot()) {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .expect("timed out waiting for state");
+    }
+
+    async fn echo(
+        addr: String,
+        ready: oneshot::Sender<SocketAddr>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let listener = TcpListener::bind(&addr).await?;
+        let _ = ready.send(listener.local_addr()?);
+
+        loop {
+            let (mut socket, _) = listener.accept().await?;
+
+            tokio::spawn(async move {
+                let mut buf = [0; 1024];
+
+                loop {
+                    let n = match socket.read(&mut buf).await {
+                        Ok(0) => return,
+                        Ok(n) => n,
+                        Err(e) => {
+                            eprintln!("failed to read from socket; err = {:?}", e);
+                            return;
+                        }
+                    };
+
+                    if let Err(e) = socket.write_all(&buf[0..n]).await {
+                        eprintln!("failed to write to socket; err = {:?}", e);
+                        return;
+                    }
+                }
+            });
+        }
+    }
+}
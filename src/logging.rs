@@ -0,0 +1,339 @@
+//! Logs, as `tracing` events written one per line to stdout.
+//!
+//! Each connection runs in a `connection` span carrying its ID, peer and upstream, and
+//! every event inside it is logged with those fields too. `--log-format text` writes
+//! `LEVEL message; key=value ...`, and `--log-format json` writes an object per line
+//! for log aggregation. Events from dependencies such as hyper are only logged at warn
+//! and above, whatever `--log-level` is.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Write};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::{Map, Value};
+use tracing::field::{Field, Visit};
+use tracing::level_filters::LevelFilter;
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Level, Metadata, Subscriber};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("unknown log format: {}", s)),
+        }
+    }
+}
+
+/// Log through a [`Logger`] writing to stdout from now on.
+pub fn init(format: Format, level: LevelFilter) -> Result<(), Box<dyn std::error::Error>> {
+    let logger = Logger::new(format, level, Box::new(io::stdout()));
+    tracing::subscriber::set_global_default(logger)?;
+    Ok(())
+}
+
+thread_local! {
+    /// The spans entered on this thread, innermost last.
+    static ENTERED: RefCell<Vec<Id>> = const { RefCell::new(Vec::new()) };
+}
+
+struct Span {
+    parent: Option<Id>,
+    fields: Fields,
+    refs: usize,
+}
+
+pub struct Logger {
+    format: Format,
+    level: LevelFilter,
+    next_id: AtomicU64,
+    spans: Mutex<HashMap<u64, Span>>,
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+impl Logger {
+    pub fn new(format: Format, level: LevelFilter, out: Box<dyn Write + Send>) -> Self {
+        Logger {
+            format,
+            level,
+            next_id: AtomicU64::new(1),
+            spans: Default::default(),
+            out: Mutex::new(out),
+        }
+    }
+
+    /// The fields of `id` and the spans it's in, outermost first.
+    fn span_fields(&self, id: Option<Id>, fields: &mut Fields) {
+        let spans = self.spans.lock().unwrap();
+        let mut chain = Vec::new();
+        let mut next = id;
+        while let Some(span) = next.and_then(|id| spans.get(&id.into_u64())) {
+            chain.push(span);
+            next = span.parent.clone();
+        }
+        for span in chain.into_iter().rev() {
+            fields.extend(span.fields.0.clone());
+        }
+    }
+
+    fn format(&self, level: &Level, target: &str, message: Value, fields: Fields) -> String {
+        match self.format {
+            Format::Text => {
+                let message = match message {
+                    Value::String(s) => s,
+                    message => message.to_string(),
+                };
+                let mut line = format!("{} {}", level, message);
+                for (i, (key, value)) in fields.0.iter().enumerate() {
+                    line.push_str(if i == 0 { "; " } else { " " });
+                    match value {
+                        Value::String(s) => line += &format!("{}={}", key, s),
+                        value => line += &format!("{}={}", key, value),
+                    }
+                }
+                line
+            }
+            Format::Json => {
+                let unix_ms = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_millis() as u64;
+                let mut line = Map::new();
+                line.insert("unix_ms".into(), unix_ms.into());
+                line.insert("level".into(), level.to_string().into());
+                line.insert("target".into(), target.into());
+                line.insert("message".into(), message);
+                for (key, value) in fields.0 {
+                    line.entry(key.to_string()).or_insert(value);
+                }
+                Value::Object(line).to_string()
+            }
+        }
+    }
+}
+
+fn current() -> Option<Id> {
+    ENTERED.with(|entered| entered.borrow().last().cloned())
+}
+
+impl Subscriber for Logger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        let ours = metadata.target().starts_with(env!("CARGO_CRATE_NAME"));
+        let level = if ours {
+            self.level
+        } else {
+            self.level.min(LevelFilter::WARN)
+        };
+        level >= *metadata.level()
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        Some(self.level)
+    }
+
+    fn new_span(&self, attrs: &Attributes<'_>) -> Id {
+        let parent = if attrs.is_contextual() {
+            current()
+        } else {
+            attrs.parent().cloned()
+        };
+        let mut fields = Fields::default();
+        attrs.record(&mut fields);
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.spans.lock().unwrap().insert(
+            id,
+            Span {
+                parent,
+                fields,
+                refs: 1,
+            },
+        );
+        Id::from_u64(id)
+    }
+
+    fn record(&self, id: &Id, values: &Record<'_>) {
+        let mut fields = Fields::default();
+        values.record(&mut fields);
+        if let Some(span) = self.spans.lock().unwrap().get_mut(&id.into_u64()) {
+            span.fields.extend(fields.0);
+        }
+    }
+
+    fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let mut fields = Fields::default();
+        event.record(&mut fields);
+        let message = fields.remove("message").unwrap_or_default();
+        let parent = if event.is_contextual() {
+            current()
+        } else {
+            event.parent().cloned()
+        };
+        let mut all = Fields::default();
+        self.span_fields(parent, &mut all);
+        all.extend(fields.0);
+        let metadata = event.metadata();
+        let line = self.format(metadata.level(), metadata.target(), message, all);
+        let mut out = self.out.lock().unwrap();
+        let _ = writeln!(out, "{}", line);
+    }
+
+    fn enter(&self, id: &Id) {
+        ENTERED.with(|entered| entered.borrow_mut().push(id.clone()));
+    }
+
+    fn exit(&self, id: &Id) {
+        ENTERED.with(|entered| {
+            let mut entered = entered.borrow_mut();
+            if let Some(i) = entered.iter().rposition(|e| e == id) {
+                entered.remove(i);
+            }
+        });
+    }
+
+    fn clone_span(&self, id: &Id) -> Id {
+        if let Some(span) = self.spans.lock().unwrap().get_mut(&id.into_u64()) {
+            span.refs += 1;
+        }
+        id.clone()
+    }
+
+    fn try_close(&self, id: Id) -> bool {
+        let mut spans = self.spans.lock().unwrap();
+        match spans.get_mut(&id.into_u64()) {
+            Some(span) if span.refs > 1 => {
+                span.refs -= 1;
+                false
+            }
+            Some(_) => {
+                spans.remove(&id.into_u64());
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Field values as JSON, in the order they were recorded: numbers and booleans as
+/// themselves, anything else as a string.
+#[derive(Clone, Default)]
+struct Fields(Vec<(&'static str, Value)>);
+
+impl Fields {
+    /// Add `fields`, replacing any with the same name.
+    fn extend(&mut self, fields: Vec<(&'static str, Value)>) {
+        for (name, value) in fields {
+            self.insert(name, value);
+        }
+    }
+
+    fn insert(&mut self, name: &'static str, value: Value) {
+        match self.0.iter_mut().find(|(n, _)| *n == name) {
+            Some((_, v)) => *v = value,
+            None => self.0.push((name, value)),
+        }
+    }
+
+    fn remove(&mut self, name: &str) -> Option<Value> {
+        let i = self.0.iter().position(|(n, _)| *n == name)?;
+        Some(self.0.remove(i).1)
+    }
+}
+
+impl Visit for Fields {
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.insert(field.name(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.insert(field.name(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.insert(field.name(), value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.insert(field.name(), value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.insert(field.name(), format!("{:?}", value).into());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn log(format: Format, level: LevelFilter) -> Vec<String> {
+        let buffer = Buffer::default();
+        let logger = Logger::new(format, level, Box::new(buffer.clone()));
+        tracing::subscriber::with_default(logger, || {
+            let span = tracing::info_span!("connection", id = 7u64, peer = "127.0.0.1:1234");
+            let _entered = span.enter();
+            tracing::info!("connection opened");
+            tracing::debug!("hidden");
+            tracing::warn!(error = %"refused", "failed to connect upstream");
+            tracing::warn!(target: "hyper", "not ours");
+            tracing::info!(target: "hyper", "not ours either");
+        });
+        let out = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        out.lines().map(String::from).collect()
+    }
+
+    #[test]
+    fn test_text() {
+        assert_eq!(
+            log(Format::Text, LevelFilter::INFO),
+            [
+                "INFO connection opened; id=7 peer=127.0.0.1:1234",
+                "WARN failed to connect upstream; id=7 peer=127.0.0.1:1234 error=refused",
+                "WARN not ours; id=7 peer=127.0.0.1:1234",
+            ]
+        );
+        assert_eq!(log(Format::Text, LevelFilter::ERROR), Vec::<String>::new());
+        assert_eq!(log(Format::Text, LevelFilter::DEBUG).len(), 4);
+    }
+
+    #[test]
+    fn test_json() {
+        let lines = log(Format::Json, LevelFilter::INFO);
+        let line: Value = serde_json::from_str(&lines[1]).unwrap();
+        assert_eq!(line["level"], "WARN");
+        assert_eq!(line["message"], "failed to connect upstream");
+        assert_eq!(line["id"], 7);
+        assert_eq!(line["error"], "refused");
+        assert!(line["unix_ms"].is_u64());
+    }
+}
@@ -0,0 +1,397 @@
+//! STARTTLS aware proxying for mail protocols (`--mode smtp` and `--mode imap`).
+//!
+//! Until a connection upgrades to TLS these protocols are line based, so lines are
+//! read in both directions to follow the STARTTLS negotiation. With `--starttls
+//! pass` the upgrade goes ahead and the connection is tagged `starttls=upgraded`
+//! (or `refused`), after which bytes are copied verbatim. With `--starttls strip`
+//! STARTTLS is removed from the server's advertised capabilities and a client that
+//! asks for it anyway is refused without involving the server, which tests that
+//! clients insist on TLS when they should.
+
+use std::error::Error;
+use std::io;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+
+use crate::connection::{self, Connection, Direction};
+use crate::state::State;
+use crate::{connect_upstream, register_sockets, track_close, track_open, Args, Mode};
+
+/// Lines longer than this aren't part of a STARTTLS negotiation; stop looking.
+const MAX_LINE: u64 = 8 * 1024;
+
+/// What to do when a client and server negotiate STARTTLS.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StartTls {
+    Pass,
+    Strip,
+}
+
+impl FromStr for StartTls {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pass" => Ok(StartTls::Pass),
+            "strip" => Ok(StartTls::Strip),
+            _ => Err(format!("unknown starttls policy: {}", s)),
+        }
+    }
+}
+
+/// Read a line including its `\n`, or whatever was available before end of stream
+/// or `MAX_LINE`.
+async fn read_line<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    line: &mut Vec<u8>,
+) -> io::Result<usize> {
+    line.clear();
+    reader.take(MAX_LINE).read_until(b'\n', line).await
+}
+
+fn words(line: &[u8]) -> impl Iterator<Item = &[u8]> {
+    line.split(|b| b.is_ascii_whitespace())
+        .filter(|w| !w.is_empty())
+}
+
+fn is_starttls_command(mode: Mode, line: &[u8]) -> bool {
+    let mut words = words(line);
+    if mode == Mode::Imap {
+        // Skip the tag.
+        words.next();
+    }
+    words
+        .next()
+        .is_some_and(|w| w.eq_ignore_ascii_case(b"STARTTLS"))
+        && words.next().is_none()
+}
+
+/// What we answer in the server's place when refusing STARTTLS.
+fn refusal(mode: Mode, command: &[u8]) -> Vec<u8> {
+    match mode {
+        Mode::Imap => {
+            let tag = words(command).next().unwrap_or(b"*");
+            let mut reply = tag.to_vec();
+            reply.extend_from_slice(b" BAD STARTTLS not available\r\n");
+            reply
+        }
+        _ => b"454 4.7.0 TLS not available\r\n".to_vec(),
+    }
+}
+
+/// Whether a reply line is the last of an SMTP reply (`250 ...` rather than `250-...`).
+fn is_final_smtp_line(line: &[u8]) -> bool {
+    line.get(3) != Some(&b'-')
+}
+
+/// Remove a `STARTTLS` line from a complete (possibly multi-line) SMTP reply.
+fn strip_smtp(reply: &[u8]) -> Vec<u8> {
+    let mut lines: Vec<Vec<u8>> = reply
+        .split_inclusive(|b| *b == b'\n')
+        .filter(|line| {
+            !line
+                .get(4..)
+                .is_some_and(|text| text.trim_ascii().eq_ignore_ascii_case(b"STARTTLS"))
+        })
+        .map(|line| line.to_vec())
+        .collect();
+    // If the final line went, the new last line must say it's the last.
+    if let Some(last) = lines.last_mut() {
+        if last.len() > 3 && last[3] == b'-' {
+            last[3] = b' ';
+        }
+    }
+    lines.concat()
+}
+
+/// Remove the STARTTLS capability from an IMAP response line, wherever a
+/// capability list appears (untagged CAPABILITY or a `[CAPABILITY ...]` code).
+fn strip_imap(line: &[u8]) -> Vec<u8> {
+    let upper = line.to_ascii_uppercase();
+    if !words(&upper).any(|w| w.starts_with(b"CAPABILITY") || w.starts_with(b"[CAPABILITY")) {
+        return line.to_vec();
+    }
+    let needle = b" STARTTLS";
+    let mut out = Vec::with_capacity(line.len());
+    let mut i = 0;
+    while i < line.len() {
+        let end = i + needle.len();
+        let at_token = upper.get(i..end) == Some(&needle[..])
+            && matches!(upper.get(end), None | Some(b' ' | b']' | b'\r' | b'\n'));
+        if at_token {
+            i = end;
+        } else {
+            out.push(line[i]);
+            i += 1;
+        }
+    }
+    out
+}
+
+/// Whether the server accepted STARTTLS, given a reply line, or `None` if the line
+/// isn't the reply to it.
+fn accepted(mode: Mode, line: &[u8]) -> Option<bool> {
+    match mode {
+        Mode::Imap => {
+            let mut words = words(line);
+            if words.next()? == b"*" {
+                return None;
+            }
+            Some(words.next()?.eq_ignore_ascii_case(b"OK"))
+        }
+        _ => Some(line.starts_with(b"220")),
+    }
+}
+
+fn tag(conn: &Connection, value: &str) {
+    conn.set_tags([("starttls".to_string(), Some(value.to_string()))]);
+}
+
+pub async fn proxy(
+    mut downstream: TcpStream,
+    args: &Args,
+    state: &State,
+    conn: &Connection,
+) -> Result<(), Box<dyn Error>> {
+    let connect_start = Instant::now();
+    let mut upstream = connect_upstream(&args.upstream_addr, args, state).await?;
+    state.observe_connect(conn, connect_start.elapsed());
+    conn.connected();
+    track_open(state, conn);
+    let _sockets = register_sockets(state, conn, &downstream, &upstream);
+    let (ri, wi) = downstream.split();
+    let (ro, mut wo) = upstream.split();
+    let mut ri = BufReader::new(ri);
+    let mut ro = BufReader::new(ro);
+    // Both directions write to the client: replies, and our own refusals.
+    let wi = Mutex::new(wi);
+    let requested = AtomicBool::new(false);
+    let mode = args.mode;
+
+    let client_to_server = async {
+        let mut line = Vec::new();
+        while read_line(&mut ri, &mut line).await? > 0 {
+            if line.ends_with(b"\n") && is_starttls_command(mode, &line) {
+                if args.starttls == StartTls::Strip {
+                    let reply = refusal(mode, &line);
+                    wi.lock().await.write_all(&reply).await?;
+                    conn.add_bytes(Direction::Down, reply.len() as u64);
+                    tag(conn, "stripped");
+                    continue;
+                }
+                // Set before forwarding, so the reply can't be read before we know
+                // to expect it.
+                requested.store(true, Ordering::Relaxed);
+                tag(conn, "requested");
+            }
+            wo.write_all(&line).await?;
+            conn.add_bytes(Direction::Up, line.len() as u64);
+            if requested.load(Ordering::Relaxed) || !line.ends_with(b"\n") {
+                break;
+            }
+        }
+        connection::copy(&mut ri, &mut wo, conn, Direction::Up, |_| {}).await?;
+        wo.shutdown().await
+    };
+
+    let server_to_client = async {
+        let mut line = Vec::new();
+        let mut reply = Vec::new();
+        while read_line(&mut ro, &mut line).await? > 0 {
+            let complete = line.ends_with(b"\n");
+            let out = match mode {
+                Mode::Imap if args.starttls == StartTls::Strip => strip_imap(&line),
+                Mode::Imap => line.clone(),
+                _ => {
+                    reply.extend_from_slice(&line);
+                    if complete && !is_final_smtp_line(&line) {
+                        continue;
+                    }
+                    let whole = std::mem::take(&mut reply);
+                    if args.starttls == StartTls::Strip {
+                        strip_smtp(&whole)
+                    } else {
+                        whole
+                    }
+                }
+            };
+            wi.lock().await.write_all(&out).await?;
+            conn.add_bytes(Direction::Down, out.len() as u64);
+            if !complete {
+                break;
+            }
+            if requested.load(Ordering::Relaxed) {
+                match accepted(mode, &line) {
+                    Some(true) => {
+                        tag(conn, "upgraded");
+                        break;
+                    }
+                    Some(false) => {
+                        tag(conn, "refused");
+                        requested.store(false, Ordering::Relaxed);
+                    }
+                    None => {}
+                }
+            }
+        }
+        let mut wi = wi.lock().await;
+        connection::copy(&mut ro, &mut *wi, conn, Direction::Down, |_| {}).await?;
+        wi.shutdown().await
+    };
+
+    let sample = async {
+        let mut interval = tokio::time::interval(connection::SAMPLE_INTERVAL);
+        loop {
+            interval.tick().await;
+            conn.sample();
+        }
+    };
+
+    let result = tokio::select! {
+        result = async { tokio::try_join!(client_to_server, server_to_client) } => result,
+        _ = sample => unreachable!(),
+    };
+
+    track_close(state, conn, result.is_ok());
+    result?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use clap::Parser;
+    use futures::FutureExt;
+    use tokio::net::TcpListener;
+    use tokio::sync::oneshot;
+
+    #[test]
+    fn test_strip_capabilities() {
+        assert_eq!(
+            strip_smtp(b"250-mx\r\n250-PIPELINING\r\n250 STARTTLS\r\n"),
+            b"250-mx\r\n250 PIPELINING\r\n"
+        );
+        assert_eq!(
+            strip_smtp(b"250-mx\r\n250-STARTTLS\r\n250 8BITMIME\r\n"),
+            b"250-mx\r\n250 8BITMIME\r\n"
+        );
+        assert_eq!(
+            strip_imap(b"* OK [CAPABILITY IMAP4rev1 STARTTLS LOGINDISABLED] ready\r\n"),
+            b"* OK [CAPABILITY IMAP4rev1 LOGINDISABLED] ready\r\n"
+        );
+        assert_eq!(
+            strip_imap(b"* CAPABILITY IMAP4rev1 starttls\r\n"),
+            b"* CAPABILITY IMAP4rev1\r\n"
+        );
+        assert_eq!(
+            strip_imap(b"* 1 FETCH (BODY STARTTLS)\r\n"),
+            b"* 1 FETCH (BODY STARTTLS)\r\n"
+        );
+        assert!(is_starttls_command(Mode::Imap, b"a1 starttls\r\n"));
+        assert!(is_starttls_command(Mode::Smtp, b"STARTTLS\r\n"));
+        assert!(!is_starttls_command(
+            Mode::Smtp,
+            b"MAIL FROM:<starttls>\r\n"
+        ));
+    }
+
+    /// A minimal SMTP server which, after accepting STARTTLS, echoes raw bytes.
+    async fn smtp_server(listener: TcpListener) {
+        let (stream, _) = listener.accept().await.unwrap();
+        let (r, mut w) = stream.into_split();
+        let mut r = BufReader::new(r);
+        w.write_all(b"220 mx ESMTP\r\n").await.unwrap();
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if r.read_line(&mut line).await.unwrap() == 0 {
+                return;
+            }
+            if line.starts_with("EHLO") {
+                w.write_all(b"250-mx\r\n250-PIPELINING\r\n250 STARTTLS\r\n")
+                    .await
+                    .unwrap();
+            } else if line.starts_with("STARTTLS") {
+                w.write_all(b"220 go ahead\r\n").await.unwrap();
+                let mut buf = [0; 3];
+                r.read_exact(&mut buf).await.unwrap();
+                w.write_all(&buf).await.unwrap();
+                return;
+            }
+        }
+    }
+
+    async fn start(policy: &str) -> (BufReader<TcpStream>, Arc<State>) {
+        let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = server.local_addr().unwrap();
+        tokio::spawn(smtp_server(server));
+        let args = Args::parse_from([
+            "tproxy",
+            "--listen-addr",
+            "127.0.0.1:0",
+            "--upstream-addr",
+            &server_addr.to_string(),
+            "--mode",
+            "smtp",
+            "--starttls",
+            policy,
+        ]);
+        let state = Arc::new(State::new());
+        let (tx, rx) = oneshot::channel();
+        tokio::spawn(crate::listen(args, state.clone(), tx).map(|r| {
+            if let Err(err) = r {
+                println!("failed to listen; error={}", err);
+            }
+        }));
+        let client = TcpStream::connect(rx.await.unwrap()).await.unwrap();
+        (BufReader::new(client), state)
+    }
+
+    async fn reply(client: &mut BufReader<TcpStream>) -> String {
+        let mut reply = String::new();
+        loop {
+            let mut line = String::new();
+            client.read_line(&mut line).await.unwrap();
+            reply.push_str(&line);
+            if is_final_smtp_line(line.as_bytes()) {
+                return reply;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pass() {
+        let (mut client, state) = start("pass").await;
+        assert_eq!(reply(&mut client).await, "220 mx ESMTP\r\n");
+        client.write_all(b"EHLO me\r\n").await.unwrap();
+        assert!(reply(&mut client).await.contains("STARTTLS"));
+        client.write_all(b"STARTTLS\r\n").await.unwrap();
+        assert_eq!(reply(&mut client).await, "220 go ahead\r\n");
+        // What would be a TLS handshake is now copied verbatim.
+        client.write_all(b"\x16\x03\x01").await.unwrap();
+        let mut buf = [0; 3];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"\x16\x03\x01");
+        assert_eq!(state.connections()[0].tags()["starttls"], "upgraded");
+    }
+
+    #[tokio::test]
+    async fn test_strip() {
+        let (mut client, state) = start("strip").await;
+        reply(&mut client).await;
+        client.write_all(b"EHLO me\r\n").await.unwrap();
+        assert_eq!(reply(&mut client).await, "250-mx\r\n250 PIPELINING\r\n");
+        client.write_all(b"STARTTLS\r\n").await.unwrap();
+        assert!(reply(&mut client).await.starts_with("454"));
+        assert_eq!(state.connections()[0].tags()["starttls"], "stripped");
+    }
+}
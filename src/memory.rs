@@ -0,0 +1,123 @@
+//! Memory usage reporting for `GET /debug/memory`.
+//!
+//! With the `alloc-stats` feature the global allocator is wrapped to count live
+//! bytes and allocations. Independently of that, the report estimates how much
+//! memory each subsystem holds, which is what usually matters in long soaks.
+
+#[cfg(feature = "alloc-stats")]
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::Ordering;
+#[cfg(feature = "alloc-stats")]
+use std::sync::atomic::{AtomicU64, AtomicUsize};
+use std::sync::Arc;
+
+use serde_json::{json, Value};
+use warp::{Filter, Rejection, Reply};
+
+use crate::connection::COPY_BUFFER_SIZE;
+use crate::state::State;
+
+/// The system allocator, counting what passes through it.
+#[cfg(feature = "alloc-stats")]
+pub struct CountingAllocator;
+
+#[cfg(feature = "alloc-stats")]
+static ALLOCATED_BYTES: AtomicUsize = AtomicUsize::new(0);
+#[cfg(feature = "alloc-stats")]
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+#[cfg(feature = "alloc-stats")]
+static DEALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+#[cfg(feature = "alloc-stats")]
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            ALLOCATED_BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+            ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        ALLOCATED_BYTES.fetch_sub(layout.size(), Ordering::Relaxed);
+        DEALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new = System.realloc(ptr, layout, new_size);
+        if !new.is_null() {
+            ALLOCATED_BYTES.fetch_sub(layout.size(), Ordering::Relaxed);
+            ALLOCATED_BYTES.fetch_add(new_size, Ordering::Relaxed);
+        }
+        new
+    }
+}
+
+#[cfg(feature = "alloc-stats")]
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+/// Allocator counters, or `null` when built without `alloc-stats`.
+#[cfg(not(feature = "alloc-stats"))]
+fn allocator() -> Value {
+    Value::Null
+}
+
+#[cfg(feature = "alloc-stats")]
+fn allocator() -> Value {
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let deallocations = DEALLOCATIONS.load(Ordering::Relaxed);
+    json!({
+        "allocated_bytes": ALLOCATED_BYTES.load(Ordering::Relaxed),
+        "allocations_total": allocations,
+        "deallocations_total": deallocations,
+        "live_allocations": allocations.saturating_sub(deallocations),
+    })
+}
+
+pub fn report(state: &State) -> Value {
+    let conns = state.connections();
+    let records: usize = conns.iter().map(|c| c.estimated_size()).sum();
+    // Each open connection has a copy buffer per direction.
+    let active = state.active_connections.load(Ordering::Relaxed);
+    json!({
+        "allocator": allocator(),
+        "estimates": {
+            "connection_records": { "count": conns.len(), "bytes": records },
+            "copy_buffers": { "count": active * 2, "bytes": active * 2 * COPY_BUFFER_SIZE },
+        },
+    })
+}
+
+pub fn routes(
+    state: Arc<State>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("debug" / "memory")
+        .and(warp::get())
+        .map(move || warp::reply::json(&report(&state)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report() {
+        let state = State::new();
+        state.open_connection("127.0.0.1:1234".parse().unwrap(), "up:80".into());
+        let report = report(&state);
+        assert_eq!(report["estimates"]["connection_records"]["count"], 1);
+        assert!(
+            report["estimates"]["connection_records"]["bytes"]
+                .as_u64()
+                .unwrap()
+                > 0
+        );
+        assert_eq!(
+            report["allocator"].is_null(),
+            !cfg!(feature = "alloc-stats")
+        );
+    }
+}
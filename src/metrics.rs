@@ -0,0 +1,841 @@
+//! Prometheus metrics served on the debug server at `/metrics`.
+
+use std::fmt::Write;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use warp::{Filter, Rejection, Reply};
+
+use crate::state::State;
+
+/// Default bounds for latency histograms, in seconds.
+pub const DEFAULT_LATENCY_BUCKETS: &str = "0.005,0.01,0.025,0.05,0.1,0.25,0.5,1,2.5,5,10";
+
+/// Default bounds for size histograms, in bytes.
+pub const DEFAULT_SIZE_BUCKETS: &str =
+    "256,1024,4096,16384,65536,262144,1048576,4194304,16777216,67108864,268435456,1073741824";
+
+/// Histogram bucket upper bounds, parsed from a comma-separated list such as `0.1,0.5,1`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Buckets(pub Vec<f64>);
+
+impl FromStr for Buckets {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bounds = s
+            .split(',')
+            .map(|b| {
+                b.trim()
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|b| b.is_finite())
+                    .ok_or_else(|| format!("invalid bucket bound: {}", b))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if bounds.windows(2).any(|w| w[0] >= w[1]) {
+            return Err("bucket bounds must be strictly increasing".to_string());
+        }
+        Ok(Buckets(bounds))
+    }
+}
+
+/// Escape a Prometheus label value.
+pub fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Check that `name` can be used as a Prometheus label name.
+pub fn parse_label_name(name: &str) -> Result<String, String> {
+    let mut chars = name.chars();
+    let valid = matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if valid {
+        Ok(name.to_string())
+    } else {
+        Err(format!("invalid label name: {}", name))
+    }
+}
+
+#[derive(Debug)]
+pub struct Histogram {
+    bounds: Vec<f64>,
+    /// One count per bound, plus a final one for `+Inf`. Not cumulative.
+    counts: Vec<AtomicU64>,
+    /// Bits of the `f64` sum of all observations.
+    sum: AtomicU64,
+}
+
+impl Histogram {
+    pub fn new(buckets: Buckets) -> Self {
+        let counts = (0..=buckets.0.len()).map(|_| AtomicU64::new(0)).collect();
+        Self {
+            bounds: buckets.0,
+            counts,
+            sum: AtomicU64::new(0f64.to_bits()),
+        }
+    }
+
+    pub fn observe(&self, value: f64) {
+        let i = self
+            .bounds
+            .iter()
+            .position(|b| value <= *b)
+            .unwrap_or(self.bounds.len());
+        self.counts[i].fetch_add(1, Ordering::Relaxed);
+        let _ = self
+            .sum
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+                Some((f64::from_bits(bits) + value).to_bits())
+            });
+    }
+
+    /// The number of observations so far.
+    #[cfg(test)]
+    pub fn count(&self) -> u64 {
+        self.counts.iter().map(|c| c.load(Ordering::Relaxed)).sum()
+    }
+
+    /// Write the `_bucket`, `_sum` and `_count` series. `labels` is either empty or a
+    /// comma-terminated list such as `direction="up",`.
+    pub fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let mut cumulative = 0;
+        for (bound, count) in self.bounds.iter().zip(&self.counts) {
+            cumulative += count.load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{}le=\"{}\"}} {}",
+                name, labels, bound, cumulative
+            );
+        }
+        cumulative += self.counts[self.bounds.len()].load(Ordering::Relaxed);
+        let _ = writeln!(
+            out,
+            "{}_bucket{{{}le=\"+Inf\"}} {}",
+            name, labels, cumulative
+        );
+        let labels = labels.trim_end_matches(',');
+        let sum = f64::from_bits(self.sum.load(Ordering::Relaxed));
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, sum);
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, cumulative);
+    }
+}
+
+/// Render every metric in the Prometheus text exposition format.
+pub fn render(state: &State) -> String {
+    let mut out = String::new();
+    // The per-connection metrics are labelled by listener and client network.
+    let mut segments: Vec<_> = state.segments.snapshot().into_iter().collect();
+    segments.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let name = "tproxy_active_connections";
+    header(&mut out, name, "gauge", "Connections being proxied.");
+    for (labels, segment) in &segments {
+        let active = segment.active_connections.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}{{{}}} {}", name, labels, active);
+    }
+    let name = "tproxy_completed_connections_total";
+    header(
+        &mut out,
+        name,
+        "counter",
+        "Connections which finished without an error.",
+    );
+    for (labels, segment) in &segments {
+        let completed = segment.completed_connections.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}{{{}}} {}", name, labels, completed);
+    }
+
+    let name = "tproxy_bytes_total";
+    header(
+        &mut out,
+        name,
+        "counter",
+        "Bytes copied by every connection, by direction.",
+    );
+    let mut bytes: Vec<_> = state.bytes_by_segment().into_iter().collect();
+    bytes.sort();
+    for (labels, (up, down)) in bytes {
+        let _ = writeln!(out, "{}{{{},direction=\"up\"}} {}", name, labels, up);
+        let _ = writeln!(out, "{}{{{},direction=\"down\"}} {}", name, labels, down);
+    }
+
+    let name = "tproxy_accept_errors_total";
+    header(&mut out, name, "counter", "Errors accepting connections.");
+    let _ = writeln!(
+        out,
+        "{} {}",
+        name,
+        state.accept_errors.load(Ordering::Relaxed)
+    );
+    let name = "tproxy_upstream_connect_errors_total";
+    header(
+        &mut out,
+        name,
+        "counter",
+        "Upstream connects which failed, after any retries.",
+    );
+    let _ = writeln!(
+        out,
+        "{} {}",
+        name,
+        state.connect_errors.load(Ordering::Relaxed)
+    );
+
+    let name = "tproxy_upstream_connect_timeouts_total";
+    header(
+        &mut out,
+        name,
+        "counter",
+        "Upstream connect attempts which took longer than --connect-timeout.",
+    );
+    let _ = writeln!(
+        out,
+        "{} {}",
+        name,
+        state.connect_timeouts.load(Ordering::Relaxed)
+    );
+
+    let name = "tproxy_upstream_ejected_connects_total";
+    header(
+        &mut out,
+        name,
+        "counter",
+        "Upstream connects failed fast because the upstream was ejected.",
+    );
+    let _ = writeln!(
+        out,
+        "{} {}",
+        name,
+        state.ejected_connects.load(Ordering::Relaxed)
+    );
+
+    let name = "tproxy_faults_injected_total";
+    header(&mut out, name, "counter", "Faults injected, by kind.");
+    for (kind, counter) in [
+        ("connect", &state.connect_faults_injected),
+        ("drop", &state.drops_injected),
+        ("reset", &state.resets_injected),
+        ("http", &state.http_faults_injected),
+        ("dns", &state.dns_faults_injected),
+        ("udp", &state.udp_faults_injected),
+    ] {
+        let _ = writeln!(
+            out,
+            "{}{{kind=\"{}\"}} {}",
+            name,
+            kind,
+            counter.load(Ordering::Relaxed)
+        );
+    }
+
+    let name = "tproxy_upstream_connect_duration_seconds";
+    header(
+        &mut out,
+        name,
+        "histogram",
+        "Time taken to connect to the upstream.",
+    );
+    for (labels, segment) in &segments {
+        let labels = format!("{},", labels);
+        segment.connect_latency.render(&mut out, name, &labels);
+    }
+
+    let name = "tproxy_connection_size_bytes";
+    header(
+        &mut out,
+        name,
+        "histogram",
+        "Bytes copied per connection and direction.",
+    );
+    for (labels, segment) in &segments {
+        let up = format!("{},direction=\"up\",", labels);
+        segment.connection_size_up.render(&mut out, name, &up);
+        let down = format!("{},direction=\"down\",", labels);
+        segment.connection_size_down.render(&mut out, name, &down);
+    }
+
+    let name = "tproxy_half_closed_connections";
+    header(
+        &mut out,
+        name,
+        "gauge",
+        "Active connections with one direction finished.",
+    );
+    let _ = writeln!(
+        out,
+        "{} {}",
+        name,
+        state.half_closed_connections.load(Ordering::Relaxed)
+    );
+    let name = "tproxy_half_closed_reaped_total";
+    header(
+        &mut out,
+        name,
+        "counter",
+        "Connections closed for staying half-closed past --half-close-timeout.",
+    );
+    let _ = writeln!(
+        out,
+        "{} {}",
+        name,
+        state.half_closed_reaped.load(Ordering::Relaxed)
+    );
+
+    let name = "tproxy_upstream_connects_paced_total";
+    header(
+        &mut out,
+        name,
+        "counter",
+        "Upstream connects delayed by --upstream-connect-rate.",
+    );
+    let _ = writeln!(
+        out,
+        "{} {}",
+        name,
+        state.paced_connects.load(Ordering::Relaxed)
+    );
+
+    let name = "tproxy_upstream_connect_retries_total";
+    header(
+        &mut out,
+        name,
+        "counter",
+        "Upstream connects retried, after EADDRNOTAVAIL or --connect-retries.",
+    );
+    let _ = writeln!(
+        out,
+        "{} {}",
+        name,
+        state.connect_retries.load(Ordering::Relaxed)
+    );
+    let name = "tproxy_retry_budget_exhausted_total";
+    header(
+        &mut out,
+        name,
+        "counter",
+        "Upstream connect retries refused by --retry-budget.",
+    );
+    let _ = writeln!(
+        out,
+        "{} {}",
+        name,
+        state.retry_budget_exhausted.load(Ordering::Relaxed)
+    );
+    let name = "tproxy_retry_budget_usage_ratio";
+    header(
+        &mut out,
+        name,
+        "gauge",
+        "The share of --retry-budget used over the last 10s.",
+    );
+    let _ = writeln!(out, "{} {}", name, state.retry_budget.usage());
+
+    let name = "tproxy_quota_rejections_total";
+    header(
+        &mut out,
+        name,
+        "counter",
+        "Connections rejected for being over a client quota, by quota.",
+    );
+    for (quota, counter) in [
+        ("connections", &state.quota_connection_rejections),
+        ("bytes", &state.quota_byte_rejections),
+    ] {
+        let _ = writeln!(
+            out,
+            "{}{{quota=\"{}\"}} {}",
+            name,
+            quota,
+            counter.load(Ordering::Relaxed)
+        );
+    }
+
+    let tagged = state.tagged.snapshot();
+    if !tagged.is_empty() {
+        let mut tagged: Vec<_> = tagged.into_iter().collect();
+        tagged.sort_by(|a, b| a.0.cmp(&b.0));
+        let name = "tproxy_tagged_connections_total";
+        header(&mut out, name, "counter", "Closed connections by tag.");
+        for (labels, stats) in &tagged {
+            let _ = writeln!(out, "{}{{{}}} {}", name, labels, stats.connections);
+        }
+        let name = "tproxy_tagged_bytes_total";
+        header(
+            &mut out,
+            name,
+            "counter",
+            "Bytes copied by closed connections, by tag.",
+        );
+        for (labels, stats) in &tagged {
+            let _ = writeln!(
+                out,
+                "{}{{{},direction=\"up\"}} {}",
+                name, labels, stats.bytes_up
+            );
+            let _ = writeln!(
+                out,
+                "{}{{{},direction=\"down\"}} {}",
+                name, labels, stats.bytes_down
+            );
+        }
+    }
+
+    let routes = state.routes.snapshot();
+    if !routes.is_empty() {
+        let mut routes: Vec<_> = routes.into_iter().collect();
+        routes.sort_by(|a, b| a.0.cmp(&b.0));
+        let name = "tproxy_route_active_connections";
+        header(&mut out, name, "gauge", "Open connections by route.");
+        for (route, stats) in &routes {
+            let route = escape_label(route);
+            let _ = writeln!(
+                out,
+                "{}{{route=\"{}\"}} {}",
+                name, route, stats.active_connections
+            );
+        }
+        let name = "tproxy_route_connections_total";
+        header(&mut out, name, "counter", "Closed connections by route.");
+        for (route, stats) in &routes {
+            let route = escape_label(route);
+            let _ = writeln!(out, "{}{{route=\"{}\"}} {}", name, route, stats.connections);
+        }
+        let name = "tproxy_route_bytes_total";
+        header(
+            &mut out,
+            name,
+            "counter",
+            "Bytes copied by closed connections, by route.",
+        );
+        for (route, stats) in &routes {
+            let route = escape_label(route);
+            let _ = writeln!(
+                out,
+                "{}{{route=\"{}\",direction=\"up\"}} {}",
+                name, route, stats.bytes_up
+            );
+            let _ = writeln!(
+                out,
+                "{}{{route=\"{}\",direction=\"down\"}} {}",
+                name, route, stats.bytes_down
+            );
+        }
+    }
+
+    let hosts = state.http_connect_hosts.snapshot();
+    if !hosts.is_empty() {
+        let mut hosts: Vec<_> = hosts.into_iter().collect();
+        hosts.sort_by(|a, b| a.0.cmp(&b.0));
+        let name = "tproxy_http_connect_active_tunnels";
+        header(
+            &mut out,
+            name,
+            "gauge",
+            "Open CONNECT tunnels by destination host.",
+        );
+        for (host, stats) in &hosts {
+            let host = escape_label(host);
+            let _ = writeln!(
+                out,
+                "{}{{host=\"{}\"}} {}",
+                name, host, stats.active_tunnels
+            );
+        }
+        let name = "tproxy_http_connect_requests_total";
+        header(
+            &mut out,
+            name,
+            "counter",
+            "CONNECT requests by destination host and result: tunnelled, denied or failed.",
+        );
+        for (host, stats) in &hosts {
+            let host = escape_label(host);
+            for (result, count) in [
+                ("tunnelled", stats.tunnels),
+                ("denied", stats.denied),
+                ("failed", stats.failed),
+            ] {
+                let _ = writeln!(
+                    out,
+                    "{}{{host=\"{}\",result=\"{}\"}} {}",
+                    name, host, result, count
+                );
+            }
+        }
+        let name = "tproxy_http_connect_bytes_total";
+        header(
+            &mut out,
+            name,
+            "counter",
+            "Bytes copied by closed CONNECT tunnels, by destination host.",
+        );
+        for (host, stats) in &hosts {
+            let host = escape_label(host);
+            let _ = writeln!(
+                out,
+                "{}{{host=\"{}\",direction=\"up\"}} {}",
+                name, host, stats.bytes_up
+            );
+            let _ = writeln!(
+                out,
+                "{}{{host=\"{}\",direction=\"down\"}} {}",
+                name, host, stats.bytes_down
+            );
+        }
+    }
+
+    let kafka = state.kafka_requests.snapshot();
+    if !kafka.is_empty() {
+        let mut kafka: Vec<_> = kafka.into_iter().collect();
+        kafka.sort();
+        let name = "tproxy_kafka_requests_total";
+        header(&mut out, name, "counter", "Kafka requests by API key.");
+        for (key, count) in kafka {
+            let _ = writeln!(
+                out,
+                "{}{{api_key=\"{}\",api=\"{}\"}} {}",
+                name,
+                key,
+                crate::kafka::api_name(key),
+                count
+            );
+        }
+    }
+
+    let fingerprints = state.tls_fingerprints.snapshot();
+    if !fingerprints.is_empty() {
+        let mut fingerprints: Vec<_> = fingerprints.into_iter().collect();
+        fingerprints.sort();
+        let name = "tproxy_tls_fingerprint_connections_total";
+        header(
+            &mut out,
+            name,
+            "counter",
+            "TLS connections by JA3 and JA4 fingerprint of their ClientHello.",
+        );
+        for ((ja3, ja4), count) in fingerprints {
+            let _ = writeln!(out, "{}{{ja3=\"{}\",ja4=\"{}\"}} {}", name, ja3, ja4, count);
+        }
+    }
+
+    let zones = state.zone_traffic.snapshot();
+    if !zones.is_empty() {
+        let mut zones: Vec<_> = zones.into_iter().collect();
+        zones.sort();
+        let name = "tproxy_zone_connections_total";
+        header(
+            &mut out,
+            name,
+            "counter",
+            "Closed --zone-upstream connections, by the zone they went from and to.",
+        );
+        for ((from, to), (connections, _, _)) in &zones {
+            let _ = writeln!(
+                out,
+                "{}{{from=\"{}\",to=\"{}\"}} {}",
+                name,
+                escape_label(from),
+                escape_label(to),
+                connections
+            );
+        }
+        let name = "tproxy_zone_bytes_total";
+        header(
+            &mut out,
+            name,
+            "counter",
+            "Bytes copied by closed --zone-upstream connections, by the zone they went from and to.",
+        );
+        let (mut cross_up, mut cross_down) = (0, 0);
+        for ((from, to), (_, up, down)) in &zones {
+            for (direction, bytes) in [("up", up), ("down", down)] {
+                let _ = writeln!(
+                    out,
+                    "{}{{from=\"{}\",to=\"{}\",direction=\"{}\"}} {}",
+                    name,
+                    escape_label(from),
+                    escape_label(to),
+                    direction,
+                    bytes
+                );
+            }
+            if from != to {
+                cross_up += up;
+                cross_down += down;
+            }
+        }
+        let name = "tproxy_cross_zone_bytes_total";
+        header(
+            &mut out,
+            name,
+            "counter",
+            "Bytes copied by closed --zone-upstream connections to another zone.",
+        );
+        for (direction, bytes) in [("up", cross_up), ("down", cross_down)] {
+            let _ = writeln!(out, "{}{{direction=\"{}\"}} {}", name, direction, bytes);
+        }
+    }
+
+    let ssh = state.ssh_client_versions.snapshot();
+    if !ssh.is_empty() {
+        let mut ssh: Vec<_> = ssh.into_iter().collect();
+        ssh.sort();
+        let name = "tproxy_ssh_connections_total";
+        header(
+            &mut out,
+            name,
+            "counter",
+            "SSH connections by client software version.",
+        );
+        for (version, count) in ssh {
+            let _ = writeln!(
+                out,
+                "{}{{client_version=\"{}\"}} {}",
+                name,
+                escape_label(&version),
+                count
+            );
+        }
+    }
+
+    let countries = state.geo_countries.snapshot();
+    if !countries.is_empty() {
+        let mut countries: Vec<_> = countries.into_iter().collect();
+        countries.sort();
+        let name = "tproxy_geo_connections_total";
+        header(&mut out, name, "counter", "Connections by client country.");
+        for (country, count) in countries {
+            let _ = writeln!(
+                out,
+                "{}{{country=\"{}\"}} {}",
+                name,
+                escape_label(&country),
+                count
+            );
+        }
+    }
+
+    let mut upstreams: Vec<_> = state
+        .upstream_connections_total
+        .snapshot()
+        .into_iter()
+        .collect();
+    if !upstreams.is_empty() {
+        upstreams.sort();
+        let name = "tproxy_upstream_connections_total";
+        header(
+            &mut out,
+            name,
+            "counter",
+            "Connections forwarded to each upstream.",
+        );
+        for (upstream, count) in upstreams {
+            let _ = writeln!(
+                out,
+                "{}{{upstream=\"{}\"}} {}",
+                name,
+                escape_label(&upstream),
+                count
+            );
+        }
+    }
+
+    let mut circuits: Vec<_> = state.circuits.snapshot().into_iter().collect();
+    if !circuits.is_empty() {
+        circuits.sort_by(|a, b| a.0.cmp(&b.0));
+        let now = std::time::Instant::now();
+        let name = "tproxy_upstream_ejected";
+        header(
+            &mut out,
+            name,
+            "gauge",
+            "Whether each upstream is ejected by --eject-after.",
+        );
+        for (upstream, circuit) in &circuits {
+            let _ = writeln!(
+                out,
+                "{}{{upstream=\"{}\"}} {}",
+                name,
+                escape_label(upstream),
+                circuit.is_ejected(now) as u8
+            );
+        }
+        let name = "tproxy_upstream_ejections_total";
+        header(
+            &mut out,
+            name,
+            "counter",
+            "Times each upstream has been ejected by --eject-after.",
+        );
+        for (upstream, circuit) in &circuits {
+            let _ = writeln!(
+                out,
+                "{}{{upstream=\"{}\"}} {}",
+                name,
+                escape_label(upstream),
+                circuit.ejections
+            );
+        }
+    }
+
+    let probes = state.probes.snapshot();
+    if !probes.is_empty() {
+        let mut probes: Vec<_> = probes.into_iter().collect();
+        probes.sort_by(|a, b| a.0.cmp(&b.0));
+        let name = "tproxy_upstream_probe_rtt_seconds";
+        header(
+            &mut out,
+            name,
+            "gauge",
+            "Time taken by the latest successful probe to connect to each upstream.",
+        );
+        for (upstream, probe) in &probes {
+            if let Ok(rtt) = probe.result {
+                let _ = writeln!(
+                    out,
+                    "{}{{upstream=\"{}\"}} {}",
+                    name,
+                    escape_label(upstream),
+                    rtt.as_secs_f64()
+                );
+            }
+        }
+        let name = "tproxy_upstream_probe_success";
+        header(
+            &mut out,
+            name,
+            "gauge",
+            "Whether the latest probe of each upstream connected.",
+        );
+        for (upstream, probe) in &probes {
+            let _ = writeln!(
+                out,
+                "{}{{upstream=\"{}\"}} {}",
+                name,
+                escape_label(upstream),
+                probe.result.is_ok() as u8
+            );
+        }
+    }
+
+    #[cfg(feature = "runtime-metrics")]
+    crate::runtime_metrics::render(&mut out, state);
+
+    out
+}
+
+pub fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+pub fn routes(
+    state: Arc<State>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("metrics")
+        .and(warp::get())
+        .map(move || render(&state))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_buckets() {
+        assert_eq!("0.1, 1,10".parse(), Ok(Buckets(vec![0.1, 1.0, 10.0])));
+        assert!("1,1".parse::<Buckets>().is_err());
+        assert!("2,1".parse::<Buckets>().is_err());
+        assert!("1,x".parse::<Buckets>().is_err());
+        assert!(DEFAULT_LATENCY_BUCKETS.parse::<Buckets>().is_ok());
+        assert!(DEFAULT_SIZE_BUCKETS.parse::<Buckets>().is_ok());
+    }
+
+    #[test]
+    fn test_labels() {
+        assert_eq!(escape_label("a\"b\\c\n"), "a\\\"b\\\\c\\n");
+        assert!(parse_label_name("team_1").is_ok());
+        assert!(parse_label_name("1team").is_err());
+        assert!(parse_label_name("te-am").is_err());
+    }
+
+    #[test]
+    fn test_tagged() {
+        let state = State::new();
+        let conn = state.open_connection("127.0.0.1:1234".parse().unwrap(), "up:80".into());
+        conn.set_tags([("team".to_string(), Some("db".to_string()))]);
+        conn.bytes_up.fetch_add(3, Ordering::Relaxed);
+        state.record_tags(&conn, &["team".to_string(), "env".to_string()]);
+        let out = render(&state);
+        assert!(out.contains("tproxy_tagged_connections_total{team=\"db\",env=\"\"} 1\n"));
+        assert!(
+            out.contains("tproxy_tagged_bytes_total{team=\"db\",env=\"\",direction=\"up\"} 3\n")
+        );
+    }
+
+    #[test]
+    fn test_totals() {
+        let state = State::new();
+        let open = state.open_connection("127.0.0.1:1234".parse().unwrap(), "up:80".into());
+        open.bytes_up.fetch_add(5, Ordering::Relaxed);
+        let closed = state.open_connection("127.0.0.1:1235".parse().unwrap(), "up:80".into());
+        closed.bytes_down.fetch_add(7, Ordering::Relaxed);
+        state.close_connection(&closed, "completed".into());
+        state.connect_errors.fetch_add(1, Ordering::Relaxed);
+        crate::track_open(&state, &open);
+        let office = state.open_connection("10.1.0.1:1236".parse().unwrap(), "up:80".into());
+        office.set_tags([
+            ("route".to_string(), Some("pg".to_string())),
+            ("client_network".to_string(), Some("office".to_string())),
+        ]);
+        crate::track_open(&state, &office);
+        crate::track_close(&state, &office, true);
+        let out = render(&state);
+        let labels = "listener=\"default\",client_network=\"other\"";
+        assert!(out.contains(&format!("tproxy_active_connections{{{}}} 1\n", labels)));
+        assert!(out.contains(&format!(
+            "tproxy_bytes_total{{{},direction=\"up\"}} 5\n",
+            labels
+        )));
+        assert!(out.contains(&format!(
+            "tproxy_bytes_total{{{},direction=\"down\"}} 7\n",
+            labels
+        )));
+        let office = "listener=\"pg\",client_network=\"office\"";
+        assert!(out.contains(&format!("tproxy_active_connections{{{}}} 0\n", office)));
+        assert!(out.contains(&format!(
+            "tproxy_completed_connections_total{{{}}} 1\n",
+            office
+        )));
+        assert!(out.contains(&format!(
+            "tproxy_connection_size_bytes_count{{{},direction=\"up\"}} 1\n",
+            office
+        )));
+        assert!(out.contains("tproxy_upstream_connect_errors_total 1\n"));
+        assert!(out.contains("tproxy_faults_injected_total{kind=\"drop\"} 0\n"));
+    }
+
+    #[test]
+    fn test_histogram() {
+        let h = Histogram::new("1,10".parse().unwrap());
+        h.observe(0.5);
+        h.observe(1.0);
+        h.observe(5.0);
+        h.observe(50.0);
+        let mut out = String::new();
+        h.render(&mut out, "h", "direction=\"up\",");
+        assert_eq!(
+            out,
+            "h_bucket{direction=\"up\",le=\"1\"} 2\n\
+             h_bucket{direction=\"up\",le=\"10\"} 3\n\
+             h_bucket{direction=\"up\",le=\"+Inf\"} 4\n\
+             h_sum{direction=\"up\"} 56.5\n\
+             h_count{direction=\"up\"} 4\n"
+        );
+    }
+}
@@ -0,0 +1,117 @@
+//! `--http-mirror`: send a copy of requests to a shadow upstream, and throw its
+//! responses away.
+//!
+//! Only a sample of requests is mirrored, `--http-mirror-percent` of those matching
+//! `--http-mirror-method` and `--http-mirror-path`, and at most
+//! `--http-mirror-max-in-flight` copies are outstanding at once, so a slow shadow can't
+//! pile up work in the proxy or be overwhelmed itself. Requests past the cap, and those
+//! with a body too large or of unknown length to buffer, are counted as skipped and
+//! forwarded as usual. Mirroring never changes what the client sees.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use hyper::client::HttpConnector;
+use hyper::header::{CONTENT_LENGTH, TRANSFER_ENCODING};
+use hyper::{Body, Client, Request, Uri};
+use rand::Rng;
+use tracing::warn;
+
+use crate::state::State;
+use crate::Args;
+
+/// The largest body buffered to send to the mirror.
+const MAX_BODY: u64 = 1024 * 1024;
+
+/// How long the mirror may take to respond before its copy is counted as failed.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Default)]
+pub struct Mirror {
+    client: Client<HttpConnector>,
+    in_flight: AtomicUsize,
+}
+
+impl Mirror {
+    /// Mirrored requests still awaiting a response.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+}
+
+/// Whether `req` is picked to be mirrored.
+fn sampled(req: &Request<Body>, args: &Args) -> bool {
+    let method = args.http_mirror_method.is_empty()
+        || args.http_mirror_method.iter().any(|m| m == req.method());
+    let path = args.http_mirror_path.is_empty()
+        || args
+            .http_mirror_path
+            .iter()
+            .any(|prefix| req.uri().path().starts_with(prefix.as_str()));
+    method && path && rand::thread_rng().gen_bool(args.http_mirror_percent / 100.0)
+}
+
+/// The length of `req`'s body, if it can be buffered.
+fn body_len(req: &Request<Body>) -> Option<u64> {
+    if req.headers().contains_key(TRANSFER_ENCODING) {
+        return None;
+    }
+    let len = match req.headers().get(CONTENT_LENGTH) {
+        Some(len) => len.to_str().ok()?.parse().ok()?,
+        None => 0,
+    };
+    (len <= MAX_BODY).then_some(len)
+}
+
+/// Send a copy of `req` to the mirror if it's sampled and there's room, returning the
+/// request to forward upstream.
+pub async fn tee(
+    req: Request<Body>,
+    args: &Args,
+    state: &Arc<State>,
+) -> Result<Request<Body>, hyper::Error> {
+    let addr = match &args.http_mirror {
+        Some(addr) if sampled(&req, args) => addr,
+        _ => return Ok(req),
+    };
+    let in_flight = &state.http_mirror.in_flight;
+    if body_len(&req).is_none() || state.http_mirror.in_flight() >= args.http_mirror_max_in_flight {
+        state.http_mirror_skipped.fetch_add(1, Ordering::Relaxed);
+        return Ok(req);
+    }
+
+    let (parts, body) = req.into_parts();
+    let body = hyper::body::to_bytes(body).await?;
+    let path = parts.uri.path_and_query().map_or("/", |p| p.as_str());
+    let uri = match format!("http://{}{}", addr, path).parse::<Uri>() {
+        Ok(uri) => uri,
+        Err(err) => {
+            warn!(mirror = %addr, error = %err, "failed to mirror request");
+            state.http_mirror_errors.fetch_add(1, Ordering::Relaxed);
+            return Ok(Request::from_parts(parts, Body::from(body)));
+        }
+    };
+    let mut copy = Request::new(Body::from(body.clone()));
+    *copy.method_mut() = parts.method.clone();
+    *copy.uri_mut() = uri;
+    *copy.headers_mut() = parts.headers.clone();
+
+    in_flight.fetch_add(1, Ordering::Relaxed);
+    state.http_mirrored.fetch_add(1, Ordering::Relaxed);
+    let state = state.clone();
+    tokio::spawn(async move {
+        let client = &state.http_mirror.client;
+        let sent = tokio::time::timeout(TIMEOUT, async {
+            let resp = client.request(copy).await?;
+            // Read the response so the connection can be reused.
+            hyper::body::to_bytes(resp.into_body()).await
+        })
+        .await;
+        if !matches!(sent, Ok(Ok(_))) {
+            state.http_mirror_errors.fetch_add(1, Ordering::Relaxed);
+        }
+        state.http_mirror.in_flight.fetch_sub(1, Ordering::Relaxed);
+    });
+    Ok(Request::from_parts(parts, Body::from(body)))
+}
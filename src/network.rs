@@ -0,0 +1,116 @@
+//! `--client-network`: named client subnets, so connections and their metrics can be
+//! broken down by network segment. The per-connection metrics are labelled with the
+//! network and the listener; with `--metrics-tag-key client_network`, so are the
+//! tagged totals.
+
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use crate::connection::Connection;
+
+/// The tag value for clients outside every `--client-network`.
+pub const OTHER: &str = "other";
+
+/// A `--client-network` rule, `NAME=ADDR/PREFIX`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Network {
+    pub name: String,
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Network {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // Compare IPv4-mapped IPv6 clients as IPv4.
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            ip => ip,
+        };
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                masked(u32::from(net) as u128, 32, self.prefix)
+                    == masked(u32::from(ip) as u128, 32, self.prefix)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                masked(u128::from(net), 128, self.prefix)
+                    == masked(u128::from(ip), 128, self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// The top `prefix` bits of a `bits` wide address.
+fn masked(addr: u128, bits: u8, prefix: u8) -> u128 {
+    if prefix == 0 {
+        0
+    } else {
+        addr >> (bits - prefix)
+    }
+}
+
+/// The name of the first of `networks` containing `ip`, or [`OTHER`].
+pub fn classify(networks: &[Network], ip: IpAddr) -> &str {
+    networks
+        .iter()
+        .find(|network| network.contains(ip))
+        .map_or(OTHER, |network| &network.name)
+}
+
+/// Tag `conn` with the network its client is in, if there are any `networks`.
+pub fn tag(networks: &[Network], conn: &Connection) {
+    if !networks.is_empty() {
+        let network = classify(networks, conn.downstream_addr.ip());
+        conn.set_tags([("client_network".to_string(), Some(network.to_string()))]);
+    }
+}
+
+impl FromStr for Network {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || format!("expected NAME=ADDR/PREFIX: {}", s);
+        let (name, cidr) = s.split_once('=').ok_or_else(err)?;
+        let (addr, prefix) = cidr.split_once('/').ok_or_else(err)?;
+        let addr: IpAddr = addr.parse().map_err(|_| err())?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix: u8 = prefix
+            .parse()
+            .ok()
+            .filter(|prefix| *prefix <= max)
+            .ok_or_else(|| format!("prefix must be from 0 to {}: {}", max, s))?;
+        if name.is_empty() || name == OTHER {
+            return Err(format!("network names can't be empty or {}: {}", OTHER, s));
+        }
+        Ok(Network {
+            name: name.to_string(),
+            addr,
+            prefix,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify() {
+        let networks: Vec<Network> = ["office=10.1.0.0/16", "vpn=10.0.0.0/8", "v6=2001:db8::/32"]
+            .iter()
+            .map(|s| s.parse().unwrap())
+            .collect();
+        let class = |ip: &str| classify(&networks, ip.parse().unwrap()).to_string();
+        assert_eq!(class("10.1.2.3"), "office");
+        assert_eq!(class("10.8.0.1"), "vpn");
+        assert_eq!(class("::ffff:10.1.0.1"), "office");
+        assert_eq!(class("2001:db8:1::1"), "v6");
+        assert_eq!(class("192.0.2.1"), "other");
+
+        let all: Network = "all=0.0.0.0/0".parse().unwrap();
+        assert!(all.contains("192.0.2.1".parse().unwrap()));
+        assert!("office=10.1.0.0".parse::<Network>().is_err());
+        assert!("office=10.1.0.0/33".parse::<Network>().is_err());
+        assert!("other=10.1.0.0/16".parse::<Network>().is_err());
+    }
+}
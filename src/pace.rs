@@ -0,0 +1,70 @@
+//! `--upstream-connect-rate`: spacing out upstream connects, independently of how fast
+//! clients arrive, so a herd of clients reconnecting after a restart reaches a
+//! recovering backend as a steady trickle of SYNs rather than all at once.
+
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+#[derive(Debug, Default)]
+pub struct Pacer(Mutex<Slots>);
+
+#[derive(Debug, Default)]
+struct Slots {
+    /// The time between connects, or `None` for no pacing.
+    interval: Option<Duration>,
+    /// When the next connect may start.
+    next: Option<Instant>,
+}
+
+impl Pacer {
+    /// Allow `rate` connects per second, or any number if it's 0.
+    pub fn set_rate(&self, rate: f64) {
+        let mut slots = self.0.lock().unwrap();
+        slots.interval = (rate > 0.0).then(|| Duration::from_secs_f64(1.0 / rate));
+    }
+
+    /// Wait for this connect's turn, returning how long that took.
+    pub async fn wait(&self) -> Duration {
+        let slot = {
+            let mut slots = self.0.lock().unwrap();
+            let interval = match slots.interval {
+                Some(interval) => interval,
+                None => return Duration::ZERO,
+            };
+            let now = Instant::now();
+            let slot = slots.next.map_or(now, |next| next.max(now));
+            slots.next = Some(slot + interval);
+            slot
+        };
+        let start = Instant::now();
+        tokio::time::sleep_until(slot).await;
+        start.elapsed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_pacer() {
+        let pacer = Pacer::default();
+        assert_eq!(pacer.wait().await, Duration::ZERO);
+
+        pacer.set_rate(100.0);
+        let start = Instant::now();
+        let waits = futures::future::join_all((0..5).map(|_| pacer.wait())).await;
+        // Each connect waits about 10ms longer than the one before.
+        assert!(waits[4] >= waits[0] + Duration::from_millis(35));
+        assert!(start.elapsed() >= Duration::from_millis(40));
+
+        // Idle time doesn't build up a burst.
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let start = Instant::now();
+        pacer.wait().await;
+        pacer.wait().await;
+        assert!(start.elapsed() >= Duration::from_millis(10));
+    }
+}
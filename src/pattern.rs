@@ -0,0 +1,355 @@
+//! A small regular expression subset for matching bytes.
+//!
+//! Supported: literal bytes, `.`, bracketed classes such as `[a-z0-9_]` and
+//! `[^,]`, the escapes `\d`, `\w` and `\s` (any other escaped byte is taken
+//! literally), the greedy quantifiers `*`, `+` and `?`, and the anchors `^` and
+//! `$`. There are no groups or alternation, which keeps matching a simple
+//! backtracking loop over a flat list of atoms.
+
+use std::cell::Cell;
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Atom {
+    Byte(u8),
+    Any,
+    Class {
+        ranges: Vec<(u8, u8)>,
+        negated: bool,
+    },
+}
+
+impl Atom {
+    fn matches(&self, b: u8) -> bool {
+        match self {
+            Atom::Byte(expected) => b == *expected,
+            Atom::Any => true,
+            Atom::Class { ranges, negated } => {
+                ranges.iter().any(|(lo, hi)| (*lo..=*hi).contains(&b)) != *negated
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Repeat {
+    One,
+    ZeroOrOne,
+    ZeroOrMore,
+    OneOrMore,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Node {
+    atom: Atom,
+    repeat: Repeat,
+}
+
+#[derive(Clone, PartialEq)]
+pub struct Pattern {
+    source: String,
+    nodes: Vec<Node>,
+    anchored_start: bool,
+    anchored_end: bool,
+}
+
+impl fmt::Debug for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Pattern({:?})", self.source)
+    }
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl FromStr for Pattern {
+    type Err = String;
+
+    fn from_str(source: &str) -> Result<Self, Self::Err> {
+        let mut bytes = source.as_bytes();
+        let anchored_start = bytes.first() == Some(&b'^');
+        if anchored_start {
+            bytes = &bytes[1..];
+        }
+        let anchored_end =
+            bytes.last() == Some(&b'$') && !ends_with_escape(&bytes[..bytes.len() - 1]);
+        if anchored_end {
+            bytes = &bytes[..bytes.len() - 1];
+        }
+
+        let mut nodes: Vec<Node> = Vec::new();
+        let mut i = 0;
+        while i < bytes.len() {
+            let atom = match bytes[i] {
+                b'.' => {
+                    i += 1;
+                    Atom::Any
+                }
+                b'[' => {
+                    let (atom, next) = parse_class(bytes, i + 1)?;
+                    i = next;
+                    atom
+                }
+                b'\\' => {
+                    let b = *bytes.get(i + 1).ok_or("pattern ends with a backslash")?;
+                    i += 2;
+                    escape(b)
+                }
+                b'*' | b'+' | b'?' => {
+                    return Err(format!("nothing to repeat in pattern: {}", source));
+                }
+                b => {
+                    i += 1;
+                    Atom::Byte(b)
+                }
+            };
+            let repeat = match bytes.get(i) {
+                Some(b'*') => Repeat::ZeroOrMore,
+                Some(b'+') => Repeat::OneOrMore,
+                Some(b'?') => Repeat::ZeroOrOne,
+                _ => Repeat::One,
+            };
+            if repeat != Repeat::One {
+                i += 1;
+            }
+            nodes.push(Node { atom, repeat });
+        }
+
+        Ok(Pattern {
+            source: source.to_string(),
+            nodes,
+            anchored_start,
+            anchored_end,
+        })
+    }
+}
+
+fn ends_with_escape(bytes: &[u8]) -> bool {
+    bytes.iter().rev().take_while(|b| **b == b'\\').count() % 2 == 1
+}
+
+fn escape(b: u8) -> Atom {
+    let class = |ranges: &[(u8, u8)]| Atom::Class {
+        ranges: ranges.to_vec(),
+        negated: false,
+    };
+    match b {
+        b'd' => class(&[(b'0', b'9')]),
+        b'w' => class(&[(b'a', b'z'), (b'A', b'Z'), (b'0', b'9'), (b'_', b'_')]),
+        b's' => class(&[(b' ', b' '), (b'\t', b'\r')]),
+        b'n' => Atom::Byte(b'\n'),
+        b'r' => Atom::Byte(b'\r'),
+        b't' => Atom::Byte(b'\t'),
+        b => Atom::Byte(b),
+    }
+}
+
+/// Parse a class starting just after its `[`. Returns the class and the index just
+/// after its `]`.
+fn parse_class(bytes: &[u8], mut i: usize) -> Result<(Atom, usize), String> {
+    let negated = bytes.get(i) == Some(&b'^');
+    if negated {
+        i += 1;
+    }
+    let mut ranges = Vec::new();
+    let mut first = true;
+    loop {
+        let lo = match bytes.get(i) {
+            None => return Err("unterminated [ in pattern".to_string()),
+            Some(b']') if !first => return Ok((Atom::Class { ranges, negated }, i + 1)),
+            Some(b'\\') => {
+                i += 1;
+                match bytes.get(i).map(|b| escape(*b)) {
+                    Some(Atom::Byte(b)) => b,
+                    Some(Atom::Class { ranges: r, .. }) => {
+                        ranges.extend(r);
+                        i += 1;
+                        first = false;
+                        continue;
+                    }
+                    _ => return Err("unterminated [ in pattern".to_string()),
+                }
+            }
+            Some(b) => *b,
+        };
+        first = false;
+        i += 1;
+        if bytes.get(i) == Some(&b'-') && bytes.get(i + 1).is_some_and(|b| *b != b']') {
+            let hi = bytes[i + 1];
+            if hi < lo {
+                return Err("invalid range in pattern".to_string());
+            }
+            ranges.push((lo, hi));
+            i += 2;
+        } else {
+            ranges.push((lo, lo));
+        }
+    }
+}
+
+impl Pattern {
+    /// The first match in `text` at or after `start`, as a `(start, end)` range.
+    pub fn find_at(&self, text: &[u8], start: usize) -> Option<(usize, usize)> {
+        if self.anchored_start {
+            if start > 0 {
+                return None;
+            }
+            return self
+                .match_here(&self.nodes, text, 0, self.anchored_end, &Cell::new(false))
+                .map(|end| (0, end));
+        }
+        (start..=text.len()).find_map(|i| {
+            self.match_here(&self.nodes, text, i, self.anchored_end, &Cell::new(false))
+                .map(|end| (i, end))
+        })
+    }
+
+    /// Match starting exactly at `pos`, returning where the match ends and whether
+    /// matching reached the end of `text`, in which case more text could change the
+    /// result. Anchors are ignored.
+    pub fn match_prefix(&self, text: &[u8], pos: usize) -> (Option<usize>, bool) {
+        let ended = Cell::new(false);
+        let end = self.match_here(&self.nodes, text, pos, false, &ended);
+        (end, ended.get())
+    }
+
+    /// Whether the pattern has `^` or `$`, which don't mean much in a stream.
+    pub fn is_anchored(&self) -> bool {
+        self.anchored_start || self.anchored_end
+    }
+
+    /// Whether the pattern can match without consuming anything, such as `x*`.
+    pub fn matches_empty(&self) -> bool {
+        self.match_prefix(b"", 0).0.is_some()
+    }
+
+    fn match_here(
+        &self,
+        nodes: &[Node],
+        text: &[u8],
+        pos: usize,
+        anchored_end: bool,
+        ended: &Cell<bool>,
+    ) -> Option<usize> {
+        let node = match nodes.first() {
+            None => return (!anchored_end || pos == text.len()).then_some(pos),
+            Some(node) => node,
+        };
+        let rest = &nodes[1..];
+        let (min, max) = match node.repeat {
+            Repeat::One => (1, 1),
+            Repeat::ZeroOrOne => (0, 1),
+            Repeat::ZeroOrMore => (0, usize::MAX),
+            Repeat::OneOrMore => (1, usize::MAX),
+        };
+        let available = text[pos..]
+            .iter()
+            .take(max)
+            .take_while(|b| node.atom.matches(**b))
+            .count();
+        if pos + available == text.len() && available < max {
+            ended.set(true);
+        }
+        // Greedy: try the longest run first and back off.
+        (min..=available)
+            .rev()
+            .find_map(|n| self.match_here(rest, text, pos + n, anchored_end, ended))
+    }
+
+    /// Replace every non-overlapping match with `replacement`, returning the new text
+    /// and the number of replacements.
+    pub fn replace_all(&self, text: &[u8], replacement: &[u8]) -> (Vec<u8>, usize) {
+        let mut out = Vec::with_capacity(text.len());
+        let mut count = 0;
+        let mut pos = 0;
+        while pos <= text.len() {
+            let (start, end) = match self.find_at(text, pos) {
+                Some(m) => m,
+                None => break,
+            };
+            out.extend_from_slice(&text[pos..start]);
+            out.extend_from_slice(replacement);
+            count += 1;
+            if end == start {
+                // An empty match; step over a byte so we make progress.
+                if let Some(b) = text.get(end) {
+                    out.push(*b);
+                }
+                pos = end + 1;
+            } else {
+                pos = end;
+            }
+        }
+        if pos < text.len() {
+            out.extend_from_slice(&text[pos..]);
+        }
+        (out, count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn find(pattern: &str, text: &str) -> Option<(usize, usize)> {
+        pattern
+            .parse::<Pattern>()
+            .unwrap()
+            .find_at(text.as_bytes(), 0)
+    }
+
+    #[test]
+    fn test_find() {
+        assert_eq!(find("abc", "xxabcxx"), Some((2, 5)));
+        assert_eq!(find("a.c", "xxabcxx"), Some((2, 5)));
+        assert_eq!(find("ab*c", "ac abbbc"), Some((0, 2)));
+        assert_eq!(find("ab+c", "ac abbbc"), Some((3, 8)));
+        assert_eq!(find("colou?r", "color"), Some((0, 5)));
+        assert_eq!(find("[0-9]+", "v1.23"), Some((1, 2)));
+        assert_eq!(find("\\d+\\.\\d+", "v1.23"), Some((1, 5)));
+        assert_eq!(find("[^,]+", ",,ab,c"), Some((2, 4)));
+        assert_eq!(find("^ab", "xab"), None);
+        assert_eq!(find("^ab", "abx"), Some((0, 2)));
+        assert_eq!(find("ab$", "abx"), None);
+        assert_eq!(find("ab$", "xab"), Some((1, 3)));
+        assert_eq!(find("a.*b", "a1b2b3"), Some((0, 5)));
+        assert_eq!(find("[\\w-]+", "  foo-bar "), Some((2, 9)));
+        assert_eq!(find("x*", "abc"), Some((0, 0)));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!("*a".parse::<Pattern>().is_err());
+        assert!("[abc".parse::<Pattern>().is_err());
+        assert!("a\\".parse::<Pattern>().is_err());
+        assert!("[z-a]".parse::<Pattern>().is_err());
+    }
+
+    #[test]
+    fn test_replace_all() {
+        let p: Pattern = "[0-9]+".parse().unwrap();
+        assert_eq!(p.replace_all(b"a1b22c333", b"#"), (b"a#b#c#".to_vec(), 3));
+        let p: Pattern = "internal\\.example\\.com".parse().unwrap();
+        assert_eq!(
+            p.replace_all(b"host=internal.example.com;", b"proxy"),
+            (b"host=proxy;".to_vec(), 1)
+        );
+        let p: Pattern = "x*".parse().unwrap();
+        assert_eq!(p.replace_all(b"ab", b"-"), (b"-a-b-".to_vec(), 3));
+    }
+
+    #[test]
+    fn test_match_prefix() {
+        let p: Pattern = "token=[0-9]+".parse().unwrap();
+        assert_eq!(p.match_prefix(b"token=12;", 0), (Some(8), false));
+        assert_eq!(p.match_prefix(b"token=12", 0), (Some(8), true));
+        assert_eq!(p.match_prefix(b"tok", 0), (None, true));
+        assert_eq!(p.match_prefix(b"tik", 0), (None, false));
+        assert!("x*".parse::<Pattern>().unwrap().matches_empty());
+        assert!(!p.matches_empty());
+    }
+}
@@ -0,0 +1,189 @@
+//! `--probe-interval`: baseline round-trip times to each upstream, measured by timing
+//! TCP connects independently of proxied traffic, so slow connections can be put down
+//! to the network or to the application from the proxy's metrics alone.
+//!
+//! Only the TCP handshake is timed; the upstream's name is resolved first. The
+//! connection is then handed to a [`HealthChecker`] (`--probe-check`), which decides
+//! whether the probe succeeded, and closed once it's done.
+//!
+//! One task probes the upstreams of every listener, and is replaced whenever the config
+//! is applied.
+
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use serde_json::{json, Value};
+use tokio::net::TcpStream;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tracing::warn;
+
+use crate::connection::unix_millis;
+use crate::health::HealthChecker;
+use crate::state::State;
+use crate::{balance, Args};
+
+/// The latest probe of one upstream.
+#[derive(Clone, Debug)]
+pub struct Probe {
+    pub result: Result<Duration, String>,
+    pub at: SystemTime,
+}
+
+impl Probe {
+    pub fn to_json(&self, upstream: &str) -> Value {
+        json!({
+            "upstream": upstream,
+            "rtt_ms": self.result.as_ref().ok().map(|rtt| rtt.as_secs_f64() * 1000.0),
+            "error": self.result.as_ref().err(),
+            "at": unix_millis(self.at),
+        })
+    }
+}
+
+/// The upstreams `args` forwards to: --upstream-addr's, each --route's, and those of
+/// --zone-upstream and --sni-route.
+fn upstreams(args: &Args) -> Vec<String> {
+    let lists =
+        std::iter::once(&args.upstream_addr).chain(args.route.iter().map(|r| &r.upstream_addr));
+    let addrs = lists
+        .flat_map(|list| balance::parse(list).unwrap_or_default())
+        .map(|upstream| upstream.addr.to_string())
+        .chain(
+            args.zone_upstream
+                .iter()
+                .map(|upstream| upstream.addr.clone()),
+        )
+        .chain(
+            args.sni_route
+                .iter()
+                .map(|route| route.upstream().to_string()),
+        );
+    let mut upstreams = Vec::new();
+    for addr in addrs {
+        if !addr.is_empty() && !upstreams.contains(&addr) {
+            upstreams.push(addr);
+        }
+    }
+    upstreams
+}
+
+/// Start probing the upstreams of `args`, if --probe-interval is set, forgetting the
+/// probes of any others.
+pub fn spawn(args: &Args, state: &Arc<State>) -> Option<JoinHandle<()>> {
+    let upstreams = if args.probe_interval.is_zero() {
+        Vec::new()
+    } else {
+        upstreams(args)
+    };
+    for (upstream, _) in state.probes.snapshot() {
+        if !upstreams.contains(&upstream) {
+            state.probes.remove(&upstream);
+        }
+    }
+    if upstreams.is_empty() {
+        return None;
+    }
+    let checker = match &args.health_checker {
+        Some(checker) => checker.0.clone(),
+        None => Arc::new(args.probe_check.clone()),
+    };
+    Some(tokio::spawn(run(
+        upstreams,
+        checker,
+        args.probe_interval,
+        args.probe_timeout,
+        state.clone(),
+    )))
+}
+
+/// Probe each of `upstreams` every `interval`, forever.
+pub async fn run(
+    upstreams: Vec<String>,
+    checker: Arc<dyn HealthChecker>,
+    interval: Duration,
+    timeout: Duration,
+    state: Arc<State>,
+) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let state = &state;
+    let checker = &*checker;
+    loop {
+        ticker.tick().await;
+        let probes = upstreams.iter().map(|upstream| async move {
+            let result = measure(upstream, checker, timeout, state).await;
+            if let Err(err) = &result {
+                warn!(%upstream, error = %err, "failed to probe upstream");
+            }
+            let probe = Probe {
+                result,
+                at: SystemTime::now(),
+            };
+            state.probes.insert(upstream.clone(), probe);
+        });
+        futures::future::join_all(probes).await;
+    }
+}
+
+/// How long a TCP connect to `upstream` takes, if it then passes `checker`.
+pub async fn measure(
+    upstream: &str,
+    checker: &dyn HealthChecker,
+    timeout: Duration,
+    state: &State,
+) -> Result<Duration, String> {
+    let addrs = state
+        .resolver
+        .resolve(upstream)
+        .await
+        .map_err(|err| err.to_string())?;
+    let probe = async {
+        let start = Instant::now();
+        let stream = TcpStream::connect(&addrs[..])
+            .await
+            .map_err(|err| err.to_string())?;
+        let rtt = start.elapsed();
+        checker.check(upstream, stream).await?;
+        Ok(rtt)
+    };
+    match tokio::time::timeout(timeout, probe).await {
+        Ok(result) => result,
+        Err(_) => Err(format!("timed out after {:?}", timeout)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::net::TcpListener;
+
+    use crate::health::Check;
+
+    #[tokio::test]
+    async fn test_probe() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = listener.local_addr().unwrap().to_string();
+        let state = Arc::new(State::new());
+        let targets = vec![upstream.clone(), "127.0.0.1:1".to_string()];
+        let task = tokio::spawn(run(
+            targets,
+            Arc::new(Check::Tcp),
+            Duration::from_millis(10),
+            Duration::from_secs(1),
+            state.clone(),
+        ));
+        while state.probes.snapshot().len() < 2 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        task.abort();
+
+        let probe = state.probes.get(&upstream).unwrap();
+        assert!(probe.result.unwrap() < Duration::from_secs(1));
+        let failed = state.probes.get(&"127.0.0.1:1".to_string()).unwrap();
+        let failed = failed.to_json("127.0.0.1:1");
+        assert!(failed["rtt_ms"].is_null());
+        assert!(failed["error"].is_string());
+    }
+}
@@ -0,0 +1,243 @@
+//! Which local process opened a connection, for `--client-process`.
+//!
+//! When the client is on the same host, the kernel's sock_diag netlink interface finds
+//! its end of the connection by address, and `/proc` then finds the process holding
+//! that socket open. Connections are tagged with its `process.pid`, `process.name` and
+//! `process.cgroup`, so "which service opened this?" can be answered from the UI. Only
+//! processes we may inspect can be found, so for other users' processes run as root.
+
+use std::io;
+use std::net::SocketAddr;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Owner {
+    pub pid: u32,
+    /// From `/proc/PID/comm`.
+    pub name: String,
+    /// The process's cgroup v2 path (e.g. /system.slice/nginx.service), if it's in one.
+    pub cgroup: Option<String>,
+}
+
+impl Owner {
+    pub fn tags(&self) -> Vec<(String, Option<String>)> {
+        vec![
+            ("process.pid".to_string(), Some(self.pid.to_string())),
+            ("process.name".to_string(), Some(self.name.clone())),
+            ("process.cgroup".to_string(), self.cgroup.clone()),
+        ]
+    }
+}
+
+/// Whether a client at `peer`, connected to us at `local`, is on this host.
+pub fn is_local(peer: SocketAddr, local: SocketAddr) -> bool {
+    let ip = peer.ip().to_canonical();
+    ip.is_loopback() || ip == local.ip().to_canonical()
+}
+
+/// The process which owns the client end of a connection from `peer` to `local`, or
+/// `None` if it can't be found.
+#[cfg(target_os = "linux")]
+pub fn lookup(peer: SocketAddr, local: SocketAddr) -> io::Result<Option<Owner>> {
+    let inode = match diag::inode(peer, local)? {
+        Some(inode) => inode,
+        None => return Ok(None),
+    };
+    let pid = match find_socket(inode)? {
+        Some(pid) => pid,
+        None => return Ok(None),
+    };
+    let name = std::fs::read_to_string(format!("/proc/{}/comm", pid))?
+        .trim_end()
+        .to_string();
+    let cgroup = std::fs::read_to_string(format!("/proc/{}/cgroup", pid))
+        .ok()
+        .and_then(|cgroups| parse_cgroup(&cgroups));
+    Ok(Some(Owner { pid, name, cgroup }))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn lookup(_peer: SocketAddr, _local: SocketAddr) -> io::Result<Option<Owner>> {
+    Ok(None)
+}
+
+/// The process with a descriptor open on socket `inode`.
+#[cfg(target_os = "linux")]
+fn find_socket(inode: u32) -> io::Result<Option<u32>> {
+    let target = format!("socket:[{}]", inode);
+    for entry in std::fs::read_dir("/proc")? {
+        let entry = entry?;
+        let pid = match entry.file_name().to_str().and_then(|s| s.parse().ok()) {
+            Some(pid) => pid,
+            None => continue,
+        };
+        // Processes come and go, and others' descriptors may be off limits.
+        let fds = match std::fs::read_dir(entry.path().join("fd")) {
+            Ok(fds) => fds,
+            Err(_) => continue,
+        };
+        for fd in fds.flatten() {
+            if std::fs::read_link(fd.path()).is_ok_and(|link| link.as_os_str() == &*target) {
+                return Ok(Some(pid));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// The cgroup v2 path from the contents of `/proc/PID/cgroup`.
+fn parse_cgroup(cgroups: &str) -> Option<String> {
+    cgroups
+        .lines()
+        .find_map(|line| line.strip_prefix("0::"))
+        .map(|path| path.to_string())
+}
+
+#[cfg(target_os = "linux")]
+mod diag {
+    use std::io;
+    use std::net::{IpAddr, SocketAddr};
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+    const SOCK_DIAG_BY_FAMILY: u16 = 20;
+    const NLMSG_ERROR: u16 = 2;
+    const HEADER_LEN: usize = 16;
+    /// The size of `struct inet_diag_req_v2`.
+    const REQUEST_LEN: usize = 56;
+    /// Where `idiag_inode` is in a response, after the header.
+    const INODE_OFFSET: usize = HEADER_LEN + 68;
+
+    /// The inode of the TCP socket bound to `src` and connected to `dst`.
+    pub fn inode(src: SocketAddr, dst: SocketAddr) -> io::Result<Option<u32>> {
+        // SAFETY: socket returns a new descriptor or -1.
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_DGRAM | libc::SOCK_CLOEXEC,
+                libc::NETLINK_SOCK_DIAG,
+            )
+        };
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: `fd` is open and nothing else owns it.
+        let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let request = request(src, dst);
+        // SAFETY: `request` is valid for its length for the duration of the call.
+        let sent = unsafe {
+            libc::send(
+                socket.as_raw_fd(),
+                request.as_ptr().cast(),
+                request.len(),
+                0,
+            )
+        };
+        if sent == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut response = [0u8; 8192];
+        // SAFETY: `response` is valid for writes of its length for the duration of the call.
+        let received = unsafe {
+            libc::recv(
+                socket.as_raw_fd(),
+                response.as_mut_ptr().cast(),
+                response.len(),
+                0,
+            )
+        };
+        if received == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        parse(&response[..received as usize])
+    }
+
+    /// An exact-match `SOCK_DIAG_BY_FAMILY` request for the socket from `src` to `dst`.
+    fn request(src: SocketAddr, dst: SocketAddr) -> Vec<u8> {
+        let (src_ip, dst_ip) = (src.ip().to_canonical(), dst.ip().to_canonical());
+        let family = match src_ip {
+            IpAddr::V4(_) => libc::AF_INET,
+            IpAddr::V6(_) => libc::AF_INET6,
+        };
+        let mut msg = Vec::with_capacity(HEADER_LEN + REQUEST_LEN);
+        msg.extend_from_slice(&((HEADER_LEN + REQUEST_LEN) as u32).to_ne_bytes());
+        msg.extend_from_slice(&SOCK_DIAG_BY_FAMILY.to_ne_bytes());
+        msg.extend_from_slice(&(libc::NLM_F_REQUEST as u16).to_ne_bytes());
+        msg.extend_from_slice(&1u32.to_ne_bytes()); // sequence number
+        msg.extend_from_slice(&0u32.to_ne_bytes()); // port ID: the kernel
+        msg.push(family as u8);
+        msg.push(libc::IPPROTO_TCP as u8);
+        msg.push(0); // no extensions
+        msg.push(0);
+        msg.extend_from_slice(&u32::MAX.to_ne_bytes()); // in any state
+        msg.extend_from_slice(&src.port().to_be_bytes());
+        msg.extend_from_slice(&dst.port().to_be_bytes());
+        for ip in [src_ip, dst_ip] {
+            let mut addr = [0u8; 16];
+            match ip {
+                IpAddr::V4(ip) => addr[..4].copy_from_slice(&ip.octets()),
+                IpAddr::V6(ip) => addr.copy_from_slice(&ip.octets()),
+            }
+            msg.extend_from_slice(&addr);
+        }
+        // Any interface, and INET_DIAG_NOCOOKIE to match on addresses alone.
+        msg.extend_from_slice(&0u32.to_ne_bytes());
+        msg.extend_from_slice(&u32::MAX.to_ne_bytes());
+        msg.extend_from_slice(&u32::MAX.to_ne_bytes());
+        msg
+    }
+
+    fn parse(response: &[u8]) -> io::Result<Option<u32>> {
+        let u32_at = |at: usize| {
+            response
+                .get(at..at + 4)
+                .map(|b| u32::from_ne_bytes(b.try_into().unwrap()))
+        };
+        let kind = response
+            .get(4..6)
+            .map(|b| u16::from_ne_bytes(b.try_into().unwrap()));
+        match kind {
+            Some(NLMSG_ERROR) => match u32_at(HEADER_LEN).map(|e| -(e as i32)) {
+                Some(libc::ENOENT) => Ok(None),
+                Some(errno) => Err(io::Error::from_raw_os_error(errno)),
+                None => Err(io::Error::new(io::ErrorKind::InvalidData, "short error")),
+            },
+            Some(SOCK_DIAG_BY_FAMILY) => u32_at(INODE_OFFSET)
+                .map(Some)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "short response")),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unexpected sock_diag response",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_cgroup() {
+        assert_eq!(
+            parse_cgroup("0::/system.slice/nginx.service\n").as_deref(),
+            Some("/system.slice/nginx.service")
+        );
+        assert_eq!(parse_cgroup("12:pids:/\n11:cpu:/\n"), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_lookup() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (_server, peer) = listener.accept().unwrap();
+        let local = client.peer_addr().unwrap();
+        assert!(is_local(peer, local));
+
+        let owner = lookup(peer, local).unwrap().unwrap();
+        assert_eq!(owner.pid, std::process::id());
+        let comm = std::fs::read_to_string("/proc/self/comm").unwrap();
+        assert_eq!(owner.name, comm.trim_end());
+        assert_eq!(lookup("127.0.0.1:1".parse().unwrap(), local).unwrap(), None);
+    }
+}
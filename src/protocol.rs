@@ -0,0 +1,177 @@
+//! Recognize the protocol a downstream client speaks from its first bytes.
+
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+use std::time::Duration;
+
+use tokio::net::TcpStream;
+
+/// How long to wait between peeks while a client has sent too little to decide.
+const PEEK_INTERVAL: Duration = Duration::from_millis(10);
+
+const HTTP_METHODS: &[&[u8]] = &[
+    b"GET ",
+    b"HEAD ",
+    b"POST ",
+    b"PUT ",
+    b"DELETE ",
+    b"OPTIONS ",
+    b"PATCH ",
+    b"CONNECT ",
+    b"TRACE ",
+    // The HTTP/2 connection preface.
+    b"PRI ",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Protocol {
+    /// A TLS ClientHello record.
+    Tls,
+    /// An HTTP/1.x request line, or the HTTP/2 preface.
+    Http,
+    /// An SSH identification banner.
+    Ssh,
+}
+
+impl FromStr for Protocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tls" => Ok(Protocol::Tls),
+            "http" => Ok(Protocol::Http),
+            "ssh" => Ok(Protocol::Ssh),
+            _ => Err(format!("unknown protocol: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Protocol::Tls => "tls",
+            Protocol::Http => "http",
+            Protocol::Ssh => "ssh",
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Match {
+    Yes,
+    No,
+    /// Every byte so far is consistent with the protocol, but there aren't enough to be sure.
+    NeedMore,
+}
+
+impl Protocol {
+    /// Check whether `bytes`, the start of a stream, look like this protocol.
+    pub fn matches(&self, bytes: &[u8]) -> Match {
+        match self {
+            Protocol::Tls => {
+                // record type handshake, major version 3, handshake type ClientHello
+                let expected: &[Option<u8>] =
+                    &[Some(0x16), Some(0x03), None, None, None, Some(0x01)];
+                for (byte, expected) in bytes.iter().zip(expected) {
+                    if matches!(expected, Some(e) if e != byte) {
+                        return Match::No;
+                    }
+                }
+                if bytes.len() >= expected.len() {
+                    Match::Yes
+                } else {
+                    Match::NeedMore
+                }
+            }
+            Protocol::Http => HTTP_METHODS
+                .iter()
+                .map(|method| prefix_match(method, bytes))
+                .fold(Match::No, |acc, m| match (acc, m) {
+                    (Match::Yes, _) | (_, Match::Yes) => Match::Yes,
+                    (Match::NeedMore, _) | (_, Match::NeedMore) => Match::NeedMore,
+                    _ => Match::No,
+                }),
+            Protocol::Ssh => prefix_match(b"SSH-", bytes),
+        }
+    }
+}
+
+fn prefix_match(prefix: &[u8], bytes: &[u8]) -> Match {
+    let n = prefix.len().min(bytes.len());
+    if prefix[..n] != bytes[..n] {
+        Match::No
+    } else if n == prefix.len() {
+        Match::Yes
+    } else {
+        Match::NeedMore
+    }
+}
+
+/// Peek at the first bytes of `stream` without consuming them and report whether they
+/// match `protocol`. Clients which close, or stay silent for `timeout`, don't match.
+pub async fn sniff(stream: &TcpStream, protocol: Protocol, timeout: Duration) -> io::Result<bool> {
+    let mut buf = [0; 16];
+    let matched = peek_until(stream, &mut buf, timeout, |bytes| {
+        Ok(match protocol.matches(bytes) {
+            _ if bytes.is_empty() => Some(false),
+            Match::Yes => Some(true),
+            Match::No => Some(false),
+            Match::NeedMore => None,
+        })
+    })
+    .await?;
+    Ok(matched.unwrap_or(false))
+}
+
+/// Peek at `stream` into `buf` until `complete`, given what has arrived so far (nothing
+/// once the client has closed), returns an answer, or `None` if `timeout` passes
+/// first. Nothing is consumed.
+pub async fn peek_until<T>(
+    stream: &TcpStream,
+    buf: &mut [u8],
+    timeout: Duration,
+    mut complete: impl FnMut(&[u8]) -> io::Result<Option<T>>,
+) -> io::Result<Option<T>> {
+    let peek = async {
+        loop {
+            let n = stream.peek(buf).await?;
+            if let Some(answer) = complete(&buf[..n])? {
+                return Ok(answer);
+            }
+            // Peek returns immediately while any data is buffered, so give the client
+            // a moment to send the rest.
+            tokio::time::sleep(PEEK_INTERVAL).await;
+        }
+    };
+    match tokio::time::timeout(timeout, peek).await {
+        Ok(answer) => answer.map(Some),
+        Err(_) => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches() {
+        let hello = [0x16, 0x03, 0x01, 0x02, 0x00, 0x01, 0x00];
+        assert_eq!(Protocol::Tls.matches(&hello), Match::Yes);
+        assert_eq!(Protocol::Tls.matches(&hello[..3]), Match::NeedMore);
+        assert_eq!(Protocol::Tls.matches(b"GET / HTTP/1.1"), Match::No);
+
+        assert_eq!(Protocol::Http.matches(b"GET / HTTP/1.1"), Match::Yes);
+        assert_eq!(Protocol::Http.matches(b"DELETE /x"), Match::Yes);
+        assert_eq!(Protocol::Http.matches(b"PO"), Match::NeedMore);
+        assert_eq!(Protocol::Http.matches(b"GETX"), Match::No);
+        assert_eq!(Protocol::Http.matches(&hello), Match::No);
+
+        assert_eq!(
+            Protocol::Ssh.matches(b"SSH-2.0-OpenSSH_8.9\r\n"),
+            Match::Yes
+        );
+        assert_eq!(Protocol::Ssh.matches(b"SS"), Match::NeedMore);
+        assert_eq!(Protocol::Ssh.matches(b"HELO"), Match::No);
+    }
+}
@@ -0,0 +1,239 @@
+//! Running the proxy in-process, for embedders' integration tests.
+//!
+//! A [`Proxy`] is configured as the command line would, with builder methods for the
+//! common flags and for what can't be given as a flag: callbacks, connection hooks,
+//! health checkers and resolver overrides. Starting it binds its listeners and returns a
+//! [`Handle`] to find their addresses, inspect the [`State`] and shut down. Admin
+//! endpoints, config reloads and signal handling stay with the binary.
+
+use std::error::Error;
+use std::ffi::OsString;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use clap::Parser;
+use tokio::task::JoinHandle;
+
+use crate::events::Events;
+use crate::health::{Checker, HealthChecker};
+use crate::hello::Callback;
+use crate::hook::{ConnectionHook, Hook};
+use crate::state::State;
+use crate::{bandwidth, reload, shutdown, Args};
+
+/// A proxy yet to be started.
+pub struct Proxy {
+    args: Args,
+    state: Arc<State>,
+}
+
+impl Proxy {
+    /// A proxy listening on `listen_addr` and forwarding to `upstream_addr`, with every
+    /// other flag at its default.
+    pub fn new(listen_addr: &str, upstream_addr: &str) -> Proxy {
+        Proxy::from_flags::<_, &str>([])
+            .expect("the defaults parse")
+            .listen_addr(listen_addr)
+            .upstream_addr(upstream_addr)
+    }
+
+    /// A proxy configured by command-line flags, without the program name:
+    /// `["--listen-addr", "127.0.0.1:0", "--upstream-addr", "db:5432", "--mode", "http"]`.
+    pub fn from_flags<I, T>(flags: I) -> Result<Proxy, clap::Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString> + Clone,
+    {
+        let argv =
+            std::iter::once(OsString::from("tproxy")).chain(flags.into_iter().map(Into::into));
+        let args = Args::try_parse_from(argv)?;
+        let state = Arc::new(State::with_buckets(
+            args.latency_buckets.clone(),
+            args.size_buckets.clone(),
+        ));
+        Ok(Proxy { args, state })
+    }
+
+    pub fn listen_addr(mut self, addr: &str) -> Self {
+        self.args.listen_addr = addr.to_string();
+        self
+    }
+
+    pub fn upstream_addr(mut self, addr: &str) -> Self {
+        self.args.upstream_addr = addr.to_string();
+        self
+    }
+
+    /// Resolve `host` to `ips` when connecting upstream, rather than asking DNS.
+    pub fn resolve(self, host: &str, ips: Vec<IpAddr>) -> Self {
+        self.state.resolver.set(host, ips);
+        self
+    }
+
+    /// Decide each new connection's upstream and tags from its first bytes.
+    pub fn client_hello(mut self, callback: Callback) -> Self {
+        self.args.client_hello = Some(callback);
+        self
+    }
+
+    /// Check upstream health with `checker` for `--probe-interval` probes.
+    pub fn health_checker(mut self, checker: Arc<dyn HealthChecker>) -> Self {
+        self.args.health_checker = Some(Checker(checker));
+        self
+    }
+
+    /// Call `hook` as connections are accepted, connected, forward bytes and close,
+    /// after any hooks added before it.
+    pub fn hook(mut self, hook: Arc<dyn ConnectionHook>) -> Self {
+        self.args.connection_hooks.push(Hook(hook));
+        self
+    }
+
+    /// The proxy's connection events. Subscribe before starting it to see every one.
+    pub fn events(&self) -> &Events {
+        &self.state.events
+    }
+
+    /// Bind the listeners and start proxying.
+    pub async fn start(self) -> Result<Handle, Box<dyn Error>> {
+        let Proxy { mut args, state } = self;
+        let listeners = crate::start_listeners(&mut args, &state).await?;
+        if listeners.addrs().is_empty() {
+            return Err("no listener could be bound".into());
+        }
+        let bandwidth = tokio::spawn(bandwidth::run(state.clone()));
+        Ok(Handle {
+            state,
+            listeners,
+            tasks: vec![bandwidth],
+        })
+    }
+}
+
+/// A running proxy. Dropping it stops the listeners and their upstream probes,
+/// leaving open connections be.
+pub struct Handle {
+    state: Arc<State>,
+    listeners: reload::Listeners,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Handle {
+    /// The address of the --listen-addr listener, or of the first route's.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.listeners.addrs()[0]
+    }
+
+    /// The bound address of each listener: --listen-addr's first, then each route's.
+    pub fn addrs(&self) -> Vec<SocketAddr> {
+        self.listeners.addrs()
+    }
+
+    /// The proxy's connections and statistics, as the admin API serves them.
+    pub fn state(&self) -> &Arc<State> {
+        &self.state
+    }
+
+    /// Stop accepting connections and probing upstreams, and wait up to `timeout` for
+    /// those open to finish, with an error if any are still open.
+    pub async fn shutdown(mut self, timeout: Duration) -> Result<(), Box<dyn Error>> {
+        self.stop();
+        shutdown::drain(&self.state, timeout, std::future::pending()).await
+    }
+
+    fn stop(&mut self) {
+        self.listeners.stop();
+        for task in self.tasks.drain(..) {
+            task.abort();
+        }
+    }
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use crate::events::Event;
+
+    #[tokio::test]
+    async fn test_proxy() {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = format!("echo.test:{}", upstream.local_addr().unwrap().port());
+        tokio::spawn(async move {
+            let (mut stream, _) = upstream.accept().await.unwrap();
+            let mut buf = [0; 5];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream.write_all(&buf).await.unwrap();
+        });
+
+        let proxy = Proxy::from_flags(["--idle-timeout", "10s"])
+            .unwrap()
+            .listen_addr("127.0.0.1:0")
+            .upstream_addr(&upstream_addr)
+            .resolve("echo.test", vec!["127.0.0.1".parse().unwrap()]);
+        let mut events = proxy.events().subscribe();
+        let handle = proxy.start().await.unwrap();
+
+        let mut client = TcpStream::connect(handle.local_addr()).await.unwrap();
+        client.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 5];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+        assert!(matches!(
+            events.recv().await.unwrap(),
+            Event::Accepted { .. }
+        ));
+        let connected = events.recv().await.unwrap();
+        assert!(
+            matches!(connected, Event::Connected { upstream_addr: addr, .. } if addr == upstream_addr)
+        );
+
+        let addr = handle.local_addr();
+        drop(client);
+        handle.shutdown(Duration::from_secs(5)).await.unwrap();
+        assert!(TcpStream::connect(addr).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_stops_probes() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap().to_string();
+        let probed = Arc::new(AtomicUsize::new(0));
+        let counter = probed.clone();
+        tokio::spawn(async move {
+            loop {
+                let _ = upstream.accept().await.unwrap();
+                counter.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+
+        let handle = Proxy::from_flags(["--probe-interval", "20ms"])
+            .unwrap()
+            .listen_addr("127.0.0.1:0")
+            .upstream_addr(&upstream_addr)
+            .start()
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(probed.load(Ordering::Relaxed) > 0);
+
+        handle.shutdown(Duration::from_secs(5)).await.unwrap();
+        // Let a probe that was already connecting be accepted.
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let stopped = probed.load(Ordering::Relaxed);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(probed.load(Ordering::Relaxed), stopped);
+    }
+}
@@ -0,0 +1,343 @@
+//! HAProxy's PROXY protocol, versions 1 and 2: a header ahead of a connection's bytes
+//! saying who the client really is, for when a load balancer or proxy sits between it
+//! and the server.
+//!
+//! With `--accept-proxy-protocol` the header is read off each downstream connection and
+//! its source taken as the client's address; with `--send-proxy-protocol` one is written
+//! to the upstream, so the backend sees the client rather than tproxy.
+//!
+//! Version 2 headers also carry the connection's tags, so traffic classes can be traced
+//! through a chain of proxies: each tag is a TLV of type `TLV_TAG` holding `KEY=VALUE`.
+//! Tags in an accepted header are set on the connection, along with the ALPN and
+//! authority TLVs a load balancer may send, as `proxy.alpn` and `proxy.authority`.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use std::time::Duration;
+
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpStream;
+
+use crate::protocol;
+
+/// Version 2 headers start with this.
+const SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// The longest version 1 header, CRLF included.
+const MAX_V1_LEN: usize = 107;
+
+const TLV_ALPN: u8 = 0x01;
+const TLV_AUTHORITY: u8 = 0x02;
+/// A connection tag, in the range the spec leaves for applications.
+pub const TLV_TAG: u8 = 0xe0;
+
+/// What an accepted header says about the connection.
+#[derive(Debug, Default, PartialEq)]
+pub struct Header {
+    /// `None` if the header doesn't carry one (v1 UNKNOWN, or v2 LOCAL or UNSPEC).
+    pub source: Option<SocketAddr>,
+    /// Tags from v2 TLVs.
+    pub tags: Vec<(String, String)>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Version {
+    V1,
+    V2,
+}
+
+impl FromStr for Version {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "v1" | "1" => Ok(Version::V1),
+            "v2" | "2" => Ok(Version::V2),
+            _ => Err(format!(
+                "unknown PROXY protocol version: {} (expected v1 or v2)",
+                s
+            )),
+        }
+    }
+}
+
+/// The header for a connection from `source` to `destination`, with `tags` as TLVs in
+/// version 2 (as many as fit). Mixed address families are sent as IPv6, with the IPv4
+/// address mapped.
+pub fn encode(
+    version: Version,
+    source: SocketAddr,
+    destination: SocketAddr,
+    tags: &BTreeMap<String, String>,
+) -> Vec<u8> {
+    let (source, destination) = match (source.ip(), destination.ip()) {
+        (IpAddr::V4(_), IpAddr::V4(_)) | (IpAddr::V6(_), IpAddr::V6(_)) => (source, destination),
+        _ => (to_v6(source), to_v6(destination)),
+    };
+    match version {
+        Version::V1 => {
+            let family = if source.is_ipv4() { "TCP4" } else { "TCP6" };
+            format!(
+                "PROXY {} {} {} {} {}\r\n",
+                family,
+                source.ip(),
+                destination.ip(),
+                source.port(),
+                destination.port()
+            )
+            .into_bytes()
+        }
+        Version::V2 => {
+            let mut header = SIGNATURE.to_vec();
+            // Version 2, PROXY command.
+            header.push(0x21);
+            let mut addrs = Vec::new();
+            match (source.ip(), destination.ip()) {
+                (IpAddr::V4(src), IpAddr::V4(dst)) => {
+                    header.push(0x11);
+                    addrs.extend_from_slice(&src.octets());
+                    addrs.extend_from_slice(&dst.octets());
+                }
+                (IpAddr::V6(src), IpAddr::V6(dst)) => {
+                    header.push(0x21);
+                    addrs.extend_from_slice(&src.octets());
+                    addrs.extend_from_slice(&dst.octets());
+                }
+                _ => unreachable!("families were made to match"),
+            }
+            addrs.extend_from_slice(&source.port().to_be_bytes());
+            addrs.extend_from_slice(&destination.port().to_be_bytes());
+            for (key, value) in tags {
+                let tag = format!("{}={}", key, value);
+                if addrs.len() + 3 + tag.len() > u16::MAX as usize {
+                    break;
+                }
+                addrs.push(TLV_TAG);
+                addrs.extend_from_slice(&(tag.len() as u16).to_be_bytes());
+                addrs.extend_from_slice(tag.as_bytes());
+            }
+            header.extend_from_slice(&(addrs.len() as u16).to_be_bytes());
+            header.extend_from_slice(&addrs);
+            header
+        }
+    }
+}
+
+fn to_v6(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V4(ip) => SocketAddr::new(ip.to_ipv6_mapped().into(), addr.port()),
+        IpAddr::V6(_) => addr,
+    }
+}
+
+/// How much of `buf` a complete header takes, `None` if more bytes are needed.
+fn header_len(buf: &[u8]) -> io::Result<Option<usize>> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+    if buf.len() >= 16 && buf.starts_with(SIGNATURE) {
+        let len = u16::from_be_bytes([buf[14], buf[15]]) as usize;
+        return Ok(Some(16 + len));
+    }
+    if buf.starts_with(b"PROXY ") {
+        return match buf.windows(2).position(|w| w == b"\r\n") {
+            Some(end) if end + 2 <= MAX_V1_LEN => Ok(Some(end + 2)),
+            Some(_) => Err(invalid("PROXY header is too long")),
+            None if buf.len() >= MAX_V1_LEN => Err(invalid("PROXY header is too long")),
+            None => Ok(None),
+        };
+    }
+    let n = buf.len();
+    if SIGNATURE.starts_with(&buf[..n.min(12)]) || b"PROXY ".starts_with(&buf[..n.min(6)]) {
+        return Ok(None);
+    }
+    Err(invalid("connection did not start with a PROXY header"))
+}
+
+/// The source address and tags in a complete header.
+pub fn parse(header: &[u8]) -> io::Result<Header> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+    if let Some(rest) = header.strip_prefix(&SIGNATURE[..]) {
+        let (ver_cmd, family, addrs) = (rest[0], rest[1], &rest[4..]);
+        if ver_cmd >> 4 != 2 {
+            return Err(invalid("unsupported PROXY protocol version"));
+        }
+        match ver_cmd & 0x0f {
+            0 => return Ok(Header::default()),
+            1 => {}
+            _ => return Err(invalid("unknown PROXY command")),
+        }
+        let (source, tlvs) = match family >> 4 {
+            0 => (None, &addrs[..0]),
+            1 if addrs.len() >= 12 => {
+                let octets: [u8; 4] = addrs[..4].try_into().unwrap();
+                let port = u16::from_be_bytes([addrs[8], addrs[9]]);
+                let ip = Ipv4Addr::from(octets).into();
+                (Some(SocketAddr::new(ip, port)), &addrs[12..])
+            }
+            2 if addrs.len() >= 36 => {
+                let octets: [u8; 16] = addrs[..16].try_into().unwrap();
+                let port = u16::from_be_bytes([addrs[32], addrs[33]]);
+                let ip = Ipv6Addr::from(octets).into();
+                (Some(SocketAddr::new(ip, port)), &addrs[36..])
+            }
+            // UNIX sockets have no address we could use.
+            3 if addrs.len() >= 216 => (None, &addrs[216..]),
+            _ => return Err(invalid("invalid PROXY address block")),
+        };
+        return Ok(Header {
+            source,
+            tags: parse_tlvs(tlvs)?,
+        });
+    }
+    let line = std::str::from_utf8(header)
+        .ok()
+        .and_then(|line| line.strip_suffix("\r\n"))
+        .ok_or_else(|| invalid("invalid PROXY header"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields[..] {
+        ["PROXY", "UNKNOWN", ..] => Ok(Header::default()),
+        ["PROXY", "TCP4" | "TCP6", source, _, port, _] => {
+            let ip: IpAddr = source
+                .parse()
+                .map_err(|_| invalid("invalid PROXY source"))?;
+            let port: u16 = port.parse().map_err(|_| invalid("invalid PROXY port"))?;
+            Ok(Header {
+                source: Some(SocketAddr::new(ip, port)),
+                tags: Vec::new(),
+            })
+        }
+        _ => Err(invalid("invalid PROXY header")),
+    }
+}
+
+/// The tags in a v2 header's TLVs, skipping the types we don't use and tags which
+/// aren't UTF-8 `KEY=VALUE`.
+fn parse_tlvs(mut tlvs: &[u8]) -> io::Result<Vec<(String, String)>> {
+    let mut tags = Vec::new();
+    while !tlvs.is_empty() {
+        if tlvs.len() < 3 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "truncated PROXY TLV",
+            ));
+        }
+        let len = u16::from_be_bytes([tlvs[1], tlvs[2]]) as usize;
+        let value = tlvs
+            .get(3..3 + len)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "truncated PROXY TLV"))?;
+        let value = std::str::from_utf8(value).ok();
+        match (tlvs[0], value) {
+            (TLV_ALPN, Some(alpn)) => tags.push(("proxy.alpn".to_string(), alpn.to_string())),
+            (TLV_AUTHORITY, Some(authority)) => {
+                tags.push(("proxy.authority".to_string(), authority.to_string()))
+            }
+            (TLV_TAG, Some(tag)) => {
+                if let Some((key, value)) = tag.split_once('=').filter(|(key, _)| !key.is_empty()) {
+                    tags.push((key.to_string(), value.to_string()));
+                }
+            }
+            _ => {}
+        }
+        tlvs = &tlvs[3 + len..];
+    }
+    Ok(tags)
+}
+
+/// Read a header of either version off `stream`, leaving the bytes after it unread.
+pub async fn accept(stream: &mut TcpStream, timeout: Duration) -> io::Result<Header> {
+    let mut buf = vec![0; 16 + u16::MAX as usize];
+    let len = protocol::peek_until(stream, &mut buf, timeout, |bytes| {
+        if bytes.is_empty() {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        header_len(bytes)
+    })
+    .await?
+    .ok_or_else(|| io::Error::new(io::ErrorKind::TimedOut, "timed out reading PROXY header"))?;
+    buf.truncate(len);
+    stream.read_exact(&mut buf).await?;
+    parse(&buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_parse() {
+        let client: SocketAddr = "192.0.2.10:51234".parse().unwrap();
+        let proxy: SocketAddr = "198.51.100.1:5432".parse().unwrap();
+        let none = BTreeMap::new();
+        let source = |header: &[u8]| parse(header).unwrap().source;
+        let v1 = encode(Version::V1, client, proxy, &none);
+        assert_eq!(v1, b"PROXY TCP4 192.0.2.10 198.51.100.1 51234 5432\r\n");
+        assert_eq!(header_len(&v1).unwrap(), Some(v1.len()));
+        assert_eq!(source(&v1), Some(client));
+
+        let v2 = encode(Version::V2, client, proxy, &none);
+        assert_eq!(v2.len(), 28);
+        assert_eq!(header_len(&v2[..20]).unwrap(), Some(28));
+        assert_eq!(source(&v2), Some(client));
+
+        let client6: SocketAddr = "[2001:db8::1]:443".parse().unwrap();
+        let v2 = encode(Version::V2, client6, proxy, &none);
+        assert_eq!(source(&v2), Some(client6));
+        let v1 = encode(Version::V1, client6, proxy, &none);
+        assert!(v1.starts_with(b"PROXY TCP6 2001:db8::1 ::ffff:198.51.100.1 "));
+
+        assert_eq!(source(b"PROXY UNKNOWN\r\n"), None);
+        let mut local = SIGNATURE.to_vec();
+        local.extend_from_slice(&[0x20, 0x00, 0x00, 0x00]);
+        assert_eq!(source(&local), None);
+
+        assert_eq!(header_len(b"PRO").unwrap(), None);
+        assert_eq!(header_len(b"\r\n\r\n").unwrap(), None);
+        assert!(header_len(b"GET / HTTP/1.1\r\n").is_err());
+        assert!(header_len(&[b'x'; 200]).is_err());
+        assert!(parse(b"PROXY TCP4 nonsense\r\n").is_err());
+    }
+
+    #[test]
+    fn test_tlvs() {
+        let client: SocketAddr = "192.0.2.10:51234".parse().unwrap();
+        let proxy: SocketAddr = "198.51.100.1:5432".parse().unwrap();
+        let tags: BTreeMap<String, String> = [("env", "staging"), ("team", "db=core")]
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let v2 = encode(Version::V2, client, proxy, &tags);
+        assert_eq!(header_len(&v2).unwrap(), Some(v2.len()));
+        let header = parse(&v2).unwrap();
+        assert_eq!(header.source, Some(client));
+        assert_eq!(header.tags, tags.into_iter().collect::<Vec<_>>());
+
+        // Standard TLVs a load balancer sends, one we skip, and a tag which isn't one.
+        let mut header = encode(Version::V2, client, proxy, &BTreeMap::new());
+        let tlvs: &[(u8, &[u8])] = &[
+            (TLV_ALPN, b"h2"),
+            (TLV_AUTHORITY, b"db.example.com"),
+            (0x04, b"\0\0"),
+            (TLV_TAG, b"no-equals"),
+        ];
+        for (kind, value) in tlvs {
+            header.push(*kind);
+            header.extend_from_slice(&(value.len() as u16).to_be_bytes());
+            header.extend_from_slice(value);
+        }
+        let len = (header.len() - 16) as u16;
+        header[14..16].copy_from_slice(&len.to_be_bytes());
+        assert_eq!(
+            parse(&header).unwrap().tags,
+            [
+                ("proxy.alpn".to_string(), "h2".to_string()),
+                ("proxy.authority".to_string(), "db.example.com".to_string()),
+            ]
+        );
+
+        header.truncate(header.len() - 2);
+        let len = (header.len() - 16) as u16;
+        header[14..16].copy_from_slice(&len.to_be_bytes());
+        assert!(parse(&header).is_err());
+    }
+}
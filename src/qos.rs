@@ -0,0 +1,283 @@
+//! `--bandwidth-cap` and `--qos-class`: a bandwidth limit shared by every connection,
+//! with priority classes scheduled strictly in order.
+//!
+//! Each direction is a token bucket filled at the cap. A connection sending while a
+//! connection of a higher class is waiting for the bucket waits behind it, so bulk
+//! traffic in a low class soaks up only what latency-sensitive classes leave over, as on
+//! a link with priority queueing. Connections matching no class are in the lowest,
+//! [`DEFAULT`].
+
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::sync::watch;
+use tokio::time::Instant;
+
+use crate::connection::{Connection, Direction};
+use crate::network::Network;
+
+/// The class of connections matching no `--qos-class`.
+pub const DEFAULT: &str = "default";
+
+/// The most a bucket holds, as time at the cap, so idle time doesn't build a burst.
+const BURST: Duration = Duration::from_millis(50);
+
+/// One condition of a class.
+#[derive(Clone, Debug, PartialEq)]
+enum Match {
+    /// The client is in this network.
+    Cidr(Network),
+    /// The client connected to this port.
+    Port(u16),
+    /// The connection has this tag.
+    Tag(String, String),
+}
+
+/// A `--qos-class`, NAME=MATCH[,MATCH...].
+#[derive(Clone, Debug, PartialEq)]
+pub struct Class {
+    pub name: String,
+    matches: Vec<Match>,
+}
+
+impl FromStr for Class {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || {
+            format!(
+                "expected NAME=MATCH[,MATCH...] with each MATCH cidr:ADDR/PREFIX, port:PORT or tag:KEY=VALUE: {}",
+                s
+            )
+        };
+        let (name, matches) = s.split_once('=').ok_or_else(err)?;
+        if name.is_empty() || name == DEFAULT {
+            return Err(format!("class names can't be empty or {}: {}", DEFAULT, s));
+        }
+        let matches = matches
+            .split(',')
+            .map(|m| match m.split_once(':') {
+                Some(("cidr", cidr)) => Ok(Match::Cidr(format!("{}={}", name, cidr).parse()?)),
+                Some(("port", port)) => port.parse().map(Match::Port).map_err(|_| err()),
+                Some(("tag", tag)) => match tag.split_once('=') {
+                    Some((key, value)) => Ok(Match::Tag(key.to_string(), value.to_string())),
+                    None => Err(err()),
+                },
+                _ => Err(err()),
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Class {
+            name: name.to_string(),
+            matches,
+        })
+    }
+}
+
+impl Class {
+    fn matches(&self, conn: &Connection, local: SocketAddr) -> bool {
+        let tags = conn.tags();
+        self.matches.iter().any(|m| match m {
+            Match::Cidr(network) => network.contains(conn.downstream_addr.ip()),
+            Match::Port(port) => local.port() == *port,
+            Match::Tag(key, value) => tags.get(key) == Some(value),
+        })
+    }
+}
+
+/// The priority of `conn`, 0 being the highest, and the name of its class: the first
+/// of `classes` it matches, or [`DEFAULT`] after them all.
+pub fn classify<'a>(
+    classes: &'a [Class],
+    conn: &Connection,
+    local: SocketAddr,
+) -> (usize, &'a str) {
+    classes
+        .iter()
+        .position(|class| class.matches(conn, local))
+        .map_or((classes.len(), DEFAULT), |i| (i, &classes[i].name))
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// Bytes per second, or `None` for no cap.
+    rate: Option<u64>,
+    /// Bytes which may be sent now. Negative once a send has borrowed ahead.
+    tokens: f64,
+    refilled: Instant,
+    /// Senders waiting, by priority.
+    waiting: Vec<usize>,
+}
+
+impl Bucket {
+    fn refill(&mut self, rate: u64) {
+        let now = Instant::now();
+        let burst = rate as f64 * BURST.as_secs_f64();
+        let earned = now.duration_since(self.refilled).as_secs_f64() * rate as f64;
+        self.tokens = (self.tokens + earned).min(burst);
+        self.refilled = now;
+    }
+
+    fn waiting(&mut self, priority: usize) -> &mut usize {
+        if self.waiting.len() <= priority {
+            self.waiting.resize(priority + 1, 0);
+        }
+        &mut self.waiting[priority]
+    }
+}
+
+#[derive(Debug)]
+struct Link {
+    bucket: Mutex<Bucket>,
+    /// Bumped whenever a sender stops waiting, so those behind it look again.
+    changed: watch::Sender<()>,
+}
+
+impl Default for Link {
+    fn default() -> Self {
+        Link {
+            bucket: Mutex::new(Bucket {
+                rate: None,
+                tokens: 0.0,
+                refilled: Instant::now(),
+                waiting: Vec::new(),
+            }),
+            changed: watch::channel(()).0,
+        }
+    }
+}
+
+/// A sender counted as waiting on a link until dropped, even if its send is cancelled.
+struct Waiting<'a> {
+    link: &'a Link,
+    priority: usize,
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        *self.link.bucket.lock().unwrap().waiting(self.priority) -= 1;
+        self.link.changed.send_replace(());
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Scheduler {
+    up: Link,
+    down: Link,
+}
+
+impl Scheduler {
+    /// Cap each direction at `rate` bytes per second, or not at all if it's `None`.
+    pub fn set_rate(&self, rate: Option<u64>) {
+        for link in [&self.up, &self.down] {
+            let mut bucket = link.bucket.lock().unwrap();
+            bucket.rate = rate;
+            bucket.tokens = 0.0;
+            bucket.refilled = Instant::now();
+        }
+    }
+
+    pub fn rate(&self) -> Option<u64> {
+        self.up.bucket.lock().unwrap().rate
+    }
+
+    /// Wait until `n` bytes may be sent `direction` by a connection of `priority`.
+    pub async fn acquire(&self, direction: Direction, priority: usize, n: usize) {
+        let link = match direction {
+            Direction::Up => &self.up,
+            Direction::Down => &self.down,
+        };
+        let mut changed = link.changed.subscribe();
+        let mut waiting = None;
+        loop {
+            let wait = {
+                let mut bucket = link.bucket.lock().unwrap();
+                let rate = match bucket.rate {
+                    Some(rate) => rate,
+                    None => return,
+                };
+                bucket.refill(rate);
+                let behind = bucket.waiting.iter().take(priority).any(|w| *w > 0);
+                if !behind && bucket.tokens >= 0.0 {
+                    bucket.tokens -= n as f64;
+                    // Unlock before `waiting` is dropped, telling the others.
+                    drop(bucket);
+                    return;
+                }
+                if waiting.is_none() {
+                    *bucket.waiting(priority) += 1;
+                    waiting = Some(Waiting { link, priority });
+                }
+                // Behind a higher class, wait to hear it's gone; otherwise for tokens.
+                Duration::from_secs_f64((-bucket.tokens).max(0.0) / rate as f64)
+                    .max(Duration::from_millis(1))
+            };
+            tokio::select! {
+                _ = tokio::time::sleep(wait) => {}
+                _ = changed.changed() => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    #[test]
+    fn test_classify() {
+        let classes: Vec<Class> = ["interactive=port:22,tag:team=db", "office=cidr:10.0.0.0/8"]
+            .iter()
+            .map(|s| s.parse().unwrap())
+            .collect();
+        assert!("bulk=port:ssh".parse::<Class>().is_err());
+        assert!("default=port:80".parse::<Class>().is_err());
+
+        let local = "127.0.0.1:8080".parse().unwrap();
+        let conn = Connection::new(1, "10.1.2.3:5000".parse().unwrap(), "up:80".into());
+        assert_eq!(classify(&classes, &conn, local), (1, "office"));
+        assert_eq!(
+            classify(&classes, &conn, "127.0.0.1:22".parse().unwrap()),
+            (0, "interactive")
+        );
+        conn.set_tags([("team".to_string(), Some("db".to_string()))]);
+        assert_eq!(classify(&classes, &conn, local), (0, "interactive"));
+        let other = Connection::new(2, "192.168.0.1:5000".parse().unwrap(), "up:80".into());
+        assert_eq!(classify(&classes, &other, local), (2, DEFAULT));
+    }
+
+    #[tokio::test]
+    async fn test_scheduler() {
+        let scheduler = Arc::new(Scheduler::default());
+        scheduler.acquire(Direction::Up, 0, 1_000_000).await;
+
+        // 100KB/s, so each 5KB chunk takes 50ms.
+        scheduler.set_rate(Some(100_000));
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let sender = |priority: usize| {
+            let (scheduler, sent) = (scheduler.clone(), sent.clone());
+            tokio::spawn(async move {
+                for _ in 0..4 {
+                    scheduler.acquire(Direction::Down, priority, 5_000).await;
+                    sent.lock().unwrap().push(priority);
+                }
+            })
+        };
+        let start = Instant::now();
+        let (low, high) = (sender(1), sender(0));
+        low.await.unwrap();
+        high.await.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(300));
+        // Once both are waiting, the high class goes first.
+        let sent = sent.lock().unwrap().clone();
+        let last_high = sent.iter().rposition(|p| *p == 0).unwrap();
+        assert!(last_high < 6, "{:?}", sent);
+        // The other direction isn't held up.
+        let start = Instant::now();
+        scheduler.acquire(Direction::Up, 1, 5_000).await;
+        assert!(start.elapsed() < Duration::from_millis(20));
+    }
+}
@@ -0,0 +1,190 @@
+//! Per-client quotas, to emulate provider-side limits: how many connections a client
+//! may have open at once, and how many bytes it may move in an hour.
+//!
+//! Quotas are checked when a connection is accepted, so a connection which goes over
+//! the byte quota runs to completion and the client's next one is rejected. Bytes are
+//! counted in fixed hour-long windows starting at the client's first connection.
+
+use std::fmt;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Value};
+
+use crate::state::ShardedMap;
+
+pub const WINDOW: Duration = Duration::from_secs(3600);
+
+/// Limits which apply to each client separately. Zero means unlimited.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Limits {
+    pub max_connections: usize,
+    pub max_bytes_per_hour: u64,
+}
+
+impl Limits {
+    pub fn is_unlimited(&self) -> bool {
+        self.max_connections == 0 && self.max_bytes_per_hour == 0
+    }
+}
+
+/// Which quota a rejected connection was over.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Exceeded {
+    Connections,
+    Bytes,
+}
+
+impl fmt::Display for Exceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Exceeded::Connections => "connections",
+            Exceeded::Bytes => "bytes",
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Usage {
+    connections: usize,
+    window_start: Instant,
+    bytes: u64,
+    rejected: u64,
+}
+
+impl Usage {
+    fn new(now: Instant) -> Self {
+        Self {
+            connections: 0,
+            window_start: now,
+            bytes: 0,
+            rejected: 0,
+        }
+    }
+
+    fn roll(&mut self, now: Instant) {
+        if now.duration_since(self.window_start) >= WINDOW {
+            self.window_start = now;
+            self.bytes = 0;
+        }
+    }
+}
+
+/// Usage by client. A client's entry is dropped once it has no open connections and
+/// nothing counted against its current window.
+#[derive(Debug, Default)]
+pub struct Quotas {
+    usage: ShardedMap<IpAddr, Usage>,
+}
+
+impl Quotas {
+    /// Count a new connection from `client`, unless it's over a quota. Admitted
+    /// connections must be passed to `release` when they close.
+    pub fn admit(&self, client: IpAddr, limits: &Limits) -> Result<(), Exceeded> {
+        self.admit_at(client, limits, Instant::now())
+    }
+
+    fn admit_at(&self, client: IpAddr, limits: &Limits, now: Instant) -> Result<(), Exceeded> {
+        let result = self.usage.with_entry(client, Usage::new(now), |usage| {
+            usage.roll(now);
+            let result = if limits.max_connections > 0
+                && usage.connections >= limits.max_connections
+            {
+                Err(Exceeded::Connections)
+            } else if limits.max_bytes_per_hour > 0 && usage.bytes >= limits.max_bytes_per_hour {
+                Err(Exceeded::Bytes)
+            } else {
+                usage.connections += 1;
+                Ok(())
+            };
+            if result.is_err() {
+                usage.rejected += 1;
+            }
+            result
+        });
+        if result.is_err() {
+            self.prune(client);
+        }
+        result
+    }
+
+    /// Count a closed connection's bytes against `client`.
+    pub fn release(&self, client: IpAddr, bytes: u64) {
+        let now = Instant::now();
+        self.usage.with_entry(client, Usage::new(now), |usage| {
+            usage.roll(now);
+            usage.connections = usage.connections.saturating_sub(1);
+            usage.bytes += bytes;
+        });
+        self.prune(client);
+    }
+
+    fn prune(&self, client: IpAddr) {
+        self.usage.remove_if(&client, |usage| {
+            usage.connections == 0 && (usage.bytes == 0 || usage.window_start.elapsed() >= WINDOW)
+        });
+    }
+
+    /// Each tracked client's usage, for `/api/quotas`.
+    pub fn to_json(&self) -> Value {
+        let mut clients: Vec<_> = self.usage.snapshot().into_iter().collect();
+        clients.sort_by_key(|(client, _)| *client);
+        let clients: Vec<Value> = clients
+            .into_iter()
+            .map(|(client, usage)| {
+                let resets_in = WINDOW.saturating_sub(usage.window_start.elapsed());
+                json!({
+                    "client": client.to_string(),
+                    "connections": usage.connections,
+                    "bytes": usage.bytes,
+                    "rejected": usage.rejected,
+                    "window_resets_in_ms": resets_in.as_millis() as u64,
+                })
+            })
+            .collect();
+        json!(clients)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quotas() {
+        let quotas = Quotas::default();
+        let client: IpAddr = "10.0.0.1".parse().unwrap();
+        let other: IpAddr = "10.0.0.2".parse().unwrap();
+        let limits = Limits {
+            max_connections: 2,
+            max_bytes_per_hour: 100,
+        };
+        let now = Instant::now();
+
+        assert_eq!(quotas.admit_at(client, &limits, now), Ok(()));
+        assert_eq!(quotas.admit_at(client, &limits, now), Ok(()));
+        assert_eq!(
+            quotas.admit_at(client, &limits, now),
+            Err(Exceeded::Connections)
+        );
+        assert_eq!(quotas.admit_at(other, &limits, now), Ok(()));
+
+        quotas.release(client, 60);
+        quotas.release(client, 60);
+        assert_eq!(quotas.admit_at(client, &limits, now), Err(Exceeded::Bytes));
+        let usage = &quotas.to_json()[0];
+        assert_eq!(usage["client"], "10.0.0.1");
+        assert_eq!(usage["bytes"], 120);
+        assert_eq!(usage["rejected"], 2);
+
+        // The next window starts afresh.
+        assert_eq!(quotas.admit_at(client, &limits, now + WINDOW), Ok(()));
+        assert_eq!(
+            quotas.admit_at(client, &Limits::default(), now + WINDOW),
+            Ok(())
+        );
+
+        quotas.release(other, 0);
+        assert_eq!(quotas.to_json().as_array().unwrap().len(), 1);
+    }
+}
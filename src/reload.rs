@@ -0,0 +1,610 @@
+//! `--config` reloads on SIGHUP, without dropping connections.
+//!
+//! The command line is expanded against the file again, so its flags still win. Routes
+//! added to the file start listening and removed ones stop; every other listener is
+//! handed the new flags, which apply to the connections it accepts from then on, so a
+//! changed upstream address or limit takes effect without a restart. Open connections
+//! carry on with the flags they started with.
+//!
+//! Flags which are set up once at startup, listed in `STARTUP_ONLY`, are logged rather
+//! than applied when they change. Datagram modes (udp, quic and dns) don't pick up
+//! changes at all.
+//!
+//! The last `--config-history` configs applied are kept, each with what it changed, so
+//! `POST /api/config/rollback/N` can apply the one N reloads back again without anyone
+//! having to remember what the file said. The next SIGHUP reads the file as usual.
+
+use std::collections::VecDeque;
+use std::error::Error;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use clap::Parser;
+use futures::FutureExt;
+use serde_json::{json, Value};
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+use crate::config;
+use crate::probe;
+use crate::state::State;
+use crate::Args;
+
+/// Flags which a reload can't apply.
+const STARTUP_ONLY: &[&str] = &[
+    "mode",
+    "debug-addr",
+    "no-admin",
+    "capture",
+    "capture-rotate-bytes",
+    "capture-upload",
+    "capture-upload-endpoint",
+    "capture-upload-region",
+    "capture-upload-retention",
+    "geoip-db",
+    "sni-route",
+    "toxics",
+    "delay",
+    "delay-jitter",
+    "rate-limit",
+    "rate-limit-up",
+    "rate-limit-down",
+    "connect-toxic",
+    "upstream-connect-rate",
+    "upstream-flow-label",
+    "backlog",
+    "latency-buckets",
+    "size-buckets",
+    "nofile-target",
+    "fd-warn-ratio",
+];
+
+/// A request to apply the config N back, answered with whether that worked.
+type Rollback = (usize, oneshot::Sender<Result<(), String>>);
+
+/// A config which was applied.
+#[derive(Debug)]
+struct Applied {
+    at: SystemTime,
+    /// How it came to be applied: startup, reload or rollback.
+    cause: &'static str,
+    argv: Vec<String>,
+    effective: Value,
+}
+
+/// The configs applied most recently, for the API.
+#[derive(Debug, Default)]
+pub struct History {
+    /// Oldest first.
+    applied: Mutex<VecDeque<Applied>>,
+    /// The reload task, if the config can be reloaded.
+    rollbacks: Mutex<Option<mpsc::UnboundedSender<Rollback>>>,
+}
+
+impl History {
+    fn record(&self, cause: &'static str, argv: &[String], effective: &Value, keep: usize) {
+        let mut applied = self.applied.lock().unwrap();
+        applied.push_back(Applied {
+            at: SystemTime::now(),
+            cause,
+            argv: argv.to_vec(),
+            effective: effective.clone(),
+        });
+        while applied.len() > keep.max(1) {
+            applied.pop_front();
+        }
+    }
+
+    /// The command line of the config applied `n` configs ago, 0 being the current one.
+    fn argv(&self, n: usize) -> Option<Vec<String>> {
+        let applied = self.applied.lock().unwrap();
+        let i = applied.len().checked_sub(n + 1)?;
+        Some(applied[i].argv.clone())
+    }
+
+    pub fn len(&self) -> usize {
+        self.applied.lock().unwrap().len()
+    }
+
+    /// The configs kept, newest first, each with the flags it changed from the one
+    /// before. The oldest has `null` changes, as what came before it was forgotten.
+    pub fn to_json(&self) -> Value {
+        let applied = self.applied.lock().unwrap();
+        let entries: Vec<Value> = applied
+            .iter()
+            .enumerate()
+            .rev()
+            .map(|(i, config)| {
+                let changes = i.checked_sub(1).map(|prev| {
+                    let before = &applied[prev].effective;
+                    let changes: serde_json::Map<String, Value> = config
+                        .effective
+                        .as_object()
+                        .into_iter()
+                        .flatten()
+                        .filter(|(flag, value)| before[flag.as_str()] != **value)
+                        .map(|(flag, value)| {
+                            let change = json!({ "from": before[flag.as_str()], "to": value });
+                            (flag.clone(), change)
+                        })
+                        .collect();
+                    changes
+                });
+                json!({
+                    "n": applied.len() - 1 - i,
+                    "applied_at": config
+                        .at
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_millis() as u64,
+                    "cause": config.cause,
+                    "changes": changes,
+                })
+            })
+            .collect();
+        json!(entries)
+    }
+
+    /// Apply the config `n` configs ago again.
+    pub async fn rollback(&self, n: usize) -> Result<(), String> {
+        let rollbacks = self.rollbacks.lock().unwrap().clone();
+        let rollbacks = rollbacks.ok_or("the config can only be rolled back with --config")?;
+        let (reply, result) = oneshot::channel();
+        rollbacks
+            .send((n, reply))
+            .map_err(|_| "the config is no longer being reloaded")?;
+        result
+            .await
+            .map_err(|_| "the config is no longer being reloaded")?
+    }
+}
+
+struct Listener {
+    route_name: Option<String>,
+    listen_addr: String,
+    addr: SocketAddr,
+    updates: watch::Sender<Arc<Args>>,
+    task: JoinHandle<()>,
+}
+
+/// The running listeners: the one for --listen-addr, if any, then one per --route; and
+/// the task probing their upstreams.
+#[derive(Default)]
+pub struct Listeners {
+    listeners: Vec<Listener>,
+    probes: Option<JoinHandle<()>>,
+}
+
+impl Listeners {
+    /// The bound address of each listener.
+    pub fn addrs(&self) -> Vec<SocketAddr> {
+        self.listeners
+            .iter()
+            .map(|listener| listener.addr)
+            .collect()
+    }
+
+    /// Stop every listener, and probing. Connections they accepted carry on.
+    pub fn stop(&mut self) {
+        for listener in self.listeners.drain(..) {
+            listener.task.abort();
+        }
+        if let Some(probes) = self.probes.take() {
+            probes.abort();
+        }
+    }
+
+    /// Start, update and stop listeners to match `args`, which must already have its
+    /// resources open, and restart probing their upstreams. A listener is replaced
+    /// rather than updated if its listen address changed.
+    pub async fn apply(&mut self, args: &Args, state: &Arc<State>) {
+        let mut wanted = Vec::new();
+        if !args.listen_addr.is_empty() {
+            wanted.push(args.clone());
+        }
+        for route in &args.route {
+            wanted.push(Args {
+                listen_addr: route.listen_addr.clone(),
+                upstream_addr: route.upstream_addr.clone(),
+                route_name: Some(route.name.clone()),
+                ..args.clone()
+            });
+        }
+
+        if let Some(probes) = self.probes.take() {
+            probes.abort();
+        }
+        self.probes = probe::spawn(args, state);
+
+        let mut running = std::mem::take(&mut self.listeners);
+        for args in wanted {
+            let current = running.iter().position(|listener| {
+                listener.route_name == args.route_name && listener.listen_addr == args.listen_addr
+            });
+            match current {
+                Some(i) => {
+                    let listener = running.remove(i);
+                    // The listener only goes away with its task, which we abort first.
+                    let _ = listener.updates.send(Arc::new(args));
+                    self.listeners.push(listener);
+                }
+                None => {
+                    if let Some(listener) = spawn(args, state).await {
+                        self.listeners.push(listener);
+                    }
+                }
+            }
+        }
+        for listener in running {
+            // Connections it accepted run in their own tasks and carry on.
+            listener.task.abort();
+            match &listener.route_name {
+                Some(name) => info!(route = %name, addr = %listener.addr, "stopped listening"),
+                None => info!(addr = %listener.addr, "stopped listening"),
+            }
+        }
+    }
+}
+
+async fn spawn(args: Args, state: &Arc<State>) -> Option<Listener> {
+    let route_name = args.route_name.clone();
+    if let Some(name) = &route_name {
+        state
+            .routes
+            .with_entry(name.clone(), Default::default(), |_| ());
+    }
+    let listen_addr = args.listen_addr.clone();
+    let (updates, rx) = watch::channel(Arc::new(args));
+    let (ready_tx, ready_rx) = oneshot::channel();
+    let task = tokio::spawn(crate::listen_for(rx, state.clone(), ready_tx).map(|r| {
+        if let Err(err) = r {
+            warn!(error = %err, "failed to listen");
+        }
+    }));
+    // If the listener failed to bind its task has logged why.
+    let addr = ready_rx.await.ok()?;
+    match &route_name {
+        Some(name) => info!(route = %name, %addr, "listening"),
+        None => info!(%addr, "listening"),
+    }
+    Some(Listener {
+        route_name,
+        listen_addr,
+        addr,
+        updates,
+        task,
+    })
+}
+
+/// Reload on every SIGHUP, forever. `cli` is the command line as given, `argv` what it
+/// expanded to at startup and `args` what `listeners` were started with.
+pub async fn run(
+    cli: Vec<String>,
+    argv: Vec<String>,
+    args: Args,
+    mut listeners: Listeners,
+    state: Arc<State>,
+) {
+    let mut hangups = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(err) => {
+            warn!(error = %err, "failed to watch for SIGHUP");
+            return;
+        }
+    };
+    let mut running = match Running::new(&argv, args, &state) {
+        Ok(running) => running,
+        Err(err) => {
+            warn!(error = %err, "failed to reload config");
+            return;
+        }
+    };
+    let (rollbacks_tx, mut rollbacks) = mpsc::unbounded_channel();
+    *state.config_history.rollbacks.lock().unwrap() = Some(rollbacks_tx);
+    loop {
+        tokio::select! {
+            hangup = hangups.recv() => {
+                if hangup.is_none() {
+                    break;
+                }
+                match running.reload(&cli, &mut listeners, &state).await {
+                    Ok(()) => info!(config = %running.effective, "reloaded config"),
+                    Err(err) => warn!(error = %err, "failed to reload config"),
+                }
+            }
+            Some((n, reply)) = rollbacks.recv() => {
+                let result = running
+                    .rollback(n, &mut listeners, &state)
+                    .await
+                    .map_err(|err| err.to_string());
+                match &result {
+                    Ok(()) => info!(n, config = %running.effective, "rolled back config"),
+                    Err(err) => warn!(error = %err, "failed to roll back config"),
+                }
+                let _ = reply.send(result);
+            }
+        }
+    }
+}
+
+/// The flags the listeners were last given.
+struct Running {
+    args: Args,
+    effective: serde_json::Value,
+}
+
+impl Running {
+    fn new(argv: &[String], args: Args, state: &State) -> Result<Running, Box<dyn Error>> {
+        let effective = config::effective(argv)?;
+        state
+            .config_history
+            .record("startup", argv, &effective, args.config_history);
+        Ok(Running { args, effective })
+    }
+
+    /// Expand `cli` against the config file again and apply the result to `listeners`.
+    /// Nothing changes if the file doesn't parse.
+    async fn reload(
+        &mut self,
+        cli: &[String],
+        listeners: &mut Listeners,
+        state: &Arc<State>,
+    ) -> Result<(), Box<dyn Error>> {
+        let argv = config::expand(cli.to_vec())?;
+        self.apply(argv, "reload", listeners, state).await
+    }
+
+    /// Apply the config `n` configs ago again.
+    async fn rollback(
+        &mut self,
+        n: usize,
+        listeners: &mut Listeners,
+        state: &Arc<State>,
+    ) -> Result<(), Box<dyn Error>> {
+        let argv = state
+            .config_history
+            .argv(n)
+            .ok_or_else(|| format!("only {} configs are kept", state.config_history.len()))?;
+        self.apply(argv, "rollback", listeners, state).await
+    }
+
+    async fn apply(
+        &mut self,
+        argv: Vec<String>,
+        cause: &'static str,
+        listeners: &mut Listeners,
+        state: &Arc<State>,
+    ) -> Result<(), Box<dyn Error>> {
+        let args = Args::try_parse_from(&argv)?;
+        crate::check_listeners(&args)?;
+        let effective = config::effective(&argv)?;
+        let ignored: Vec<&str> = STARTUP_ONLY
+            .iter()
+            .copied()
+            .filter(|flag| effective[flag] != self.effective[flag])
+            .collect();
+        if !ignored.is_empty() {
+            info!(flags = %ignored.join(","), "restart to apply config changes");
+        }
+        let args = Args {
+            client_hello: self.args.client_hello.clone(),
+            geoip: self.args.geoip.clone(),
+            capture_writer: self.args.capture_writer.clone(),
+            health_checker: self.args.health_checker.clone(),
+            ..args
+        };
+        listeners.apply(&args, state).await;
+        state
+            .config_history
+            .record(cause, &argv, &effective, args.config_history);
+        self.args = args;
+        self.effective = effective;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// An upstream which answers every connection with `name`.
+    async fn named_upstream(name: &'static str) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    stream.write_all(name.as_bytes()).await.unwrap();
+                    let mut buf = [0; 16];
+                    let _ = stream.read(&mut buf).await;
+                });
+            }
+        });
+        addr
+    }
+
+    async fn greeting(stream: &mut TcpStream) -> String {
+        let mut buf = [0; 16];
+        let n = stream.read(&mut buf).await.unwrap();
+        String::from_utf8_lossy(&buf[..n]).into_owned()
+    }
+
+    /// An upstream which counts the connections it accepts.
+    async fn counting_upstream() -> (SocketAddr, Arc<std::sync::atomic::AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepted = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = accepted.clone();
+        tokio::spawn(async move {
+            loop {
+                let _ = listener.accept().await.unwrap();
+                counter.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            }
+        });
+        (addr, accepted)
+    }
+
+    #[tokio::test]
+    async fn test_probes_follow_reloads() {
+        use std::sync::atomic::Ordering;
+
+        let (a, probed_a) = counting_upstream().await;
+        let (b, probed_b) = counting_upstream().await;
+        let route =
+            |name: &str, upstream: SocketAddr| format!("{}=127.0.0.1:0->{}", name, upstream);
+        let args = |interval: &str, routes: &[String]| {
+            let mut argv = vec![
+                "tproxy".to_string(),
+                "--probe-interval".into(),
+                interval.to_string(),
+            ];
+            for route in routes {
+                argv.extend(["--route".to_string(), route.clone()]);
+            }
+            Args::parse_from(argv)
+        };
+        let state = Arc::new(State::new());
+        let mut listeners = Listeners::default();
+        // Two routes to the same upstream probe it once.
+        listeners
+            .apply(&args("20ms", &[route("one", a), route("two", a)]), &state)
+            .await;
+        tokio::time::sleep(Duration::from_millis(110)).await;
+        let probes = probed_a.load(Ordering::Relaxed);
+        assert!((2..=8).contains(&probes), "{} probes", probes);
+
+        listeners
+            .apply(&args("20ms", &[route("two", b)]), &state)
+            .await;
+        let before = probed_a.load(Ordering::Relaxed);
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert!(probed_a.load(Ordering::Relaxed) <= before + 1);
+        assert!(probed_b.load(Ordering::Relaxed) > 0);
+        assert!(state.probes.get(&a.to_string()).is_none());
+
+        // A longer interval applies from the reload on.
+        listeners
+            .apply(&args("1s", &[route("two", b)]), &state)
+            .await;
+        let before = probed_b.load(Ordering::Relaxed);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(probed_b.load(Ordering::Relaxed) <= before + 1);
+
+        listeners.stop();
+        let before = probed_b.load(Ordering::Relaxed);
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(probed_b.load(Ordering::Relaxed), before);
+    }
+
+    #[tokio::test]
+    async fn test_reload() {
+        let old = named_upstream("old").await;
+        let new = named_upstream("new").await;
+        let path = std::env::temp_dir().join(format!("tproxy-reload-{}.toml", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let write = |text: String| std::fs::write(&path, text).unwrap();
+        write(format!(
+            "listen_addr = \"127.0.0.1:0\"\nupstream_addr = \"{}\"\n",
+            old
+        ));
+
+        let cli: Vec<String> = ["tproxy", "--config", &path]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        let argv = config::expand(cli.clone()).unwrap();
+        let state = Arc::new(State::new());
+        let args = Args::parse_from(&argv);
+        let mut running = Running::new(&argv, args, &state).unwrap();
+        let mut listeners = Listeners::default();
+        listeners.apply(&running.args, &state).await;
+        let addr = listeners.addrs()[0];
+        let mut open = TcpStream::connect(addr).await.unwrap();
+        assert_eq!(greeting(&mut open).await, "old");
+
+        write(format!(
+            "listen_addr = \"127.0.0.1:0\"\nupstream_addr = \"{}\"\nroute = [\"extra=127.0.0.1:0->{}\"]\n",
+            new, old
+        ));
+        running.reload(&cli, &mut listeners, &state).await.unwrap();
+        let addrs = listeners.addrs();
+        assert_eq!(addrs[0], addr);
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        assert_eq!(greeting(&mut client).await, "new");
+        let mut client = TcpStream::connect(addrs[1]).await.unwrap();
+        assert_eq!(greeting(&mut client).await, "old");
+        // The connection from before the reload is still proxied.
+        open.write_all(b"still here").await.unwrap();
+        assert_eq!(state.snapshot().active_connections, 3);
+
+        write("listen_addr = \"127.0.0.1:0\"\n".to_string());
+        assert!(running.reload(&cli, &mut listeners, &state).await.is_err());
+        assert_eq!(listeners.addrs().len(), 2);
+
+        write(format!(
+            "listen_addr = \"127.0.0.1:0\"\nupstream_addr = \"{}\"\n",
+            new
+        ));
+        running.reload(&cli, &mut listeners, &state).await.unwrap();
+        assert_eq!(listeners.addrs(), vec![addr]);
+        assert!(TcpStream::connect(addrs[1]).await.is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_rollback() {
+        let old = named_upstream("old").await;
+        let new = named_upstream("new").await;
+        let path =
+            std::env::temp_dir().join(format!("tproxy-rollback-{}.toml", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let write = |upstream: SocketAddr| {
+            let text = format!(
+                "listen_addr = \"127.0.0.1:0\"\nupstream_addr = \"{}\"\nconfig_history = 2\n",
+                upstream
+            );
+            std::fs::write(&path, text).unwrap()
+        };
+        write(old);
+
+        let cli: Vec<String> = ["tproxy", "--config", &path]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        let argv = config::expand(cli.clone()).unwrap();
+        let state = Arc::new(State::new());
+        let mut running = Running::new(&argv, Args::parse_from(&argv), &state).unwrap();
+        let mut listeners = Listeners::default();
+        listeners.apply(&running.args, &state).await;
+        let addr = listeners.addrs()[0];
+
+        write(new);
+        running.reload(&cli, &mut listeners, &state).await.unwrap();
+        let history = state.config_history.to_json();
+        assert_eq!(history[0]["cause"], "reload");
+        assert_eq!(
+            history[0]["changes"],
+            json!({ "upstream-addr": { "from": old.to_string(), "to": new.to_string() } })
+        );
+        assert_eq!(history[1]["changes"], Value::Null);
+
+        running.rollback(1, &mut listeners, &state).await.unwrap();
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        assert_eq!(greeting(&mut client).await, "old");
+        let history = state.config_history.to_json();
+        assert_eq!(history.as_array().unwrap().len(), 2);
+        assert_eq!(history[0]["cause"], "rollback");
+        assert!(running.rollback(2, &mut listeners, &state).await.is_err());
+        // Without the reload task there's nothing to roll back with.
+        assert!(State::new().config_history.rollback(0).await.is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
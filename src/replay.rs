@@ -0,0 +1,219 @@
+//! `tproxy replay`: send the client bytes of a `--capture` to an upstream again, at
+//! the pace they were captured or faster, to turn captures into regression load.
+
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::time::Duration;
+
+use clap::Parser;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::Instant;
+
+use crate::capture::{self, Event};
+use crate::connection::{Direction, COPY_BUFFER_SIZE};
+use crate::parse_duration;
+
+/// Replay the client bytes of a capture against an upstream
+#[derive(Parser, Clone, Debug)]
+#[clap(name = "tproxy replay", bin_name = "tproxy replay")]
+pub struct Args {
+    /// Capture written by --capture
+    capture: String,
+
+    /// Address to replay connections to
+    #[clap(short, long)]
+    target: String,
+
+    /// How many times faster than captured to send bytes, or 0 to send them as fast as
+    /// possible
+    #[clap(long, default_value = "1")]
+    speed: f64,
+
+    /// How long to wait for the upstream to finish responding after a connection's last
+    /// bytes are sent
+    #[clap(long, default_value = "5s", parse(try_from_str = parse_duration))]
+    wait: Duration,
+}
+
+/// How one replayed connection went.
+#[derive(Debug, Default)]
+pub struct Outcome {
+    pub id: u64,
+    pub sent: u64,
+    pub received: u64,
+    /// Bytes the upstream sent in the capture.
+    pub expected: u64,
+    pub error: Option<String>,
+}
+
+pub async fn run(args: Args) -> Result<(), Box<dyn Error>> {
+    if !(args.speed >= 0.0 && args.speed.is_finite()) {
+        return Err("speed must be a non-negative number".into());
+    }
+    let events = capture::read(&args.capture)?;
+    let outcomes = replay(events, &args.target, args.speed, args.wait).await;
+    let mut failed = 0;
+    for outcome in &outcomes {
+        match &outcome.error {
+            Some(err) => {
+                failed += 1;
+                println!(
+                    "failed to replay connection; id={} error={}",
+                    outcome.id, err
+                );
+            }
+            None => println!(
+                "replayed connection; id={} sent={} received={} expected={}",
+                outcome.id, outcome.sent, outcome.received, outcome.expected
+            ),
+        }
+    }
+    println!("replayed {} connections; failed={}", outcomes.len(), failed);
+    if failed > 0 {
+        return Err(format!("{} connections failed", failed).into());
+    }
+    Ok(())
+}
+
+/// Replay each connection in `events` against `target`, scaling the gaps between
+/// events by `1 / speed`.
+pub async fn replay(events: Vec<Event>, target: &str, speed: f64, wait: Duration) -> Vec<Outcome> {
+    let first = events.iter().map(|e| e.at).min().unwrap_or_default();
+    let mut connections: BTreeMap<u64, Vec<Event>> = BTreeMap::new();
+    for event in events {
+        connections.entry(event.id).or_default().push(event);
+    }
+    let start = Instant::now();
+    let at = move |event: &Event| {
+        if speed == 0.0 {
+            start
+        } else {
+            start + (event.at - first).div_f64(speed)
+        }
+    };
+    let replays = connections.into_iter().map(|(id, events)| {
+        let mut outcome = Outcome {
+            id,
+            expected: events
+                .iter()
+                .filter(|e| e.direction == Direction::Down)
+                .map(|e| e.data.len() as u64)
+                .sum(),
+            ..Outcome::default()
+        };
+        async move {
+            tokio::time::sleep_until(at(&events[0])).await;
+            if let Err(err) = replay_one(&events, target, wait, &at, &mut outcome).await {
+                outcome.error = Some(err.to_string());
+            }
+            outcome
+        }
+    });
+    futures::future::join_all(replays).await
+}
+
+async fn replay_one(
+    events: &[Event],
+    target: &str,
+    wait: Duration,
+    at: &impl Fn(&Event) -> Instant,
+    outcome: &mut Outcome,
+) -> Result<(), Box<dyn Error>> {
+    let mut stream = TcpStream::connect(target).await?;
+    let (mut reader, mut writer) = stream.split();
+    let received = Cell::new(0);
+    let read = async {
+        let mut buf = vec![0; COPY_BUFFER_SIZE];
+        loop {
+            let n = reader.read(&mut buf).await?;
+            if n == 0 {
+                return Ok::<_, std::io::Error>(());
+            }
+            received.set(received.get() + n as u64);
+        }
+    };
+    tokio::pin!(read);
+    let write = async {
+        for event in events.iter().filter(|e| e.direction == Direction::Up) {
+            tokio::time::sleep_until(at(event)).await;
+            writer.write_all(&event.data).await?;
+            outcome.sent += event.data.len() as u64;
+        }
+        writer.shutdown().await
+    };
+    let result = tokio::select! {
+        result = &mut read => result,
+        result = write => match result {
+            Ok(()) => tokio::time::timeout(wait, &mut read).await.unwrap_or(Ok(())),
+            Err(err) => Err(err),
+        },
+    };
+    outcome.received = received.get();
+    Ok(result?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::net::TcpListener;
+
+    fn event(id: u64, at_ms: u64, direction: Direction, data: &[u8]) -> Event {
+        Event {
+            id,
+            at: Duration::from_millis(at_ms),
+            direction,
+            data: data.to_vec(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_replay() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let (mut r, mut w) = stream.split();
+                    let _ = tokio::io::copy(&mut r, &mut w).await;
+                });
+            }
+        });
+
+        let events = vec![
+            event(1, 1000, Direction::Up, b"hello "),
+            event(2, 1050, Direction::Up, b"PING"),
+            event(1, 1100, Direction::Down, b"hello "),
+            event(1, 1200, Direction::Up, b"world"),
+            event(1, 1250, Direction::Down, b"world"),
+        ];
+        let start = std::time::Instant::now();
+        let outcomes = replay(events.clone(), &target, 1.0, Duration::from_secs(1)).await;
+        // Paced from the first event, not from time zero.
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(200) && elapsed < Duration::from_millis(900));
+        assert_eq!(outcomes.len(), 2);
+        assert_eq!(outcomes[0].id, 1);
+        assert_eq!(outcomes[0].sent, 11);
+        assert_eq!(outcomes[0].received, 11);
+        assert_eq!(outcomes[0].expected, 11);
+        assert_eq!(outcomes[1].received, 4);
+        assert!(outcomes.iter().all(|o| o.error.is_none()));
+
+        let start = std::time::Instant::now();
+        replay(events, &target, 0.0, Duration::from_secs(1)).await;
+        assert!(start.elapsed() < Duration::from_millis(200));
+
+        let outcomes = replay(
+            vec![event(1, 0, Direction::Up, b"x")],
+            "127.0.0.1:1",
+            1.0,
+            Duration::ZERO,
+        )
+        .await;
+        assert!(outcomes[0].error.is_some());
+    }
+}
@@ -0,0 +1,361 @@
+//! Runtime overrides of how upstream host names resolve, set through
+//! `/api/resolver/overrides`, to simulate DNS changes and split-horizon setups
+//! without touching `/etc/hosts`.
+//!
+//! Upstream connects look their host up again once its last answer is
+//! `--upstream-dns-ttl` old, every connect by default, so DNS-based failover is
+//! followed. With `--upstream-dns-balance`, connects take turns starting at each of the
+//! addresses a host resolves to, falling back to the others in order. Answers are
+//! dropped once stale, and at most `MAX_CACHED` hosts' answers and turns are kept, so
+//! connecting to many hosts doesn't grow memory without bound.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Value};
+use tracing::info;
+
+/// The most hosts whose answers, or `--upstream-dns-balance` turns, are remembered.
+const MAX_CACHED: usize = 1024;
+
+#[derive(Debug, Default)]
+pub struct Resolver {
+    /// Addresses by lowercase host name.
+    overrides: Mutex<BTreeMap<String, Vec<IpAddr>>>,
+    /// The last lookup of each address, and when it was.
+    answers: Mutex<HashMap<String, (Instant, Vec<SocketAddr>)>>,
+    /// The turns taken for `--upstream-dns-balance`, by address.
+    turns: Mutex<HashMap<String, usize>>,
+}
+
+fn normalize(host: &str) -> String {
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+impl Resolver {
+    /// Resolve a `HOST:PORT` address, using the override for `HOST` if there is one.
+    pub async fn resolve(&self, addr: &str) -> io::Result<Vec<SocketAddr>> {
+        if let Some(addrs) = self.overridden(addr) {
+            return addrs;
+        }
+        Ok(tokio::net::lookup_host(addr).await?.collect())
+    }
+
+    fn overridden(&self, addr: &str) -> Option<io::Result<Vec<SocketAddr>>> {
+        let (host, port) = addr.rsplit_once(':')?;
+        let ips = self
+            .overrides
+            .lock()
+            .unwrap()
+            .get(&normalize(host))
+            .cloned()?;
+        Some(
+            port.parse()
+                .map(|port| {
+                    ips.into_iter()
+                        .map(|ip| SocketAddr::new(ip, port))
+                        .collect()
+                })
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid port number")),
+        )
+    }
+
+    /// Resolve `addr` for an upstream connect: as `resolve` does, but reusing the last
+    /// answer until it's `ttl` old and, with `balance`, starting at the next of its
+    /// addresses each call.
+    pub async fn lookup(
+        &self,
+        addr: &str,
+        ttl: Duration,
+        balance: bool,
+    ) -> io::Result<Vec<SocketAddr>> {
+        let mut addrs = match self.overridden(addr) {
+            Some(addrs) => addrs?,
+            None => {
+                let cached = self
+                    .answers
+                    .lock()
+                    .unwrap()
+                    .get(addr)
+                    .and_then(|(at, addrs)| (at.elapsed() < ttl).then(|| addrs.clone()));
+                match cached {
+                    Some(addrs) => addrs,
+                    None => {
+                        let addrs: Vec<SocketAddr> = tokio::net::lookup_host(addr).await?.collect();
+                        self.answered(addr, &addrs, ttl);
+                        addrs
+                    }
+                }
+            }
+        };
+        if balance && addrs.len() > 1 {
+            let mut turns = self.turns.lock().unwrap();
+            if turns.len() >= MAX_CACHED && !turns.contains_key(addr) {
+                // Start every host over at its first address rather than grow.
+                turns.clear();
+            }
+            let turn = turns.entry(addr.to_string()).or_default();
+            let len = addrs.len();
+            addrs.rotate_left(*turn % len);
+            *turn = turn.wrapping_add(1);
+        }
+        Ok(addrs)
+    }
+
+    /// Remember `addrs` as `addr`'s answer for `ttl`, dropping expired answers, and the
+    /// oldest if there are still `MAX_CACHED`. Nothing is kept if `ttl` is zero.
+    fn answered(&self, addr: &str, addrs: &[SocketAddr], ttl: Duration) {
+        let mut answers = self.answers.lock().unwrap();
+        let previous = answers.remove(addr);
+        if let Some((_, previous)) = previous.filter(|(_, previous)| previous != addrs) {
+            info!(upstream = %addr, from = ?previous, to = ?addrs, "upstream addresses changed");
+        }
+        if ttl.is_zero() {
+            return;
+        }
+        answers.retain(|_, (at, _)| at.elapsed() < ttl);
+        if answers.len() >= MAX_CACHED {
+            let oldest = answers
+                .iter()
+                .min_by_key(|(_, (at, _))| *at)
+                .map(|(addr, _)| addr.clone());
+            if let Some(oldest) = oldest {
+                answers.remove(&oldest);
+            }
+        }
+        answers.insert(addr.to_string(), (Instant::now(), addrs.to_vec()));
+    }
+
+    /// Apply `{"db.internal": ["10.0.0.5", "10.0.0.6"], "old.internal": null}`,
+    /// pinning each host to its addresses, or clearing its override for `null`.
+    pub fn update(&self, body: &Value) -> Result<(), String> {
+        let fields = body
+            .as_object()
+            .ok_or("expected an object of host names to addresses")?;
+        let mut updates = Vec::new();
+        for (host, addrs) in fields {
+            if host.is_empty() || host.parse::<IpAddr>().is_ok() {
+                return Err(format!("expected a host name: {}", host));
+            }
+            let addrs = match addrs {
+                Value::Null => None,
+                Value::Array(addrs) if !addrs.is_empty() => Some(
+                    addrs
+                        .iter()
+                        .map(|addr| {
+                            addr.as_str()
+                                .and_then(|addr| addr.parse().ok())
+                                .ok_or_else(|| format!("invalid address for {}: {}", host, addr))
+                        })
+                        .collect::<Result<Vec<IpAddr>, _>>()?,
+                ),
+                _ => {
+                    return Err(format!(
+                        "{} must be a non-empty list of addresses, or null",
+                        host
+                    ))
+                }
+            };
+            updates.push((normalize(host), addrs));
+        }
+        let mut overrides = self.overrides.lock().unwrap();
+        for (host, addrs) in updates {
+            match addrs {
+                Some(addrs) => overrides.insert(host, addrs),
+                None => overrides.remove(&host),
+            };
+        }
+        Ok(())
+    }
+
+    /// Pin `host` to `ips`.
+    pub fn set(&self, host: &str, ips: Vec<IpAddr>) {
+        self.overrides.lock().unwrap().insert(normalize(host), ips);
+    }
+
+    /// Clear the override for `host`, returning whether there was one.
+    pub fn remove(&self, host: &str) -> bool {
+        self.overrides
+            .lock()
+            .unwrap()
+            .remove(&normalize(host))
+            .is_some()
+    }
+
+    pub fn clear(&self) {
+        self.overrides.lock().unwrap().clear();
+    }
+
+    pub fn to_json(&self) -> Value {
+        let overrides = self.overrides.lock().unwrap();
+        let overrides: serde_json::Map<String, Value> = overrides
+            .iter()
+            .map(|(host, ips)| {
+                let ips: Vec<String> = ips.iter().map(IpAddr::to_string).collect();
+                (host.clone(), json!(ips))
+            })
+            .collect();
+        Value::Object(overrides)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_resolve() {
+        let resolver = Resolver::default();
+        resolver
+            .update(&json!({ "DB.internal.": ["10.0.0.5", "::1"] }))
+            .unwrap();
+        assert_eq!(
+            resolver.resolve("db.internal:5432").await.unwrap(),
+            [
+                "10.0.0.5:5432".parse::<SocketAddr>().unwrap(),
+                "[::1]:5432".parse().unwrap(),
+            ]
+        );
+        assert_eq!(
+            resolver.to_json(),
+            json!({ "db.internal": ["10.0.0.5", "::1"] })
+        );
+        assert_eq!(
+            resolver.resolve("127.0.0.1:80").await.unwrap(),
+            ["127.0.0.1:80".parse::<SocketAddr>().unwrap()]
+        );
+
+        assert!(resolver.update(&json!({ "a": [] })).is_err());
+        assert!(resolver.update(&json!({ "a": ["nope"] })).is_err());
+        assert!(resolver
+            .update(&json!({ "10.0.0.1": ["10.0.0.2"] }))
+            .is_err());
+        resolver.update(&json!({ "db.internal": null })).unwrap();
+        assert_eq!(resolver.to_json(), json!({}));
+        assert!(!resolver.remove("db.internal"));
+    }
+
+    #[tokio::test]
+    async fn test_lookup() {
+        let resolver = Resolver::default();
+        let stale: SocketAddr = "10.0.0.1:80".parse().unwrap();
+        resolver
+            .answers
+            .lock()
+            .unwrap()
+            .insert("127.0.0.1:80".into(), (Instant::now(), vec![stale]));
+        let ttl = Duration::from_secs(60);
+        assert_eq!(
+            resolver.lookup("127.0.0.1:80", ttl, false).await.unwrap(),
+            [stale]
+        );
+        let fresh = ["127.0.0.1:80".parse::<SocketAddr>().unwrap()];
+        assert_eq!(
+            resolver
+                .lookup("127.0.0.1:80", Duration::ZERO, false)
+                .await
+                .unwrap(),
+            fresh
+        );
+        assert!(resolver.answers.lock().unwrap().is_empty());
+        assert_eq!(
+            resolver.lookup("127.0.0.1:80", ttl, false).await.unwrap(),
+            fresh
+        );
+        assert_eq!(resolver.answers.lock().unwrap().len(), 1);
+
+        resolver.set(
+            "db.internal",
+            vec![[10, 0, 0, 5].into(), [10, 0, 0, 6].into()],
+        );
+        let first = |addrs: Vec<SocketAddr>| addrs[0].to_string();
+        let mut firsts = Vec::new();
+        for _ in 0..3 {
+            let addrs = resolver.lookup("db.internal:80", ttl, true).await.unwrap();
+            assert_eq!(addrs.len(), 2);
+            firsts.push(first(addrs));
+        }
+        assert_eq!(firsts, ["10.0.0.5:80", "10.0.0.6:80", "10.0.0.5:80"]);
+        assert_eq!(
+            first(resolver.lookup("db.internal:80", ttl, false).await.unwrap()),
+            "10.0.0.5:80"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_lookup_cache_bounded() {
+        let resolver = Resolver::default();
+        let ttl = Duration::from_millis(50);
+        for host in 1..=3 {
+            let addr = format!("127.0.0.{}:80", host);
+            resolver.lookup(&addr, ttl, false).await.unwrap();
+        }
+        assert_eq!(resolver.answers.lock().unwrap().len(), 3);
+        tokio::time::sleep(ttl).await;
+        resolver.lookup("127.0.0.4:80", ttl, false).await.unwrap();
+        assert_eq!(resolver.answers.lock().unwrap().len(), 1);
+
+        let ttl = Duration::from_secs(60);
+        for port in 0..MAX_CACHED + 10 {
+            let addr = format!("127.0.0.1:{}", port);
+            resolver.lookup(&addr, ttl, false).await.unwrap();
+        }
+        assert_eq!(resolver.answers.lock().unwrap().len(), MAX_CACHED);
+
+        resolver.set(
+            "db.internal",
+            vec![[10, 0, 0, 5].into(), [10, 0, 0, 6].into()],
+        );
+        for port in 0..MAX_CACHED + 10 {
+            let addr = format!("db.internal:{}", port);
+            resolver.lookup(&addr, ttl, true).await.unwrap();
+        }
+        assert!(resolver.turns.lock().unwrap().len() <= MAX_CACHED);
+    }
+
+    #[tokio::test]
+    async fn test_http_connect_cache_bounded() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::net::{TcpListener, TcpStream};
+
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = upstream.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            loop {
+                drop(upstream.accept().await.unwrap());
+            }
+        });
+        let handle = crate::Proxy::from_flags([
+            "--listen-addr",
+            "127.0.0.1:0",
+            "--mode",
+            "http-connect",
+            "--upstream-dns-ttl",
+            "50ms",
+        ])
+        .unwrap()
+        .start()
+        .await
+        .unwrap();
+        let proxy = handle.local_addr();
+        for round in 0..3 {
+            // Nothing listens on the other loopback addresses, so those are refused, but
+            // each CONNECT still looks its host up.
+            for host in 1..=4 {
+                let target = format!("127.0.0.{}:{}", round * 4 + host, port);
+                let mut client = TcpStream::connect(proxy).await.unwrap();
+                let request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n", target, target);
+                client.write_all(request.as_bytes()).await.unwrap();
+                let mut status = [0; 12];
+                client.read_exact(&mut status).await.unwrap();
+            }
+            let cached = handle.state().resolver.answers.lock().unwrap().len();
+            assert!(cached <= 4, "{} answers cached", cached);
+            tokio::time::sleep(Duration::from_millis(60)).await;
+        }
+        handle.shutdown(Duration::from_secs(5)).await.unwrap();
+    }
+}
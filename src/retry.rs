@@ -0,0 +1,140 @@
+//! `--retry-budget`: a cap on upstream connect retries as a share of dials, so a
+//! struggling upstream sees a bounded trickle of retries rather than a retry storm.
+//!
+//! Dials and retries are counted over the last [`WINDOW`]. A retry is allowed while
+//! retries stay within the budget of dials, or under [`MIN_RETRIES`] so a quiet proxy
+//! can still retry its occasional failure.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+pub const WINDOW: Duration = Duration::from_secs(10);
+
+/// Retries allowed per window however few dials there were.
+pub const MIN_RETRIES: u64 = 10;
+
+#[derive(Debug)]
+pub struct Budget(Mutex<Inner>);
+
+#[derive(Debug)]
+struct Inner {
+    /// Retries allowed per dial.
+    ratio: f64,
+    start: Instant,
+    /// Dials and retries by second since `start`, oldest first.
+    seconds: VecDeque<(u64, u64, u64)>,
+}
+
+impl Default for Budget {
+    fn default() -> Self {
+        Budget(Mutex::new(Inner {
+            ratio: 0.2,
+            start: Instant::now(),
+            seconds: VecDeque::new(),
+        }))
+    }
+}
+
+impl Inner {
+    /// The dials and retries in the window ending at `now`, pruning older seconds.
+    fn totals(&mut self, now: Instant) -> (u64, u64) {
+        let second = now.duration_since(self.start).as_secs();
+        let oldest = second.saturating_sub(WINDOW.as_secs() - 1);
+        while self.seconds.front().is_some_and(|(s, _, _)| *s < oldest) {
+            self.seconds.pop_front();
+        }
+        self.seconds
+            .iter()
+            .fold((0, 0), |(dials, retries), (_, d, r)| {
+                (dials + d, retries + r)
+            })
+    }
+
+    fn count(&mut self, now: Instant, dials: u64, retries: u64) {
+        let second = now.duration_since(self.start).as_secs();
+        match self.seconds.back_mut() {
+            Some((s, d, r)) if *s == second => {
+                *d += dials;
+                *r += retries;
+            }
+            _ => self.seconds.push_back((second, dials, retries)),
+        }
+    }
+
+    fn allowed(&self, dials: u64) -> u64 {
+        MIN_RETRIES.max((self.ratio * dials as f64) as u64)
+    }
+}
+
+impl Budget {
+    /// Allow retries up to `ratio` of dials.
+    pub fn set_ratio(&self, ratio: f64) {
+        self.0.lock().unwrap().ratio = ratio;
+    }
+
+    /// Count a first attempt to connect upstream.
+    pub fn dialed(&self) {
+        self.dialed_at(Instant::now());
+    }
+
+    fn dialed_at(&self, now: Instant) {
+        self.0.lock().unwrap().count(now, 1, 0);
+    }
+
+    /// Whether there's budget left for a retry, counting it if so.
+    pub fn try_retry(&self) -> bool {
+        self.try_retry_at(Instant::now())
+    }
+
+    fn try_retry_at(&self, now: Instant) -> bool {
+        let mut inner = self.0.lock().unwrap();
+        let (dials, retries) = inner.totals(now);
+        if retries >= inner.allowed(dials) {
+            return false;
+        }
+        inner.count(now, 0, 1);
+        true
+    }
+
+    /// The share of the budget used in the current window.
+    pub fn usage(&self) -> f64 {
+        let mut inner = self.0.lock().unwrap();
+        let (dials, retries) = inner.totals(Instant::now());
+        retries as f64 / inner.allowed(dials) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_budget() {
+        let budget = Budget::default();
+        let start = budget.0.lock().unwrap().start;
+        for _ in 0..100 {
+            budget.dialed_at(start);
+        }
+        // 20% of 100 dials.
+        assert_eq!((0..30).filter(|_| budget.try_retry_at(start)).count(), 20);
+        assert_eq!(budget.usage(), 1.0);
+
+        // The window moves on.
+        let later = start + WINDOW;
+        assert!(budget.try_retry_at(later));
+        let (dials, retries) = budget.0.lock().unwrap().totals(later);
+        assert_eq!((dials, retries), (0, 1));
+
+        // Few dials still get a few retries.
+        let budget = Budget::default();
+        budget.set_ratio(0.0);
+        budget.dialed();
+        assert_eq!(
+            (0..20).filter(|_| budget.try_retry()).count() as u64,
+            MIN_RETRIES
+        );
+    }
+}
@@ -0,0 +1,287 @@
+//! Search and replace on the bytes of TCP-mode connections, see `--replace`.
+//!
+//! A [`Reader`] wraps one direction of a connection and rewrites matches of each
+//! rule as bytes pass through. Bytes which could be the start of a match are held
+//! back until enough follow to decide, but never for longer than `FLUSH_DELAY`
+//! or beyond `MAX_MATCH_LEN`, so that a request ending in the first half of a match
+//! isn't stalled waiting for bytes that aren't coming.
+
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::Ordering;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio::time::Sleep;
+
+use crate::connection::{Connection, Direction, COPY_BUFFER_SIZE};
+use crate::http::split_slashes;
+use crate::pattern::Pattern;
+use crate::state::State;
+
+/// The longest match which is found across reads.
+pub const MAX_MATCH_LEN: usize = 4096;
+
+/// How long to hold back a possible partial match while no more bytes arrive.
+const FLUSH_DELAY: Duration = Duration::from_millis(20);
+
+/// A rule such as `up:/internal\.example\.com/proxy.example.com/`.
+#[derive(Clone, Debug)]
+pub struct Rule {
+    source: String,
+    /// `None` for both directions.
+    direction: Option<Direction>,
+    pattern: Pattern,
+    replacement: Vec<u8>,
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl FromStr for Rule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (direction, rest) = s
+            .split_once(':')
+            .ok_or_else(|| format!("expected DIRECTION:/PATTERN/REPLACEMENT/: {}", s))?;
+        let direction = match direction {
+            "both" => None,
+            direction => Some(direction.parse()?),
+        };
+        let (pattern, replacement) = split_slashes(rest)
+            .ok_or_else(|| format!("expected /PATTERN/REPLACEMENT/ in rule: {}", s))?;
+        let pattern: Pattern = pattern.parse()?;
+        if pattern.is_anchored() || pattern.matches_empty() {
+            return Err(format!(
+                "pattern must not be anchored or match nothing: {}",
+                pattern
+            ));
+        }
+        Ok(Rule {
+            source: s.to_string(),
+            direction,
+            pattern,
+            replacement: replacement.into_bytes(),
+        })
+    }
+}
+
+/// Rewrite `pending` into `out` with the first rule to match at each position,
+/// counting into `counts`. Returns how many bytes of `pending` were used: unless
+/// `flush`, stop where more bytes could change whether or how a rule matches.
+fn rewrite(
+    rules: &[&Rule],
+    pending: &[u8],
+    flush: bool,
+    out: &mut Vec<u8>,
+    counts: &mut [u64],
+) -> usize {
+    let mut copied = 0;
+    let mut i = 0;
+    'scan: while i < pending.len() {
+        for (n, rule) in rules.iter().enumerate() {
+            let (end, ended) = rule.pattern.match_prefix(pending, i);
+            if ended && !flush {
+                break 'scan;
+            }
+            if let Some(end) = end {
+                out.extend_from_slice(&pending[copied..i]);
+                out.extend_from_slice(&rule.replacement);
+                counts[n] += 1;
+                i = end;
+                copied = end;
+                continue 'scan;
+            }
+        }
+        i += 1;
+    }
+    out.extend_from_slice(&pending[copied..i]);
+    i
+}
+
+/// Reads from `inner`, rewritten by the rules for one direction.
+pub struct Reader<'a, R> {
+    inner: R,
+    rules: Vec<&'a Rule>,
+    conn: &'a Connection,
+    state: &'a State,
+    buf: Vec<u8>,
+    /// Bytes read but not yet rewritten.
+    pending: Vec<u8>,
+    /// Rewritten bytes, from `out_pos`, waiting to be read.
+    out: Vec<u8>,
+    out_pos: usize,
+    flush: Option<Pin<Box<Sleep>>>,
+    eof: bool,
+}
+
+impl<'a, R> Reader<'a, R> {
+    pub fn new(
+        inner: R,
+        rules: &'a [Rule],
+        direction: Direction,
+        conn: &'a Connection,
+        state: &'a State,
+    ) -> Self {
+        Self {
+            inner,
+            rules: rules
+                .iter()
+                .filter(|rule| rule.direction.is_none_or(|d| d == direction))
+                .collect(),
+            conn,
+            state,
+            buf: vec![0; COPY_BUFFER_SIZE],
+            pending: Vec::new(),
+            out: Vec::new(),
+            out_pos: 0,
+            flush: None,
+            eof: false,
+        }
+    }
+
+    fn process(&mut self, flush: bool) {
+        let flush = flush || self.pending.len() >= MAX_MATCH_LEN;
+        let mut counts = vec![0; self.rules.len()];
+        self.out.drain(..self.out_pos);
+        self.out_pos = 0;
+        let used = rewrite(
+            &self.rules,
+            &self.pending,
+            flush,
+            &mut self.out,
+            &mut counts,
+        );
+        self.pending.drain(..used);
+        for (rule, count) in self.rules.iter().zip(counts) {
+            if count > 0 {
+                self.conn.add_replacements(&rule.source, count);
+                self.state
+                    .stream_replacements
+                    .fetch_add(count as usize, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Reader<'_, R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.out_pos < this.out.len() {
+                let n = buf.remaining().min(this.out.len() - this.out_pos);
+                buf.put_slice(&this.out[this.out_pos..this.out_pos + n]);
+                this.out_pos += n;
+                return Poll::Ready(Ok(()));
+            }
+            if this.eof {
+                return Poll::Ready(Ok(()));
+            }
+            let mut read = ReadBuf::new(&mut this.buf);
+            match Pin::new(&mut this.inner).poll_read(cx, &mut read) {
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Ready(Ok(())) => {
+                    let n = read.filled().len();
+                    this.pending.extend_from_slice(&this.buf[..n]);
+                    this.flush = None;
+                    this.eof = n == 0;
+                    this.process(this.eof);
+                }
+                Poll::Pending if this.pending.is_empty() => return Poll::Pending,
+                Poll::Pending => {
+                    let sleep = this
+                        .flush
+                        .get_or_insert_with(|| Box::pin(tokio::time::sleep(FLUSH_DELAY)));
+                    if sleep.as_mut().poll(cx).is_pending() {
+                        return Poll::Pending;
+                    }
+                    this.flush = None;
+                    this.process(true);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn rewrite_all(rules: &[Rule], chunks: &[&[u8]]) -> (Vec<u8>, Vec<u64>) {
+        let rules: Vec<&Rule> = rules.iter().collect();
+        let mut counts = vec![0; rules.len()];
+        let mut pending = Vec::new();
+        let mut out = Vec::new();
+        for chunk in chunks {
+            pending.extend_from_slice(chunk);
+            let used = rewrite(&rules, &pending, false, &mut out, &mut counts);
+            pending.drain(..used);
+        }
+        rewrite(&rules, &pending, true, &mut out, &mut counts);
+        (out, counts)
+    }
+
+    #[test]
+    fn test_rewrite() {
+        let rules: Vec<Rule> = [
+            "up:/internal\\.example\\.com/proxy/",
+            "both:/token=\\w+/token=x/",
+        ]
+        .iter()
+        .map(|s| s.parse().unwrap())
+        .collect();
+        let (out, counts) = rewrite_all(
+            &rules,
+            &[
+                b"GET / HTTP/1.1\r\nHost: inter",
+                b"nal.example.com\r\nX: token=ab",
+                b"c1\r\n",
+            ],
+        );
+        assert_eq!(out, b"GET / HTTP/1.1\r\nHost: proxy\r\nX: token=x\r\n");
+        assert_eq!(counts, [1, 1]);
+
+        // A partial match at the end is left alone.
+        let (out, counts) = rewrite_all(&rules, &[b"internal.exam"]);
+        assert_eq!(out, b"internal.exam");
+        assert_eq!(counts, [0, 0]);
+
+        assert!("up:/x*/y/".parse::<Rule>().is_err());
+        assert!("up:/^x/y/".parse::<Rule>().is_err());
+        assert!("left:/x/y/".parse::<Rule>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reader_flushes() {
+        let state = State::new();
+        let conn = Connection::new(1, "127.0.0.1:1234".parse().unwrap(), "up:80".into());
+        let rules = vec!["down:/secret/******/".parse::<Rule>().unwrap()];
+        let (mut upstream, inner) = tokio::io::duplex(64);
+        let mut reader = Reader::new(inner, &rules, Direction::Down, &conn, &state);
+
+        upstream.write_all(b"the secret is sec").await.unwrap();
+        let mut buf = [0; 64];
+        let n = reader.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"the ****** is ");
+        // Nothing follows the possible match, so it's flushed as is.
+        let n = reader.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"sec");
+
+        assert_eq!(conn.detail()["replacements"]["down:/secret/******/"], 1);
+        assert_eq!(state.snapshot().stream_replacements, 1);
+    }
+}
@@ -0,0 +1,93 @@
+//! `--route`: extra named listeners in the same process, each forwarding to its own
+//! upstream with its own stats and optionally its own `--route-toxics`, so a config
+//! file can describe, say, a database that's flaky from boot next to one that isn't.
+
+use std::str::FromStr;
+
+use crate::toxic::Setting;
+
+/// A `--route` rule, `NAME=LISTEN_ADDR->UPSTREAM_ADDR`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Route {
+    pub name: String,
+    pub listen_addr: String,
+    pub upstream_addr: String,
+}
+
+impl FromStr for Route {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || format!("expected NAME=LISTEN_ADDR->UPSTREAM_ADDR: {}", s);
+        let (name, addrs) = s.split_once('=').ok_or_else(err)?;
+        let (listen_addr, upstream_addr) = addrs.split_once("->").ok_or_else(err)?;
+        if listen_addr.is_empty() || upstream_addr.is_empty() {
+            return Err(err());
+        }
+        let valid = !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !valid {
+            return Err(format!(
+                "route names may only use letters, digits, - and _: {}",
+                name
+            ));
+        }
+        Ok(Route {
+            name: name.to_string(),
+            listen_addr: listen_addr.to_string(),
+            upstream_addr: upstream_addr.to_string(),
+        })
+    }
+}
+
+/// A `--route-toxics` rule, `NAME=TOXICS` with TOXICS as for `--toxics`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RouteToxics {
+    pub route: String,
+    pub setting: Setting,
+}
+
+impl FromStr for RouteToxics {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (route, setting) = s
+            .split_once('=')
+            .filter(|(route, _)| !route.is_empty())
+            .ok_or_else(|| format!("expected NAME=TOXICS: {}", s))?;
+        Ok(RouteToxics {
+            route: route.to_string(),
+            setting: setting.parse()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_route() {
+        assert_eq!(
+            "pg=127.0.0.1:6432->10.0.0.5:5432".parse(),
+            Ok(Route {
+                name: "pg".to_string(),
+                listen_addr: "127.0.0.1:6432".to_string(),
+                upstream_addr: "10.0.0.5:5432".to_string(),
+            })
+        );
+        assert!("pg=127.0.0.1:6432".parse::<Route>().is_err());
+        assert!("=127.0.0.1:6432->10.0.0.5:5432".parse::<Route>().is_err());
+        assert!("p g=127.0.0.1:6432->10.0.0.5:5432"
+            .parse::<Route>()
+            .is_err());
+        assert!("pg=->10.0.0.5:5432".parse::<Route>().is_err());
+
+        let toxics: RouteToxics = "pg=down:latency=20ms".parse().unwrap();
+        assert_eq!(toxics.route, "pg");
+        assert!("=lte".parse::<RouteToxics>().is_err());
+        assert!("pg=nonsense".parse::<RouteToxics>().is_err());
+    }
+}
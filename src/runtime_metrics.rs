@@ -0,0 +1,122 @@
+//! Tokio runtime metrics, enabled with the `runtime-metrics` feature.
+//!
+//! A probe task measures how long freshly spawned tasks wait before they are first
+//! polled, which grows when the runtime is saturated. Per-worker poll, park, steal
+//! and busy-time counters come from tokio's own stats, which additionally require
+//! building with `RUSTFLAGS="--cfg tokio_unstable"`.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::metrics::{header, Buckets, Histogram};
+use crate::state::State;
+
+/// How often the scheduling delay probe runs.
+const PROBE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Scheduling delays are usually far below connect latencies, so use finer buckets.
+const SCHEDULING_DELAY_BUCKETS: &str =
+    "0.00001,0.00005,0.0001,0.0005,0.001,0.005,0.01,0.05,0.1,0.5,1";
+
+#[derive(Debug)]
+pub struct RuntimeMetrics {
+    scheduling_delay: Histogram,
+}
+
+impl RuntimeMetrics {
+    pub fn new() -> Self {
+        let buckets: Buckets = SCHEDULING_DELAY_BUCKETS.parse().unwrap();
+        Self {
+            scheduling_delay: Histogram::new(buckets),
+        }
+    }
+}
+
+/// Periodically spawn an empty task and record how long it took to start running.
+pub async fn probe(state: Arc<State>) {
+    let mut interval = tokio::time::interval(PROBE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let spawned = Instant::now();
+        if let Ok(delay) = tokio::spawn(async move { spawned.elapsed() }).await {
+            state.runtime.scheduling_delay.observe(delay.as_secs_f64());
+        }
+    }
+}
+
+pub fn render(out: &mut String, state: &State) {
+    let name = "tproxy_runtime_scheduling_delay_seconds";
+    header(
+        out,
+        name,
+        "histogram",
+        "Time between spawning a task and its first poll.",
+    );
+    state.runtime.scheduling_delay.render(out, name, "");
+
+    #[cfg(tokio_unstable)]
+    render_worker_stats(out);
+}
+
+#[cfg(tokio_unstable)]
+fn render_worker_stats(out: &mut String) {
+    use std::fmt::Write;
+
+    let handle = tokio::runtime::Handle::current();
+    let workers: Vec<_> = handle.stats().workers().collect();
+
+    header(
+        out,
+        "tproxy_runtime_workers",
+        "gauge",
+        "Number of runtime worker threads.",
+    );
+    let _ = writeln!(out, "tproxy_runtime_workers {}", workers.len());
+
+    let series: [(&str, &str, fn(&tokio::runtime::stats::WorkerStats) -> f64); 4] = [
+        (
+            "tproxy_runtime_worker_polls_total",
+            "Tasks polled by each worker.",
+            |w| w.poll_count() as f64,
+        ),
+        (
+            "tproxy_runtime_worker_parks_total",
+            "Times each worker parked.",
+            |w| w.park_count() as f64,
+        ),
+        (
+            "tproxy_runtime_worker_steals_total",
+            "Tasks stolen by each worker.",
+            |w| w.steal_count() as f64,
+        ),
+        (
+            "tproxy_runtime_worker_busy_seconds_total",
+            "Time each worker spent busy.",
+            |w| w.total_busy_duration().as_secs_f64(),
+        ),
+    ];
+    for (name, help, value) in series {
+        header(out, name, "counter", help);
+        for (i, worker) in workers.iter().enumerate() {
+            let _ = writeln!(out, "{}{{worker=\"{}\"}} {}", name, i, value(worker));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_probe() {
+        let state = Arc::new(State::new());
+        let probe = tokio::spawn(probe(state.clone()));
+        tokio::time::sleep(PROBE_INTERVAL * 2).await;
+        probe.abort();
+
+        let mut out = String::new();
+        render(&mut out, &state);
+        assert!(out.contains("tproxy_runtime_scheduling_delay_seconds_count"));
+        assert!(!out.contains("tproxy_runtime_scheduling_delay_seconds_count{} 0\n"));
+    }
+}
@@ -0,0 +1,522 @@
+//! `--capture-upload`: ship rotated capture files to an S3-compatible bucket, and
+//! expire old ones, so long-running rigs don't fill their disks.
+//!
+//! Requests are signed with AWS Signature Version 4 using `AWS_ACCESS_KEY_ID`,
+//! `AWS_SECRET_ACCESS_KEY` and, if set, `AWS_SESSION_TOKEN`. Buckets are addressed
+//! path-style (`ENDPOINT/BUCKET/KEY`) over plain HTTP, as served by MinIO, Ceph and
+//! other S3-compatible stores; tproxy has no TLS client. The signed requests and the
+//! captures themselves travel in the clear, so there's no default endpoint: it has to
+//! be given, and should be a store on the local network.
+
+use std::fmt::Write as _;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hyper::body::Bytes;
+use hyper::{Body, Client, Method, Request, Uri};
+use tokio::sync::mpsc::UnboundedReceiver;
+use tracing::{info, warn};
+
+use crate::digest::{hex, hmac_sha256, sha256};
+
+/// Where and how to upload.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Bucket {
+    /// `http://HOST[:PORT]`, without a trailing slash.
+    pub endpoint: String,
+    pub bucket: String,
+    /// Prepended to each object's name, with a `/` if it's not empty.
+    pub prefix: String,
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+    pub session_token: Option<String>,
+}
+
+impl Bucket {
+    /// A bucket from an `s3://BUCKET[/PREFIX]` URL and credentials in the environment.
+    pub fn from_env(url: &str, endpoint: &str, region: &str) -> Result<Bucket, String> {
+        let path = url
+            .strip_prefix("s3://")
+            .ok_or_else(|| format!("expected s3://BUCKET[/PREFIX]: {}", url))?;
+        let (bucket, prefix) = path.split_once('/').unwrap_or((path, ""));
+        if bucket.is_empty() {
+            return Err(format!("expected s3://BUCKET[/PREFIX]: {}", url));
+        }
+        if !endpoint.starts_with("http://") {
+            return Err(format!(
+                "the upload endpoint must be http://, as there is no TLS client: {}",
+                endpoint
+            ));
+        }
+        let env = |name: &str| std::env::var(name).map_err(|_| format!("{} is not set", name));
+        Ok(Bucket {
+            endpoint: endpoint.trim_end_matches('/').to_string(),
+            bucket: bucket.to_string(),
+            prefix: prefix.trim_matches('/').to_string(),
+            region: region.to_string(),
+            access_key: env("AWS_ACCESS_KEY_ID")?,
+            secret_key: env("AWS_SECRET_ACCESS_KEY")?,
+            session_token: std::env::var("AWS_SESSION_TOKEN").ok(),
+        })
+    }
+
+    fn key(&self, name: &str) -> String {
+        if self.prefix.is_empty() {
+            name.to_string()
+        } else {
+            format!("{}/{}", self.prefix, name)
+        }
+    }
+
+    pub async fn put(&self, key: &str, body: Bytes) -> Result<(), String> {
+        self.send(Method::PUT, key, &[], body).await.map(|_| ())
+    }
+
+    pub async fn delete(&self, key: &str) -> Result<(), String> {
+        self.send(Method::DELETE, key, &[], Bytes::new())
+            .await
+            .map(|_| ())
+    }
+
+    /// The keys of every object whose key starts with `prefix`.
+    pub async fn list(&self, prefix: &str) -> Result<Vec<String>, String> {
+        let mut keys = Vec::new();
+        let mut token = None;
+        loop {
+            let mut query = vec![
+                ("list-type", "2".to_string()),
+                ("prefix", prefix.to_string()),
+            ];
+            if let Some(token) = token.take() {
+                query.push(("continuation-token", token));
+            }
+            let body = self.send(Method::GET, "", &query, Bytes::new()).await?;
+            let body = String::from_utf8_lossy(&body);
+            keys.extend(elements(&body, "Key"));
+            match elements(&body, "NextContinuationToken").pop() {
+                Some(next)
+                    if elements(&body, "IsTruncated").first().map(String::as_str)
+                        == Some("true") =>
+                {
+                    token = Some(next)
+                }
+                _ => return Ok(keys),
+            }
+        }
+    }
+
+    async fn send(
+        &self,
+        method: Method,
+        key: &str,
+        query: &[(&str, String)],
+        body: Bytes,
+    ) -> Result<Bytes, String> {
+        let mut path = format!("/{}", uri_encode(&self.bucket, true));
+        if !key.is_empty() {
+            path.push('/');
+            path.push_str(&uri_encode(key, false));
+        }
+        let mut query: Vec<(String, String)> = query
+            .iter()
+            .map(|(k, v)| (uri_encode(k, true), uri_encode(v, true)))
+            .collect();
+        query.sort();
+        let query = query
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect::<Vec<_>>()
+            .join("&");
+        let uri: Uri = if query.is_empty() {
+            format!("{}{}", self.endpoint, path)
+        } else {
+            format!("{}{}?{}", self.endpoint, path, query)
+        }
+        .parse()
+        .map_err(|err| format!("invalid upload URL: {}", err))?;
+        let host = uri.authority().map(|a| a.to_string()).unwrap_or_default();
+
+        let payload_hash = hex(&sha256(&body));
+        let amz_date = amz_date(SystemTime::now());
+        let mut headers = vec![
+            ("host".to_string(), host),
+            ("x-amz-content-sha256".to_string(), payload_hash.clone()),
+            ("x-amz-date".to_string(), amz_date.clone()),
+        ];
+        if let Some(token) = &self.session_token {
+            headers.push(("x-amz-security-token".to_string(), token.clone()));
+        }
+        let authorization = authorization(
+            &Signing {
+                method: method.as_str(),
+                path: &path,
+                query: &query,
+                headers: &headers,
+                payload_hash: &payload_hash,
+            },
+            &amz_date,
+            &self.region,
+            "s3",
+            &self.access_key,
+            &self.secret_key,
+        );
+
+        let mut req = Request::builder().method(method).uri(uri);
+        for (name, value) in &headers {
+            if name != "host" {
+                req = req.header(name.as_str(), value.as_str());
+            }
+        }
+        let req = req
+            .header("authorization", authorization)
+            .body(Body::from(body))
+            .map_err(|err| err.to_string())?;
+        let resp = Client::new()
+            .request(req)
+            .await
+            .map_err(|err| err.to_string())?;
+        let status = resp.status();
+        let body = hyper::body::to_bytes(resp.into_body())
+            .await
+            .map_err(|err| err.to_string())?;
+        if !status.is_success() {
+            let message = elements(&String::from_utf8_lossy(&body), "Message").pop();
+            return Err(format!(
+                "{} {}",
+                status,
+                message.unwrap_or_else(|| "from object store".to_string())
+            ));
+        }
+        Ok(body)
+    }
+}
+
+/// Upload each rotated capture file sent on `rotated`, removing it locally once it's
+/// uploaded, then delete uploaded captures older than `retention` (unless it's zero).
+pub async fn upload_rotated(
+    mut rotated: UnboundedReceiver<String>,
+    bucket: Bucket,
+    retention: Duration,
+) {
+    while let Some(path) = rotated.recv().await {
+        let name = match Path::new(&path).file_name().and_then(|n| n.to_str()) {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
+        let key = bucket.key(&name);
+        match upload_file(&bucket, &path, &key).await {
+            Ok(()) => info!(%key, "uploaded capture"),
+            Err(err) => {
+                // Leave the file for someone to upload by hand.
+                warn!(%path, error = %err, "failed to upload capture");
+                continue;
+            }
+        }
+        if !retention.is_zero() {
+            if let Err(err) = expire(&bucket, &name, retention).await {
+                warn!(error = %err, "failed to expire captures");
+            }
+        }
+    }
+}
+
+async fn upload_file(bucket: &Bucket, path: &str, key: &str) -> Result<(), String> {
+    // Read once and shared between attempts, as captures can be hundreds of MB.
+    let body = Bytes::from(tokio::fs::read(path).await.map_err(|err| err.to_string())?);
+    let mut attempt = 0;
+    loop {
+        match bucket.put(key, body.clone()).await {
+            Ok(()) => break,
+            Err(err) if attempt >= 2 => return Err(err),
+            Err(_) => {
+                tokio::time::sleep(Duration::from_secs(1 << attempt)).await;
+                attempt += 1;
+            }
+        }
+    }
+    tokio::fs::remove_file(path)
+        .await
+        .map_err(|err| err.to_string())
+}
+
+/// Delete captures rotated from the same file as `name` more than `retention` ago.
+/// Rotated files are named `FILE.UNIX_MILLIS`, which is what's compared.
+async fn expire(bucket: &Bucket, name: &str, retention: Duration) -> Result<(), String> {
+    let base = match name.rsplit_once('.') {
+        Some((base, _)) => base,
+        None => return Ok(()),
+    };
+    let prefix = bucket.key(&format!("{}.", base));
+    let cutoff = SystemTime::now()
+        .checked_sub(retention)
+        .unwrap_or(UNIX_EPOCH)
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    for key in bucket.list(&prefix).await? {
+        let rotated_at = key.strip_prefix(&prefix).map(str::parse::<u128>);
+        if matches!(rotated_at, Some(Ok(at)) if at < cutoff) {
+            bucket.delete(&key).await?;
+            info!(%key, "expired capture");
+        }
+    }
+    Ok(())
+}
+
+/// The text of each `<TAG>...</TAG>` element, in order.
+fn elements(xml: &str, tag: &str) -> Vec<String> {
+    let open = format!("<{}>", tag);
+    let close = format!("</{}>", tag);
+    let mut out = Vec::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find(&open) {
+        rest = &rest[start + open.len()..];
+        let end = match rest.find(&close) {
+            Some(end) => end,
+            None => break,
+        };
+        out.push(
+            rest[..end]
+                .replace("&lt;", "<")
+                .replace("&gt;", ">")
+                .replace("&quot;", "\"")
+                .replace("&apos;", "'")
+                .replace("&amp;", "&"),
+        );
+        rest = &rest[end + close.len()..];
+    }
+    out
+}
+
+/// Percent-encode everything but unreserved characters, and `/` unless `slash`.
+fn uri_encode(s: &str, slash: bool) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        if b.is_ascii_alphanumeric() || b"-_.~".contains(&b) || (b == b'/' && !slash) {
+            out.push(b as char);
+        } else {
+            let _ = write!(out, "%{:02X}", b);
+        }
+    }
+    out
+}
+
+/// A request as far as Signature Version 4 is concerned. `query` is the canonical
+/// query string and `headers` are lowercase names, all of which are signed.
+struct Signing<'a> {
+    method: &'a str,
+    path: &'a str,
+    query: &'a str,
+    headers: &'a [(String, String)],
+    payload_hash: &'a str,
+}
+
+fn authorization(
+    req: &Signing,
+    amz_date: &str,
+    region: &str,
+    service: &str,
+    access_key: &str,
+    secret_key: &str,
+) -> String {
+    let mut headers: Vec<_> = req.headers.iter().collect();
+    headers.sort();
+    let signed_headers = headers
+        .iter()
+        .map(|(name, _)| name.as_str())
+        .collect::<Vec<_>>()
+        .join(";");
+    let mut canonical = format!("{}\n{}\n{}\n", req.method, req.path, req.query);
+    for (name, value) in &headers {
+        let _ = writeln!(canonical, "{}:{}", name, value.trim());
+    }
+    let _ = write!(canonical, "\n{}\n{}", signed_headers, req.payload_hash);
+
+    let date = &amz_date[..8];
+    let scope = format!("{}/{}/{}/aws4_request", date, region, service);
+    let to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        amz_date,
+        scope,
+        hex(&sha256(canonical.as_bytes()))
+    );
+    let mut key = hmac_sha256(format!("AWS4{}", secret_key).as_bytes(), date.as_bytes());
+    for part in [region, service, "aws4_request"] {
+        key = hmac_sha256(&key, part.as_bytes());
+    }
+    let signature = hex(&hmac_sha256(&key, to_sign.as_bytes()));
+    format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+        access_key, scope, signed_headers, signature
+    )
+}
+
+/// `YYYYMMDDTHHMMSSZ`, in UTC.
+fn amz_date(t: SystemTime) -> String {
+    let secs = t.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as i64;
+    let (days, secs) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+    // Civil date from days since the epoch (Howard Hinnant's algorithm).
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::BTreeMap;
+    use std::sync::{Arc, Mutex};
+
+    use warp::Filter;
+
+    #[test]
+    fn test_signing() {
+        assert_eq!(amz_date(UNIX_EPOCH), "19700101T000000Z");
+        assert_eq!(
+            amz_date(UNIX_EPOCH + Duration::from_secs(951_868_800 + 3723)),
+            "20000301T010203Z"
+        );
+        assert_eq!(uri_encode("a b/c~", false), "a%20b/c~");
+        assert_eq!(uri_encode("a/b", true), "a%2Fb");
+
+        // The get-vanilla case from the AWS Signature Version 4 test suite.
+        let headers = [
+            ("host".to_string(), "example.amazonaws.com".to_string()),
+            ("x-amz-date".to_string(), "20150830T123600Z".to_string()),
+        ];
+        let req = Signing {
+            method: "GET",
+            path: "/",
+            query: "",
+            headers: &headers,
+            payload_hash: &hex(&sha256(b"")),
+        };
+        assert_eq!(
+            authorization(
+                &req,
+                "20150830T123600Z",
+                "us-east-1",
+                "service",
+                "AKIDEXAMPLE",
+                "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY"
+            ),
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, \
+             SignedHeaders=host;x-amz-date, \
+             Signature=5fa00fa31553b73ebf1942676e86291e8372ff2a2260956d9b8aae1d763fbf31"
+        );
+    }
+
+    type Objects = Arc<Mutex<BTreeMap<String, Vec<u8>>>>;
+
+    /// A bucket served from memory: PUT stores, DELETE removes and GET lists every key.
+    fn fake_store() -> (String, Objects) {
+        let objects = Arc::new(Mutex::new(BTreeMap::<String, Vec<u8>>::new()));
+        let store = objects.clone();
+        let routes = warp::method()
+            .and(warp::path::full())
+            .and(warp::header::<String>("authorization"))
+            .and(warp::body::bytes())
+            .map(
+                move |method: Method,
+                      path: warp::path::FullPath,
+                      auth: String,
+                      body: hyper::body::Bytes| {
+                    assert!(auth.starts_with("AWS4-HMAC-SHA256 Credential=AK/"));
+                    let key = path
+                        .as_str()
+                        .trim_start_matches("/captures")
+                        .trim_start_matches('/');
+                    let mut objects = store.lock().unwrap();
+                    let reply = match method {
+                        Method::PUT => {
+                            objects.insert(key.to_string(), body.to_vec());
+                            String::new()
+                        }
+                        Method::DELETE => {
+                            objects.remove(key);
+                            String::new()
+                        }
+                        _ => {
+                            let mut xml = "<ListBucketResult>".to_string();
+                            for key in objects.keys() {
+                                let _ = write!(xml, "<Contents><Key>{}</Key></Contents>", key);
+                            }
+                            xml + "<IsTruncated>false</IsTruncated></ListBucketResult>"
+                        }
+                    };
+                    reply
+                },
+            );
+        let (addr, server) = warp::serve(routes).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        (format!("http://{}", addr), objects)
+    }
+
+    #[tokio::test]
+    async fn test_upload_rotated() {
+        let (endpoint, objects) = fake_store();
+        let bucket = Bucket {
+            endpoint,
+            bucket: "captures".to_string(),
+            prefix: "rig1".to_string(),
+            region: "us-east-1".to_string(),
+            access_key: "AK".to_string(),
+            secret_key: "SK".to_string(),
+            session_token: None,
+        };
+        let path = std::env::temp_dir().join(format!(
+            "tproxy-upload-{}.ndjson.{}",
+            std::process::id(),
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_millis()
+        ));
+        std::fs::write(&path, "{}\n").unwrap();
+        let name = path.file_name().unwrap().to_str().unwrap().to_string();
+        // Rotated in 1970, so well past retention.
+        bucket
+            .put(&bucket.key("capture.ndjson.1000"), Bytes::new())
+            .await
+            .unwrap();
+
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        tx.send(path.to_str().unwrap().to_string()).unwrap();
+        drop(tx);
+        upload_rotated(rx, bucket.clone(), Duration::from_secs(3600)).await;
+
+        assert!(!path.exists());
+        let uploaded = objects.lock().unwrap()[&format!("rig1/{}", name)].clone();
+        assert_eq!(uploaded, b"{}\n");
+        // Only captures rotated from the same file are expired.
+        assert!(objects
+            .lock()
+            .unwrap()
+            .contains_key("rig1/capture.ndjson.1000"));
+
+        expire(&bucket, "capture.ndjson.2000", Duration::from_secs(3600))
+            .await
+            .unwrap();
+        assert_eq!(
+            bucket.list("rig1/").await.unwrap(),
+            [format!("rig1/{}", name)]
+        );
+    }
+}
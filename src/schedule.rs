@@ -0,0 +1,206 @@
+//! Schedules for when the listener accepts connections, see `--schedule`.
+//!
+//! Outside its schedule the listener socket is closed, so clients are refused just as
+//! they would be by a service down for maintenance, and it's bound again when the
+//! schedule next allows.
+
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::parse_duration;
+
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Rule {
+    /// Accept between these times of the UTC day. A window with `start` after `end`
+    /// wraps past midnight.
+    Daily { start: Duration, end: Duration },
+    /// Accept for `open`, then refuse for `closed`, repeating from startup.
+    Flap { open: Duration, closed: Duration },
+    /// Accept once this long has passed since startup.
+    After(Duration),
+    /// Accept until this long has passed since startup.
+    For(Duration),
+}
+
+impl FromStr for Rule {
+    type Err = String;
+
+    /// `daily:09:00-17:00`, `flap:30s/10s`, `after:1m` or `for:10m`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, arg) = s
+            .split_once(':')
+            .ok_or_else(|| format!("expected KIND:ARGUMENT in schedule: {}", s))?;
+        match kind {
+            "daily" => {
+                let (start, end) = arg
+                    .split_once('-')
+                    .ok_or_else(|| format!("expected daily:HH:MM-HH:MM: {}", s))?;
+                let (start, end) = (parse_time_of_day(start)?, parse_time_of_day(end)?);
+                if start == end {
+                    return Err(format!("empty daily window: {}", s));
+                }
+                Ok(Rule::Daily { start, end })
+            }
+            "flap" => {
+                let (open, closed) = arg
+                    .split_once('/')
+                    .ok_or_else(|| format!("expected flap:OPEN/CLOSED: {}", s))?;
+                let (open, closed) = (parse_duration(open)?, parse_duration(closed)?);
+                if open.is_zero() || closed.is_zero() {
+                    return Err(format!("flap periods must not be zero: {}", s));
+                }
+                Ok(Rule::Flap { open, closed })
+            }
+            "after" => Ok(Rule::After(parse_duration(arg)?)),
+            "for" => Ok(Rule::For(parse_duration(arg)?)),
+            _ => Err(format!("unknown schedule: {}", s)),
+        }
+    }
+}
+
+fn parse_time_of_day(s: &str) -> Result<Duration, String> {
+    let (hours, minutes) = s
+        .split_once(':')
+        .ok_or_else(|| format!("expected HH:MM: {}", s))?;
+    let hours: u64 = hours.parse().map_err(|_| format!("invalid time: {}", s))?;
+    let minutes: u64 = minutes
+        .parse()
+        .map_err(|_| format!("invalid time: {}", s))?;
+    // 24:00 is allowed as the end of the day.
+    if hours > 24 || minutes > 59 || (hours == 24 && minutes > 0) {
+        return Err(format!("invalid time: {}", s));
+    }
+    Ok(Duration::from_secs(hours * 3600 + minutes * 60))
+}
+
+impl Rule {
+    /// Whether the rule allows accepting, and how long until that changes.
+    fn check(&self, time_of_day: Duration, elapsed: Duration) -> (bool, Option<Duration>) {
+        match *self {
+            Rule::Daily { start, end } => {
+                let inside = if start < end {
+                    start <= time_of_day && time_of_day < end
+                } else {
+                    time_of_day >= start || time_of_day < end
+                };
+                let boundary = if inside { end } else { start };
+                let until = if boundary > time_of_day {
+                    boundary - time_of_day
+                } else {
+                    boundary + DAY - time_of_day
+                };
+                (inside, Some(until))
+            }
+            Rule::Flap { open, closed } => {
+                let period = (open + closed).as_millis();
+                let phase = Duration::from_millis((elapsed.as_millis() % period) as u64);
+                if phase < open {
+                    (true, Some(open - phase))
+                } else {
+                    (false, Some(open + closed - phase))
+                }
+            }
+            Rule::After(after) if elapsed < after => (false, Some(after - elapsed)),
+            Rule::After(_) => (true, None),
+            Rule::For(until) if elapsed < until => (true, Some(until - elapsed)),
+            Rule::For(_) => (false, None),
+        }
+    }
+}
+
+/// Whether `rules` allow accepting, and how long until that could change. The listener
+/// accepts inside any of the daily windows, if there are any, while every other rule
+/// allows it.
+pub fn check(rules: &[Rule], now: SystemTime, elapsed: Duration) -> (bool, Option<Duration>) {
+    let since_epoch = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let time_of_day = Duration::from_millis((since_epoch.as_millis() % DAY.as_millis()) as u64);
+    let mut daily = None;
+    let mut open = true;
+    let mut until: Option<Duration> = None;
+    for rule in rules {
+        let (allowed, change) = rule.check(time_of_day, elapsed);
+        if matches!(rule, Rule::Daily { .. }) {
+            daily = Some(daily.unwrap_or(false) || allowed);
+        } else {
+            open &= allowed;
+        }
+        if let Some(change) = change {
+            until = Some(until.map_or(change, |until| until.min(change)));
+        }
+    }
+    (open && daily.unwrap_or(true), until)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(hours: u64, minutes: u64) -> SystemTime {
+        // 2022-01-01T00:00:00Z, plus the time of day.
+        UNIX_EPOCH + Duration::from_secs(1640995200 + hours * 3600 + minutes * 60)
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            "daily:09:00-17:30".parse(),
+            Ok(Rule::Daily {
+                start: Duration::from_secs(9 * 3600),
+                end: Duration::from_secs(17 * 3600 + 30 * 60),
+            })
+        );
+        assert_eq!(
+            "flap:30s/10s".parse(),
+            Ok(Rule::Flap {
+                open: Duration::from_secs(30),
+                closed: Duration::from_secs(10),
+            })
+        );
+        assert_eq!("after:1m".parse(), Ok(Rule::After(Duration::from_secs(60))));
+        assert!("daily:9-17".parse::<Rule>().is_err());
+        assert!("daily:25:00-26:00".parse::<Rule>().is_err());
+        assert!("flap:0s/1s".parse::<Rule>().is_err());
+        assert!("weekly:mon".parse::<Rule>().is_err());
+    }
+
+    #[test]
+    fn test_check() {
+        let rules: Vec<Rule> = vec!["daily:09:00-17:00".parse().unwrap()];
+        assert_eq!(
+            check(&rules, at(8, 0), Duration::ZERO),
+            (false, Some(Duration::from_secs(3600)))
+        );
+        assert_eq!(
+            check(&rules, at(16, 30), Duration::ZERO),
+            (true, Some(Duration::from_secs(1800)))
+        );
+        assert_eq!(
+            check(&rules, at(18, 0), Duration::ZERO),
+            (false, Some(Duration::from_secs(15 * 3600)))
+        );
+
+        // Windows wrap past midnight, and are combined with flapping.
+        let rules: Vec<Rule> = vec![
+            "daily:22:00-02:00".parse().unwrap(),
+            "flap:30s/10s".parse().unwrap(),
+        ];
+        assert_eq!(
+            check(&rules, at(1, 0), Duration::from_secs(45)),
+            (true, Some(Duration::from_secs(25)))
+        );
+        assert_eq!(
+            check(&rules, at(1, 0), Duration::from_secs(35)),
+            (false, Some(Duration::from_secs(5)))
+        );
+        assert!(!check(&rules, at(3, 0), Duration::from_secs(45)).0);
+
+        let rules: Vec<Rule> = vec!["for:1m".parse().unwrap()];
+        assert_eq!(
+            check(&rules, at(0, 0), Duration::from_secs(90)),
+            (false, None)
+        );
+        assert_eq!(check(&[], at(0, 0), Duration::ZERO), (true, None));
+    }
+}
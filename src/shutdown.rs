@@ -0,0 +1,108 @@
+//! Graceful shutdown on SIGINT or SIGTERM.
+//!
+//! The proxy drains as it would through `POST /api/drain`: TCP listeners close, and
+//! HTTP connections close once their in-flight request is answered. It then waits up
+//! to `--drain-timeout` for open connections to finish before exiting, with an error if
+//! any were cut off. A second signal while draining exits straight away.
+
+use std::error::Error;
+use std::future::Future;
+use std::time::Duration;
+
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+use crate::state::State;
+
+/// How often to check whether connections have finished.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+pub struct Signals {
+    interrupt: Signal,
+    terminate: Signal,
+}
+
+impl Signals {
+    pub fn new() -> std::io::Result<Self> {
+        Ok(Signals {
+            interrupt: signal(SignalKind::interrupt())?,
+            terminate: signal(SignalKind::terminate())?,
+        })
+    }
+
+    /// Wait for SIGINT or SIGTERM, returning its name.
+    pub async fn recv(&mut self) -> &'static str {
+        tokio::select! {
+            _ = self.interrupt.recv() => "SIGINT",
+            _ = self.terminate.recv() => "SIGTERM",
+        }
+    }
+}
+
+/// Stop accepting connections and wait for those open to finish, for up to `timeout`
+/// or until `interrupted` completes with the name of another signal.
+pub async fn drain(
+    state: &State,
+    timeout: Duration,
+    interrupted: impl Future<Output = &'static str>,
+) -> Result<(), Box<dyn Error>> {
+    tokio::pin!(interrupted);
+    state.draining.send_replace(true);
+    let deadline = Instant::now() + timeout;
+    loop {
+        let open = state.open_connections();
+        if open == 0 {
+            info!("drained connections");
+            return Ok(());
+        }
+        if Instant::now() >= deadline {
+            return Err(format!("{} connections still open after --drain-timeout", open).into());
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(POLL_INTERVAL) => {}
+            signal = &mut interrupted => {
+                warn!(signal, open, "stopped draining");
+                return Err(format!("{} while draining", signal).into());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::future;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_drain() {
+        let state = Arc::new(State::new());
+        let conn = state.open_connection("127.0.0.1:1234".parse().unwrap(), "up:80".into());
+
+        let err = drain(&state, Duration::from_millis(50), future::pending())
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "1 connections still open after --drain-timeout"
+        );
+        assert!(state.is_draining());
+
+        let closer = state.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(150)).await;
+            closer.close_connection(&conn, "completed".into());
+        });
+        drain(&state, Duration::from_secs(5), future::pending())
+            .await
+            .unwrap();
+
+        state.open_connection("127.0.0.1:1235".parse().unwrap(), "up:80".into());
+        let err = drain(&state, Duration::from_secs(5), future::ready("SIGTERM"))
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "SIGTERM while draining");
+    }
+}
@@ -0,0 +1,421 @@
+//! Socket options which can be changed while connections are open, through
+//! `/api/socket` (defaults for new connections) and
+//! `/api/connections/{id}/socket` (one live connection).
+//!
+//! An IPv6 flow label can't be: Linux fixes it when a socket connects, so it's set for
+//! upstream connects with `--upstream-flow-label` instead.
+
+use std::io;
+use std::net::{SocketAddr, SocketAddrV6};
+use std::os::unix::io::{AsRawFd, BorrowedFd, RawFd};
+use std::time::Duration;
+
+use serde_json::{json, Value};
+use socket2::{SockRef, TcpKeepalive};
+use tokio::net::{TcpSocket, TcpStream};
+
+use crate::parse_duration;
+
+/// Options to set. Fields left as `None` are not changed.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SocketOptions {
+    pub nodelay: Option<bool>,
+    /// The keepalive idle time, or zero to turn keepalive off.
+    pub keepalive: Option<Duration>,
+    pub send_buffer: Option<usize>,
+    pub recv_buffer: Option<usize>,
+    /// The IPv6 traffic class, or the IPv4 TOS byte on IPv4 sockets: DSCP in the top six
+    /// bits and ECN in the bottom two.
+    pub traffic_class: Option<u8>,
+}
+
+impl SocketOptions {
+    /// Parse `{"nodelay": true, "keepalive": "30s", "send_buffer": 65536,
+    /// "recv_buffer": 65536, "traffic_class": 184}`, where every field is optional and a
+    /// keepalive of `"0"` or `false` disables it.
+    pub fn from_json(body: &Value) -> Result<Self, String> {
+        let fields = body
+            .as_object()
+            .ok_or("expected an object of socket options")?;
+        let mut opts = SocketOptions::default();
+        for (key, value) in fields {
+            match key.as_str() {
+                "nodelay" => {
+                    opts.nodelay = Some(value.as_bool().ok_or("nodelay must be a boolean")?)
+                }
+                "keepalive" => {
+                    opts.keepalive = Some(match value {
+                        Value::Bool(false) => Duration::ZERO,
+                        Value::String(s) => parse_duration(s)?,
+                        _ => return Err("keepalive must be a duration or false".to_string()),
+                    })
+                }
+                "send_buffer" | "recv_buffer" => {
+                    let size = value
+                        .as_u64()
+                        .filter(|n| *n > 0)
+                        .ok_or_else(|| format!("{} must be a positive integer", key))?
+                        as usize;
+                    if key == "send_buffer" {
+                        opts.send_buffer = Some(size);
+                    } else {
+                        opts.recv_buffer = Some(size);
+                    }
+                }
+                "traffic_class" => {
+                    let class = value
+                        .as_u64()
+                        .filter(|n| *n <= u8::MAX as u64)
+                        .ok_or("traffic_class must be an integer from 0 to 255")?;
+                    opts.traffic_class = Some(class as u8);
+                }
+                _ => return Err(format!("unknown socket option: {}", key)),
+            }
+        }
+        Ok(opts)
+    }
+
+    /// Take any options set in `other`.
+    pub fn merge(&mut self, other: &SocketOptions) {
+        self.nodelay = other.nodelay.or(self.nodelay);
+        self.keepalive = other.keepalive.or(self.keepalive);
+        self.send_buffer = other.send_buffer.or(self.send_buffer);
+        self.recv_buffer = other.recv_buffer.or(self.recv_buffer);
+        self.traffic_class = other.traffic_class.or(self.traffic_class);
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "nodelay": self.nodelay,
+            "keepalive": self.keepalive.map(|d| format!("{}s", d.as_secs())),
+            "send_buffer": self.send_buffer,
+            "recv_buffer": self.recv_buffer,
+            "traffic_class": self.traffic_class,
+        })
+    }
+}
+
+/// The file descriptors of a connection's sockets, while it's being proxied.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Sockets {
+    pub downstream: Option<RawFd>,
+    pub upstream: Option<RawFd>,
+}
+
+/// Set the given options on a socket.
+///
+/// `fd` must be an open socket for the duration of the call, which
+/// [`Connection::register_sockets`](crate::connection::Connection::register_sockets)
+/// guarantees for the descriptors it hands out.
+pub fn apply(fd: RawFd, opts: &SocketOptions) -> io::Result<()> {
+    // SAFETY: the caller guarantees `fd` stays open until we return.
+    let fd = unsafe { BorrowedFd::borrow_raw(fd) };
+    let socket = SockRef::from(&fd);
+    if let Some(nodelay) = opts.nodelay {
+        socket.set_nodelay(nodelay)?;
+    }
+    match opts.keepalive {
+        Some(idle) if idle.is_zero() => socket.set_keepalive(false)?,
+        Some(idle) => socket.set_tcp_keepalive(&TcpKeepalive::new().with_time(idle))?,
+        None => {}
+    }
+    if let Some(size) = opts.send_buffer {
+        socket.set_send_buffer_size(size)?;
+    }
+    if let Some(size) = opts.recv_buffer {
+        socket.set_recv_buffer_size(size)?;
+    }
+    if let Some(class) = opts.traffic_class {
+        if is_ipv6(&socket)? {
+            setsockopt(
+                fd.as_raw_fd(),
+                libc::IPPROTO_IPV6,
+                libc::IPV6_TCLASS,
+                class as libc::c_int,
+            )?;
+        } else {
+            socket.set_tos(class as u32)?;
+        }
+    }
+    Ok(())
+}
+
+/// Shut down both directions of a socket, waking anything reading from or writing to it.
+/// `fd` must be open for the duration of the call, as for [`apply`].
+pub fn shutdown(fd: RawFd) -> io::Result<()> {
+    // SAFETY: the caller guarantees `fd` stays open until we return.
+    let fd = unsafe { BorrowedFd::borrow_raw(fd) };
+    SockRef::from(&fd).shutdown(std::net::Shutdown::Both)
+}
+
+fn is_ipv6(socket: &SockRef) -> io::Result<bool> {
+    Ok(socket.local_addr()?.as_socket_ipv6().is_some())
+}
+
+fn setsockopt<T>(fd: RawFd, level: libc::c_int, name: libc::c_int, value: T) -> io::Result<()> {
+    // SAFETY: `value` is a valid `T` for the duration of the call, and we pass its size.
+    let result = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            (&value as *const T).cast(),
+            std::mem::size_of::<T>() as libc::socklen_t,
+        )
+    };
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// The IPv6 traffic class of a socket.
+fn traffic_class_v6(fd: RawFd) -> io::Result<u8> {
+    let mut class: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // SAFETY: getsockopt writes at most `len` bytes to `class`.
+    let result = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::IPPROTO_IPV6,
+            libc::IPV6_TCLASS,
+            (&mut class as *mut libc::c_int).cast(),
+            &mut len,
+        )
+    };
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(class as u8)
+}
+
+/// The flow label request for `IPV6_FLOWLABEL_MGR`, from `linux/in6.h`.
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct FlowLabelReq {
+    dst: libc::in6_addr,
+    /// The label, in network byte order.
+    label: u32,
+    action: u8,
+    share: u8,
+    flags: u16,
+    expires: u16,
+    linger: u16,
+    pad: u32,
+}
+
+/// Connect to `addr` like `TcpStream::connect`, sending IPv6 flow label `label` (the
+/// low 20 bits) on every packet. IPv4 has no flow labels, so IPv4 addresses connect as
+/// usual.
+#[cfg(target_os = "linux")]
+pub async fn connect_with_flow_label(addr: SocketAddr, label: u32) -> io::Result<TcpStream> {
+    use std::os::unix::io::{FromRawFd, IntoRawFd};
+
+    const IPV6_FLOWLABEL_MGR: libc::c_int = 32;
+    const IPV6_FLOWINFO_SEND: libc::c_int = 33;
+    const IPV6_FL_A_GET: u8 = 0;
+    const IPV6_FL_S_ANY: u8 = 255;
+    const IPV6_FL_F_CREATE: u16 = 1;
+
+    let addr = match addr {
+        SocketAddr::V6(addr) => addr,
+        SocketAddr::V4(_) => return TcpStream::connect(addr).await,
+    };
+    let label = (label & 0xf_ffff).to_be();
+    let socket = socket2::Socket::new(socket2::Domain::IPV6, socket2::Type::STREAM, None)?;
+    let fd = socket.as_raw_fd();
+    // Lease the label for the destination, which the kernel requires before it will
+    // send it, then have connect take it from the address.
+    let request = FlowLabelReq {
+        dst: libc::in6_addr {
+            s6_addr: addr.ip().octets(),
+        },
+        label,
+        action: IPV6_FL_A_GET,
+        share: IPV6_FL_S_ANY,
+        flags: IPV6_FL_F_CREATE,
+        expires: 0,
+        linger: 0,
+        pad: 0,
+    };
+    setsockopt(fd, libc::IPPROTO_IPV6, IPV6_FLOWLABEL_MGR, request)?;
+    setsockopt(fd, libc::IPPROTO_IPV6, IPV6_FLOWINFO_SEND, 1 as libc::c_int)?;
+    socket.set_nonblocking(true)?;
+    // SAFETY: we own the descriptor, which `into_raw_fd` gives up.
+    let socket = unsafe { TcpSocket::from_raw_fd(socket.into_raw_fd()) };
+    // Like sin6_flowinfo, `flowinfo` is in network byte order.
+    let addr = SocketAddrV6::new(*addr.ip(), addr.port(), label, addr.scope_id());
+    socket.connect(addr.into()).await
+}
+
+#[cfg(not(target_os = "linux"))]
+pub async fn connect_with_flow_label(addr: SocketAddr, _label: u32) -> io::Result<TcpStream> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "flow labels are only supported on Linux",
+    ))
+}
+
+/// Where a connection redirected to us by an iptables `REDIRECT` rule was originally
+/// addressed, or `None` if it wasn't redirected.
+#[cfg(target_os = "linux")]
+pub fn original_dst(stream: &TcpStream) -> io::Result<Option<SocketAddr>> {
+    const SO_ORIGINAL_DST: libc::c_int = 80;
+    let local = stream.local_addr()?;
+    let level = if local.is_ipv4() {
+        libc::SOL_IP
+    } else {
+        libc::SOL_IPV6
+    };
+    // SAFETY: getsockopt writes at most `len` bytes of an address into the storage.
+    let result = unsafe {
+        socket2::SockAddr::init(|storage, len| {
+            if libc::getsockopt(
+                stream.as_raw_fd(),
+                level,
+                SO_ORIGINAL_DST,
+                storage.cast(),
+                len,
+            ) == -1
+            {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(())
+            }
+        })
+    };
+    match result {
+        // Without a NAT entry there's no original destination.
+        Err(err) if err.raw_os_error() == Some(libc::ENOENT) => Ok(None),
+        Err(err) => Err(err),
+        Ok((_, addr)) => Ok(addr.as_socket().filter(|addr| *addr != local)),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn original_dst(_stream: &TcpStream) -> io::Result<Option<SocketAddr>> {
+    Ok(None)
+}
+
+/// Read back a socket's current options. The kernel may round buffer sizes (Linux
+/// doubles them), so these can differ from what was set.
+pub fn read(fd: RawFd) -> io::Result<Value> {
+    // SAFETY: as for `apply`.
+    let fd = unsafe { BorrowedFd::borrow_raw(fd) };
+    let socket = SockRef::from(&fd);
+    let keepalive = if socket.keepalive()? {
+        socket.keepalive_time()?
+    } else {
+        Duration::ZERO
+    };
+    let traffic_class = if is_ipv6(&socket)? {
+        traffic_class_v6(fd.as_raw_fd())?
+    } else {
+        socket.tos()? as u8
+    };
+    Ok(SocketOptions {
+        nodelay: Some(socket.nodelay()?),
+        keepalive: Some(keepalive),
+        send_buffer: Some(socket.send_buffer_size()?),
+        recv_buffer: Some(socket.recv_buffer_size()?),
+        traffic_class: Some(traffic_class),
+    }
+    .to_json())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::io::AsRawFd;
+
+    #[test]
+    fn test_from_json() {
+        let opts = SocketOptions::from_json(&json!({
+            "nodelay": true,
+            "keepalive": "30s",
+            "recv_buffer": 4096,
+        }))
+        .unwrap();
+        assert_eq!(
+            opts,
+            SocketOptions {
+                nodelay: Some(true),
+                keepalive: Some(Duration::from_secs(30)),
+                send_buffer: None,
+                recv_buffer: Some(4096),
+                traffic_class: None,
+            }
+        );
+        let off = SocketOptions::from_json(&json!({ "keepalive": false })).unwrap();
+        assert_eq!(off.keepalive, Some(Duration::ZERO));
+        assert!(SocketOptions::from_json(&json!({ "nodelay": 1 })).is_err());
+        assert!(SocketOptions::from_json(&json!({ "linger": 1 })).is_err());
+        assert!(SocketOptions::from_json(&json!({ "send_buffer": 0 })).is_err());
+        assert!(SocketOptions::from_json(&json!({ "traffic_class": 256 })).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_apply() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = tokio::net::TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let fd = stream.as_raw_fd();
+        let opts = SocketOptions {
+            nodelay: Some(true),
+            keepalive: Some(Duration::from_secs(42)),
+            ..Default::default()
+        };
+        apply(fd, &opts).unwrap();
+        let current = read(fd).unwrap();
+        assert_eq!(current["nodelay"], true);
+        assert_eq!(current["keepalive"], "42s");
+
+        apply(
+            fd,
+            &SocketOptions::from_json(&json!({ "keepalive": false })).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(read(fd).unwrap()["keepalive"], "0s");
+    }
+
+    #[tokio::test]
+    async fn test_ipv6() {
+        let listener = tokio::net::TcpListener::bind("[::1]:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let stream = connect_with_flow_label(addr, 0x12345).await.unwrap();
+        let fd = stream.as_raw_fd();
+
+        let mut request = FlowLabelReq {
+            dst: libc::in6_addr { s6_addr: [0; 16] },
+            label: 0,
+            action: 0,
+            share: 0,
+            flags: 0,
+            expires: 0,
+            linger: 0,
+            pad: 0,
+        };
+        let mut len = std::mem::size_of::<FlowLabelReq>() as libc::socklen_t;
+        // SAFETY: getsockopt writes at most `len` bytes to `request`.
+        let result = unsafe {
+            libc::getsockopt(
+                fd,
+                libc::IPPROTO_IPV6,
+                32,
+                (&mut request as *mut FlowLabelReq).cast(),
+                &mut len,
+            )
+        };
+        assert_eq!(result, 0, "{}", io::Error::last_os_error());
+        assert_eq!(u32::from_be(request.label), 0x12345);
+
+        let opts = SocketOptions {
+            traffic_class: Some(0xb8),
+            ..Default::default()
+        };
+        apply(fd, &opts).unwrap();
+        assert_eq!(read(fd).unwrap()["traffic_class"], 0xb8);
+    }
+}
@@ -0,0 +1,279 @@
+//! SSH awareness for TCP mode.
+//!
+//! SSH starts in the clear: each side sends an identification line such as
+//! `SSH-2.0-OpenSSH_8.9p1 Ubuntu-3`, then a KEXINIT packet listing the algorithms
+//! it supports. A [`Tracker`] per direction reads both, tagging the connection with
+//! `ssh.client_version` and `ssh.server_version`, and once both KEXINITs have been
+//! seen, with the key exchange, host key and cipher that were negotiated. Client
+//! versions are also counted for `/metrics`. Everything after KEXINIT is encrypted
+//! and left alone.
+
+use std::sync::Mutex;
+
+use crate::connection::{Connection, Direction};
+use crate::state::State;
+
+/// The identification line is at most 255 bytes, but servers may send other lines
+/// before it.
+const MAX_BANNER_SIZE: usize = 8 * 1024;
+
+/// RFC 4253 requires implementations to handle packets of at least this size.
+const MAX_PACKET_SIZE: usize = 35000;
+
+const SSH_MSG_KEXINIT: u8 = 20;
+
+/// The name-lists of a KEXINIT which we negotiate.
+#[derive(Debug, Default, Clone, PartialEq)]
+struct KexInit {
+    kex: Vec<String>,
+    host_key: Vec<String>,
+    cipher_client_to_server: Vec<String>,
+}
+
+/// What both directions of a connection have seen, to negotiate algorithms once
+/// both sides' KEXINITs are known.
+#[derive(Debug, Default)]
+pub struct Session {
+    client: Mutex<Option<KexInit>>,
+    server: Mutex<Option<KexInit>>,
+}
+
+#[derive(Debug)]
+enum Phase {
+    Banner(Vec<u8>),
+    KexInit(Vec<u8>),
+    Done,
+}
+
+/// Watches the bytes flowing in one direction of a connection.
+#[derive(Debug)]
+pub struct Tracker<'a> {
+    direction: Direction,
+    phase: Phase,
+    session: &'a Session,
+}
+
+impl<'a> Tracker<'a> {
+    pub fn new(direction: Direction, session: &'a Session) -> Self {
+        Self {
+            direction,
+            phase: Phase::Banner(Vec::new()),
+            session,
+        }
+    }
+
+    /// Inspect the next chunk of bytes, recording what's seen on `conn`.
+    pub fn feed(&mut self, data: &[u8], conn: &Connection, state: &State) {
+        if let Phase::Banner(buf) = &mut self.phase {
+            buf.extend_from_slice(data);
+            // Clients must start with the identification line; don't wait for a
+            // newline from anything else.
+            if self.direction == Direction::Up && !b"SSH-".starts_with(&buf[..buf.len().min(4)]) {
+                self.phase = Phase::Done;
+                return;
+            }
+            let (version, rest) = match parse_banner(buf) {
+                Some(Ok(parsed)) => parsed,
+                None if buf.len() < MAX_BANNER_SIZE => return,
+                _ => {
+                    self.phase = Phase::Done;
+                    return;
+                }
+            };
+            let rest = rest.to_vec();
+            let key = match self.direction {
+                Direction::Up => {
+                    state.ssh_client_versions.with_entry(
+                        software(&version).to_string(),
+                        0,
+                        |count| *count += 1,
+                    );
+                    "ssh.client_version"
+                }
+                Direction::Down => "ssh.server_version",
+            };
+            conn.set_tags([(key.to_string(), Some(version))]);
+            self.phase = Phase::KexInit(Vec::new());
+            return self.feed(&rest, conn, state);
+        }
+        if let Phase::KexInit(buf) = &mut self.phase {
+            buf.extend_from_slice(data);
+            let len = match buf.get(..4) {
+                Some(len) => u32::from_be_bytes(len.try_into().unwrap()) as usize,
+                None => return,
+            };
+            if len > MAX_PACKET_SIZE {
+                self.phase = Phase::Done;
+                return;
+            }
+            if buf.len() < 4 + len {
+                return;
+            }
+            let kexinit = parse_kexinit(&buf[4..4 + len]);
+            self.phase = Phase::Done;
+            if let Some(kexinit) = kexinit {
+                self.record(kexinit, conn);
+            }
+        }
+    }
+
+    fn record(&self, kexinit: KexInit, conn: &Connection) {
+        let slot = match self.direction {
+            Direction::Up => &self.session.client,
+            Direction::Down => &self.session.server,
+        };
+        *slot.lock().unwrap() = Some(kexinit);
+        let (client, server) = (
+            self.session.client.lock().unwrap().clone(),
+            self.session.server.lock().unwrap().clone(),
+        );
+        if let (Some(client), Some(server)) = (client, server) {
+            conn.set_tags([
+                ("ssh.kex".to_string(), negotiate(&client.kex, &server.kex)),
+                (
+                    "ssh.host_key".to_string(),
+                    negotiate(&client.host_key, &server.host_key),
+                ),
+                (
+                    "ssh.cipher".to_string(),
+                    negotiate(
+                        &client.cipher_client_to_server,
+                        &server.cipher_client_to_server,
+                    ),
+                ),
+            ]);
+        }
+    }
+}
+
+/// Find the identification line, returning it without the `SSH-2.0-` prefix or line
+/// ending, and whatever follows it. `None` means more bytes are needed.
+fn parse_banner(buf: &[u8]) -> Option<Result<(String, &[u8]), ()>> {
+    let mut pos = 0;
+    loop {
+        let end = pos + buf[pos..].iter().position(|b| *b == b'\n')?;
+        let line = &buf[pos..end];
+        if let Some(id) = line.strip_prefix(b"SSH-") {
+            let id = String::from_utf8_lossy(id);
+            let id = id.trim_end_matches('\r');
+            // protoversion-softwareversion [comments]
+            return Some(match id.split_once('-') {
+                Some((_, software)) => Ok((software.to_string(), &buf[end + 1..])),
+                None => Err(()),
+            });
+        }
+        pos = end + 1;
+    }
+}
+
+/// The software version without comments, e.g. `OpenSSH_8.9p1`.
+fn software(version: &str) -> &str {
+    version.split(' ').next().unwrap_or(version)
+}
+
+fn parse_kexinit(packet: &[u8]) -> Option<KexInit> {
+    let padding = *packet.first()? as usize;
+    let payload = packet.get(1..packet.len().checked_sub(padding)?)?;
+    if *payload.first()? != SSH_MSG_KEXINIT {
+        return None;
+    }
+    // The message type and a 16 byte cookie precede the name-lists.
+    let mut pos = 17;
+    let mut lists = Vec::new();
+    for _ in 0..3 {
+        let len = u32::from_be_bytes(payload.get(pos..pos + 4)?.try_into().ok()?) as usize;
+        let list = payload.get(pos + 4..pos + 4 + len)?;
+        lists.push(
+            String::from_utf8_lossy(list)
+                .split(',')
+                .filter(|name| !name.is_empty())
+                .map(String::from)
+                .collect(),
+        );
+        pos += 4 + len;
+    }
+    let mut lists = lists.into_iter();
+    Some(KexInit {
+        kex: lists.next()?,
+        host_key: lists.next()?,
+        cipher_client_to_server: lists.next()?,
+    })
+}
+
+/// The first of the client's algorithms which the server also supports.
+fn negotiate(client: &[String], server: &[String]) -> Option<String> {
+    client.iter().find(|name| server.contains(name)).cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kexinit(kex: &str, host_key: &str, cipher: &str) -> Vec<u8> {
+        let mut payload = vec![SSH_MSG_KEXINIT];
+        payload.extend_from_slice(&[0; 16]);
+        for list in [
+            kex, host_key, cipher, cipher, "", "", "none", "none", "", "",
+        ] {
+            payload.extend_from_slice(&(list.len() as u32).to_be_bytes());
+            payload.extend_from_slice(list.as_bytes());
+        }
+        payload.extend_from_slice(&[0, 0, 0, 0, 0]);
+        let padding = 4;
+        let mut packet = ((payload.len() + 1 + padding) as u32)
+            .to_be_bytes()
+            .to_vec();
+        packet.push(padding as u8);
+        packet.extend_from_slice(&payload);
+        packet.extend_from_slice(&[0; 4]);
+        packet
+    }
+
+    #[test]
+    fn test_tracker() {
+        let state = State::new();
+        let conn = Connection::new(1, "127.0.0.1:1234".parse().unwrap(), "up:22".into());
+        let session = Session::default();
+        let mut up = Tracker::new(Direction::Up, &session);
+        let mut down = Tracker::new(Direction::Down, &session);
+
+        let mut client = b"SSH-2.0-OpenSSH_8.9p1 Ubuntu-3\r\n".to_vec();
+        client.extend(kexinit(
+            "curve25519-sha256,diffie-hellman-group14-sha256",
+            "ssh-ed25519,rsa-sha2-512",
+            "chacha20-poly1305@openssh.com,aes128-ctr",
+        ));
+        for b in &client {
+            up.feed(std::slice::from_ref(b), &conn, &state);
+        }
+        let mut server = b"a pre-banner line\r\nSSH-2.0-dropbear_2022.83\r\n".to_vec();
+        server.extend(kexinit(
+            "diffie-hellman-group14-sha256,curve25519-sha256",
+            "rsa-sha2-512",
+            "aes128-ctr",
+        ));
+        down.feed(&server, &conn, &state);
+
+        let tags = conn.tags();
+        assert_eq!(tags["ssh.client_version"], "OpenSSH_8.9p1 Ubuntu-3");
+        assert_eq!(tags["ssh.server_version"], "dropbear_2022.83");
+        assert_eq!(tags["ssh.kex"], "curve25519-sha256");
+        assert_eq!(tags["ssh.host_key"], "rsa-sha2-512");
+        assert_eq!(tags["ssh.cipher"], "aes128-ctr");
+        assert_eq!(
+            state.ssh_client_versions.get(&"OpenSSH_8.9p1".to_string()),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn test_not_ssh() {
+        let state = State::new();
+        let conn = Connection::new(1, "127.0.0.1:1234".parse().unwrap(), "up:22".into());
+        let session = Session::default();
+        let mut up = Tracker::new(Direction::Up, &session);
+        up.feed(b"GET / HTTP/1.1\r\n", &conn, &state);
+        assert!(matches!(up.phase, Phase::Done));
+        assert!(conn.tags().is_empty());
+    }
+}
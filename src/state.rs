@@ -0,0 +1,858 @@
+//! Shared proxy statistics.
+//!
+//! Counters are plain atomics and per-key data lives in a [`ShardedMap`], so
+//! connection tasks never contend on a single lock. Readers such as the debug
+//! server take a [`Snapshot`] instead of locking everything at once.
+
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{BuildHasher, Hash};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Value};
+use tokio::sync::watch;
+
+use crate::bandwidth::Bandwidth;
+use crate::breaker::Circuit;
+use crate::capture;
+use crate::connection::{Activity, Connection};
+use crate::events::{Event, Events};
+use crate::heatmap::Heatmap;
+use crate::http_cache::Cache;
+use crate::http_connect::HostStats;
+use crate::metrics::{
+    escape_label, Buckets, Histogram, DEFAULT_LATENCY_BUCKETS, DEFAULT_SIZE_BUCKETS,
+};
+use crate::mirror::Mirror;
+use crate::pace::Pacer;
+use crate::probe::Probe;
+use crate::qos::Scheduler;
+use crate::quota::Quotas;
+use crate::reload::History;
+use crate::resolver::Resolver;
+use crate::retry::Budget;
+use crate::sockopt::SocketOptions;
+use crate::toxic::{ConnectToxic, Toxics};
+use crate::tunnel::Tunnels;
+
+/// Number of shards in each [`ShardedMap`].
+const SHARDS: usize = 16;
+
+/// How many closed connections to keep records of.
+const CLOSED_HISTORY: usize = 1000;
+
+/// The most upstreams or CONNECT hosts counted apart. Clients choose these in
+/// http-connect mode, so past this many the rest are counted together under [`OTHER`],
+/// bounding memory and the number of metric series.
+pub const MAX_KEYS: usize = 1024;
+
+/// The key counts for upstreams and hosts past [`MAX_KEYS`] are folded into.
+pub const OTHER: &str = "other";
+
+#[derive(Debug)]
+pub struct State {
+    pub active_connections: AtomicUsize,
+    /// Active connections with one direction finished.
+    pub half_closed_connections: AtomicUsize,
+    pub half_closed_reaped: AtomicUsize,
+    pub completed_connections: AtomicUsize,
+    pub accept_errors: AtomicUsize,
+    pub shed_connections: AtomicUsize,
+    /// Connections closed on accept by `--overflow-policy reject`.
+    pub overflow_rejections: AtomicUsize,
+    pub open_fds: AtomicUsize,
+    pub fd_limit: AtomicU64,
+    pub fd_exhausted: AtomicBool,
+    /// Set while `--schedule` has closed the listener.
+    pub listener_closed: AtomicBool,
+    /// Upstream connects which failed, after any retries.
+    pub connect_errors: AtomicUsize,
+    /// Upstream connect attempts which hit `--connect-timeout`.
+    pub connect_timeouts: AtomicUsize,
+    /// Connects failed fast because the upstream is ejected.
+    pub ejected_connects: AtomicUsize,
+    pub addr_not_avail_errors: AtomicUsize,
+    pub upstream_cap_rejections: AtomicUsize,
+    /// Upstream connects delayed by `--upstream-connect-rate`.
+    pub paced_connects: AtomicUsize,
+    /// Upstream connects retried, and retries refused by `--retry-budget`.
+    pub connect_retries: AtomicUsize,
+    pub retry_budget_exhausted: AtomicUsize,
+    pub protocol_mismatches: AtomicUsize,
+    /// Connections closed for a missing or invalid `--accept-proxy-protocol` header.
+    pub proxy_header_errors: AtomicUsize,
+    pub geo_denials: AtomicUsize,
+    pub quota_connection_rejections: AtomicUsize,
+    pub quota_byte_rejections: AtomicUsize,
+    pub http_requests: AtomicUsize,
+    pub http_faults_injected: AtomicUsize,
+    pub connect_faults_injected: AtomicUsize,
+    /// Connections closed by `--drop-probability`.
+    pub drops_injected: AtomicUsize,
+    /// Connections reset by `--reset-probability`.
+    pub resets_injected: AtomicUsize,
+    /// Connections closed by `--max-conn-duration`.
+    pub max_duration_closes: AtomicUsize,
+    pub http_cache_hits: AtomicUsize,
+    pub http_cache_misses: AtomicUsize,
+    pub http_cache_stores: AtomicUsize,
+    pub http_mirrored: AtomicUsize,
+    pub http_mirror_skipped: AtomicUsize,
+    pub http_mirror_errors: AtomicUsize,
+    pub dns_queries: AtomicUsize,
+    pub dns_faults_injected: AtomicUsize,
+    pub udp_faults_injected: AtomicUsize,
+    pub stream_replacements: AtomicUsize,
+    pub fuzz_mutations: AtomicUsize,
+    pub upstream_connections: ShardedMap<String, usize>,
+    /// Connections opened to each upstream.
+    pub upstream_connections_total: ShardedMap<String, u64>,
+    /// The next upstream for each round-robin `--upstream-addr` list.
+    pub round_robin: ShardedMap<String, usize>,
+    /// Connections open now to each upstream, for `--lb-strategy least-conn`.
+    pub upstream_open: ShardedMap<String, usize>,
+    /// The ID of the connection from each client address being proxied.
+    pub by_addr: ShardedMap<SocketAddr, u64>,
+    next_connection_id: AtomicU64,
+    connections: ShardedMap<u64, Arc<Connection>>,
+    /// Closed connection IDs, oldest first, so their records can be evicted.
+    closed: Mutex<VecDeque<u64>>,
+    /// Bytes copied up and down by closed connections, by upstream address. Only
+    /// changed with `closed` locked.
+    closed_bytes: Mutex<HashMap<String, (u64, u64)>>,
+    /// Per tag combination totals, for the tag keys chosen with `--metrics-tag-key`.
+    /// Keyed by the rendered Prometheus label set.
+    pub tagged: ShardedMap<String, TagStats>,
+    /// Connections accepted by each `--route` listener, by route name.
+    pub routes: ShardedMap<String, RouteStats>,
+    /// Tunnels in http-connect mode, by destination host.
+    pub http_connect_hosts: ShardedMap<String, HostStats>,
+    /// Kafka requests seen in kafka mode, by API key.
+    pub kafka_requests: ShardedMap<i16, u64>,
+    /// SSH connections by client software version.
+    pub ssh_client_versions: ShardedMap<String, u64>,
+    /// TLS connections by JA3 and JA4 fingerprint, with `--tls-fingerprint`.
+    pub tls_fingerprints: ShardedMap<(String, String), u64>,
+    /// Connections by client country, with `--geoip-db`.
+    pub geo_countries: ShardedMap<String, u64>,
+    /// The latest `--probe-interval` probe of each upstream.
+    pub probes: ShardedMap<String, Probe>,
+    /// When a connect to each upstream last failed.
+    pub upstream_failures: ShardedMap<String, Instant>,
+    /// `--eject-after` state of each upstream which has failed a connect.
+    pub circuits: ShardedMap<String, Circuit>,
+    /// Connections and bytes up and down with `--zone-upstream`, by the zone they went
+    /// from and to.
+    pub zone_traffic: ShardedMap<(String, String), (u64, u64, u64)>,
+    /// Socket options applied to every new connection, set through the API.
+    pub socket_defaults: Mutex<SocketOptions>,
+    /// Toxics new connections start with, from `--toxics` or the API.
+    pub toxic_defaults: Mutex<Toxics>,
+    /// Applied to every upstream connect, from `--connect-toxic` or the API.
+    pub connect_toxic: Mutex<ConnectToxic>,
+    /// The `--capture` writer, for transcripts.
+    pub capture: Mutex<Option<Arc<capture::Writer>>>,
+    pub resolver: Resolver,
+    pub connect_pacer: Pacer,
+    pub retry_budget: Budget,
+    /// Shares out `--bandwidth-cap` among connections.
+    pub qos: Arc<Scheduler>,
+    /// The configs applied most recently, for `/api/config/history`.
+    pub config_history: History,
+    /// Connection events, for embedders.
+    pub events: Events,
+    pub tunnels: Tunnels,
+    /// From `--upstream-flow-label`, 0 for none.
+    pub upstream_flow_label: AtomicU32,
+    pub quotas: Quotas,
+    /// Set through `/api/drain`: the listener closes, and HTTP connections close once
+    /// their in-flight request is answered.
+    pub draining: watch::Sender<bool>,
+    pub http_cache: Cache,
+    pub http_mirror: Mirror,
+    pub connect_latency: Histogram,
+    /// Connect latencies over time, for `/api/latency`.
+    pub connect_heatmap: Heatmap,
+    /// Bytes copied per minute and per hour, for `/api/bandwidth`.
+    pub bandwidth: Bandwidth,
+    pub connection_size_up: Histogram,
+    pub connection_size_down: Histogram,
+    /// The per-connection metrics broken down by listener and client network, keyed by
+    /// their rendered labels.
+    pub segments: ShardedMap<String, Arc<Segment>>,
+    /// The buckets for each segment's latency and size histograms.
+    buckets: (Buckets, Buckets),
+    #[cfg(feature = "runtime-metrics")]
+    pub runtime: crate::runtime_metrics::RuntimeMetrics,
+}
+
+/// The listener label for connections accepted on `--listen-addr` rather than a route.
+pub const DEFAULT_LISTENER: &str = "default";
+
+/// The per-connection metrics for one listener and client network, see
+/// [`State::segment`].
+#[derive(Debug)]
+pub struct Segment {
+    /// `listener="...",client_network="..."`.
+    pub labels: String,
+    pub active_connections: AtomicUsize,
+    pub completed_connections: AtomicUsize,
+    /// Bytes copied up and down by closed connections. Only changed with `closed`
+    /// locked.
+    pub closed_bytes_up: AtomicU64,
+    pub closed_bytes_down: AtomicU64,
+    pub connect_latency: Histogram,
+    pub connection_size_up: Histogram,
+    pub connection_size_down: Histogram,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TagStats {
+    pub connections: u64,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RouteStats {
+    pub active_connections: u64,
+    /// Closed connections, and the bytes they copied.
+    pub connections: u64,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+}
+
+impl RouteStats {
+    pub fn to_json(&self, name: &str) -> Value {
+        json!({
+            "name": name,
+            "active_connections": self.active_connections,
+            "connections": self.connections,
+            "bytes_up": self.bytes_up,
+            "bytes_down": self.bytes_down,
+        })
+    }
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A point-in-time copy of [`State`].
+#[derive(PartialEq, Debug, Default)]
+pub struct Snapshot {
+    pub active_connections: usize,
+    pub half_closed_connections: usize,
+    pub half_closed_reaped: usize,
+    pub completed_connections: usize,
+    pub accept_errors: usize,
+    pub shed_connections: usize,
+    pub overflow_rejections: usize,
+    pub open_fds: usize,
+    pub fd_limit: u64,
+    pub fd_exhausted: bool,
+    pub listener_closed: bool,
+    pub connect_errors: usize,
+    pub connect_timeouts: usize,
+    pub ejected_connects: usize,
+    pub addr_not_avail_errors: usize,
+    pub upstream_cap_rejections: usize,
+    pub paced_connects: usize,
+    pub connect_retries: usize,
+    pub retry_budget_exhausted: usize,
+    pub protocol_mismatches: usize,
+    pub proxy_header_errors: usize,
+    pub geo_denials: usize,
+    pub quota_connection_rejections: usize,
+    pub quota_byte_rejections: usize,
+    pub http_requests: usize,
+    pub http_faults_injected: usize,
+    pub connect_faults_injected: usize,
+    pub drops_injected: usize,
+    pub resets_injected: usize,
+    pub max_duration_closes: usize,
+    pub http_cache_hits: usize,
+    pub http_cache_misses: usize,
+    pub http_cache_stores: usize,
+    pub http_mirrored: usize,
+    pub http_mirror_skipped: usize,
+    pub http_mirror_errors: usize,
+    pub dns_queries: usize,
+    pub dns_faults_injected: usize,
+    pub udp_faults_injected: usize,
+    pub stream_replacements: usize,
+    pub fuzz_mutations: usize,
+    pub upstream_connections: HashMap<String, usize>,
+    pub upstream_connections_total: HashMap<String, u64>,
+    pub by_addr: HashMap<SocketAddr, Activity>,
+}
+
+impl Snapshot {
+    /// The snapshot as `/api/stats` serves it, with clients counted rather than listed.
+    pub fn to_json(&self) -> Value {
+        json!({
+            "active_connections": self.active_connections,
+            "half_closed_connections": self.half_closed_connections,
+            "half_closed_reaped": self.half_closed_reaped,
+            "completed_connections": self.completed_connections,
+            "accept_errors": self.accept_errors,
+            "shed_connections": self.shed_connections,
+            "overflow_rejections": self.overflow_rejections,
+            "open_fds": self.open_fds,
+            "fd_limit": self.fd_limit,
+            "fd_exhausted": self.fd_exhausted,
+            "listener_closed": self.listener_closed,
+            "connect_errors": self.connect_errors,
+            "connect_timeouts": self.connect_timeouts,
+            "ejected_connects": self.ejected_connects,
+            "addr_not_avail_errors": self.addr_not_avail_errors,
+            "upstream_cap_rejections": self.upstream_cap_rejections,
+            "paced_connects": self.paced_connects,
+            "connect_retries": self.connect_retries,
+            "retry_budget_exhausted": self.retry_budget_exhausted,
+            "protocol_mismatches": self.protocol_mismatches,
+            "proxy_header_errors": self.proxy_header_errors,
+            "geo_denials": self.geo_denials,
+            "quota_connection_rejections": self.quota_connection_rejections,
+            "quota_byte_rejections": self.quota_byte_rejections,
+            "http_requests": self.http_requests,
+            "http_faults_injected": self.http_faults_injected,
+            "connect_faults_injected": self.connect_faults_injected,
+            "drops_injected": self.drops_injected,
+            "resets_injected": self.resets_injected,
+            "max_duration_closes": self.max_duration_closes,
+            "http_cache_hits": self.http_cache_hits,
+            "http_cache_misses": self.http_cache_misses,
+            "http_cache_stores": self.http_cache_stores,
+            "http_mirrored": self.http_mirrored,
+            "http_mirror_skipped": self.http_mirror_skipped,
+            "http_mirror_errors": self.http_mirror_errors,
+            "dns_queries": self.dns_queries,
+            "dns_faults_injected": self.dns_faults_injected,
+            "udp_faults_injected": self.udp_faults_injected,
+            "stream_replacements": self.stream_replacements,
+            "fuzz_mutations": self.fuzz_mutations,
+            "upstream_connections": self.upstream_connections,
+            "upstream_connections_total": self.upstream_connections_total,
+            "clients": self.by_addr.len(),
+        })
+    }
+}
+
+impl State {
+    pub fn new() -> Self {
+        Self::with_buckets(
+            DEFAULT_LATENCY_BUCKETS.parse().unwrap(),
+            DEFAULT_SIZE_BUCKETS.parse().unwrap(),
+        )
+    }
+
+    /// Create a `State` whose latency and size histograms use the given buckets.
+    pub fn with_buckets(latency: Buckets, size: Buckets) -> Self {
+        Self {
+            active_connections: Default::default(),
+            half_closed_connections: Default::default(),
+            half_closed_reaped: Default::default(),
+            completed_connections: Default::default(),
+            accept_errors: Default::default(),
+            shed_connections: Default::default(),
+            overflow_rejections: Default::default(),
+            open_fds: Default::default(),
+            fd_limit: Default::default(),
+            fd_exhausted: Default::default(),
+            listener_closed: Default::default(),
+            connect_errors: Default::default(),
+            connect_timeouts: Default::default(),
+            ejected_connects: Default::default(),
+            addr_not_avail_errors: Default::default(),
+            upstream_cap_rejections: Default::default(),
+            paced_connects: Default::default(),
+            connect_retries: Default::default(),
+            retry_budget_exhausted: Default::default(),
+            protocol_mismatches: Default::default(),
+            proxy_header_errors: Default::default(),
+            geo_denials: Default::default(),
+            quota_connection_rejections: Default::default(),
+            quota_byte_rejections: Default::default(),
+            http_requests: Default::default(),
+            http_faults_injected: Default::default(),
+            connect_faults_injected: Default::default(),
+            drops_injected: Default::default(),
+            resets_injected: Default::default(),
+            max_duration_closes: Default::default(),
+            http_cache_hits: Default::default(),
+            http_cache_misses: Default::default(),
+            http_cache_stores: Default::default(),
+            http_mirrored: Default::default(),
+            http_mirror_skipped: Default::default(),
+            http_mirror_errors: Default::default(),
+            dns_queries: Default::default(),
+            dns_faults_injected: Default::default(),
+            udp_faults_injected: Default::default(),
+            stream_replacements: Default::default(),
+            fuzz_mutations: Default::default(),
+            upstream_connections: Default::default(),
+            upstream_connections_total: Default::default(),
+            round_robin: Default::default(),
+            upstream_open: Default::default(),
+            by_addr: Default::default(),
+            next_connection_id: Default::default(),
+            connections: Default::default(),
+            closed: Default::default(),
+            closed_bytes: Default::default(),
+            tagged: Default::default(),
+            routes: Default::default(),
+            http_connect_hosts: Default::default(),
+            kafka_requests: Default::default(),
+            ssh_client_versions: Default::default(),
+            tls_fingerprints: Default::default(),
+            geo_countries: Default::default(),
+            probes: Default::default(),
+            upstream_failures: Default::default(),
+            circuits: Default::default(),
+            zone_traffic: Default::default(),
+            socket_defaults: Default::default(),
+            toxic_defaults: Default::default(),
+            connect_toxic: Default::default(),
+            capture: Default::default(),
+            resolver: Default::default(),
+            connect_pacer: Default::default(),
+            retry_budget: Default::default(),
+            qos: Default::default(),
+            config_history: Default::default(),
+            events: Default::default(),
+            tunnels: Default::default(),
+            upstream_flow_label: Default::default(),
+            quotas: Default::default(),
+            draining: watch::channel(false).0,
+            http_cache: Default::default(),
+            http_mirror: Default::default(),
+            connect_latency: Histogram::new(latency.clone()),
+            connect_heatmap: Heatmap::default(),
+            bandwidth: Bandwidth::default(),
+            connection_size_up: Histogram::new(size.clone()),
+            connection_size_down: Histogram::new(size.clone()),
+            segments: Default::default(),
+            buckets: (latency, size),
+            #[cfg(feature = "runtime-metrics")]
+            runtime: crate::runtime_metrics::RuntimeMetrics::new(),
+        }
+    }
+
+    /// Copy out the current statistics. Each field is read independently, so the
+    /// snapshot is not atomic across fields.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            active_connections: self.active_connections.load(Ordering::Relaxed),
+            half_closed_connections: self.half_closed_connections.load(Ordering::Relaxed),
+            half_closed_reaped: self.half_closed_reaped.load(Ordering::Relaxed),
+            completed_connections: self.completed_connections.load(Ordering::Relaxed),
+            accept_errors: self.accept_errors.load(Ordering::Relaxed),
+            shed_connections: self.shed_connections.load(Ordering::Relaxed),
+            overflow_rejections: self.overflow_rejections.load(Ordering::Relaxed),
+            open_fds: self.open_fds.load(Ordering::Relaxed),
+            fd_limit: self.fd_limit.load(Ordering::Relaxed),
+            fd_exhausted: self.fd_exhausted.load(Ordering::Relaxed),
+            listener_closed: self.listener_closed.load(Ordering::Relaxed),
+            connect_errors: self.connect_errors.load(Ordering::Relaxed),
+            connect_timeouts: self.connect_timeouts.load(Ordering::Relaxed),
+            ejected_connects: self.ejected_connects.load(Ordering::Relaxed),
+            addr_not_avail_errors: self.addr_not_avail_errors.load(Ordering::Relaxed),
+            upstream_cap_rejections: self.upstream_cap_rejections.load(Ordering::Relaxed),
+            paced_connects: self.paced_connects.load(Ordering::Relaxed),
+            connect_retries: self.connect_retries.load(Ordering::Relaxed),
+            retry_budget_exhausted: self.retry_budget_exhausted.load(Ordering::Relaxed),
+            protocol_mismatches: self.protocol_mismatches.load(Ordering::Relaxed),
+            proxy_header_errors: self.proxy_header_errors.load(Ordering::Relaxed),
+            geo_denials: self.geo_denials.load(Ordering::Relaxed),
+            quota_connection_rejections: self.quota_connection_rejections.load(Ordering::Relaxed),
+            quota_byte_rejections: self.quota_byte_rejections.load(Ordering::Relaxed),
+            http_requests: self.http_requests.load(Ordering::Relaxed),
+            http_faults_injected: self.http_faults_injected.load(Ordering::Relaxed),
+            connect_faults_injected: self.connect_faults_injected.load(Ordering::Relaxed),
+            drops_injected: self.drops_injected.load(Ordering::Relaxed),
+            resets_injected: self.resets_injected.load(Ordering::Relaxed),
+            max_duration_closes: self.max_duration_closes.load(Ordering::Relaxed),
+            http_cache_hits: self.http_cache_hits.load(Ordering::Relaxed),
+            http_cache_misses: self.http_cache_misses.load(Ordering::Relaxed),
+            http_cache_stores: self.http_cache_stores.load(Ordering::Relaxed),
+            http_mirrored: self.http_mirrored.load(Ordering::Relaxed),
+            http_mirror_skipped: self.http_mirror_skipped.load(Ordering::Relaxed),
+            http_mirror_errors: self.http_mirror_errors.load(Ordering::Relaxed),
+            dns_queries: self.dns_queries.load(Ordering::Relaxed),
+            dns_faults_injected: self.dns_faults_injected.load(Ordering::Relaxed),
+            udp_faults_injected: self.udp_faults_injected.load(Ordering::Relaxed),
+            stream_replacements: self.stream_replacements.load(Ordering::Relaxed),
+            fuzz_mutations: self.fuzz_mutations.load(Ordering::Relaxed),
+            upstream_connections: self.upstream_connections.snapshot(),
+            upstream_connections_total: self.upstream_connections_total.snapshot(),
+            by_addr: self
+                .by_addr
+                .snapshot()
+                .into_iter()
+                .filter_map(|(addr, id)| Some((addr, self.connection(id)?.activity())))
+                .collect(),
+        }
+    }
+
+    pub fn is_draining(&self) -> bool {
+        *self.draining.borrow()
+    }
+
+    /// Connections which haven't closed yet, whether or not they've reached their
+    /// upstream.
+    pub fn open_connections(&self) -> usize {
+        self.connections
+            .snapshot()
+            .values()
+            .filter(|conn| !conn.is_closed())
+            .count()
+    }
+
+    /// Record how long `conn` took to reach its upstream.
+    pub fn observe_connect(&self, conn: &Connection, latency: Duration) {
+        self.connect_latency.observe(latency.as_secs_f64());
+        self.segment(conn)
+            .connect_latency
+            .observe(latency.as_secs_f64());
+        self.connect_heatmap.record(latency);
+    }
+
+    /// The segment `conn` is counted under: its listener, from its `route` tag, and its
+    /// `client_network` tag, as they were the first time it was asked for. Past
+    /// [`MAX_KEYS`] segments, as clients can choose tags, the rest share one.
+    pub fn segment(&self, conn: &Connection) -> Arc<Segment> {
+        conn.segment(|| {
+            let tags = conn.tags();
+            let label = |key: &str, default: &str| {
+                escape_label(tags.get(key).map_or(default, String::as_str))
+            };
+            let mut labels = format!(
+                "listener=\"{}\",client_network=\"{}\"",
+                label("route", DEFAULT_LISTENER),
+                label("client_network", crate::network::OTHER),
+            );
+            if self.segments.len() >= MAX_KEYS && self.segments.get(&labels).is_none() {
+                labels = format!("listener=\"{}\",client_network=\"{}\"", OTHER, OTHER);
+            }
+            let (latency, size) = &self.buckets;
+            let segment = Arc::new(Segment {
+                labels: labels.clone(),
+                active_connections: Default::default(),
+                completed_connections: Default::default(),
+                closed_bytes_up: Default::default(),
+                closed_bytes_down: Default::default(),
+                connect_latency: Histogram::new(latency.clone()),
+                connection_size_up: Histogram::new(size.clone()),
+                connection_size_down: Histogram::new(size.clone()),
+            });
+            self.segments
+                .with_entry(labels, segment, |segment| segment.clone())
+        })
+    }
+
+    /// Allocate an ID and a record for a newly accepted connection.
+    pub fn open_connection(
+        &self,
+        downstream_addr: SocketAddr,
+        upstream_addr: String,
+    ) -> Arc<Connection> {
+        let id = self.next_connection_id.fetch_add(1, Ordering::Relaxed) + 1;
+        self.upstream_connections_total
+            .with_capped_entry(upstream_addr.clone(), 0, |count| *count += 1);
+        self.upstream_open
+            .with_entry(upstream_addr.clone(), 0, |count| *count += 1);
+        let conn = Arc::new(Connection::new(id, downstream_addr, upstream_addr));
+        conn.set_toxics(*self.toxic_defaults.lock().unwrap());
+        conn.set_events(self.events.clone());
+        self.connections.insert(id, conn.clone());
+        self.events.send(Event::Accepted {
+            id,
+            downstream_addr,
+        });
+        conn
+    }
+
+    /// Mark `conn` closed. Its record is kept until [`CLOSED_HISTORY`] newer
+    /// connections have closed.
+    pub fn close_connection(&self, conn: &Connection, reason: String) {
+        let mut closed = self.closed.lock().unwrap();
+        if !conn.is_closed() {
+            self.upstream_open
+                .with_entry(conn.upstream_addr.clone(), 1, |count| *count -= 1);
+            self.upstream_open
+                .remove_if(&conn.upstream_addr, |count| *count == 0);
+            self.events.send(Event::Closed {
+                id: conn.id,
+                reason: reason.clone(),
+                bytes_up: conn.bytes_up.load(Ordering::Relaxed),
+                bytes_down: conn.bytes_down.load(Ordering::Relaxed),
+            });
+        }
+        conn.close(reason);
+        {
+            let mut closed_bytes = self.closed_bytes.lock().unwrap();
+            let key = if closed_bytes.len() < MAX_KEYS
+                || closed_bytes.contains_key(&conn.upstream_addr)
+            {
+                conn.upstream_addr.clone()
+            } else {
+                OTHER.to_string()
+            };
+            let entry = closed_bytes.entry(key).or_default();
+            entry.0 += conn.bytes_up.load(Ordering::Relaxed);
+            entry.1 += conn.bytes_down.load(Ordering::Relaxed);
+            let segment = self.segment(conn);
+            segment
+                .closed_bytes_up
+                .fetch_add(conn.bytes_up.load(Ordering::Relaxed), Ordering::Relaxed);
+            segment
+                .closed_bytes_down
+                .fetch_add(conn.bytes_down.load(Ordering::Relaxed), Ordering::Relaxed);
+        }
+        closed.push_back(conn.id);
+        if closed.len() > CLOSED_HISTORY {
+            if let Some(id) = closed.pop_front() {
+                self.connections.remove(&id);
+            }
+        }
+    }
+
+    /// Bytes copied up and down by every connection so far, open or closed.
+    pub fn bytes_total(&self) -> (u64, u64) {
+        self.bytes_by_upstream()
+            .values()
+            .fold((0, 0), |(up, down), (u, d)| (up + u, down + d))
+    }
+
+    /// Bytes copied up and down by every connection so far, by upstream address.
+    pub fn bytes_by_upstream(&self) -> HashMap<String, (u64, u64)> {
+        // With `closed` locked no connection can move from open to the closed totals
+        // partway through, so the totals never go backwards.
+        let _closed = self.closed.lock().unwrap();
+        let mut totals = self.closed_bytes.lock().unwrap().clone();
+        for conn in self.connections.snapshot().values() {
+            if !conn.is_closed() {
+                let entry = totals.entry(conn.upstream_addr.clone()).or_default();
+                entry.0 += conn.bytes_up.load(Ordering::Relaxed);
+                entry.1 += conn.bytes_down.load(Ordering::Relaxed);
+            }
+        }
+        totals
+    }
+
+    /// Bytes copied up and down by every connection so far, by segment labels.
+    pub fn bytes_by_segment(&self) -> HashMap<String, (u64, u64)> {
+        // As in `bytes_by_upstream`, `closed` keeps the totals from going backwards.
+        let _closed = self.closed.lock().unwrap();
+        let mut totals: HashMap<String, (u64, u64)> = self
+            .segments
+            .snapshot()
+            .into_iter()
+            .map(|(labels, segment)| {
+                let up = segment.closed_bytes_up.load(Ordering::Relaxed);
+                (
+                    labels,
+                    (up, segment.closed_bytes_down.load(Ordering::Relaxed)),
+                )
+            })
+            .collect();
+        for conn in self.connections.snapshot().values() {
+            if !conn.is_closed() {
+                let entry = totals.entry(self.segment(conn).labels.clone()).or_default();
+                entry.0 += conn.bytes_up.load(Ordering::Relaxed);
+                entry.1 += conn.bytes_down.load(Ordering::Relaxed);
+            }
+        }
+        totals
+    }
+
+    /// Add a closed connection to the totals for its values of `keys`.
+    pub fn record_tags(&self, conn: &Connection, keys: &[String]) {
+        if keys.is_empty() {
+            return;
+        }
+        let tags = conn.tags();
+        let labels: Vec<String> = keys
+            .iter()
+            .map(|k| {
+                let value = tags.get(k).map(String::as_str).unwrap_or("");
+                format!("{}=\"{}\"", k, escape_label(value))
+            })
+            .collect();
+        self.tagged
+            .with_entry(labels.join(","), TagStats::default(), |stats| {
+                stats.connections += 1;
+                stats.bytes_up += conn.bytes_up.load(Ordering::Relaxed);
+                stats.bytes_down += conn.bytes_down.load(Ordering::Relaxed);
+            });
+    }
+
+    pub fn route_opened(&self, route: &str) {
+        self.routes
+            .with_entry(route.to_string(), RouteStats::default(), |stats| {
+                stats.active_connections += 1;
+            });
+    }
+
+    pub fn route_closed(&self, route: &str, conn: &Connection) {
+        self.routes
+            .with_entry(route.to_string(), RouteStats::default(), |stats| {
+                stats.active_connections -= 1;
+                stats.connections += 1;
+                stats.bytes_up += conn.bytes_up.load(Ordering::Relaxed);
+                stats.bytes_down += conn.bytes_down.load(Ordering::Relaxed);
+            });
+    }
+
+    pub fn connection(&self, id: u64) -> Option<Arc<Connection>> {
+        self.connections.get(&id)
+    }
+
+    /// All connection records, ordered by ID.
+    pub fn connections(&self) -> Vec<Arc<Connection>> {
+        let mut conns: Vec<_> = self.connections.snapshot().into_values().collect();
+        conns.sort_by_key(|c| c.id);
+        conns
+    }
+}
+
+/// A hash map split across several independently locked shards.
+#[derive(Debug)]
+pub struct ShardedMap<K, V> {
+    hasher: RandomState,
+    shards: Vec<Mutex<HashMap<K, V>>>,
+}
+
+impl<K: Hash + Eq, V> Default for ShardedMap<K, V> {
+    fn default() -> Self {
+        Self {
+            hasher: RandomState::new(),
+            shards: (0..SHARDS).map(|_| Mutex::new(HashMap::new())).collect(),
+        }
+    }
+}
+
+impl<K: Hash + Eq, V> ShardedMap<K, V> {
+    fn shard(&self, key: &K) -> &Mutex<HashMap<K, V>> {
+        &self.shards[self.hasher.hash_one(key) as usize % self.shards.len()]
+    }
+
+    pub fn insert(&self, key: K, value: V) -> Option<V> {
+        self.shard(&key).lock().unwrap().insert(key, value)
+    }
+
+    pub fn remove(&self, key: &K) -> Option<V> {
+        self.shard(key).lock().unwrap().remove(key)
+    }
+
+    /// Remove the entry for `key` if `f` says to, checking and removing under the
+    /// same lock.
+    pub fn remove_if(&self, key: &K, f: impl FnOnce(&V) -> bool) -> Option<V> {
+        let mut shard = self.shard(key).lock().unwrap();
+        if shard.get(key).is_some_and(f) {
+            shard.remove(key)
+        } else {
+            None
+        }
+    }
+
+    /// Run `f` on the entry for `key` while holding its shard lock, so
+    /// read-modify-write updates are atomic per key.
+    pub fn with_entry<R>(&self, key: K, default: V, f: impl FnOnce(&mut V) -> R) -> R {
+        let mut shard = self.shard(&key).lock().unwrap();
+        f(shard.entry(key).or_insert(default))
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|s| s.lock().unwrap().len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<K: Hash + Eq + From<&'static str>, V> ShardedMap<K, V> {
+    /// As `with_entry`, but once the map has [`MAX_KEYS`] entries a new `key` shares
+    /// the [`OTHER`] entry instead. Concurrent inserts may overshoot the cap slightly.
+    pub fn with_capped_entry<R>(&self, key: K, default: V, f: impl FnOnce(&mut V) -> R) -> R {
+        let known = self.shard(&key).lock().unwrap().contains_key(&key);
+        let key = if known || self.len() < MAX_KEYS {
+            key
+        } else {
+            K::from(OTHER)
+        };
+        self.with_entry(key, default, f)
+    }
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> ShardedMap<K, V> {
+    pub fn get(&self, key: &K) -> Option<V> {
+        self.shard(key).lock().unwrap().get(key).cloned()
+    }
+
+    pub fn snapshot(&self) -> HashMap<K, V> {
+        let mut out = HashMap::new();
+        for shard in &self.shards {
+            let shard = shard.lock().unwrap();
+            out.extend(shard.iter().map(|(k, v)| (k.clone(), v.clone())));
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sharded_map() {
+        let map = ShardedMap::default();
+        for i in 0..100 {
+            map.insert(i, i * 2);
+        }
+        assert_eq!(map.remove(&7), Some(14));
+        assert_eq!(map.get(&7), None);
+        map.with_entry(7, 0, |v| *v += 1);
+        assert_eq!(map.get(&7), Some(1));
+        assert_eq!(map.with_entry(8, 0, |v| *v), 16);
+        assert_eq!(map.with_entry(100, 0, |v| *v), 0);
+        let snapshot = map.snapshot();
+        assert_eq!(snapshot.len(), 101);
+        assert_eq!(snapshot[&7], 1);
+    }
+
+    #[test]
+    fn test_segments() {
+        let state = State::new();
+        let client = "127.0.0.1:1234".parse().unwrap();
+        for i in 0..MAX_KEYS + 2 {
+            let conn = state.open_connection(client, "up:80".into());
+            conn.set_tags([("client_network".to_string(), Some(format!("net{}", i)))]);
+            state.segment(&conn);
+        }
+        let segments = state.segments.snapshot();
+        assert_eq!(segments.len(), MAX_KEYS + 1);
+        let other = &segments["listener=\"other\",client_network=\"other\""];
+        assert_eq!(other.labels, "listener=\"other\",client_network=\"other\"");
+        assert!(segments.contains_key("listener=\"default\",client_network=\"net0\""));
+    }
+
+    #[test]
+    fn test_capped_entries() {
+        let map: ShardedMap<String, u64> = ShardedMap::default();
+        for i in 0..MAX_KEYS + 10 {
+            map.with_capped_entry(format!("host{}", i), 0, |v| *v += 1);
+        }
+        map.with_capped_entry("host0".to_string(), 0, |v| *v += 1);
+        assert_eq!(map.len(), MAX_KEYS + 1);
+        assert_eq!(map.get(&OTHER.to_string()), Some(10));
+        assert_eq!(map.get(&"host0".to_string()), Some(2));
+    }
+}
@@ -0,0 +1,442 @@
+//! Latency and bandwidth toxics, applied to each direction of a connection as its
+//! bytes are forwarded.
+//!
+//! New connections start with the proxy-wide defaults, set with `--toxics` or through
+//! the API. Real access networks are asymmetric, so the defaults can differ by
+//! direction, and there are presets for common links.
+//!
+//! Connect toxics are separate: they act on the upstream dial, before any bytes flow,
+//! to exercise clients' connect timeouts and retries.
+
+use std::io;
+use std::str::FromStr;
+use std::time::Duration;
+
+use rand::Rng;
+use serde_json::{json, Value};
+
+use crate::connection::Direction;
+use crate::parse_duration;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Toxic {
+    /// Delay before forwarding each chunk of bytes.
+    pub latency: Duration,
+    /// Vary `latency` by up to this much either way.
+    pub jitter: Duration,
+    /// Bytes per second.
+    pub rate: Option<u64>,
+}
+
+impl Toxic {
+    /// How long to hold the next chunk back, if at all.
+    pub fn delay(&self) -> Option<Duration> {
+        if self.latency.is_zero() && self.jitter.is_zero() {
+            return None;
+        }
+        let jitter = self.jitter.as_secs_f64();
+        let delay = self.latency.as_secs_f64() + rand::thread_rng().gen_range(-jitter..=jitter);
+        Some(Duration::from_secs_f64(delay.max(0.0)))
+    }
+
+    /// Apply `{"latency": "200ms", "jitter": "50ms", "rate": "1MBps"}`, where each
+    /// field is optional and `null` clears it.
+    pub fn update(&mut self, body: &Value) -> Result<(), String> {
+        let fields = body.as_object().ok_or("expected an object of toxics")?;
+        let mut toxic = *self;
+        for (key, value) in fields {
+            let duration = || match value {
+                Value::Null => Ok(Duration::ZERO),
+                Value::String(s) => parse_duration(s),
+                _ => Err(format!("{} must be a duration or null", key)),
+            };
+            match key.as_str() {
+                "latency" => toxic.latency = duration()?,
+                "jitter" => toxic.jitter = duration()?,
+                "rate" => {
+                    toxic.rate = match value {
+                        Value::Null => None,
+                        Value::String(s) => Some(parse_rate(s)?),
+                        Value::Number(n) => Some(
+                            n.as_u64()
+                                .filter(|n| *n > 0)
+                                .ok_or("rate must be positive")?,
+                        ),
+                        _ => return Err("rate must be a rate such as 1MBps, or null".to_string()),
+                    }
+                }
+                _ => return Err(format!("unknown toxic: {}", key)),
+            }
+        }
+        *self = toxic;
+        Ok(())
+    }
+
+    pub fn to_json(self) -> Value {
+        json!({
+            "latency": format!("{}ms", self.latency.as_millis()),
+            "jitter": format!("{}ms", self.jitter.as_millis()),
+            "rate": self.rate,
+        })
+    }
+}
+
+/// The toxics for both directions of a connection.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Toxics {
+    pub up: Toxic,
+    pub down: Toxic,
+}
+
+impl Toxics {
+    pub fn get(&self, direction: Direction) -> Toxic {
+        match direction {
+            Direction::Up => self.up,
+            Direction::Down => self.down,
+        }
+    }
+
+    pub fn get_mut(&mut self, direction: Direction) -> &mut Toxic {
+        match direction {
+            Direction::Up => &mut self.up,
+            Direction::Down => &mut self.down,
+        }
+    }
+
+    /// Apply `Toxic::update` to one direction or both.
+    pub fn update(&mut self, direction: Option<Direction>, body: &Value) -> Result<(), String> {
+        let mut updated = *self;
+        for d in [Direction::Up, Direction::Down] {
+            if direction.is_none_or(|direction| direction == d) {
+                updated.get_mut(d).update(body)?;
+            }
+        }
+        *self = updated;
+        Ok(())
+    }
+
+    pub fn to_json(self) -> Value {
+        json!({ "up": self.up.to_json(), "down": self.down.to_json() })
+    }
+}
+
+/// Toxics for the upstream connect, set with `--connect-toxic` or through the API.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ConnectToxic {
+    /// Delay before dialing.
+    pub delay: Duration,
+    /// Chance, from 0 to 1, of failing the connect with ECONNREFUSED.
+    pub refuse: f64,
+    /// Chance of failing the connect with a timeout instead.
+    pub timeout: f64,
+    /// How long a connect that times out hangs first.
+    pub timeout_after: Duration,
+}
+
+impl Default for ConnectToxic {
+    fn default() -> Self {
+        ConnectToxic {
+            delay: Duration::ZERO,
+            refuse: 0.0,
+            timeout: 0.0,
+            timeout_after: Duration::from_secs(10),
+        }
+    }
+}
+
+impl ConnectToxic {
+    /// Apply `{"delay": "1s", "refuse": 0.5, "timeout": 0.1, "timeout_after": "30s"}`,
+    /// where each field is optional and `null` resets it.
+    pub fn update(&mut self, body: &Value) -> Result<(), String> {
+        let fields = body
+            .as_object()
+            .ok_or("expected an object of connect toxics")?;
+        let mut toxic = *self;
+        for (key, value) in fields {
+            let duration = |default: Duration| match value {
+                Value::Null => Ok(default),
+                Value::String(s) => parse_duration(s),
+                _ => Err(format!("{} must be a duration or null", key)),
+            };
+            let chance = || match value {
+                Value::Null => Ok(0.0),
+                Value::Number(n) => n
+                    .as_f64()
+                    .filter(|p| (0.0..=1.0).contains(p))
+                    .ok_or_else(|| format!("{} must be between 0 and 1", key)),
+                // From --connect-toxic.
+                Value::String(s) => s
+                    .parse()
+                    .ok()
+                    .filter(|p| (0.0..=1.0).contains(p))
+                    .ok_or_else(|| format!("{} must be between 0 and 1", key)),
+                _ => Err(format!("{} must be a number or null", key)),
+            };
+            match key.as_str() {
+                "delay" => toxic.delay = duration(Duration::ZERO)?,
+                "refuse" => toxic.refuse = chance()?,
+                "timeout" => toxic.timeout = chance()?,
+                "timeout_after" => toxic.timeout_after = duration(Self::default().timeout_after)?,
+                _ => return Err(format!("unknown connect toxic: {}", key)),
+            }
+        }
+        if toxic.refuse + toxic.timeout > 1.0 {
+            return Err("refuse and timeout can't add up to more than 1".to_string());
+        }
+        *self = toxic;
+        Ok(())
+    }
+
+    /// The error to fail a connect with, if it's chosen to fail, and how long to hang
+    /// before returning it.
+    pub fn fault(&self) -> Option<(Duration, io::Error)> {
+        if self.refuse == 0.0 && self.timeout == 0.0 {
+            return None;
+        }
+        let roll: f64 = rand::thread_rng().gen();
+        if roll < self.refuse {
+            let err = io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                "connection refused (injected)",
+            );
+            Some((Duration::ZERO, err))
+        } else if roll < self.refuse + self.timeout {
+            let err = io::Error::new(io::ErrorKind::TimedOut, "connect timed out (injected)");
+            Some((self.timeout_after, err))
+        } else {
+            None
+        }
+    }
+
+    pub fn to_json(self) -> Value {
+        json!({
+            "delay": format!("{}ms", self.delay.as_millis()),
+            "refuse": self.refuse,
+            "timeout": self.timeout,
+            "timeout_after": format!("{}ms", self.timeout_after.as_millis()),
+        })
+    }
+}
+
+impl FromStr for ConnectToxic {
+    type Err = String;
+
+    /// Parse `delay=200ms,refuse=0.1`, with keys as for `ConnectToxic::update`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut toxic = ConnectToxic::default();
+        toxic.update(&parse_fields(s)?)?;
+        Ok(toxic)
+    }
+}
+
+/// Presets for common access links, as `(name, down, up)` toxics. Upstream is towards
+/// the server: a client behind the proxy sees `down` on its downloads. Latency applies
+/// in each direction, so round trips take the sum of both.
+const PRESETS: &[(&str, &str, &str)] = &[
+    (
+        "dsl",
+        "latency=25ms,jitter=5ms,rate=16Mbps",
+        "latency=25ms,jitter=5ms,rate=1Mbps",
+    ),
+    (
+        "cable",
+        "latency=10ms,jitter=3ms,rate=100Mbps",
+        "latency=10ms,jitter=3ms,rate=10Mbps",
+    ),
+    (
+        "lte",
+        "latency=30ms,jitter=10ms,rate=20Mbps",
+        "latency=40ms,jitter=15ms,rate=5Mbps",
+    ),
+    (
+        "3g",
+        "latency=100ms,jitter=30ms,rate=1.6Mbps",
+        "latency=150ms,jitter=40ms,rate=768kbps",
+    ),
+    (
+        "satellite",
+        "latency=300ms,jitter=20ms,rate=25Mbps",
+        "latency=300ms,jitter=20ms,rate=3Mbps",
+    ),
+];
+
+/// A `--toxics` setting: a preset name, or `DIRECTION:KEY=VALUE,...` with DIRECTION
+/// `up`, `down` or `both` and keys as for `Toxic::update`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Setting {
+    updates: Vec<(Option<Direction>, Value)>,
+}
+
+impl Setting {
+    /// Apply the setting over `toxics`.
+    pub fn apply(&self, toxics: &mut Toxics) {
+        for (direction, body) in &self.updates {
+            toxics
+                .update(*direction, body)
+                .expect("settings are checked when parsed");
+        }
+    }
+}
+
+impl FromStr for Setting {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let updates = match PRESETS.iter().find(|(name, _, _)| *name == s) {
+            Some((_, down, up)) => vec![
+                (Some(Direction::Down), parse_fields(down)?),
+                (Some(Direction::Up), parse_fields(up)?),
+            ],
+            None => {
+                let (direction, fields) = s.split_once(':').ok_or_else(|| {
+                    let names: Vec<&str> = PRESETS.iter().map(|(name, _, _)| *name).collect();
+                    format!(
+                        "expected a preset ({}) or DIRECTION:KEY=VALUE,...: {}",
+                        names.join(", "),
+                        s
+                    )
+                })?;
+                let direction = match direction {
+                    "both" => None,
+                    direction => Some(direction.parse()?),
+                };
+                vec![(direction, parse_fields(fields)?)]
+            }
+        };
+        let setting = Setting { updates };
+        // Check every value now, so that applying can't fail.
+        for (direction, body) in &setting.updates {
+            Toxics::default().update(*direction, body)?;
+        }
+        Ok(setting)
+    }
+}
+
+/// Parse `latency=20ms,rate=5Mbps` into an object for `Toxic::update`.
+fn parse_fields(s: &str) -> Result<Value, String> {
+    let mut fields = serde_json::Map::new();
+    for field in s.split(',') {
+        let (key, value) = field
+            .split_once('=')
+            .ok_or_else(|| format!("expected KEY=VALUE: {}", field))?;
+        fields.insert(key.trim().to_string(), Value::from(value.trim()));
+    }
+    Ok(Value::Object(fields))
+}
+
+/// Parse a rate such as `500KBps`, `1.5MBps` or `10Mbps` into bytes per second. Units
+/// are decimal; a bare number is bytes per second.
+pub fn parse_rate(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(s.len());
+    let (value, unit) = s.split_at(split);
+    let value: f64 = value.parse().map_err(|_| format!("invalid rate: {}", s))?;
+    let unit = unit.trim();
+    let (prefix, bits) = if let Some(prefix) = unit.strip_suffix("Bps") {
+        (prefix, false)
+    } else if let Some(prefix) = unit.strip_suffix("bps") {
+        (prefix, true)
+    } else if unit.is_empty() {
+        ("", false)
+    } else {
+        return Err(format!("invalid rate unit: {}", unit));
+    };
+    let scale = match prefix {
+        "" => 1.0,
+        "K" | "k" => 1e3,
+        "M" => 1e6,
+        "G" => 1e9,
+        _ => return Err(format!("invalid rate unit: {}", unit)),
+    };
+    let rate = value * scale / if bits { 8.0 } else { 1.0 };
+    if rate < 1.0 {
+        return Err(format!("rate must be at least 1 byte per second: {}", s));
+    }
+    Ok(rate as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_rate() {
+        assert_eq!(parse_rate("1MBps"), Ok(1_000_000));
+        assert_eq!(parse_rate("500KBps"), Ok(500_000));
+        assert_eq!(parse_rate("10Mbps"), Ok(1_250_000));
+        assert_eq!(parse_rate("1.5kBps"), Ok(1500));
+        assert_eq!(parse_rate("64"), Ok(64));
+        assert!(parse_rate("fast").is_err());
+        assert!(parse_rate("1TBps").is_err());
+        assert!(parse_rate("0").is_err());
+    }
+
+    #[test]
+    fn test_update() {
+        let mut toxic = Toxic::default();
+        toxic
+            .update(&json!({ "latency": "200ms", "rate": "1KBps" }))
+            .unwrap();
+        assert_eq!(toxic.latency, Duration::from_millis(200));
+        assert_eq!(toxic.rate, Some(1000));
+        assert_eq!(toxic.delay(), Some(Duration::from_millis(200)));
+
+        toxic.update(&json!({ "rate": null })).unwrap();
+        assert_eq!(toxic.rate, None);
+        assert!(toxic.update(&json!({ "latency": 5 })).is_err());
+        assert!(toxic.update(&json!({ "loss": "1" })).is_err());
+        assert_eq!(toxic.latency, Duration::from_millis(200));
+    }
+
+    #[test]
+    fn test_setting() {
+        let mut toxics = Toxics::default();
+        "lte".parse::<Setting>().unwrap().apply(&mut toxics);
+        assert_eq!(toxics.down.rate, Some(2_500_000));
+        assert_eq!(toxics.up.rate, Some(625_000));
+        assert_eq!(toxics.up.latency, Duration::from_millis(40));
+
+        // Later settings override earlier ones.
+        "up:latency=80ms,rate=5Mbps"
+            .parse::<Setting>()
+            .unwrap()
+            .apply(&mut toxics);
+        assert_eq!(toxics.up.latency, Duration::from_millis(80));
+        assert_eq!(toxics.up.jitter, Duration::from_millis(15));
+        assert_eq!(toxics.down.latency, Duration::from_millis(30));
+
+        for preset in PRESETS {
+            assert!(preset.0.parse::<Setting>().is_ok());
+        }
+        assert!("dialup".parse::<Setting>().is_err());
+        assert!("both:latency".parse::<Setting>().is_err());
+        assert!("both:rate=fast".parse::<Setting>().is_err());
+        assert!("left:rate=1MBps".parse::<Setting>().is_err());
+    }
+
+    #[test]
+    fn test_connect_toxic() {
+        let toxic: ConnectToxic = "delay=200ms,refuse=1".parse().unwrap();
+        assert_eq!(toxic.delay, Duration::from_millis(200));
+        let (hang, err) = toxic.fault().unwrap();
+        assert_eq!(hang, Duration::ZERO);
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+
+        let mut toxic = ConnectToxic::default();
+        assert!(toxic.fault().is_none());
+        toxic
+            .update(&json!({ "timeout": 1.0, "timeout_after": "2s" }))
+            .unwrap();
+        let (hang, err) = toxic.fault().unwrap();
+        assert_eq!(hang, Duration::from_secs(2));
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        assert!(toxic.update(&json!({ "refuse": 0.5 })).is_err());
+        assert!(toxic.update(&json!({ "timeout": 2 })).is_err());
+        assert!(toxic.update(&json!({ "reset": 1 })).is_err());
+        toxic.update(&json!({ "timeout": null })).unwrap();
+        assert!(toxic.fault().is_none());
+    }
+}
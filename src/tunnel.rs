@@ -0,0 +1,474 @@
+//! `--tunnel` and `--accept-tunnel`: many connections multiplexed over one persistent
+//! connection between two tproxies, for when only a single port crosses a firewall
+//! between test networks.
+//!
+//! The tproxy with `--tunnel` has the other as its upstream, and opens a stream on the
+//! tunnel for each connection it accepts. The other, run with `--accept-tunnel`, proxies
+//! each stream to its own `--upstream-addr`. The tunnel is dialed when the first
+//! connection arrives, and again for the next one after it breaks.
+//!
+//! Frames are a stream ID (u32), a type (u8) and a length (u32), all big-endian, then
+//! for DATA that many bytes. Each side may have `WINDOW` bytes of a stream in flight,
+//! and is granted more with WINDOW frames (whose length is the grant) as the other side
+//! delivers them, so a stream whose reader is slow doesn't hold up the rest.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter, DuplexStream};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, oneshot, Semaphore};
+use tracing::{info, warn};
+
+use crate::connection::{Connection, Direction, COPY_BUFFER_SIZE};
+use crate::state::State;
+use crate::{connect_upstream, track_close, track_open, Args};
+
+/// Bytes of a stream either side may send before the other grants it more.
+const WINDOW: usize = 256 * 1024;
+
+/// The largest DATA frame we accept.
+const MAX_DATA_LEN: usize = 64 * 1024;
+
+#[derive(Debug, PartialEq)]
+enum Frame {
+    Open(u32),
+    Data(u32, Vec<u8>),
+    /// No more data on the stream from the sender.
+    Fin(u32),
+    /// The stream is abandoned in both directions.
+    Reset(u32),
+    Window(u32, u32),
+}
+
+impl Frame {
+    fn encode(&self) -> Vec<u8> {
+        let (id, kind, len, data): (u32, u8, u32, &[u8]) = match self {
+            Frame::Open(id) => (*id, 0, 0, &[]),
+            Frame::Data(id, data) => (*id, 1, data.len() as u32, data),
+            Frame::Fin(id) => (*id, 2, 0, &[]),
+            Frame::Reset(id) => (*id, 3, 0, &[]),
+            Frame::Window(id, grant) => (*id, 4, *grant, &[]),
+        };
+        let mut frame = Vec::with_capacity(9 + data.len());
+        frame.extend_from_slice(&id.to_be_bytes());
+        frame.push(kind);
+        frame.extend_from_slice(&len.to_be_bytes());
+        frame.extend_from_slice(data);
+        frame
+    }
+
+    /// Read the next frame, or `None` once the tunnel is closed.
+    async fn read<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Option<Frame>> {
+        let mut header = [0; 9];
+        match reader.read_exact(&mut header).await {
+            Ok(_) => {}
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err),
+        }
+        let id = u32::from_be_bytes(header[..4].try_into().unwrap());
+        let len = u32::from_be_bytes(header[5..].try_into().unwrap());
+        let frame = match header[4] {
+            0 => Frame::Open(id),
+            1 if len as usize <= MAX_DATA_LEN => {
+                let mut data = vec![0; len as usize];
+                reader.read_exact(&mut data).await?;
+                Frame::Data(id, data)
+            }
+            2 => Frame::Fin(id),
+            3 => Frame::Reset(id),
+            4 => Frame::Window(id, len),
+            kind => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid tunnel frame; type={} length={}", kind, len),
+                ))
+            }
+        };
+        Ok(Some(frame))
+    }
+}
+
+/// One end of a tunnel.
+#[derive(Debug)]
+pub struct Session {
+    frames: mpsc::Sender<Frame>,
+    streams: Mutex<HashMap<u32, Stream>>,
+    next_id: AtomicU32,
+    closed: AtomicBool,
+}
+
+#[derive(Debug)]
+struct Stream {
+    /// Data from the other side, until it sends FIN.
+    inbound: Option<mpsc::UnboundedSender<Vec<u8>>>,
+    /// How much more we may send.
+    credit: Arc<Semaphore>,
+    /// Dropped to abandon the stream.
+    _reset: oneshot::Sender<()>,
+}
+
+impl Session {
+    /// Run a tunnel over `socket`, returning the streams the other side opens.
+    fn start(socket: TcpStream) -> (Arc<Session>, mpsc::UnboundedReceiver<DuplexStream>) {
+        let (frames, outgoing) = mpsc::channel(256);
+        let session = Arc::new(Session {
+            frames,
+            streams: Mutex::default(),
+            next_id: AtomicU32::new(1),
+            closed: AtomicBool::new(false),
+        });
+        let (opened, accepted) = mpsc::unbounded_channel();
+        let (reader, writer) = socket.into_split();
+        tokio::spawn(write(session.clone(), writer, outgoing));
+        tokio::spawn(session.clone().read(reader, opened));
+        (session, accepted)
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Relaxed)
+    }
+
+    /// Open a stream to the other side.
+    async fn open(self: &Arc<Self>) -> io::Result<DuplexStream> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let stream = self.add_stream(id);
+        self.send(Frame::Open(id)).await?;
+        Ok(stream)
+    }
+
+    async fn send(&self, frame: Frame) -> io::Result<()> {
+        self.frames
+            .send(frame)
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "tunnel closed"))
+    }
+
+    /// Abandon every stream.
+    fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+        self.streams.lock().unwrap().clear();
+    }
+
+    /// Start pumping stream `id` between its frames and the returned end of a pipe.
+    fn add_stream(self: &Arc<Self>, id: u32) -> DuplexStream {
+        let (local, pumped) = tokio::io::duplex(COPY_BUFFER_SIZE * 8);
+        let (inbound, rx) = mpsc::unbounded_channel();
+        let credit = Arc::new(Semaphore::new(WINDOW));
+        let (reset, reset_rx) = oneshot::channel();
+        let stream = Stream {
+            inbound: Some(inbound),
+            credit: credit.clone(),
+            _reset: reset,
+        };
+        self.streams.lock().unwrap().insert(id, stream);
+        tokio::spawn(self.clone().pump(id, pumped, rx, credit, reset_rx));
+        local
+    }
+
+    async fn pump(
+        self: Arc<Self>,
+        id: u32,
+        stream: DuplexStream,
+        mut inbound: mpsc::UnboundedReceiver<Vec<u8>>,
+        credit: Arc<Semaphore>,
+        reset: oneshot::Receiver<()>,
+    ) {
+        let (mut reader, mut writer) = tokio::io::split(stream);
+        let outbound = async {
+            let mut buf = vec![0; COPY_BUFFER_SIZE];
+            loop {
+                let n = reader.read(&mut buf).await?;
+                if n == 0 {
+                    return self.send(Frame::Fin(id)).await;
+                }
+                // The semaphore is never closed; it's dropped with the stream.
+                credit.acquire_many(n as u32).await.unwrap().forget();
+                self.send(Frame::Data(id, buf[..n].to_vec())).await?;
+            }
+        };
+        let inbound = async {
+            while let Some(data) = inbound.recv().await {
+                writer.write_all(&data).await?;
+                self.send(Frame::Window(id, data.len() as u32)).await?;
+            }
+            writer.shutdown().await
+        };
+        let result = tokio::select! {
+            result = async { tokio::try_join!(outbound, inbound) } => result.map(|_| ()),
+            // The other side reset the stream, or the tunnel closed.
+            _ = reset => Ok(()),
+        };
+        if self.streams.lock().unwrap().remove(&id).is_some() && result.is_err() {
+            let _ = self.send(Frame::Reset(id)).await;
+        }
+    }
+
+    async fn read(
+        self: Arc<Self>,
+        mut reader: tokio::net::tcp::OwnedReadHalf,
+        opened: mpsc::UnboundedSender<DuplexStream>,
+    ) {
+        loop {
+            let frame = match Frame::read(&mut reader).await {
+                Ok(Some(frame)) => frame,
+                Ok(None) => break,
+                Err(err) => {
+                    warn!(error = %err, "failed to read from tunnel");
+                    break;
+                }
+            };
+            match frame {
+                Frame::Open(id) => {
+                    let stream = self.add_stream(id);
+                    if opened.send(stream).is_err() {
+                        self.streams.lock().unwrap().remove(&id);
+                        let _ = self.send(Frame::Reset(id)).await;
+                    }
+                }
+                // Frames for streams we've let go of are dropped.
+                Frame::Data(id, data) => {
+                    if let Some(stream) = self.streams.lock().unwrap().get(&id) {
+                        if let Some(inbound) = &stream.inbound {
+                            let _ = inbound.send(data);
+                        }
+                    }
+                }
+                Frame::Fin(id) => {
+                    if let Some(stream) = self.streams.lock().unwrap().get_mut(&id) {
+                        stream.inbound = None;
+                    }
+                }
+                Frame::Reset(id) => {
+                    self.streams.lock().unwrap().remove(&id);
+                }
+                Frame::Window(id, grant) => {
+                    if let Some(stream) = self.streams.lock().unwrap().get(&id) {
+                        stream.credit.add_permits(grant as usize);
+                    }
+                }
+            }
+        }
+        self.close();
+    }
+}
+
+async fn write(
+    session: Arc<Session>,
+    writer: tokio::net::tcp::OwnedWriteHalf,
+    mut outgoing: mpsc::Receiver<Frame>,
+) {
+    let mut writer = BufWriter::new(writer);
+    let written = async {
+        while let Some(frame) = outgoing.recv().await {
+            writer.write_all(&frame.encode()).await?;
+            // Batch whatever else is ready into the same write.
+            while let Ok(frame) = outgoing.try_recv() {
+                writer.write_all(&frame.encode()).await?;
+            }
+            writer.flush().await?;
+        }
+        Ok::<_, io::Error>(())
+    };
+    if let Err(err) = written.await {
+        warn!(error = %err, "failed to write to tunnel");
+    }
+    session.close();
+}
+
+/// The `--tunnel` sessions, one per peer.
+#[derive(Debug, Default)]
+pub struct Tunnels(tokio::sync::Mutex<HashMap<String, Arc<Session>>>);
+
+impl Tunnels {
+    /// The open session to `peer`, dialing one if there isn't one.
+    async fn session(&self, peer: &str, args: &Args, state: &State) -> io::Result<Arc<Session>> {
+        // Held while dialing, so a burst of connections shares one new tunnel.
+        let mut sessions = self.0.lock().await;
+        if let Some(session) = sessions.get(peer).filter(|session| !session.is_closed()) {
+            return Ok(session.clone());
+        }
+        let socket = connect_upstream(peer, args, state).await?;
+        socket.set_nodelay(true)?;
+        let (session, _) = Session::start(socket);
+        info!(%peer, "tunnel opened");
+        sessions.insert(peer.to_string(), session.clone());
+        Ok(session)
+    }
+
+    #[cfg(test)]
+    pub async fn len(&self) -> usize {
+        self.0.lock().await.len()
+    }
+}
+
+/// Proxy a connection accepted with `--tunnel` over a stream to the peer.
+pub async fn proxy(
+    downstream: TcpStream,
+    args: &Args,
+    state: &State,
+    conn: &Connection,
+) -> Result<(), Box<dyn Error>> {
+    let connect_start = Instant::now();
+    let session = state
+        .tunnels
+        .session(&args.upstream_addr, args, state)
+        .await?;
+    let upstream = session.open().await?;
+    state.observe_connect(conn, connect_start.elapsed());
+    conn.connected();
+    track_open(state, conn);
+    let result = splice(downstream, upstream, conn).await;
+    track_close(state, conn, result.is_ok());
+    Ok(result?)
+}
+
+/// Serve a tunnel from a `--tunnel` peer, proxying each of its streams to the upstream.
+pub async fn accept(socket: TcpStream, args: Arc<Args>, state: Arc<State>, peer: SocketAddr) {
+    if let Err(err) = socket.set_nodelay(true) {
+        warn!(error = %err, "failed to set TCP_NODELAY");
+    }
+    let (_session, mut opened) = Session::start(socket);
+    info!(%peer, "tunnel opened");
+    while let Some(stream) = opened.recv().await {
+        let args = args.clone();
+        let state = state.clone();
+        tokio::spawn(async move {
+            let conn = state.open_connection(peer, args.upstream_addr.clone());
+            conn.set_tags([("tunnel.peer".to_string(), Some(peer.to_string()))]);
+            let result = serve(stream, &args, &state, &conn).await;
+            let reason = match &result {
+                Ok(()) => "completed".to_string(),
+                Err(err) => {
+                    warn!(id = conn.id, error = %err, "failed to forward");
+                    err.to_string()
+                }
+            };
+            state.record_tags(&conn, &args.metrics_tag_key);
+            state.close_connection(&conn, reason);
+        });
+    }
+    info!(%peer, "tunnel closed");
+}
+
+async fn serve(
+    downstream: DuplexStream,
+    args: &Args,
+    state: &State,
+    conn: &Connection,
+) -> io::Result<()> {
+    let connect_start = Instant::now();
+    let upstream = connect_upstream(&args.upstream_addr, args, state).await?;
+    state.observe_connect(conn, connect_start.elapsed());
+    conn.connected();
+    track_open(state, conn);
+    let result = splice(downstream, upstream, conn).await;
+    track_close(state, conn, result.is_ok());
+    result
+}
+
+/// Forward bytes both ways between `downstream` and `upstream` until both are done.
+async fn splice<D, U>(downstream: D, upstream: U, conn: &Connection) -> io::Result<()>
+where
+    D: AsyncRead + AsyncWrite,
+    U: AsyncRead + AsyncWrite,
+{
+    let (mut ri, mut wi) = tokio::io::split(downstream);
+    let (mut ro, mut wo) = tokio::io::split(upstream);
+    let client_to_server = async {
+        conn.forward(&mut ri, &mut wo, Direction::Up, |_| {})
+            .await?;
+        wo.shutdown().await
+    };
+    let server_to_client = async {
+        conn.forward(&mut ro, &mut wi, Direction::Down, |_| {})
+            .await?;
+        wi.shutdown().await
+    };
+    tokio::try_join!(client_to_server, server_to_client)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use clap::Parser;
+    use futures::FutureExt;
+    use tokio::net::TcpListener;
+
+    async fn proxy_to(upstream: &str, flag: &str, state: &Arc<State>) -> SocketAddr {
+        let args = Args::parse_from([
+            "tproxy",
+            "--listen-addr",
+            "127.0.0.1:0",
+            "--upstream-addr",
+            upstream,
+            flag,
+        ]);
+        let (tx, rx) = oneshot::channel();
+        tokio::spawn(crate::listen(args, state.clone(), tx).map(|r| {
+            if let Err(err) = r {
+                println!("failed to listen; error={}", err);
+            }
+        }));
+        rx.await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_tunnel() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let (mut r, mut w) = stream.split();
+                    let _ = tokio::io::copy(&mut r, &mut w).await;
+                });
+            }
+        });
+        let far = Arc::new(State::new());
+        let peer = proxy_to(&upstream, "--accept-tunnel", &far).await;
+        let near = Arc::new(State::new());
+        let addr = proxy_to(&peer.to_string(), "--tunnel", &near).await;
+
+        // More than a window's worth on each stream, all at once.
+        let clients = (0..4u8).map(|i| async move {
+            let mut client = TcpStream::connect(addr).await.unwrap();
+            let sent = vec![b'a' + i; WINDOW * 2];
+            let (mut r, mut w) = client.split();
+            let write = async {
+                w.write_all(&sent).await.unwrap();
+                w.shutdown().await.unwrap();
+            };
+            let mut echoed = Vec::new();
+            let read = r.read_to_end(&mut echoed);
+            let (_, n) = tokio::join!(write, read);
+            assert_eq!(n.unwrap(), sent.len());
+            assert!(echoed == sent);
+        });
+        futures::future::join_all(clients).await;
+
+        assert_eq!(near.tunnels.len().await, 1);
+        crate::tests::wait_for(&far, |s| s.completed_connections == 4).await;
+        let conn = &far.connections()[0];
+        assert!(conn.tags().contains_key("tunnel.peer"));
+
+        let frame = Frame::Window(7, 1024);
+        let mut encoded = frame.encode();
+        encoded.extend_from_slice(&Frame::Data(7, b"hi".to_vec()).encode());
+        let mut reader = &encoded[..];
+        assert_eq!(Frame::read(&mut reader).await.unwrap(), Some(frame));
+        assert_eq!(
+            Frame::read(&mut reader).await.unwrap(),
+            Some(Frame::Data(7, b"hi".to_vec()))
+        );
+        assert_eq!(Frame::read(&mut reader).await.unwrap(), None);
+    }
+}
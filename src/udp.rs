@@ -0,0 +1,664 @@
+//! Datagram proxying for `--mode udp` and `--mode quic`.
+//!
+//! Each client gets a session with its own upstream socket, so replies can be told
+//! apart and sent back to the right client. A session ends once it has been idle
+//! for `--udp-session-timeout`. Sessions are recorded as connections, so they show
+//! up in the API and UI like TCP connections do.
+//!
+//! In UDP mode sessions are keyed by the client's address. In QUIC mode they are
+//! keyed by QUIC connection ID instead: the IDs the server chooses are learned from
+//! its long header packets, and a client packet carrying a known ID is routed to
+//! its existing session even if it arrives from a new address, which is what
+//! happens when a client migrates or its NAT rebinds. Packets are never decrypted.
+//!
+//! `--udp-fault` drops, duplicates or reorders whole datagrams, as a lossy network
+//! would, to exercise clients' retransmission. A reordered datagram is held back and
+//! sent after the next one going the same way, or on its own once it has been held
+//! for `REORDER_DELAY`.
+
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::io;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+use tokio::net::UdpSocket;
+use tokio::sync::oneshot;
+use tracing::warn;
+
+use crate::connection::{Connection, Direction};
+use crate::state::State;
+use crate::{track_close, track_open, Args, Mode};
+
+/// Large enough for any UDP payload.
+const MAX_DATAGRAM_SIZE: usize = 65535;
+
+/// The most datagrams held for a client while its session is opened; more are dropped.
+const MAX_QUEUED: usize = 64;
+
+/// The longest a reordered datagram waits for another to overtake it.
+const REORDER_DELAY: Duration = Duration::from_millis(20);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FaultKind {
+    Drop,
+    Duplicate,
+    Reorder,
+}
+
+/// `KIND:PROBABILITY[:DIRECTION]`, where the kind is one of `drop`, `duplicate` or
+/// `reorder`, e.g. `drop:0.1` or `reorder:0.05:down`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Fault {
+    pub kind: FaultKind,
+    pub probability: f64,
+    /// Both if `None`.
+    pub direction: Option<Direction>,
+}
+
+impl FromStr for Fault {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(3, ':');
+        let kind = match parts.next() {
+            Some("drop") => FaultKind::Drop,
+            Some("duplicate") => FaultKind::Duplicate,
+            Some("reorder") => FaultKind::Reorder,
+            _ => return Err(format!("invalid udp fault kind: {}", s)),
+        };
+        let probability = parts
+            .next()
+            .and_then(|p| p.parse::<f64>().ok())
+            .filter(|p| (0.0..=1.0).contains(p))
+            .ok_or_else(|| format!("invalid fault probability: {}", s))?;
+        let direction = parts.next().map(str::parse).transpose()?;
+        Ok(Fault {
+            kind,
+            probability,
+            direction,
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Session {
+    upstream: UdpSocket,
+    /// Where replies go; this changes when a QUIC client migrates.
+    client: Mutex<SocketAddr>,
+    last_active: Mutex<Instant>,
+    /// Datagrams held back by a reorder fault, up then down.
+    held: [Mutex<Option<Vec<u8>>>; 2],
+    conn: Arc<Connection>,
+}
+
+impl Session {
+    fn touch(&self) {
+        *self.last_active.lock().unwrap() = Instant::now();
+    }
+
+    /// The datagrams to send for `packet` going `direction` after `faults`: none, the
+    /// packet, the packet twice, or it followed by one held back before.
+    fn faulted<'a>(
+        &self,
+        faults: &[Fault],
+        direction: Direction,
+        packet: &'a [u8],
+        state: &State,
+    ) -> Vec<Cow<'a, [u8]>> {
+        if faults.is_empty() {
+            return vec![Cow::Borrowed(packet)];
+        }
+        let mut rng = rand::thread_rng();
+        let fault = faults
+            .iter()
+            .filter(|f| f.direction.is_none_or(|d| d == direction))
+            .find(|f| rng.gen_bool(f.probability));
+        if fault.is_some() {
+            state.udp_faults_injected.fetch_add(1, Ordering::Relaxed);
+        }
+        let mut held = self.held[direction as usize].lock().unwrap();
+        let mut out = match fault.map(|f| f.kind) {
+            Some(FaultKind::Drop) => vec![],
+            Some(FaultKind::Duplicate) => vec![Cow::Borrowed(packet), Cow::Borrowed(packet)],
+            // Only one is held back at a time; behind another, this one goes as usual.
+            Some(FaultKind::Reorder) if held.is_none() => {
+                *held = Some(packet.to_vec());
+                return vec![];
+            }
+            _ => vec![Cow::Borrowed(packet)],
+        };
+        out.extend(held.take().map(Cow::Owned));
+        out
+    }
+
+    fn holding(&self, direction: Direction) -> bool {
+        self.held[direction as usize].lock().unwrap().is_some()
+    }
+
+    /// Send `packet` upstream, or to the client through `socket` if going down.
+    async fn send(&self, direction: Direction, packet: &[u8], socket: &UdpSocket) {
+        let (sent, bytes) = match direction {
+            Direction::Up => (self.upstream.send(packet).await, &self.conn.bytes_up),
+            Direction::Down => {
+                let client = *self.client.lock().unwrap();
+                (socket.send_to(packet, client).await, &self.conn.bytes_down)
+            }
+        };
+        match sent {
+            Ok(n) => {
+                bytes.fetch_add(n as u64, Ordering::Relaxed);
+            }
+            Err(err) if direction == Direction::Up => {
+                warn!(id = self.conn.id, error = %err, "failed to send upstream")
+            }
+            Err(err) => warn!(id = self.conn.id, error = %err, "failed to send downstream"),
+        }
+    }
+}
+
+/// Send the datagrams for `packet` going `direction` after `--udp-fault`s, releasing
+/// one held back by a reorder fault after `REORDER_DELAY` if nothing overtakes it.
+async fn forward(
+    args: &Args,
+    state: &State,
+    socket: &Arc<UdpSocket>,
+    session: &Arc<Session>,
+    direction: Direction,
+    packet: &[u8],
+) {
+    for packet in session.faulted(&args.udp_fault, direction, packet, state) {
+        session.send(direction, &packet, socket).await;
+    }
+    // Only this task holds datagrams going this way, so one held now is this packet.
+    if session.holding(direction) {
+        let (socket, session) = (socket.clone(), session.clone());
+        tokio::spawn(async move {
+            tokio::time::sleep(REORDER_DELAY).await;
+            let held = session.held[direction as usize].lock().unwrap().take();
+            if let Some(packet) = held {
+                session.send(direction, &packet, &socket).await;
+            }
+        });
+    }
+}
+
+#[derive(Debug, Default)]
+struct Routes {
+    by_addr: HashMap<SocketAddr, Arc<Session>>,
+    by_cid: HashMap<Vec<u8>, Arc<Session>>,
+    /// The lengths of the connection IDs in `by_cid`. Short header packets don't
+    /// say how long their destination ID is, so each known length is tried.
+    cid_lens: HashSet<usize>,
+    /// Datagrams from clients whose sessions are being opened, oldest first.
+    opening: HashMap<SocketAddr, Vec<Vec<u8>>>,
+}
+
+/// What became of a datagram from a client without a session.
+#[derive(Debug, PartialEq)]
+enum Queued {
+    /// It's the first, so the session needs opening.
+    Opening,
+    /// The session is already being opened.
+    Waiting,
+    /// There are already `--max-connections` sessions.
+    Refused,
+}
+
+impl Routes {
+    /// Find the session for a client packet, following QUIC connection IDs in QUIC
+    /// mode before falling back to the client's address.
+    fn lookup(&mut self, mode: Mode, packet: &[u8], from: SocketAddr) -> Option<Arc<Session>> {
+        if mode == Mode::Quic {
+            if let Some(session) = self.lookup_cid(packet) {
+                let mut client = session.client.lock().unwrap();
+                if *client != from {
+                    self.by_addr.remove(&client);
+                    self.by_addr.insert(from, session.clone());
+                    *client = from;
+                }
+                drop(client);
+                return Some(session);
+            }
+        }
+        self.by_addr.get(&from).cloned()
+    }
+
+    fn lookup_cid(&self, packet: &[u8]) -> Option<Arc<Session>> {
+        if let Some((dcid, _)) = long_header_cids(packet) {
+            return self.by_cid.get(dcid).cloned();
+        }
+        self.cid_lens.iter().find_map(|len| {
+            let dcid = packet.get(1..1 + len)?;
+            self.by_cid.get(dcid).cloned()
+        })
+    }
+
+    fn add_cid(&mut self, cid: &[u8], session: &Arc<Session>) {
+        if cid.is_empty() || self.by_cid.contains_key(cid) {
+            return;
+        }
+        self.cid_lens.insert(cid.len());
+        self.by_cid.insert(cid.to_vec(), session.clone());
+    }
+
+    /// Hold `packet` from `from` until its session is open, unless that would make more
+    /// than `max_sessions` (if not 0).
+    fn queue(&mut self, from: SocketAddr, packet: &[u8], max_sessions: usize) -> Queued {
+        if let Some(queued) = self.opening.get_mut(&from) {
+            if queued.len() < MAX_QUEUED {
+                queued.push(packet.to_vec());
+            }
+            return Queued::Waiting;
+        }
+        if max_sessions > 0 && self.by_addr.len() + self.opening.len() >= max_sessions {
+            return Queued::Refused;
+        }
+        self.opening.insert(from, vec![packet.to_vec()]);
+        Queued::Opening
+    }
+
+    fn remove(&mut self, session: &Arc<Session>) {
+        self.by_addr.retain(|_, s| !Arc::ptr_eq(s, session));
+        self.by_cid.retain(|_, s| !Arc::ptr_eq(s, session));
+        self.cid_lens = self.by_cid.keys().map(|cid| cid.len()).collect();
+    }
+}
+
+/// The destination and source connection IDs of a QUIC long header packet.
+fn long_header_cids(packet: &[u8]) -> Option<(&[u8], &[u8])> {
+    // Long headers have the high bit set, followed by a 4 byte version.
+    if packet.first()? & 0x80 == 0 {
+        return None;
+    }
+    let dcid_len = *packet.get(5)? as usize;
+    let dcid = packet.get(6..6 + dcid_len)?;
+    let scid_len = *packet.get(6 + dcid_len)? as usize;
+    let scid_start = 7 + dcid_len;
+    let scid = packet.get(scid_start..scid_start + scid_len)?;
+    Some((dcid, scid))
+}
+
+/// A UDP socket connected to `addr`, bound to an ephemeral port of the same family.
+pub async fn connect(addr: &str, state: &State) -> io::Result<UdpSocket> {
+    let addr = state
+        .resolver
+        .resolve(addr)
+        .await?
+        .into_iter()
+        .next()
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "upstream address resolved to nothing",
+            )
+        })?;
+    let local: SocketAddr = if addr.is_ipv4() {
+        ([0, 0, 0, 0], 0).into()
+    } else {
+        ([0u16; 8], 0).into()
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(addr).await?;
+    Ok(socket)
+}
+
+/// Receive datagrams and forward them to the upstream, like `listen` does for
+/// streams. Sessions are opened in their own tasks, so a slow upstream lookup only
+/// holds up the client waiting for it.
+pub async fn listen(
+    args: Arc<Args>,
+    state: Arc<State>,
+    ready: oneshot::Sender<SocketAddr>,
+) -> Result<(), Box<dyn Error>> {
+    let socket = Arc::new(UdpSocket::bind(&args.listen_addr).await?);
+    let _ = ready.send(socket.local_addr()?);
+    let routes = Arc::new(Mutex::new(Routes::default()));
+
+    let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+    loop {
+        let (n, from) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(err) => {
+                warn!(error = %err, "failed to receive");
+                state.accept_errors.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+        };
+        let packet = &buf[..n];
+        let session = {
+            let mut known = routes.lock().unwrap();
+            match known.lookup(args.mode, packet, from) {
+                Some(session) => session,
+                None => {
+                    match known.queue(from, packet, args.max_connections) {
+                        Queued::Opening => {
+                            tokio::spawn(open(
+                                args.clone(),
+                                state.clone(),
+                                socket.clone(),
+                                routes.clone(),
+                                from,
+                            ));
+                        }
+                        Queued::Waiting => {}
+                        Queued::Refused => {
+                            state.overflow_rejections.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                    continue;
+                }
+            }
+        };
+        session.touch();
+        forward(&args, &state, &socket, &session, Direction::Up, packet).await;
+    }
+}
+
+/// Open a session for `from`, then send on the datagrams it sent meanwhile.
+async fn open(
+    args: Arc<Args>,
+    state: Arc<State>,
+    socket: Arc<UdpSocket>,
+    routes: Arc<Mutex<Routes>>,
+    from: SocketAddr,
+) {
+    let upstream = match connect(&args.upstream_addr, &state).await {
+        Ok(upstream) => upstream,
+        Err(err) => {
+            routes.lock().unwrap().opening.remove(&from);
+            warn!(client = %from, error = %err, "failed to open session");
+            return;
+        }
+    };
+
+    let conn = state.open_connection(from, args.upstream_addr.clone());
+    crate::network::tag(&args.client_network, &conn);
+    conn.connected();
+    track_open(&state, &conn);
+    let session = Arc::new(Session {
+        upstream,
+        client: Mutex::new(from),
+        last_active: Mutex::new(Instant::now()),
+        held: Default::default(),
+        conn,
+    });
+    // Until the server answers, a QUIC client addresses it by the ID it made up.
+    let dcid = match args.mode {
+        Mode::Quic => routes
+            .lock()
+            .unwrap()
+            .opening
+            .get(&from)
+            .and_then(|queued| long_header_cids(queued.first()?))
+            .map(|(dcid, _)| dcid.to_vec()),
+        _ => None,
+    };
+    tokio::spawn(reply(
+        args.clone(),
+        state.clone(),
+        socket.clone(),
+        routes.clone(),
+        session.clone(),
+    ));
+    // Send what was queued, in order, until nothing more has been; only then is the
+    // session routed to, so one task sends up at a time, as `forward` needs.
+    loop {
+        let queued = {
+            let mut routes = routes.lock().unwrap();
+            let queued = routes.opening.get_mut(&from).map(std::mem::take);
+            if queued.as_ref().is_none_or(Vec::is_empty) {
+                routes.opening.remove(&from);
+                routes.by_addr.insert(from, session.clone());
+                if let Some(dcid) = &dcid {
+                    routes.add_cid(dcid, &session);
+                }
+                break;
+            }
+            queued.unwrap_or_default()
+        };
+        for packet in queued {
+            forward(&args, &state, &socket, &session, Direction::Up, &packet).await;
+        }
+    }
+}
+
+/// Send the upstream's replies for a session back to its client, until the session
+/// has been idle for too long.
+async fn reply(
+    args: Arc<Args>,
+    state: Arc<State>,
+    socket: Arc<UdpSocket>,
+    routes: Arc<Mutex<Routes>>,
+    session: Arc<Session>,
+) {
+    let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+    let reason = loop {
+        let idle = session.last_active.lock().unwrap().elapsed();
+        let remaining = match args.udp_session_timeout.checked_sub(idle) {
+            Some(remaining) if !remaining.is_zero() => remaining,
+            _ => break "idle".to_string(),
+        };
+        let n = match tokio::time::timeout(remaining, session.upstream.recv(&mut buf)).await {
+            // Look again; the client may have kept the session alive meanwhile.
+            Err(_) => continue,
+            Ok(Err(err)) => break err.to_string(),
+            Ok(Ok(n)) => n,
+        };
+        let packet = &buf[..n];
+        if args.mode == Mode::Quic {
+            if let Some((_, scid)) = long_header_cids(packet) {
+                routes.lock().unwrap().add_cid(scid, &session);
+            }
+        }
+        session.touch();
+        forward(&args, &state, &socket, &session, Direction::Down, packet).await;
+    };
+    routes.lock().unwrap().remove(&session);
+    track_close(&state, &session.conn, reason == "idle");
+    state.close_connection(&session.conn, reason);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use clap::Parser;
+    use futures::FutureExt;
+
+    /// A QUIC long header packet with the given connection IDs.
+    fn long_header(dcid: &[u8], scid: &[u8]) -> Vec<u8> {
+        let mut out = vec![0xc0, 0, 0, 0, 1, dcid.len() as u8];
+        out.extend_from_slice(dcid);
+        out.push(scid.len() as u8);
+        out.extend_from_slice(scid);
+        out.extend_from_slice(b"payload");
+        out
+    }
+
+    /// Start a UDP echo server and a proxy in front of it, with any extra `flags`.
+    async fn start(mode: &str, flags: &[&str]) -> (SocketAddr, Arc<State>) {
+        let echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let echo_addr = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+            loop {
+                let (n, from) = echo.recv_from(&mut buf).await.unwrap();
+                // Reply with a long header carrying the server's connection ID, then
+                // echo the datagram.
+                echo.send_to(&long_header(b"", b"server-cid"), from)
+                    .await
+                    .unwrap();
+                echo.send_to(&buf[..n], from).await.unwrap();
+            }
+        });
+        let echo_addr = echo_addr.to_string();
+        let args = Args::parse_from(
+            [
+                "tproxy",
+                "--listen-addr",
+                "127.0.0.1:0",
+                "--upstream-addr",
+                &echo_addr,
+                "--mode",
+                mode,
+                "--udp-session-timeout",
+                "200ms",
+            ]
+            .iter()
+            .chain(flags),
+        );
+        let state = Arc::new(State::new());
+        let (tx, rx) = oneshot::channel();
+        tokio::spawn(listen(Arc::new(args), state.clone(), tx).map(|r| {
+            if let Err(err) = r {
+                println!("failed to listen; error={}", err);
+            }
+        }));
+        (rx.await.unwrap(), state)
+    }
+
+    async fn exchange(client: &UdpSocket, proxy: SocketAddr, packet: &[u8]) -> Vec<u8> {
+        client.send_to(packet, proxy).await.unwrap();
+        let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+        // Skip the server's connection ID announcement.
+        client.recv(&mut buf).await.unwrap();
+        let n = client.recv(&mut buf).await.unwrap();
+        buf[..n].to_vec()
+    }
+
+    #[test]
+    fn test_long_header_cids() {
+        let packet = long_header(b"abcd", b"xy");
+        assert_eq!(long_header_cids(&packet), Some((&b"abcd"[..], &b"xy"[..])));
+        assert_eq!(long_header_cids(b"\x40abcd"), None);
+        assert_eq!(long_header_cids(b"\xc0\0\0\0\x01\x10ab"), None);
+    }
+
+    #[tokio::test]
+    async fn test_faulted() {
+        let state = State::new();
+        let client = "127.0.0.1:1234".parse().unwrap();
+        let session = Session {
+            upstream: UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+            client: Mutex::new(client),
+            last_active: Mutex::new(Instant::now()),
+            held: Default::default(),
+            conn: state.open_connection(client, "127.0.0.1:53".into()),
+        };
+        let faulted = |fault: &str, direction, packet: &'static [u8]| -> Vec<Vec<u8>> {
+            let faults = [fault.parse().unwrap()];
+            session
+                .faulted(&faults, direction, packet, &state)
+                .into_iter()
+                .map(Cow::into_owned)
+                .collect()
+        };
+        assert!(faulted("drop:1", Direction::Up, b"a").is_empty());
+        assert_eq!(faulted("drop:1:down", Direction::Up, b"a"), [b"a"]);
+        assert_eq!(faulted("duplicate:1", Direction::Down, b"a"), [b"a", b"a"]);
+        assert!(faulted("reorder:1", Direction::Up, b"a").is_empty());
+        assert_eq!(faulted("drop:0", Direction::Down, b"b"), [b"b"]);
+        assert_eq!(faulted("reorder:1", Direction::Up, b"b"), [b"b", b"a"]);
+        assert_eq!(state.snapshot().udp_faults_injected, 4);
+
+        assert!("drop".parse::<Fault>().is_err());
+        assert!("drop:2".parse::<Fault>().is_err());
+        assert!("drop:0.5:sideways".parse::<Fault>().is_err());
+        assert!("jitter:0.5".parse::<Fault>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_udp() {
+        let (proxy, state) = start("udp", &[]).await;
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(proxy).await.unwrap();
+        assert_eq!(exchange(&client, proxy, b"hello").await, b"hello");
+        assert_eq!(exchange(&client, proxy, b"again").await, b"again");
+        assert_eq!(state.snapshot().active_connections, 1);
+
+        crate::tests::wait_for(&state, |s| s.completed_connections == 1).await;
+        assert_eq!(state.snapshot().active_connections, 0);
+    }
+
+    #[tokio::test]
+    async fn test_max_sessions() {
+        let (proxy, state) = start("udp", &["--max-connections", "1"]).await;
+        let first = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        first.connect(proxy).await.unwrap();
+        assert_eq!(exchange(&first, proxy, b"hello").await, b"hello");
+
+        let second = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        second.connect(proxy).await.unwrap();
+        second.send(b"hello").await.unwrap();
+        let mut buf = [0; 16];
+        let reply = tokio::time::timeout(Duration::from_millis(100), second.recv(&mut buf));
+        assert!(reply.await.is_err());
+        assert_eq!(state.snapshot().overflow_rejections, 1);
+        assert_eq!(exchange(&first, proxy, b"again").await, b"again");
+    }
+
+    #[test]
+    fn test_queue() {
+        let mut routes = Routes::default();
+        let (a, b) = (
+            "127.0.0.1:1".parse().unwrap(),
+            "127.0.0.1:2".parse().unwrap(),
+        );
+        assert_eq!(routes.queue(a, b"1", 1), Queued::Opening);
+        assert_eq!(routes.queue(a, b"2", 1), Queued::Waiting);
+        assert_eq!(routes.queue(b, b"1", 1), Queued::Refused);
+        assert_eq!(routes.queue(b, b"1", 0), Queued::Opening);
+        for _ in 0..MAX_QUEUED {
+            routes.queue(a, b"more", 0);
+        }
+        assert_eq!(routes.opening[&a].len(), MAX_QUEUED);
+        assert_eq!(routes.opening[&a][..2], [b"1".to_vec(), b"2".to_vec()]);
+    }
+
+    #[tokio::test]
+    async fn test_lone_reordered_datagram() {
+        let (proxy, state) = start("udp", &["--udp-fault", "reorder:1:up"]).await;
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(proxy).await.unwrap();
+        // Nothing follows it to overtake it, so it goes on its own after a while.
+        let sent = Instant::now();
+        let echoed =
+            tokio::time::timeout(Duration::from_secs(5), exchange(&client, proxy, b"hello"))
+                .await
+                .unwrap();
+        assert_eq!(echoed, b"hello");
+        assert!(sent.elapsed() >= REORDER_DELAY);
+        assert_eq!(state.snapshot().udp_faults_injected, 1);
+    }
+
+    #[tokio::test]
+    async fn test_quic_migration() {
+        let (proxy, state) = start("quic", &[]).await;
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(proxy).await.unwrap();
+        let initial = long_header(b"client-chosen", b"client-cid");
+        assert_eq!(exchange(&client, proxy, &initial).await, initial);
+
+        // The client moves to a new address and sends a short header packet to the
+        // server's connection ID.
+        let migrated = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        migrated.connect(proxy).await.unwrap();
+        let mut short = vec![0x40];
+        short.extend_from_slice(b"server-cid");
+        short.extend_from_slice(b"payload");
+        assert_eq!(exchange(&migrated, proxy, &short).await, short);
+
+        let conns = state.connections();
+        assert_eq!(conns.len(), 1);
+        assert_eq!(
+            conns[0].bytes_up.load(Ordering::Relaxed),
+            (initial.len() + short.len()) as u64
+        );
+    }
+}
@@ -0,0 +1,313 @@
+//! WebSocket awareness for TCP mode.
+//!
+//! Each direction of a proxied connection gets a [`Tracker`] which looks at the
+//! first bytes for an HTTP/1 head. If the client asks to upgrade to a WebSocket
+//! and the server answers `101 Switching Protocols`, the trackers switch to parsing
+//! frame headers from then on, counting frames and messages per direction. Any
+//! other traffic is left alone after its first bytes. Only the first request on a
+//! connection is considered, which is where browsers and clients upgrade.
+
+use serde_json::{json, Value};
+
+use crate::connection::{Connection, Direction};
+
+/// Give up looking for an HTTP head after this many bytes.
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+
+/// The longest frame header: 2 bytes, an 8 byte length and a 4 byte mask.
+const MAX_FRAME_HEADER_SIZE: usize = 14;
+
+/// Frame and message counts for one direction of a WebSocket.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct DirectionStats {
+    pub frames: u64,
+    pub control_frames: u64,
+    pub messages: u64,
+    pub message_bytes: u64,
+    pub largest_message: u64,
+}
+
+impl DirectionStats {
+    fn to_json(&self) -> Value {
+        json!({
+            "frames": self.frames,
+            "control_frames": self.control_frames,
+            "messages": self.messages,
+            "message_bytes": self.message_bytes,
+            "largest_message": self.largest_message,
+        })
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Stats {
+    pub up: DirectionStats,
+    pub down: DirectionStats,
+}
+
+impl Stats {
+    pub fn direction(&mut self, direction: Direction) -> &mut DirectionStats {
+        match direction {
+            Direction::Up => &mut self.up,
+            Direction::Down => &mut self.down,
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({ "up": self.up.to_json(), "down": self.down.to_json() })
+    }
+}
+
+#[derive(Debug)]
+enum Phase {
+    /// Buffering what may be an HTTP head.
+    Head(Vec<u8>),
+    Frames,
+    /// Not (or no longer) a WebSocket we understand; ignore the rest.
+    Opaque,
+}
+
+/// Watches the bytes flowing in one direction of a connection.
+#[derive(Debug)]
+pub struct Tracker {
+    direction: Direction,
+    phase: Phase,
+    /// A partially received frame header.
+    header: Vec<u8>,
+    /// Payload bytes left to skip in the current frame.
+    remaining: u64,
+    /// Payload bytes so far in the current (possibly fragmented) message.
+    message: u64,
+}
+
+impl Tracker {
+    pub fn new(direction: Direction) -> Self {
+        Self {
+            direction,
+            phase: Phase::Head(Vec::new()),
+            header: Vec::new(),
+            remaining: 0,
+            message: 0,
+        }
+    }
+
+    /// Inspect the next chunk of bytes, recording what's seen on `conn`.
+    pub fn feed(&mut self, data: &[u8], conn: &Connection) {
+        if let Phase::Head(buf) = &mut self.phase {
+            buf.extend_from_slice(data);
+            match parse_head(self.direction, buf) {
+                Head::Partial if buf.len() < MAX_HEAD_SIZE => return,
+                Head::Upgrade(len) => {
+                    if self.direction == Direction::Down {
+                        conn.set_websocket();
+                    }
+                    // Whatever followed the head is the start of the first frame.
+                    let rest = buf.split_off(len);
+                    self.phase = Phase::Frames;
+                    self.frames(&rest, conn);
+                    return;
+                }
+                _ => {
+                    self.phase = Phase::Opaque;
+                    return;
+                }
+            }
+        }
+        if let Phase::Frames = self.phase {
+            self.frames(data, conn);
+        }
+    }
+
+    fn frames(&mut self, mut data: &[u8], conn: &Connection) {
+        while !data.is_empty() {
+            if self.remaining > 0 {
+                let n = self.remaining.min(data.len() as u64);
+                self.remaining -= n;
+                data = &data[n as usize..];
+                continue;
+            }
+            let take = (MAX_FRAME_HEADER_SIZE - self.header.len()).min(data.len());
+            self.header.extend_from_slice(&data[..take]);
+            let frame = match parse_frame_header(&self.header) {
+                Some(frame) => frame,
+                None => {
+                    data = &data[take..];
+                    continue;
+                }
+            };
+            // Hand back what we took beyond the header; it's payload or the next frame.
+            let unused = self.header.len() - frame.header_len;
+            data = &data[take - unused..];
+            self.header.clear();
+            self.remaining = frame.payload_len;
+            self.record(&frame, conn);
+        }
+    }
+
+    fn record(&mut self, frame: &Frame, conn: &Connection) {
+        let control = frame.opcode & 0x8 != 0;
+        if !control {
+            self.message += frame.payload_len;
+        }
+        let message = (!control && frame.fin).then_some(self.message);
+        if message.is_some() {
+            self.message = 0;
+        }
+        // Until the server's 101 has been seen this records nothing, so frames sent
+        // after a refused upgrade aren't counted.
+        conn.update_websocket(|stats| {
+            let stats = stats.direction(self.direction);
+            stats.frames += 1;
+            if control {
+                stats.control_frames += 1;
+            }
+            if let Some(size) = message {
+                stats.messages += 1;
+                stats.message_bytes += size;
+                stats.largest_message = stats.largest_message.max(size);
+            }
+        });
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Head {
+    Partial,
+    /// A WebSocket upgrade request or acceptance, with the length of its head.
+    Upgrade(usize),
+    Other,
+}
+
+fn parse_head(direction: Direction, buf: &[u8]) -> Head {
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let (parsed, upgrade) = match direction {
+        Direction::Up => {
+            let mut req = httparse::Request::new(&mut headers);
+            let parsed = req.parse(buf);
+            (parsed, is_upgrade(req.headers))
+        }
+        Direction::Down => {
+            let mut resp = httparse::Response::new(&mut headers);
+            let parsed = resp.parse(buf);
+            (parsed, resp.code == Some(101) && is_upgrade(resp.headers))
+        }
+    };
+    match parsed {
+        Ok(httparse::Status::Partial) => Head::Partial,
+        Ok(httparse::Status::Complete(len)) if upgrade => Head::Upgrade(len),
+        _ => Head::Other,
+    }
+}
+
+fn is_upgrade(headers: &[httparse::Header]) -> bool {
+    headers.iter().any(|h| {
+        h.name.eq_ignore_ascii_case("upgrade")
+            && std::str::from_utf8(h.value)
+                .is_ok_and(|v| v.trim().eq_ignore_ascii_case("websocket"))
+    })
+}
+
+#[derive(Debug, PartialEq)]
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    header_len: usize,
+    payload_len: u64,
+}
+
+/// Parse a frame header from the start of `buf`, or `None` if it's incomplete.
+fn parse_frame_header(buf: &[u8]) -> Option<Frame> {
+    let (b0, b1) = (*buf.first()?, *buf.get(1)?);
+    let masked = b1 & 0x80 != 0;
+    let (payload_len, mut header_len) = match b1 & 0x7f {
+        126 => (
+            u16::from_be_bytes(buf.get(2..4)?.try_into().ok()?) as u64,
+            4,
+        ),
+        127 => (u64::from_be_bytes(buf.get(2..10)?.try_into().ok()?), 10),
+        n => (n as u64, 2),
+    };
+    if masked {
+        header_len += 4;
+    }
+    if buf.len() < header_len {
+        return None;
+    }
+    Some(Frame {
+        fin: b0 & 0x80 != 0,
+        opcode: b0 & 0x0f,
+        header_len,
+        payload_len,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REQUEST: &[u8] = b"GET /chat HTTP/1.1\r\nHost: example.com\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\n";
+    const RESPONSE: &[u8] =
+        b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\n";
+
+    /// A masked frame, as a client sends them.
+    fn frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mut out = vec![(fin as u8) << 7 | opcode];
+        match payload.len() {
+            n if n < 126 => out.push(0x80 | n as u8),
+            n => {
+                out.push(0x80 | 126);
+                out.extend_from_slice(&(n as u16).to_be_bytes());
+            }
+        }
+        out.extend_from_slice(&[1, 2, 3, 4]);
+        out.extend_from_slice(payload);
+        out
+    }
+
+    #[test]
+    fn test_tracker() {
+        let conn = Connection::new(1, "127.0.0.1:1234".parse().unwrap(), "up:80".into());
+        let mut up = Tracker::new(Direction::Up);
+        let mut down = Tracker::new(Direction::Down);
+
+        // Split the head across reads.
+        up.feed(&REQUEST[..10], &conn);
+        up.feed(&REQUEST[10..], &conn);
+        down.feed(RESPONSE, &conn);
+        assert!(conn.is_websocket());
+
+        let mut data = frame(false, 1, &[b'a'; 300]);
+        data.extend(frame(true, 0, b"bc"));
+        data.extend(frame(true, 9, b"ping"));
+        data.extend(frame(true, 2, b"xyz"));
+        // Feed a byte at a time so that headers and payloads straddle reads.
+        for b in &data {
+            up.feed(std::slice::from_ref(b), &conn);
+        }
+
+        let detail = conn.detail();
+        let stats = &detail["websocket_frames"]["up"];
+        assert_eq!(stats["frames"], 4);
+        assert_eq!(stats["control_frames"], 1);
+        assert_eq!(stats["messages"], 2);
+        assert_eq!(stats["message_bytes"], 305);
+        assert_eq!(stats["largest_message"], 302);
+        assert_eq!(detail["websocket_frames"]["down"]["frames"], 0);
+    }
+
+    #[test]
+    fn test_not_websocket() {
+        let conn = Connection::new(1, "127.0.0.1:1234".parse().unwrap(), "up:80".into());
+        let mut up = Tracker::new(Direction::Up);
+        let mut down = Tracker::new(Direction::Down);
+        up.feed(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n", &conn);
+        down.feed(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n", &conn);
+        up.feed(&frame(true, 1, b"hi"), &conn);
+        assert!(!conn.is_websocket());
+        assert!(matches!(up.phase, Phase::Opaque));
+
+        let mut tls = Tracker::new(Direction::Up);
+        tls.feed(&[0x16, 0x03, 0x01, 0x00, 0x10], &conn);
+        assert!(matches!(tls.phase, Phase::Opaque));
+    }
+}
@@ -0,0 +1,125 @@
+//! Zone-aware balancing, for `--zone-upstream` and `--zone`.
+//!
+//! Each upstream is tagged with the zone (failure domain) it runs in. New connections
+//! go to a random upstream in the proxy's own zone, spilling over to other zones only
+//! while every local upstream is failing: its last `--probe-interval` probe failed, or
+//! a connect to it failed in the last [`FAILURE_COOLDOWN`], or it's ejected by
+//! `--eject-after`. Bytes are counted by the
+//! zone they crossed from and to, so multi-AZ traffic patterns can be measured.
+
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use rand::seq::SliceRandom;
+
+use crate::breaker;
+use crate::state::State;
+
+/// How long an upstream is passed over after a connect to it fails.
+pub const FAILURE_COOLDOWN: Duration = Duration::from_secs(10);
+
+/// The zone reported for the proxy itself without `--zone`.
+pub const UNKNOWN: &str = "unknown";
+
+/// An upstream and its zone, from ZONE=ADDR.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ZonedUpstream {
+    pub zone: String,
+    pub addr: String,
+}
+
+impl FromStr for ZonedUpstream {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((zone, addr)) if !zone.is_empty() && !addr.is_empty() => Ok(ZonedUpstream {
+                zone: zone.to_string(),
+                addr: addr.to_string(),
+            }),
+            _ => Err(format!("zoned upstreams are ZONE=ADDR: {}", s)),
+        }
+    }
+}
+
+/// Whether `addr` has failed recently, so shouldn't be given new connections.
+fn is_failing(state: &State, addr: &str, now: Instant) -> bool {
+    let probe_failed = state
+        .probes
+        .get(&addr.to_string())
+        .is_some_and(|probe| probe.result.is_err());
+    let connect_failed = state
+        .upstream_failures
+        .get(&addr.to_string())
+        .is_some_and(|at| now.duration_since(at) < FAILURE_COOLDOWN);
+    probe_failed || connect_failed || breaker::is_ejected(state, addr)
+}
+
+/// The upstream for a new connection: one in `local` if any there is healthy, else a
+/// healthy one in another zone, else any at all.
+pub fn pick<'a>(
+    upstreams: &'a [ZonedUpstream],
+    local: Option<&str>,
+    state: &State,
+) -> Option<&'a ZonedUpstream> {
+    let now = Instant::now();
+    let healthy: Vec<_> = upstreams
+        .iter()
+        .filter(|u| !is_failing(state, &u.addr, now))
+        .collect();
+    let same_zone: Vec<_> = healthy
+        .iter()
+        .copied()
+        .filter(|u| Some(u.zone.as_str()) == local)
+        .collect();
+    let mut rng = rand::thread_rng();
+    same_zone
+        .choose(&mut rng)
+        .or_else(|| healthy.choose(&mut rng))
+        .copied()
+        .or_else(|| upstreams.choose(&mut rng))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::probe::Probe;
+    use std::time::SystemTime;
+
+    #[test]
+    fn test_pick() {
+        let upstreams: Vec<ZonedUpstream> = ["a=10.0.0.1:80", "a=10.0.0.2:80", "b=10.0.1.1:80"]
+            .iter()
+            .map(|s| s.parse().unwrap())
+            .collect();
+        assert!("10.0.0.1:80".parse::<ZonedUpstream>().is_err());
+        let state = State::new();
+        let picked = |local| pick(&upstreams, local, &state).unwrap().addr.clone();
+
+        for _ in 0..20 {
+            assert!(picked(Some("a")).starts_with("10.0.0."));
+            assert_eq!(picked(Some("b")), "10.0.1.1:80");
+        }
+
+        // Zone a spills over to b once both its upstreams fail.
+        state
+            .upstream_failures
+            .insert("10.0.0.1:80".to_string(), Instant::now());
+        assert_eq!(picked(Some("a")), "10.0.0.2:80");
+        state.probes.insert(
+            "10.0.0.2:80".to_string(),
+            Probe {
+                result: Err("connection refused".to_string()),
+                at: SystemTime::now(),
+            },
+        );
+        assert_eq!(picked(Some("a")), "10.0.1.1:80");
+
+        // With nothing healthy, any upstream will do.
+        state
+            .upstream_failures
+            .insert("10.0.1.1:80".to_string(), Instant::now());
+        assert!(picked(Some("a")).starts_with("10."));
+    }
+}